@@ -9,8 +9,10 @@ GEN_TEST() {
 GEN_TEST chanmon_deser
 GEN_TEST chanmon_consistency
 GEN_TEST full_stack
+GEN_TEST http_parser
 GEN_TEST peer_crypt
 GEN_TEST router
+GEN_TEST wire_replay
 
 GEN_TEST msg_accept_channel msg_targets::
 GEN_TEST msg_announcement_signatures msg_targets::
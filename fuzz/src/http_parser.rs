@@ -0,0 +1,61 @@
+use lightning_block_sync::http_parser::HttpResponseParser;
+
+use utils::test_logger;
+
+/// Bitcoin Core's REST/RPC responses may be a few MB, use something a bit smaller so that we
+/// still hit the size limit.
+const MAX_BODY_LEN: usize = 65536;
+
+#[inline]
+pub fn do_test(data: &[u8]) {
+	if data.is_empty() { return; }
+	// Use the first byte to decide how many bytes we feed the parser at a time, checking that the
+	// result doesn't depend on how the response is split across reads.
+	let step = data[0] as usize + 1;
+	let data = &data[1..];
+
+	let mut oneshot = HttpResponseParser::new(MAX_BODY_LEN);
+	let oneshot_res = oneshot.feed(data);
+
+	let mut incremental = HttpResponseParser::new(MAX_BODY_LEN);
+	let mut incremental_res = Ok(0);
+	for chunk in data.chunks(step) {
+		match incremental.feed(chunk) {
+			Ok(consumed) => {
+				assert!(consumed <= chunk.len());
+				incremental_res = incremental_res.map(|total| total + consumed);
+				if consumed != chunk.len() {
+					assert!(incremental.is_complete());
+					break;
+				}
+			},
+			Err(e) => {
+				incremental_res = Err(e);
+				break;
+			},
+		}
+		if incremental.is_complete() { break; }
+	}
+	assert_eq!(oneshot_res, incremental_res);
+	if oneshot_res.is_err() { return; }
+
+	assert_eq!(oneshot.is_complete(), incremental.is_complete());
+	if !oneshot.is_complete() {
+		assert_eq!(oneshot.eof(), incremental.eof());
+	}
+	if let Some(resp) = oneshot.into_response() {
+		assert!(resp.body.len() <= MAX_BODY_LEN);
+		assert_eq!(Some(resp), incremental.into_response());
+	} else {
+		assert!(incremental.into_response().is_none());
+	}
+}
+
+pub fn http_parser_test<Out: test_logger::Output>(data: &[u8], _out: Out) {
+	do_test(data);
+}
+
+#[no_mangle]
+pub extern "C" fn http_parser_run(data: *const u8, datalen: usize) {
+	do_test(unsafe { std::slice::from_raw_parts(data, datalen) });
+}
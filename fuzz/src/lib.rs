@@ -1,5 +1,6 @@
 extern crate bitcoin;
 extern crate lightning;
+extern crate lightning_block_sync;
 extern crate hex;
 
 pub mod utils;
@@ -7,7 +8,9 @@ pub mod utils;
 pub mod chanmon_deser;
 pub mod chanmon_consistency;
 pub mod full_stack;
+pub mod http_parser;
 pub mod peer_crypt;
 pub mod router;
+pub mod wire_replay;
 
 pub mod msg_targets;
@@ -1 +1,2 @@
+pub mod byte_utils;
 pub mod test_logger;
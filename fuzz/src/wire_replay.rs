@@ -0,0 +1,182 @@
+//! Replays a recording of the messages a node received from its peers (see
+//! lightning::ln::wire_recorder) against a ChannelManager, its ChannelMonitors and a
+//! NetGraphMsgHandler restored from a snapshot of that node taken before the recording started.
+//!
+//! The input is, in order:
+//!  * the node's 32-byte KeysManager seed,
+//!  * a 2-byte count of ChannelMonitors, each of which is given as a 4-byte length followed by
+//!    the monitor as written by ChannelMonitor::write_for_disk,
+//!  * a 4-byte length followed by the ChannelManager as written by ChannelManager::write (or a
+//!    length of 0 to start from a fresh ChannelManager),
+//!  * a 4-byte length followed by the NetworkGraph (or a length of 0 for an empty graph),
+//!  * the recording itself, as written by WireRecordWriter.
+//!
+//! All lengths are big-endian. Built in fuzztarget mode signatures are not checked, so a snapshot
+//! and recording taken from a production node can be replayed to reproduce an incident (and
+//! then minimized, like any other fuzz input).
+
+use bitcoin::hash_types::BlockHash;
+use bitcoin::network::constants::Network;
+use bitcoin::blockdata::transaction::Transaction;
+
+use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator, ChainWatchInterfaceUtil};
+use lightning::chain::keysinterface::{InMemoryChannelKeys, KeysManager};
+use lightning::chain::transaction::OutPoint;
+use lightning::ln::channelmonitor::{ChannelMonitor, ManyChannelMonitor, SimpleManyChannelMonitor};
+use lightning::ln::channelmanager::{ChannelManager, ChannelManagerReadArgs};
+use lightning::ln::wire_recorder::{read_recording, replay_recording};
+use lightning::routing::network_graph::{NetGraphMsgHandler, NetworkGraph};
+use lightning::util::config::UserConfig;
+use lightning::util::events::{EventsProvider, MessageSendEventsProvider};
+use lightning::util::logger::Logger;
+use lightning::util::ser::{Readable, ReadableArgs};
+
+use utils::byte_utils::{slice_to_be16, slice_to_be32};
+use utils::test_logger;
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, RwLock};
+
+struct FuzzEstimator {}
+impl FeeEstimator for FuzzEstimator {
+	fn get_est_sat_per_1000_weight(&self, _: ConfirmationTarget) -> u64 {
+		253
+	}
+}
+
+struct TestBroadcaster {}
+impl BroadcasterInterface for TestBroadcaster {
+	fn broadcast_transaction(&self, _tx: &Transaction) {}
+}
+
+type ReplayChannelMonitor = SimpleManyChannelMonitor<OutPoint, InMemoryChannelKeys, Arc<TestBroadcaster>, Arc<FuzzEstimator>, Arc<dyn Logger>, Arc<ChainWatchInterfaceUtil>>;
+type ReplayChannelManager = ChannelManager<InMemoryChannelKeys, Arc<ReplayChannelMonitor>, Arc<TestBroadcaster>, Arc<KeysManager>, Arc<FuzzEstimator>, Arc<dyn Logger>>;
+
+#[inline]
+pub fn do_test<Out: test_logger::Output>(data: &[u8], out: Out) {
+	let logger: Arc<dyn Logger> = Arc::new(test_logger::TestLogger::new("".to_owned(), out));
+
+	let mut read_pos = 0;
+	macro_rules! get_slice {
+		($len: expr) => {
+			{
+				let len = $len as usize;
+				if data.len() < read_pos + len {
+					return;
+				}
+				read_pos += len;
+				&data[read_pos - len..read_pos]
+			}
+		}
+	}
+
+	let mut seed = [0; 32];
+	seed.copy_from_slice(get_slice!(32));
+	let keys_manager = Arc::new(KeysManager::new(&seed, Network::Bitcoin, 0, 0));
+	let watch = Arc::new(ChainWatchInterfaceUtil::new(Network::Bitcoin));
+	let broadcast = Arc::new(TestBroadcaster {});
+	let fee_est = Arc::new(FuzzEstimator {});
+	let monitor = Arc::new(SimpleManyChannelMonitor::new(watch.clone(), broadcast.clone(), Arc::clone(&logger), fee_est.clone()));
+
+	let mut monitors = HashMap::new();
+	for _ in 0..slice_to_be16(get_slice!(2)) {
+		let monitor_len = slice_to_be32(get_slice!(4));
+		let channel_monitor = match <(BlockHash, ChannelMonitor<InMemoryChannelKeys>)>::read(&mut Cursor::new(get_slice!(monitor_len))) {
+			Ok((_, channel_monitor)) => channel_monitor,
+			Err(_) => return,
+		};
+		monitors.insert(channel_monitor.get_funding_txo(), channel_monitor);
+	}
+
+	let manager_len = slice_to_be32(get_slice!(4));
+	let channelmanager: ReplayChannelManager = if manager_len == 0 {
+		match ChannelManager::new(Network::Bitcoin, fee_est.clone(), monitor.clone(), broadcast.clone(), Arc::clone(&logger), keys_manager.clone(), UserConfig::default(), 0) {
+			Ok(channelmanager) => channelmanager,
+			Err(_) => return,
+		}
+	} else {
+		let manager_data = get_slice!(manager_len);
+		let mut monitor_refs = HashMap::new();
+		for (outpoint, channel_monitor) in monitors.iter_mut() {
+			monitor_refs.insert(*outpoint, channel_monitor);
+		}
+		let read_args = ChannelManagerReadArgs {
+			keys_manager: keys_manager.clone(),
+			fee_estimator: fee_est.clone(),
+			monitor: monitor.clone(),
+			tx_broadcaster: broadcast.clone(),
+			logger: Arc::clone(&logger),
+			default_config: UserConfig::default(),
+			channel_monitors: &mut monitor_refs,
+		};
+		match <(BlockHash, ReplayChannelManager)>::read(&mut Cursor::new(manager_data), read_args) {
+			Ok((_, channelmanager)) => channelmanager,
+			Err(_) => return,
+		}
+	};
+	for (outpoint, channel_monitor) in monitors.drain() {
+		if monitor.add_monitor(outpoint, channel_monitor).is_err() {
+			return;
+		}
+	}
+
+	let graph_len = slice_to_be32(get_slice!(4));
+	let net_graph_msg_handler = if graph_len == 0 {
+		NetGraphMsgHandler::new(watch.clone(), Arc::clone(&logger))
+	} else {
+		match <NetworkGraph>::read(&mut Cursor::new(get_slice!(graph_len))) {
+			Ok(network_graph) => NetGraphMsgHandler::from_net_graph(watch.clone(), Arc::clone(&logger), RwLock::new(network_graph)),
+			Err(_) => return,
+		}
+	};
+
+	let recording = match read_recording(&data[read_pos..]) {
+		Ok(recording) => recording,
+		Err(_) => return,
+	};
+	replay_recording(&recording, &channelmanager, &net_graph_msg_handler, Arc::clone(&logger));
+
+	// Whatever the handlers would have done in response is left in their event queues, which we
+	// drain to make sure generating them doesn't panic either.
+	channelmanager.get_and_clear_pending_msg_events();
+	channelmanager.get_and_clear_pending_events();
+	channelmanager.process_pending_htlc_forwards();
+}
+
+pub fn wire_replay_test<Out: test_logger::Output>(data: &[u8], out: Out) {
+	do_test(data, out);
+}
+
+#[no_mangle]
+pub extern "C" fn wire_replay_run(data: *const u8, datalen: usize) {
+	do_test(unsafe { std::slice::from_raw_parts(data, datalen) }, test_logger::DevNull {});
+}
+
+#[cfg(test)]
+mod tests {
+	use utils::test_logger::StringBuffer;
+
+	#[test]
+	fn test_replay_fresh_node() {
+		// A fresh node (no monitors, ChannelManager or NetworkGraph) which receives an Init and
+		// then a Ping from a peer.
+		let mut data = Vec::new();
+		data.extend_from_slice(&[1; 32]);
+		data.extend_from_slice(&[0, 0]);
+		data.extend_from_slice(&[0, 0, 0, 0]);
+		data.extend_from_slice(&[0, 0, 0, 0]);
+		// Inbound Init (type 16) with the static_remotekey feature set
+		data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 42, 0]);
+		data.extend_from_slice(&::hex::decode("030000000000000000000000000000000000000000000000000000000000000002").unwrap());
+		data.extend_from_slice(&[0, 10, 0, 16, 0, 2, 0x20, 0, 0, 2, 0x20, 0]);
+		// Inbound Ping (type 18)
+		data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 43, 0]);
+		data.extend_from_slice(&::hex::decode("030000000000000000000000000000000000000000000000000000000000000002").unwrap());
+		data.extend_from_slice(&[0, 6, 0, 18, 0, 0, 0, 0]);
+
+		let logger = StringBuffer::new();
+		super::wire_replay_test(&data, logger.clone());
+		assert!(logger.into_string().contains("Generating channel_reestablish events for 030000000000000000000000000000000000000000000000000000000000000002"));
+	}
+}
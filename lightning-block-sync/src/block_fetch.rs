@@ -0,0 +1,276 @@
+//! Downloads the blocks for a sequence of headers from one or more block sources concurrently,
+//! handing them back strictly in order.
+
+use crate::{BlockHeaderData, BlockSource, BlockSourceRespErr};
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::util::hash::BitcoinHash;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The default number of blocks we will fetch ahead of the next block to be connected, see
+/// MicroSPVClient::set_max_parallel_block_fetches.
+pub(crate) const DEFAULT_MAX_PARALLEL_BLOCK_FETCHES: usize = 8;
+
+type BlockFetchFuture<'s> = Pin<Box<dyn Future<Output = (&'s mut dyn BlockSource, Result<Block, BlockSourceRespErr>)> + 's + Send>>;
+
+/// A block source along with the request it is currently working on, if any. As BlockSource
+/// requests take a mutable reference, each source has at most one request in flight at a time,
+/// and the in-flight future owns the source until it completes.
+enum Worker<'s> {
+	Idle(&'s mut dyn BlockSource),
+	/// Fetching the block for the header at the given index.
+	Busy(usize, BlockFetchFuture<'s>),
+	/// A helper source which failed to give us a block, which we don't bother with again.
+	Dropped,
+}
+
+/// Resolves once any busy worker's request completes, returning the worker's index, the index of
+/// the header it was fetching, the source itself and the result. Must only be polled while at
+/// least one worker is busy.
+struct NextCompletion<'b, 's> {
+	workers: &'b mut Vec<Worker<'s>>,
+}
+
+impl<'b, 's> Future for NextCompletion<'b, 's> {
+	type Output = (usize, usize, &'s mut dyn BlockSource, Result<Block, BlockSourceRespErr>);
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		let workers = &mut *self.get_mut().workers;
+		for (worker_idx, worker) in workers.iter_mut().enumerate() {
+			let res = match worker {
+				Worker::Busy(_, fetch) => match fetch.as_mut().poll(cx) {
+					Poll::Ready(res) => res,
+					Poll::Pending => continue,
+				},
+				_ => continue,
+			};
+			if let Worker::Busy(header_idx, _) = std::mem::replace(worker, Worker::Dropped) {
+				let (source, block_res) = res;
+				return Poll::Ready((worker_idx, header_idx, source, block_res));
+			}
+			unreachable!();
+		}
+		Poll::Pending
+	}
+}
+
+/// Fetches the blocks for a list of headers (in ascending height order) from a primary block
+/// source and any number of helper sources.
+///
+/// Each source has at most one request in flight, and we never fetch more than max_parallel
+/// blocks past the next one to be returned, bounding the number of blocks held in memory. Any
+/// block a helper fails to provide, or provides incorrectly, is fetched from the primary source
+/// instead, and the helper is not used again. Thus, only the primary source needs to have the
+/// full chain, and only its failures are returned, though helpers which served bogus data are
+/// available via bogus_helpers so that the caller can give up on them.
+///
+/// Note that without the `tokio` feature block sources generally block while fetching, so the
+/// requests will not actually overlap.
+pub(crate) struct BlockFetcher<'s> {
+	headers: Vec<BlockHeaderData>,
+	fetched: Vec<Option<Block>>,
+	/// The index of the next header to be returned.
+	next_delivery: usize,
+	/// The index of the next header which has never been requested.
+	next_unrequested: usize,
+	/// Headers which a helper failed to provide and are now waiting on the primary source.
+	primary_retries: Vec<usize>,
+	max_parallel: usize,
+	/// The primary source is always at index 0.
+	workers: Vec<Worker<'s>>,
+	/// The indexes (in helper_sources) of the helpers which served us bogus data.
+	bogus_helpers: Vec<usize>,
+}
+
+impl<'s> BlockFetcher<'s> {
+	pub(crate) fn new(headers: Vec<BlockHeaderData>, primary_source: &'s mut dyn BlockSource, helper_sources: Vec<&'s mut dyn BlockSource>, max_parallel: usize) -> Self {
+		let mut fetched = Vec::with_capacity(headers.len());
+		fetched.resize_with(headers.len(), || None);
+		let mut workers = Vec::with_capacity(helper_sources.len() + 1);
+		workers.push(Worker::Idle(primary_source));
+		for source in helper_sources {
+			workers.push(Worker::Idle(source));
+		}
+		Self {
+			headers, fetched,
+			next_delivery: 0,
+			next_unrequested: 0,
+			primary_retries: Vec::new(),
+			max_parallel: std::cmp::max(max_parallel, 1),
+			workers,
+			bogus_helpers: Vec::new(),
+		}
+	}
+
+	/// Gets the index of the next header which has never been requested, if it is within our
+	/// window.
+	fn next_request(&mut self) -> Option<usize> {
+		if self.next_unrequested < self.headers.len() && self.next_unrequested < self.next_delivery + self.max_parallel {
+			self.next_unrequested += 1;
+			Some(self.next_unrequested - 1)
+		} else { None }
+	}
+
+	/// Hands work to every idle source. The primary source always takes the lowest header which a
+	/// helper failed to provide first, as everything after it is waiting on it.
+	fn assign_requests(&mut self) {
+		for worker_idx in 0..self.workers.len() {
+			if let Worker::Idle(_) = self.workers[worker_idx] {} else { continue; }
+			let header_idx = if worker_idx == 0 && !self.primary_retries.is_empty() {
+				let (retry_pos, _) = self.primary_retries.iter().enumerate().min_by_key(|(_, idx)| **idx).unwrap();
+				Some(self.primary_retries.swap_remove(retry_pos))
+			} else {
+				self.next_request()
+			};
+			let header_idx = match header_idx { Some(idx) => idx, None => break };
+			let header_hash = self.headers[header_idx].header.bitcoin_hash();
+			if let Worker::Idle(source) = std::mem::replace(&mut self.workers[worker_idx], Worker::Dropped) {
+				self.workers[worker_idx] = Worker::Busy(header_idx, Box::pin(async move {
+					let res = source.get_block(&header_hash).await;
+					(source, res)
+				}));
+			}
+		}
+	}
+
+	/// Gets the block for the next header, checking that it matches the header and that its
+	/// transactions match the header's merkle root and the witness commitment. Returns None once
+	/// every block has been returned, and an Err if the primary source fails to provide a block,
+	/// after which no further blocks will be returned.
+	pub(crate) async fn next_block(&mut self) -> Option<Result<(BlockHeaderData, Block), BlockSourceRespErr>> {
+		loop {
+			if self.next_delivery == self.headers.len() { return None; }
+			if let Some(block) = self.fetched[self.next_delivery].take() {
+				self.next_delivery += 1;
+				return Some(Ok((self.headers[self.next_delivery - 1].clone(), block)));
+			}
+
+			// The next block is either in flight or waiting on the primary source, which is
+			// handed it here if idle, so we always have a request to wait on.
+			self.assign_requests();
+			let (worker_idx, header_idx, source, res) = NextCompletion { workers: &mut self.workers }.await;
+			let res = res.and_then(|block| {
+				let header = &self.headers[header_idx].header;
+				if block.header != *header || !block.check_merkle_root() || !block.check_witness_commitment() {
+					Err(BlockSourceRespErr::BogusData)
+				} else { Ok(block) }
+			});
+			match res {
+				Ok(block) => {
+					self.fetched[header_idx] = Some(block);
+					self.workers[worker_idx] = Worker::Idle(source);
+				},
+				Err(e) if worker_idx == 0 => {
+					self.next_delivery = self.headers.len();
+					return Some(Err(e));
+				},
+				Err(e) => {
+					if let BlockSourceRespErr::BogusData = e {
+						self.bogus_helpers.push(worker_idx - 1);
+					}
+					self.primary_retries.push(header_idx);
+				},
+			}
+		}
+	}
+
+	/// Gets the indexes (in the helper_sources we were created with) of the helpers which served
+	/// us a block which didn't match its header, or which they otherwise flagged as bogus.
+	pub(crate) fn bogus_helpers(&self) -> &[usize] {
+		&self.bogus_helpers
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::hash_types::BlockHash;
+	use bitcoin::network::constants::Network;
+	use bitcoin::util::uint::Uint256;
+
+	/// A source which serves blocks from a list, optionally with the wrong header, and counts the
+	/// blocks it served.
+	struct BlockList {
+		blocks: Vec<Block>,
+		corrupt: bool,
+		served: usize,
+	}
+	impl BlockSource for BlockList {
+		fn get_header<'a>(&'a mut self, _header_hash: &'a BlockHash, _height_hint: Option<u32>) -> Pin<Box<dyn Future<Output = Result<BlockHeaderData, BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async { Err(BlockSourceRespErr::NoResponse) })
+		}
+		fn get_block<'a>(&'a mut self, header_hash: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<Block, BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async move {
+				let mut block = self.blocks.iter().find(|block| block.bitcoin_hash() == *header_hash).cloned().ok_or(BlockSourceRespErr::NoResponse)?;
+				if self.corrupt {
+					block.header.nonce += 1;
+				}
+				self.served += 1;
+				Ok(block)
+			})
+		}
+		fn get_best_block<'a>(&'a mut self) -> Pin<Box<dyn Future<Output = Result<(BlockHash, Option<u32>), BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async { Err(BlockSourceRespErr::NoResponse) })
+		}
+	}
+
+	fn build_chain(count: u32) -> (Vec<BlockHeaderData>, Vec<Block>) {
+		let mut blocks = vec![genesis_block(Network::Regtest)];
+		for _ in 0..count {
+			let prev = blocks.last().unwrap().header;
+			blocks.push(Block {
+				header: BlockHeader {
+					version: 1, prev_blockhash: prev.bitcoin_hash(), merkle_root: Default::default(),
+					time: prev.time + 600, bits: prev.bits, nonce: 0,
+				},
+				txdata: Vec::new(),
+			});
+		}
+		blocks.remove(0);
+		let headers = blocks.iter().enumerate().map(|(height, block)| BlockHeaderData {
+			chainwork: Uint256::from_u64(2 * (height as u64 + 1)).unwrap(), height: height as u32 + 1, header: block.header,
+		}).collect();
+		(headers, blocks)
+	}
+
+	#[tokio::test]
+	async fn test_in_order_delivery_with_helpers() {
+		let (headers, blocks) = build_chain(10);
+		let mut primary = BlockList { blocks: blocks.clone(), corrupt: false, served: 0 };
+		// One helper serves bogus blocks and one only has the first half of the chain.
+		let mut liar = BlockList { blocks: blocks.clone(), corrupt: true, served: 0 };
+		let mut partial = BlockList { blocks: blocks[..5].to_vec(), corrupt: false, served: 0 };
+		{
+			let mut fetcher = BlockFetcher::new(headers.clone(), &mut primary,
+				vec![&mut liar as &mut dyn BlockSource, &mut partial as &mut dyn BlockSource], 4);
+			for (header, block) in headers.iter().zip(blocks.iter()) {
+				let (fetched_header, fetched_block) = fetcher.next_block().await.unwrap().unwrap();
+				assert_eq!(fetched_header, *header);
+				assert_eq!(fetched_block.bitcoin_hash(), block.bitcoin_hash());
+			}
+			assert!(fetcher.next_block().await.is_none());
+			// Only the liar is reported, not the helper which merely didn't have some blocks.
+			assert_eq!(fetcher.bogus_helpers(), &[0]);
+		}
+		// The liar is dropped after its first bogus block, which is then fetched from the primary.
+		assert_eq!(liar.served, 1);
+		assert!(partial.served > 0);
+		assert_eq!(primary.served + partial.served, 10);
+	}
+
+	#[tokio::test]
+	async fn test_primary_failure() {
+		let (headers, blocks) = build_chain(6);
+		let mut primary = BlockList { blocks: blocks[..3].to_vec(), corrupt: false, served: 0 };
+		let mut fetcher = BlockFetcher::new(headers, &mut primary, Vec::new(), 2);
+		for block in blocks[..3].iter() {
+			assert_eq!(fetcher.next_block().await.unwrap().unwrap().1.bitcoin_hash(), block.bitcoin_hash());
+		}
+		assert!(fetcher.next_block().await.unwrap().is_err());
+		assert!(fetcher.next_block().await.is_none());
+	}
+}
@@ -12,8 +12,13 @@
 //!
 //! Hostnames are height.(height / 10,000).domain_suffix to keep zones at a more manageable size.
 //! bitcoinheaders.net can be used as domain_suffic to get a public copy of the header chain.
+//! See the dns_headers_zone module to serve your own copy.
+//!
+//! By default queries go via the system resolver, but any DNSResolver from the dns_resolver module
+//! may be used instead, eg to fetch headers via DNS-over-HTTPS past a censoring local resolver.
 
 use crate::{BlockHeaderData, BlockSource, BlockSourceRespErr};
+use crate::dns_resolver::{DNSResolver, SystemResolver};
 
 use bitcoin::hash_types::BlockHash;
 use bitcoin::util::hash::BitcoinHash;
@@ -24,16 +29,18 @@ use bitcoin::consensus::encode;
 
 use std::future::Future;
 use std::pin::Pin;
-use std::net::{IpAddr, Ipv6Addr};
-
-#[cfg(not(feature = "tokio"))]
-use std::net::ToSocketAddrs;
+use std::net::Ipv6Addr;
 
 /// A trait for a barebones version of a BlockSource which only allows queries from height to
 /// header hash, eg our headers-over-DNS protocol.
 pub trait SimpleHeadersClient {
 	/// Gets the header at a given height
-	fn get_header<'a>(&'a self, height: u32) -> Pin<Box<dyn Future<Output = Result<BlockHeader, BlockSourceRespErr>> + 'a + Send>>;
+	///
+	/// This takes self mutably as implementations generally need to mutate themselves to make a
+	/// query, eg DNSHeadersClient's resolvers pick fresh query IDs and DoHResolver reuses a single
+	/// keep-alive connection. As BlockSource methods take &mut self too, CachingHeadersClient never
+	/// needs shared access to its client anyway.
+	fn get_header<'a>(&'a mut self, height: u32) -> Pin<Box<dyn Future<Output = Result<BlockHeader, BlockSourceRespErr>> + 'a + Send>>;
 }
 /// Adapts a SimpleHeadersClient to a BlockSource (which always returns NoResponse for full block
 /// requests) by caching headers on difficulty adjustments and a few recent headers.
@@ -133,7 +140,7 @@ impl<C: SimpleHeadersClient + Send + Sync> CachingHeadersClient<C> {
 			match self.fetch_header_at_height(height).await {
 				Ok(_) => height += interval,
 				Err(BlockSourceRespErr::NoResponse) => return Ok(()),
-				Err(BlockSourceRespErr::BogusData) => return Err(BlockSourceRespErr::BogusData),
+				Err(e) => return Err(e),
 			}
 		}
 	}
@@ -216,7 +223,7 @@ impl<C: SimpleHeadersClient + Send + Sync + 'static> BlockSource for CachingHead
 							return self.fetch_header_at_height(highest_height).await.map(|(hash, _)| (hash, Some(highest_height)));
 						}
 					},
-					Err(BlockSourceRespErr::BogusData) => return Err(BlockSourceRespErr::BogusData),
+					Err(e) => return Err(e),
 				}
 			}
 		})
@@ -226,19 +233,36 @@ impl<C: SimpleHeadersClient + Send + Sync + 'static> BlockSource for CachingHead
 /// A client which fetches headers over DNS from a specific provider, implementing
 /// SimpleHeadersClient. You probably want to create one of these and then wrap it in a
 /// CachingHeadersClient.
-pub struct DNSHeadersClient {
+pub struct DNSHeadersClient<R: DNSResolver = SystemResolver> {
 	domain_str: String,
+	resolver: R,
+	require_authenticated: bool,
 }
 
-impl DNSHeadersClient {
+impl DNSHeadersClient<SystemResolver> {
 	/// Creates a new DNSHeadersClient which fetches headers by doing AAAA (IPv6) DNS queries to
-	/// prefixes on a given hostname (see the module documentation for info on the exact format).
+	/// prefixes on a given hostname (see the module documentation for info on the exact format)
+	/// via the system resolver.
 	pub fn new(domain_str: String) -> Self {
-		Self { domain_str }
+		Self::with_resolver(domain_str, SystemResolver)
+	}
+}
+
+impl<R: DNSResolver> DNSHeadersClient<R> {
+	/// Creates a new DNSHeadersClient which sends its queries via the given resolver.
+	pub fn with_resolver(domain_str: String, resolver: R) -> Self {
+		Self { domain_str, resolver, require_authenticated: false }
+	}
+
+	/// Sets whether we should only accept answers which the resolver indicates were validated
+	/// with DNSSEC. Note that the system resolver cannot tell us this, so setting this with it
+	/// results in every query failing.
+	pub fn set_require_authenticated(&mut self, require_authenticated: bool) {
+		self.require_authenticated = require_authenticated;
 	}
 }
 
-fn map_addrs_to_header(ips: &mut [Ipv6Addr]) -> Option<[u8; 80]> {
+pub(crate) fn map_addrs_to_header(ips: &mut [Ipv6Addr]) -> Option<[u8; 80]> {
 	if ips.len() != 6 { return None; }
 	ips.sort_unstable_by(|a, b| {
 		// Sort based on the first 4 bits in the 3rd byte...
@@ -266,19 +290,43 @@ fn map_addrs_to_header(ips: &mut [Ipv6Addr]) -> Option<[u8; 80]> {
 	Some(header)
 }
 
-impl SimpleHeadersClient for DNSHeadersClient {
-	fn get_header<'a>(&'a self, height: u32) -> Pin<Box<dyn Future<Output = Result<BlockHeader, BlockSourceRespErr>> + 'a + Send>> {
+/// Encodes a serialized header into the six IPv6 addresses of the format described in the module
+/// documentation, ie the inverse of what DNSHeadersClient decodes. Each address starts with the
+/// 2001::/16 prefix and the version is always 0.
+pub fn map_header_to_addrs(header: &[u8; 80]) -> [Ipv6Addr; 6] {
+	let mut addrs = [[0u8; 16]; 6];
+	let mut offs = 0; // in bytes * 2
+	for (idx, addr) in addrs.iter_mut().enumerate() {
+		addr[0] = 0x20;
+		addr[1] = 0x01;
+		// The ordering nibble, followed by the version (0) in the first address.
+		addr[2] = (idx as u8) << 4;
+		for i in if idx == 0 { 3..14*2 } else { 1..14*2 } {
+			let nibble = if offs % 2 == 0 { header[offs/2] >> 4 } else { header[offs/2] & 0x0f };
+			if i % 2 == 1 {
+				addr[i/2 + 2] |= nibble;
+			} else {
+				addr[i/2 + 2] |= nibble << 4;
+			}
+			offs += 1;
+		}
+	}
+	let mut res = [Ipv6Addr::UNSPECIFIED; 6];
+	for (addr, octets) in res.iter_mut().zip(addrs.iter()) {
+		*addr = Ipv6Addr::from(*octets);
+	}
+	res
+}
+
+impl<R: DNSResolver> SimpleHeadersClient for DNSHeadersClient<R> {
+	fn get_header<'a>(&'a mut self, height: u32) -> Pin<Box<dyn Future<Output = Result<BlockHeader, BlockSourceRespErr>> + 'a + Send>> {
 		Box::pin(async move {
 			let domain = format!("{}.{}.{}", height, height / 10000, self.domain_str);
-			#[cfg(not(feature = "tokio"))]
-			let lookup_res = (domain.as_str(), 0u16).to_socket_addrs();
-			#[cfg(feature = "tokio")]
-			let lookup_res = tokio::net::lookup_host((domain.as_str(), 0u16)).await;
-			let mut ips: Vec<_> = lookup_res.map_err(|_| BlockSourceRespErr::NoResponse)?
-				.filter_map(|a| match a.ip() {
-					IpAddr::V6(a) => Some(a),
-					_ => None,
-				}).collect();
+			let answer = self.resolver.resolve_aaaa(&domain).await.map_err(|_| BlockSourceRespErr::NoResponse)?;
+			if self.require_authenticated && answer.authenticated != Some(true) {
+				return Err(BlockSourceRespErr::NoResponse);
+			}
+			let mut ips = answer.addrs;
 			if ips.len() != 6 {
 				return Err(BlockSourceRespErr::NoResponse);
 			}
@@ -0,0 +1,256 @@
+//! The server side of the headers-over-DNS protocol implemented by dns_headers.
+//!
+//! HeadersZoneGenerator mirrors the header chain of any BlockSource and writes it out as standard
+//! DNS zone files, one per `(height / 10,000).domain_suffix` subdomain, which can then be loaded
+//! into any authoritative DNS server to run a private copy of bitcoinheaders.net.
+
+use crate::{BlockSource, BlockSourceRespErr};
+use crate::dns_headers::map_header_to_addrs;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::encode;
+use bitcoin::util::hash::BitcoinHash;
+
+use std::io::{self, Write};
+
+/// The number of headers in each zone, matching the naming scheme DNSHeadersClient queries.
+pub const HEADERS_PER_ZONE: u32 = 10_000;
+
+/// Headers this close to the tip may still be reorged out, so we give them a short TTL.
+const RECENT_HEADER_DEPTH: u32 = 6;
+const RECENT_HEADER_TTL: u32 = 60;
+const BURIED_HEADER_TTL: u32 = 60 * 60 * 24;
+
+/// Keeps a copy of the best header chain of a BlockSource and generates DNS zones which serve it
+/// in the format DNSHeadersClient expects.
+///
+/// Note that this keeps every header in memory (around 50MB for mainnet) and the first sync fetches
+/// every header individually, walking back from the source's tip.
+pub struct HeadersZoneGenerator {
+	domain_suffix: String,
+	primary_ns: String,
+	hostmaster: String,
+	/// The best chain, indexed by height.
+	headers: Vec<BlockHeader>,
+}
+
+impl HeadersZoneGenerator {
+	/// Creates a new, empty, HeadersZoneGenerator for zones under domain_suffix (eg
+	/// "bitcoinheaders.net"). primary_ns and hostmaster are the fully-qualified domain names used
+	/// in the SOA record of each zone.
+	pub fn new(domain_suffix: String, primary_ns: String, hostmaster: String) -> Self {
+		Self { domain_suffix, primary_ns, hostmaster, headers: Vec::new() }
+	}
+
+	/// Updates our copy of the header chain to the best chain of the given block source, walking
+	/// back from its tip until we find a header we already have (or genesis).
+	///
+	/// Returns the lowest height whose header changed (ie the first zone which must be
+	/// regenerated), or None if our chain was already up-to-date.
+	pub async fn sync_from<B: BlockSource + ?Sized>(&mut self, block_source: &mut B) -> Result<Option<u32>, BlockSourceRespErr> {
+		let (best_hash, height_hint) = block_source.get_best_block().await?;
+		let mut header = block_source.get_header(&best_hash, height_hint).await?;
+		if header.header.bitcoin_hash() != best_hash {
+			return Err(BlockSourceRespErr::BogusData);
+		}
+
+		let mut new_headers = Vec::new();
+		// The height of the most recent header on the source's chain which we already have.
+		let mut fork_height = None;
+		loop {
+			if let Some(known_header) = self.headers.get(header.height as usize) {
+				if known_header.bitcoin_hash() == header.header.bitcoin_hash() {
+					fork_height = Some(header.height);
+					break;
+				}
+			}
+			new_headers.push(header.header);
+			if header.height == 0 { break; }
+			let prev_hash = header.header.prev_blockhash;
+			let prev_header = block_source.get_header(&prev_hash, Some(header.height - 1)).await?;
+			if prev_header.header.bitcoin_hash() != prev_hash || prev_header.height != header.height - 1 {
+				return Err(BlockSourceRespErr::BogusData);
+			}
+			header = prev_header;
+		}
+
+		let first_changed_height = fork_height.map(|height| height + 1).unwrap_or(0);
+		if new_headers.is_empty() && self.headers.len() == first_changed_height as usize {
+			return Ok(None);
+		}
+		// Drop everything we had after the fork point, even if the source's new tip is lower than
+		// our old one.
+		self.headers.truncate(first_changed_height as usize);
+		self.headers.extend(new_headers.drain(..).rev());
+		Ok(Some(first_changed_height))
+	}
+
+	/// The height of the best header we have, if any.
+	pub fn tip_height(&self) -> Option<u32> {
+		if self.headers.is_empty() { None } else { Some(self.headers.len() as u32 - 1) }
+	}
+
+	/// The number of zones required to serve every header we have.
+	pub fn zone_count(&self) -> u32 {
+		(self.headers.len() as u32 + HEADERS_PER_ZONE - 1) / HEADERS_PER_ZONE
+	}
+
+	/// The fully-qualified name of the given zone, ie the origin its records are relative to.
+	pub fn zone_name(&self, zone: u32) -> String {
+		format!("{}.{}.", zone, self.domain_suffix.trim_end_matches('.'))
+	}
+
+	/// Writes a zone file for the given zone (ie the headers at heights zone * 10,000 through
+	/// zone * 10,000 + 9,999) with the given SOA serial, which should increase each time the zone
+	/// changes.
+	pub fn write_zone<W: Write>(&self, zone: u32, serial: u32, out: &mut W) -> io::Result<()> {
+		writeln!(out, "$ORIGIN {}", self.zone_name(zone))?;
+		writeln!(out, "$TTL {}", BURIED_HEADER_TTL)?;
+		writeln!(out, "@ IN SOA {} {} {} 3600 600 604800 {}", self.primary_ns, self.hostmaster, serial, RECENT_HEADER_TTL)?;
+		writeln!(out, "@ IN NS {}", self.primary_ns)?;
+
+		let tip_height = match self.tip_height() { Some(height) => height, None => return Ok(()) };
+		let start = zone.saturating_mul(HEADERS_PER_ZONE);
+		let end = std::cmp::min(start.saturating_add(HEADERS_PER_ZONE - 1), tip_height);
+		for height in start..end.saturating_add(1) {
+			let mut data = [0u8; 80];
+			data.copy_from_slice(&encode::serialize(&self.headers[height as usize]));
+			let ttl = if tip_height - height < RECENT_HEADER_DEPTH { RECENT_HEADER_TTL } else { BURIED_HEADER_TTL };
+			for addr in map_header_to_addrs(&data).iter() {
+				writeln!(out, "{} {} IN AAAA {}", height, ttl, addr)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::BlockHeaderData;
+	use crate::dns_headers::{DNSHeadersClient, SimpleHeadersClient};
+	use crate::dns_resolver::{DNSAnswer, DNSResolver};
+
+	use bitcoin::blockdata::block::Block;
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::hash_types::BlockHash;
+	use bitcoin::network::constants::Network;
+	use bitcoin::util::uint::Uint256;
+
+	use std::collections::HashMap;
+	use std::future::Future;
+	use std::net::Ipv6Addr;
+	use std::pin::Pin;
+	use std::str::FromStr;
+
+	struct HeaderChain(Vec<BlockHeader>);
+	impl BlockSource for HeaderChain {
+		fn get_header<'a>(&'a mut self, header_hash: &'a BlockHash, _height_hint: Option<u32>) -> Pin<Box<dyn Future<Output = Result<BlockHeaderData, BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async move {
+				for (height, header) in self.0.iter().enumerate() {
+					if header.bitcoin_hash() == *header_hash {
+						return Ok(BlockHeaderData { chainwork: Uint256::from_u64(0).unwrap(), height: height as u32, header: *header });
+					}
+				}
+				Err(BlockSourceRespErr::NoResponse)
+			})
+		}
+		fn get_block<'a>(&'a mut self, _header_hash: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<Block, BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async { Err(BlockSourceRespErr::NoResponse) })
+		}
+		fn get_best_block<'a>(&'a mut self) -> Pin<Box<dyn Future<Output = Result<(BlockHash, Option<u32>), BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async move { Ok((self.0.last().unwrap().bitcoin_hash(), Some(self.0.len() as u32 - 1))) })
+		}
+	}
+
+	fn extend_chain(chain: &mut Vec<BlockHeader>, count: u32, time_offset: u32) {
+		for _ in 0..count {
+			let prev = *chain.last().unwrap();
+			chain.push(BlockHeader {
+				version: 1, prev_blockhash: prev.bitcoin_hash(), merkle_root: Default::default(),
+				time: prev.time + 600 + time_offset, bits: prev.bits, nonce: 0,
+			});
+		}
+	}
+
+	/// A DNSResolver which answers from a fixed set of AAAA records, as an authoritative server
+	/// would. Names are fully-qualified but without the trailing dot, as DNSHeadersClient queries
+	/// them.
+	struct StaticResolver(HashMap<String, Vec<Ipv6Addr>>);
+	impl DNSResolver for StaticResolver {
+		fn resolve_aaaa<'a>(&'a mut self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<DNSAnswer, ()>> + 'a + Send>> {
+			Box::pin(async move {
+				let mut addrs = self.0.get(name).cloned().unwrap_or(Vec::new());
+				// DNS servers often shuffle records, so make sure ordering is recovered from the
+				// addresses themselves.
+				addrs.reverse();
+				Ok(DNSAnswer { addrs, authenticated: None })
+			})
+		}
+	}
+
+	/// Loads the AAAA records out of a zone file written by write_zone into a StaticResolver.
+	fn resolver_from_zone(zone: &str) -> StaticResolver {
+		let origin = zone.lines().next().unwrap().trim_start_matches("$ORIGIN ").trim_end_matches('.');
+		let mut records = HashMap::new();
+		for line in zone.lines() {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			if fields.len() == 5 && fields[3] == "AAAA" {
+				records.entry(format!("{}.{}", fields[0], origin)).or_insert_with(Vec::new).push(Ipv6Addr::from_str(fields[4]).unwrap());
+			}
+		}
+		StaticResolver(records)
+	}
+
+	#[tokio::test]
+	async fn test_header_addrs_round_trip() {
+		let header = genesis_block(Network::Bitcoin).header;
+		let mut data = [0u8; 80];
+		data.copy_from_slice(&encode::serialize(&header));
+		let mut records = HashMap::new();
+		records.insert("0.0.headers.example".to_owned(), map_header_to_addrs(&data).to_vec());
+		let mut client = DNSHeadersClient::with_resolver("headers.example".to_owned(), StaticResolver(records));
+		assert_eq!(client.get_header(0).await.unwrap(), header);
+		assert!(client.get_header(1).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_zone_generation_round_trip() {
+		let mut chain = vec![genesis_block(Network::Regtest).header];
+		extend_chain(&mut chain, 10, 0);
+		let mut source = HeaderChain(chain.clone());
+
+		let mut generator = HeadersZoneGenerator::new("headers.example".to_owned(), "ns.example.".to_owned(), "hostmaster.example.".to_owned());
+		assert_eq!(generator.sync_from(&mut source).await.unwrap(), Some(0));
+		assert_eq!(generator.sync_from(&mut source).await.unwrap(), None);
+		assert_eq!(generator.tip_height(), Some(10));
+		assert_eq!(generator.zone_count(), 1);
+		assert_eq!(generator.zone_name(0), "0.headers.example.");
+
+		let mut zone = Vec::new();
+		generator.write_zone(0, 1, &mut zone).unwrap();
+		let zone = String::from_utf8(zone).unwrap();
+		assert!(zone.starts_with("$ORIGIN 0.headers.example.\n"));
+		let mut client = DNSHeadersClient::with_resolver("headers.example".to_owned(), resolver_from_zone(&zone));
+		for (height, header) in chain.iter().enumerate() {
+			assert_eq!(client.get_header(height as u32).await.unwrap(), *header);
+		}
+		assert!(client.get_header(11).await.is_err());
+
+		// Reorg the last three blocks to a shorter fork and check we only replace those.
+		chain.truncate(8);
+		extend_chain(&mut chain, 2, 1);
+		source.0 = chain.clone();
+		assert_eq!(generator.sync_from(&mut source).await.unwrap(), Some(8));
+		assert_eq!(generator.tip_height(), Some(9));
+
+		let mut zone = Vec::new();
+		generator.write_zone(0, 2, &mut zone).unwrap();
+		let zone = String::from_utf8(zone).unwrap();
+		let mut client = DNSHeadersClient::with_resolver("headers.example".to_owned(), resolver_from_zone(&zone));
+		for (height, header) in chain.iter().enumerate() {
+			assert_eq!(client.get_header(height as u32).await.unwrap(), *header);
+		}
+		assert!(client.get_header(10).await.is_err());
+	}
+}
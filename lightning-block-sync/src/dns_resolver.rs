@@ -0,0 +1,421 @@
+//! Pluggable DNS transports for DNSHeadersClient.
+//!
+//! By default headers are looked up via the system resolver, which is simple but leaves us at the
+//! mercy of whatever (possibly censoring) resolver the local network hands out. This module
+//! provides resolvers which speak the DNS wire protocol themselves - DNSServerResolver queries a
+//! specific server over UDP (falling back to TCP for truncated responses) or TCP, and, with
+//! feature `rest-client` or `rpc-client`, DoHResolver tunnels queries over HTTP(S) per RFC 8484.
+//!
+//! Note that these resolvers are only "DNSSEC-aware" in the weakest sense: we do not fetch or
+//! validate any DNSSEC signatures ourselves. Instead, we ask the upstream resolver to validate and
+//! report whether it set the Authenticated Data bit in its response, which
+//! DNSHeadersClient::set_require_authenticated can insist on. The AD bit is just a header flag, so
+//! this only means anything if both the upstream resolver and the path to it are trusted, ie it is
+//! a validating resolver running locally or one reached over DoH. Over UDP or TCP across an
+//! untrusted network, anyone able to spoof a response can set it too.
+//!
+//! Responses are only accepted if they carry our (random) query ID and echo back the question we
+//! asked, see lightning::util::dns.
+
+use lightning::util::dns::{self, QueryIdSource, TYPE_AAAA};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+#[cfg(not(feature = "tokio"))]
+use std::net::ToSocketAddrs;
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(not(feature = "tokio"))]
+use std::io::{Read, Write};
+
+#[cfg(any(feature = "rest-client", feature = "rpc-client"))]
+use crate::http_clients::HttpClient;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+/// The result of an AAAA query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DNSAnswer {
+	/// The IPv6 addresses in the answer section, in the order the server returned them.
+	pub addrs: Vec<Ipv6Addr>,
+	/// Whether the upstream resolver set the Authenticated Data bit, ie claims to have validated
+	/// the answer with DNSSEC, or None if the resolver cannot tell us (eg the system resolver). We
+	/// do no validation of our own, see the module documentation.
+	pub authenticated: Option<bool>,
+}
+
+/// A DNS transport which can look up AAAA records, see the module documentation for more.
+pub trait DNSResolver : Send + Sync {
+	/// Looks up the AAAA records for the given fully-qualified domain name. A name which does not
+	/// exist should result in an empty set of addresses rather than an Err.
+	///
+	/// Sadly rust's trait system hasn't grown the ability to take impl/differentially-sized return
+	/// values yet, so we have to Box + dyn the future.
+	fn resolve_aaaa<'a>(&'a mut self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<DNSAnswer, ()>> + 'a + Send>>;
+}
+
+/// Resolves names using the operating system's resolver (or tokio's wrapper around it), which
+/// cannot tell us whether an answer was authenticated.
+pub struct SystemResolver;
+
+impl DNSResolver for SystemResolver {
+	fn resolve_aaaa<'a>(&'a mut self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<DNSAnswer, ()>> + 'a + Send>> {
+		Box::pin(async move {
+			#[cfg(not(feature = "tokio"))]
+			let lookup_res = (name, 0u16).to_socket_addrs();
+			#[cfg(feature = "tokio")]
+			let lookup_res = tokio::net::lookup_host((name, 0u16)).await;
+			let addrs = lookup_res.map_err(|_| ())?
+				.filter_map(|a| match a.ip() {
+					IpAddr::V6(a) => Some(a),
+					_ => None,
+				}).collect();
+			Ok(DNSAnswer { addrs, authenticated: None })
+		})
+	}
+}
+
+/// The EDNS UDP payload size we advertise, as recommended by DNS Flag Day 2020.
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+/// The largest response we'll accept over HTTP.
+#[cfg(any(feature = "rest-client", feature = "rpc-client"))]
+const MAX_RESPONSE_LEN: usize = 65535;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Builds an AAAA query for the given name, requesting recursion and DNSSEC validation.
+pub(crate) fn build_aaaa_query(id: u16, name: &str) -> Option<Vec<u8>> {
+	dns::build_query(id, name, TYPE_AAAA, EDNS_UDP_PAYLOAD_SIZE, true)
+}
+
+/// Parses a response to the given query (as built by build_aaaa_query), returning the answer and
+/// whether the response was truncated (in which case it should be retried over TCP).
+///
+/// We collect every AAAA record in the answer section without checking its owner name, relying on
+/// the resolver to have followed any CNAMEs for us.
+pub(crate) fn parse_aaaa_response(query: &[u8], resp: &[u8]) -> Result<(DNSAnswer, bool), ()> {
+	let parsed = dns::parse_response(query, resp)?;
+	let mut addrs = Vec::with_capacity(parsed.answers.len());
+	for (start, len) in parsed.answers {
+		if len != 16 { return Err(()); }
+		let mut octets = [0u8; 16];
+		octets.copy_from_slice(&resp[start..start + 16]);
+		addrs.push(Ipv6Addr::from(octets));
+	}
+	Ok((DNSAnswer { addrs, authenticated: Some(parsed.authenticated) }, parsed.truncated))
+}
+
+/// A resolver which sends queries directly to a specific DNS server, eg a trusted validating
+/// resolver or a public one which the local network isn't censoring.
+pub struct DNSServerResolver {
+	server: SocketAddr,
+	tcp_only: bool,
+	query_ids: QueryIdSource,
+}
+
+impl DNSServerResolver {
+	/// Creates a resolver which queries the given server over UDP, retrying over TCP if the
+	/// response is truncated.
+	pub fn new_udp(server: SocketAddr) -> Self {
+		Self { server, tcp_only: false, query_ids: QueryIdSource::new() }
+	}
+
+	/// Creates a resolver which only queries the given server over TCP.
+	pub fn new_tcp(server: SocketAddr) -> Self {
+		Self { server, tcp_only: true, query_ids: QueryIdSource::new() }
+	}
+
+	#[cfg(feature = "tokio")]
+	async fn query_udp(&self, query: &[u8]) -> Result<(DNSAnswer, bool), ()> {
+		let bind_addr: SocketAddr = if self.server.is_ipv4() { ([0u8; 4], 0).into() } else { ([0u16; 8], 0).into() };
+		let mut socket = tokio::net::UdpSocket::bind(bind_addr).await.map_err(|_| ())?;
+		socket.connect(self.server).await.map_err(|_| ())?;
+		socket.send(query).await.map_err(|_| ())?;
+		let mut buf = [0u8; EDNS_UDP_PAYLOAD_SIZE as usize];
+		// Ignore (a few) datagrams which don't match our query, eg late responses to old queries
+		for _ in 0..4 {
+			let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf)).await.map_err(|_| ())?.map_err(|_| ())?;
+			if let Ok(res) = parse_aaaa_response(query, &buf[..len]) {
+				return Ok(res);
+			}
+		}
+		Err(())
+	}
+
+	#[cfg(not(feature = "tokio"))]
+	async fn query_udp(&self, query: &[u8]) -> Result<(DNSAnswer, bool), ()> {
+		let bind_addr: SocketAddr = if self.server.is_ipv4() { ([0u8; 4], 0).into() } else { ([0u16; 8], 0).into() };
+		let socket = std::net::UdpSocket::bind(bind_addr).map_err(|_| ())?;
+		socket.set_read_timeout(Some(QUERY_TIMEOUT)).map_err(|_| ())?;
+		socket.connect(self.server).map_err(|_| ())?;
+		socket.send(query).map_err(|_| ())?;
+		let mut buf = [0u8; EDNS_UDP_PAYLOAD_SIZE as usize];
+		// Ignore (a few) datagrams which don't match our query, eg late responses to old queries
+		for _ in 0..4 {
+			let len = socket.recv(&mut buf).map_err(|_| ())?;
+			if let Ok(res) = parse_aaaa_response(query, &buf[..len]) {
+				return Ok(res);
+			}
+		}
+		Err(())
+	}
+
+	async fn query_tcp(&self, query: &[u8]) -> Result<DNSAnswer, ()> {
+		let stream = std::net::TcpStream::connect_timeout(&self.server, QUERY_TIMEOUT).map_err(|_| ())?;
+		stream.set_write_timeout(Some(QUERY_TIMEOUT)).map_err(|_| ())?;
+		stream.set_read_timeout(Some(QUERY_TIMEOUT)).map_err(|_| ())?;
+		#[cfg(feature = "tokio")]
+		let mut stream = tokio::net::TcpStream::from_std(stream).map_err(|_| ())?;
+		#[cfg(not(feature = "tokio"))]
+		let mut stream = stream;
+
+		// Over TCP, messages are prefixed with their two-byte length
+		let mut msg = Vec::with_capacity(query.len() + 2);
+		msg.extend_from_slice(&(query.len() as u16).to_be_bytes());
+		msg.extend_from_slice(query);
+		#[cfg(feature = "tokio")]
+		let resp = {
+			stream.write_all(&msg).await.map_err(|_| ())?;
+			let mut len_bytes = [0u8; 2];
+			tokio::time::timeout(QUERY_TIMEOUT, stream.read_exact(&mut len_bytes)).await.map_err(|_| ())?.map_err(|_| ())?;
+			let mut resp = vec![0u8; u16::from_be_bytes(len_bytes) as usize];
+			tokio::time::timeout(QUERY_TIMEOUT, stream.read_exact(&mut resp)).await.map_err(|_| ())?.map_err(|_| ())?;
+			resp
+		};
+		#[cfg(not(feature = "tokio"))]
+		let resp = {
+			stream.write_all(&msg).map_err(|_| ())?;
+			let mut len_bytes = [0u8; 2];
+			stream.read_exact(&mut len_bytes).map_err(|_| ())?;
+			let mut resp = vec![0u8; u16::from_be_bytes(len_bytes) as usize];
+			stream.read_exact(&mut resp).map_err(|_| ())?;
+			resp
+		};
+		let (answer, truncated) = parse_aaaa_response(query, &resp)?;
+		if truncated { return Err(()); }
+		Ok(answer)
+	}
+}
+
+impl DNSResolver for DNSServerResolver {
+	fn resolve_aaaa<'a>(&'a mut self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<DNSAnswer, ()>> + 'a + Send>> {
+		Box::pin(async move {
+			let query = build_aaaa_query(self.query_ids.next_id(), name).ok_or(())?;
+			if !self.tcp_only {
+				let (answer, truncated) = self.query_udp(&query).await?;
+				if !truncated { return Ok(answer); }
+			}
+			self.query_tcp(&query).await
+		})
+	}
+}
+
+#[cfg(any(feature = "rest-client", feature = "rpc-client"))]
+/// A resolver which sends queries to a DNS-over-HTTPS server per RFC 8484 (eg
+/// https://cloudflare-dns.com/dns-query), keeping a connection alive between queries. https URIs
+/// are only supported with the `tls` feature.
+pub struct DoHResolver {
+	http: HttpClient,
+}
+
+#[cfg(any(feature = "rest-client", feature = "rpc-client"))]
+impl DoHResolver {
+	/// Creates a new DoHResolver for the given DNS query URI.
+	pub fn new(uri: String) -> Option<Self> {
+		Some(Self { http: HttpClient::new(uri)? })
+	}
+
+	#[cfg(feature = "tls")]
+	/// Sets the TLS configuration used for https URIs. By default we trust the webpki root
+	/// certificates.
+	pub fn set_tls_config(&mut self, config: Arc<rustls::ClientConfig>) {
+		self.http.tls_config = config;
+	}
+}
+
+#[cfg(any(feature = "rest-client", feature = "rpc-client"))]
+impl DNSResolver for DoHResolver {
+	fn resolve_aaaa<'a>(&'a mut self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<DNSAnswer, ()>> + 'a + Send>> {
+		Box::pin(async move {
+			// RFC 8484 recommends an ID of 0 to maximize HTTP cache hits, and the ID offers no
+			// protection over an authenticated transport anyway.
+			let query = build_aaaa_query(0, name).ok_or(())?;
+			let mut req = {
+				let (host, path) = self.http.host_path();
+				format!("POST {} HTTP/1.1\r\nHost: {}\r\nAccept: application/dns-message\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\nConnection: keep-alive\r\n\r\n",
+					path, host, query.len()).into_bytes()
+			};
+			req.extend_from_slice(&query);
+			let resp = self.http.make_request(&req, MAX_RESPONSE_LEN).await.map_err(|_| ())?;
+			if resp.status_code != 200 { return Err(()); }
+			let (answer, _truncated) = parse_aaaa_response(&query, &resp.body)?;
+			Ok(answer)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FLAG_QR: u16 = 0x8000;
+	const FLAG_TC: u16 = 0x0200;
+	const FLAG_RD: u16 = 0x0100;
+	const FLAG_AD: u16 = 0x0020;
+	const RCODE_NXDOMAIN: u16 = 3;
+
+	/// Builds a response to the given query with the given flags and AAAA answers, using a
+	/// compression pointer back to the question for each answer's name.
+	fn build_response(query: &[u8], flags: u16, addrs: &[Ipv6Addr]) -> Vec<u8> {
+		// Strip the OPT record from the query and turn it into a response
+		let question_end = query.len() - 11;
+		let mut resp = query[..question_end].to_vec();
+		resp[2..4].copy_from_slice(&(flags | FLAG_QR).to_be_bytes());
+		resp[6..8].copy_from_slice(&(addrs.len() as u16).to_be_bytes());
+		resp[10..12].copy_from_slice(&0u16.to_be_bytes());
+		for addr in addrs {
+			resp.extend_from_slice(&[0xc0, 12]);
+			resp.extend_from_slice(&TYPE_AAAA.to_be_bytes());
+			resp.extend_from_slice(&1u16.to_be_bytes());
+			resp.extend_from_slice(&[0, 0, 0x0e, 0x10]);
+			resp.extend_from_slice(&16u16.to_be_bytes());
+			resp.extend_from_slice(&addr.octets());
+		}
+		resp
+	}
+
+	#[test]
+	fn test_query_building() {
+		let query = build_aaaa_query(0x1234, "0.0.bitcoinheaders.net.").unwrap();
+		assert_eq!(&query[..12], &[0x12, 0x34, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 1]);
+		assert_eq!(&query[12..40], b"\x010\x010\x0ebitcoinheaders\x03net\x00\x00\x1c\x00\x01");
+		assert_eq!(&query[40..], &[0, 0, 41, 0x04, 0xd0, 0, 0, 0x80, 0, 0, 0]);
+
+		assert!(build_aaaa_query(0, "bad..name").is_none());
+		assert!(build_aaaa_query(0, &"a".repeat(64)).is_none());
+	}
+
+	#[test]
+	fn test_response_parsing() {
+		let query = build_aaaa_query(42, "1.0.bitcoinheaders.net").unwrap();
+		let addrs = [Ipv6Addr::new(0x2001, 0, 0x1000, 0, 0, 0, 0, 0), Ipv6Addr::new(0x2001, 0x1000, 0, 0, 0, 0, 0, 1)];
+
+		let resp = build_response(&query, FLAG_RD | FLAG_AD, &addrs);
+		assert_eq!(parse_aaaa_response(&query, &resp), Ok((DNSAnswer { addrs: addrs.to_vec(), authenticated: Some(true) }, false)));
+		// Responses to other queries are rejected, whether they differ in ID or in question
+		let other_query = build_aaaa_query(43, "1.0.bitcoinheaders.net").unwrap();
+		assert!(parse_aaaa_response(&other_query, &resp).is_err());
+		let other_query = build_aaaa_query(42, "2.0.bitcoinheaders.net").unwrap();
+		assert!(parse_aaaa_response(&other_query, &resp).is_err());
+		let other_query = dns::build_query(42, "1.0.bitcoinheaders.net", dns::TYPE_A, EDNS_UDP_PAYLOAD_SIZE, true).unwrap();
+		assert!(parse_aaaa_response(&other_query, &resp).is_err());
+		// As are truncated messages
+		assert!(parse_aaaa_response(&query, &resp[..resp.len() - 1]).is_err());
+
+		let resp = build_response(&query, FLAG_RD | FLAG_TC, &addrs[..1]);
+		assert_eq!(parse_aaaa_response(&query, &resp), Ok((DNSAnswer { addrs: addrs[..1].to_vec(), authenticated: Some(false) }, true)));
+
+		let resp = build_response(&query, FLAG_RD | RCODE_NXDOMAIN, &[]);
+		assert_eq!(parse_aaaa_response(&query, &resp), Ok((DNSAnswer { addrs: Vec::new(), authenticated: Some(false) }, false)));
+		// SERVFAIL
+		let resp = build_response(&query, FLAG_RD | 2, &[]);
+		assert!(parse_aaaa_response(&query, &resp).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_udp_resolver() {
+		let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+		let server = socket.local_addr().unwrap();
+		let addr = Ipv6Addr::new(0x2001, 0, 0x1000, 0, 0, 0, 0, 0);
+		let responder = std::thread::spawn(move || {
+			let mut buf = [0u8; 512];
+			let (len, client) = socket.recv_from(&mut buf).unwrap();
+			let query = buf[..len].to_vec();
+			// A response to some other query is ignored...
+			let mut stale = build_response(&query, FLAG_RD | FLAG_AD, &[Ipv6Addr::LOCALHOST]);
+			stale[1] ^= 1;
+			socket.send_to(&stale, client).unwrap();
+			// ...in favor of the real one
+			socket.send_to(&build_response(&query, FLAG_RD | FLAG_AD, &[addr]), client).unwrap();
+
+			// A truncated response makes us retry over TCP, which nothing is listening on
+			let (len, client) = socket.recv_from(&mut buf).unwrap();
+			socket.send_to(&build_response(&buf[..len], FLAG_RD | FLAG_TC, &[]), client).unwrap();
+		});
+
+		let mut resolver = DNSServerResolver::new_udp(server);
+		let answer = resolver.resolve_aaaa("0.0.bitcoinheaders.net").await.unwrap();
+		assert_eq!(answer, DNSAnswer { addrs: vec![addr], authenticated: Some(true) });
+		assert!(resolver.resolve_aaaa("0.0.bitcoinheaders.net").await.is_err());
+		responder.join().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_tcp_resolver() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let server = listener.local_addr().unwrap();
+		let addr = Ipv6Addr::new(0x2001, 0, 0x1000, 0, 0, 0, 0, 0);
+		let responder = std::thread::spawn(move || {
+			use std::io::{Read, Write};
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut len_bytes = [0u8; 2];
+			stream.read_exact(&mut len_bytes).unwrap();
+			let mut query = vec![0u8; u16::from_be_bytes(len_bytes) as usize];
+			stream.read_exact(&mut query).unwrap();
+			let resp = build_response(&query, FLAG_RD | FLAG_AD, &[addr]);
+			stream.write_all(&(resp.len() as u16).to_be_bytes()).unwrap();
+			stream.write_all(&resp).unwrap();
+		});
+
+		let mut resolver = DNSServerResolver::new_tcp(server);
+		let answer = resolver.resolve_aaaa("0.0.bitcoinheaders.net").await.unwrap();
+		assert_eq!(answer, DNSAnswer { addrs: vec![addr], authenticated: Some(true) });
+		responder.join().unwrap();
+	}
+
+	#[cfg(any(feature = "rest-client", feature = "rpc-client"))]
+	#[tokio::test]
+	async fn test_doh_resolver() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let uri = format!("http://{}/dns-query", listener.local_addr().unwrap());
+		let addr = Ipv6Addr::new(0x2001, 0, 0x1000, 0, 0, 0, 0, 0);
+		let responder = std::thread::spawn(move || {
+			use std::io::{BufRead, BufReader, Read, Write};
+			let (stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream);
+			// Both queries should arrive on the same keep-alive connection
+			for status in &["200 OK", "503 Service Unavailable"] {
+				let mut content_len = 0;
+				let mut line = String::new();
+				reader.read_line(&mut line).unwrap();
+				assert_eq!(line, "POST /dns-query HTTP/1.1\r\n");
+				loop {
+					line.clear();
+					reader.read_line(&mut line).unwrap();
+					if line == "\r\n" { break; }
+					if line.to_ascii_lowercase().starts_with("content-length: ") {
+						content_len = line[16..].trim().parse().unwrap();
+					}
+				}
+				let mut query = vec![0u8; content_len];
+				reader.read_exact(&mut query).unwrap();
+				// DoH queries use an ID of 0
+				assert_eq!(&query[..2], &[0, 0]);
+				let resp = build_response(&query, FLAG_RD | FLAG_AD, &[addr]);
+				let mut http_resp = format!("HTTP/1.1 {}\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n", status, resp.len()).into_bytes();
+				http_resp.extend_from_slice(&resp);
+				reader.get_mut().write_all(&http_resp).unwrap();
+			}
+		});
+
+		let mut resolver = DoHResolver::new(uri).unwrap();
+		let answer = resolver.resolve_aaaa("0.0.bitcoinheaders.net").await.unwrap();
+		assert_eq!(answer, DNSAnswer { addrs: vec![addr], authenticated: Some(true) });
+		// Non-200 responses are errors even if they carry a DNS message
+		assert!(resolver.resolve_aaaa("0.0.bitcoinheaders.net").await.is_err());
+		responder.join().unwrap();
+	}
+}
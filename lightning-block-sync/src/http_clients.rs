@@ -2,8 +2,10 @@ use serde_json;
 
 use serde_derive::Deserialize;
 
+use crate::http_parser::{HttpParseError, HttpResponse, HttpResponseParser};
 use crate::utils::hex_to_uint256;
 use crate::{BlockHeaderData, BlockSource, BlockSourceRespErr};
+use crate::utxo_lookup::BlockHashSource;
 
 use bitcoin::hashes::hex::{ToHex, FromHex};
 use bitcoin::hash_types::{BlockHash, TxMerkleNode};
@@ -12,11 +14,10 @@ use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::consensus::encode;
 
 use std::convert::TryInto;
-use std::cmp;
 use std::future::Future;
 use std::pin::Pin;
 use std::net::ToSocketAddrs;
-use std::io::Write;
+use std::sync::Mutex;
 use std::time::Duration;
 
 #[cfg(feature = "rpc-client")]
@@ -24,17 +25,22 @@ use crate::utils::hex_to_vec;
 #[cfg(feature = "rpc-client")]
 use std::sync::atomic::{AtomicUsize, Ordering};
 #[cfg(feature = "rpc-client")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "rpc-client")]
 use base64;
 
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
 #[cfg(feature = "tokio")]
 use tokio::net::TcpStream;
 #[cfg(feature = "tokio")]
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[cfg(not(feature = "tokio"))]
 use std::net::TcpStream;
 #[cfg(not(feature = "tokio"))]
-use std::io::Read;
+use std::io::{Read, Write};
 
 /// Splits an HTTP URI into its component parts - (is_ssl, hostname, port number, and HTTP path)
 fn split_uri<'a>(uri: &'a str) -> Option<(bool, &'a str, u16, &'a str)> {
@@ -80,168 +86,116 @@ fn split_uri<'a>(uri: &'a str) -> Option<(bool, &'a str, u16, &'a str)> {
 	Some((ssl, host, port, path))
 }
 
-async fn read_http_resp(mut socket: TcpStream, max_resp: usize) -> Option<Vec<u8>> {
-	let mut resp = Vec::new();
-	let mut bytes_read = 0;
-	macro_rules! read_socket { () => { {
-		#[cfg(feature = "tokio")]
-		let res = socket.read(&mut resp[bytes_read..]).await;
-		#[cfg(not(feature = "tokio"))]
-		let res = socket.read(&mut resp[bytes_read..]);
-		match res {
-			Ok(0) => return None,
-			Ok(b) => b,
-			Err(_) => return None,
-		}
-	} } }
-
-	let mut actual_len = 0;
-	let mut ok_found = false;
-	let mut chunked = false;
-	// We expect the HTTP headers to fit in 8KB, and use resp as a temporary buffer for headers
-	// until we know our real length.
-	resp.extend_from_slice(&[0; 8192]);
-	'read_headers: loop {
-		if bytes_read >= 8192 { return None; }
-		bytes_read += read_socket!();
-		for line in resp[..bytes_read].split(|c| *c == '\n' as u8 || *c == '\r' as u8) {
-			let content_header = b"Content-Length: ";
-			if line.len() > content_header.len() && line[..content_header.len()].eq_ignore_ascii_case(content_header) {
-				actual_len = match match std::str::from_utf8(&line[content_header.len()..]){
-					Ok(s) => s, Err(_) => return None,
-				}.parse() {
-					Ok(len) => len, Err(_) => return None,
-				};
-			}
-			let http_resp_1 = b"HTTP/1.1 200 ";
-			let http_resp_0 = b"HTTP/1.0 200 ";
-			if line.len() > http_resp_1.len() && (line[..http_resp_1.len()].eq_ignore_ascii_case(http_resp_1) ||
-				                                  line[..http_resp_0.len()].eq_ignore_ascii_case(http_resp_0)) {
-				ok_found = true;
-			}
-			let transfer_encoding = b"Transfer-Encoding: ";
-			if line.len() > transfer_encoding.len() && line[..transfer_encoding.len()].eq_ignore_ascii_case(transfer_encoding) {
-				match &*String::from_utf8_lossy(&line[transfer_encoding.len()..]).to_ascii_lowercase() {
-					"chunked" => chunked = true,
-					_ => return None, // Unsupported
-				}
-			}
-		}
-		for (idx, window) in resp[..bytes_read].windows(4).enumerate() {
-			if window[0..2] == *b"\n\n" || window[0..2] == *b"\r\r" {
-				resp = resp.split_off(idx + 2);
-				resp.resize(bytes_read - idx - 2, 0);
-				break 'read_headers;
-			} else if window[0..4] == *b"\r\n\r\n" {
-				resp = resp.split_off(idx + 4);
-				resp.resize(bytes_read - idx - 4, 0);
-				break 'read_headers;
-			}
+/// Failure type for requests made by RESTClient and RPCClient.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HttpClientError {
+	/// We failed to connect to the server, or the connection failed before we received a response.
+	Transport,
+	/// The server sent a response which we failed to parse.
+	InvalidResponse(HttpParseError),
+	/// The server responded with a non-200 HTTP status code (and, for JSON-RPC requests, without
+	/// a JSON-RPC error object).
+	Status(u16),
+	/// The server responded with a JSON-RPC error object.
+	RPCError {
+		/// The JSON-RPC error code, eg -5 for RPC_INVALID_ADDRESS_OR_KEY
+		code: i64,
+		/// The human-readable error message
+		message: String,
+	},
+	/// The response body was not the JSON or binary data we expected.
+	InvalidData,
+	/// We failed to read Bitcoin Core's cookie file.
+	CookieUnavailable,
+}
+
+/// The underlying byte stream of an HTTP connection, which may be wrapped in TLS.
+enum HttpStream {
+	Plain(TcpStream),
+	#[cfg(all(feature = "tls", feature = "tokio"))]
+	Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+	#[cfg(all(feature = "tls", not(feature = "tokio")))]
+	Tls(Box<rustls::StreamOwned<rustls::ClientSession, TcpStream>>),
+}
+
+impl HttpStream {
+	async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self {
+			#[cfg(feature = "tokio")]
+			HttpStream::Plain(stream) => stream.read(buf).await,
+			#[cfg(not(feature = "tokio"))]
+			HttpStream::Plain(stream) => stream.read(buf),
+			#[cfg(all(feature = "tls", feature = "tokio"))]
+			HttpStream::Tls(stream) => stream.read(buf).await,
+			#[cfg(all(feature = "tls", not(feature = "tokio")))]
+			HttpStream::Tls(stream) => stream.read(buf),
 		}
 	}
-	if !ok_found || (!chunked && (actual_len == 0 || actual_len > max_resp)) { return None; } // Sorry, not implemented
-	bytes_read = resp.len();
-	if !chunked {
-		resp.resize(actual_len, 0);
-		while bytes_read < actual_len {
-			bytes_read += read_socket!();
-		}
-		Some(resp)
-	} else {
-		actual_len = 0;
-		let mut chunk_remaining = 0;
-		'read_bytes: loop {
-			if chunk_remaining == 0 {
-				let mut bytes_skipped = 0;
-				let mut finished_read = false;
-				let mut lineiter = resp[actual_len..bytes_read].split(|c| *c == '\n' as u8 || *c == '\r' as u8).peekable();
-				loop {
-					let line = match lineiter.next() { Some(line) => line, None => break };
-					if lineiter.peek().is_none() { // We haven't yet read to the end of this line
-						if line.len() > 8 {
-							// No reason to ever have a chunk length line longer than 4 chars
-							return None;
-						}
-						break;
-					}
-					bytes_skipped += line.len() + 1;
-					if line.len() == 0 { continue; } // Probably between the \r and \n
-					match usize::from_str_radix(&match std::str::from_utf8(line) {
-						Ok(s) => s, Err(_) => return None,
-					}, 16) {
-						Ok(chunklen) => {
-							if chunklen == 0 { finished_read = true; }
-							chunk_remaining = chunklen;
-							match lineiter.next() {
-								Some(l) if l.is_empty() => {
-									// Drop \r after \n
-									bytes_skipped += 1;
-									if actual_len + bytes_skipped > bytes_read {
-										// Go back and get more bytes so we can skip trailing \n
-										chunk_remaining = 0;
-									}
-								},
-								Some(_) => {},
-								None => {
-									// Go back and get more bytes so we can skip trailing \n
-									chunk_remaining = 0;
-								},
-							}
-							break;
-						},
-						Err(_) => return None,
-					}
-				}
-				if chunk_remaining != 0 {
-					bytes_read -= bytes_skipped;
-					resp.drain(actual_len..actual_len + bytes_skipped);
-					if actual_len + chunk_remaining > max_resp { return None; }
-					let already_in_chunk = cmp::min(bytes_read - actual_len, chunk_remaining);
-					actual_len += already_in_chunk;
-					chunk_remaining -= already_in_chunk;
-					continue 'read_bytes;
-				} else {
-					if finished_read {
-						// Note that we may leave some extra \r\ns to be read, but that's OK,
-						// we'll ignore then when parsing headers for the next request.
-						resp.resize(actual_len, 0);
-						return Some(resp);
-					} else {
-						// Need to read more bytes to figure out chunk length
-					}
-				}
-			}
-			resp.resize(bytes_read + cmp::max(10, chunk_remaining), 0);
-			let avail = read_socket!();
-			bytes_read += avail;
-			if chunk_remaining != 0 {
-				let chunk_read = cmp::min(chunk_remaining, avail);
-				chunk_remaining -= chunk_read;
-				actual_len += chunk_read;
-			}
+
+	async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+		match self {
+			#[cfg(feature = "tokio")]
+			HttpStream::Plain(stream) => stream.write_all(buf).await,
+			#[cfg(not(feature = "tokio"))]
+			HttpStream::Plain(stream) => stream.write_all(buf),
+			#[cfg(all(feature = "tls", feature = "tokio"))]
+			HttpStream::Tls(stream) => stream.write_all(buf).await,
+			#[cfg(all(feature = "tls", not(feature = "tokio")))]
+			HttpStream::Tls(stream) => stream.write_all(buf),
 		}
 	}
 }
 
-#[cfg(feature = "rest-client")]
-pub struct RESTClient {
+#[cfg(feature = "tls")]
+fn default_tls_config() -> Arc<rustls::ClientConfig> {
+	let mut config = rustls::ClientConfig::new();
+	config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+	Arc::new(config)
+}
+
+/// The maximum number of idle keep-alive connections an HttpClient holds on to.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// An HTTP(S) endpoint which we keep a pool of keep-alive connections open to. Each request takes
+/// an idle connection from the pool (or opens a new one if there are none) and returns it to the
+/// pool once the response has been read, so sequential requests share one connection while
+/// concurrent requests each get their own.
+pub(crate) struct HttpClient {
 	uri: String,
+	idle_streams: Mutex<Vec<HttpStream>>,
+	read_timeout: Duration,
+	#[cfg(feature = "tls")]
+	pub(crate) tls_config: Arc<rustls::ClientConfig>,
 }
 
-#[cfg(feature = "rest-client")]
-impl RESTClient {
-	pub fn new(uri: String) -> Option<Self> {
+impl HttpClient {
+	/// Creates a new HttpClient for the given URI, returning None if the URI is invalid or if it
+	/// is an https URI and we were built without the `tls` feature.
+	pub(crate) fn new(uri: String) -> Option<Self> {
 		match split_uri(&uri) {
-			Some((ssl, _host, _port, _path)) if !ssl => Some(Self { uri }),
-			_ => None,
+			#[cfg(not(feature = "tls"))]
+			Some((true, _host, _port, _path)) => None,
+			Some(_) => Some(Self {
+				uri,
+				idle_streams: Mutex::new(Vec::new()),
+				read_timeout: Duration::from_secs(2),
+				#[cfg(feature = "tls")]
+				tls_config: default_tls_config(),
+			}),
+			None => None,
 		}
 	}
 
-	async fn make_raw_rest_call(&self, req_path: &str) -> Result<Vec<u8>, ()> {
-		let (ssl, host, port, path) = split_uri(&self.uri).unwrap();
-		if ssl { unreachable!(); }
+	/// Gets the (hostname, HTTP path) pair for our URI.
+	pub(crate) fn host_path(&self) -> (&str, &str) {
+		let (_ssl, host, _port, path) = split_uri(&self.uri).unwrap();
+		(host, path)
+	}
+
+	async fn connect(&self) -> Result<HttpStream, ()> {
+		let (ssl, host, port, _path) = split_uri(&self.uri).unwrap();
 
-		let mut stream = match std::net::TcpStream::connect_timeout(&match (host, port).to_socket_addrs() {
+		let stream = match std::net::TcpStream::connect_timeout(&match (host, port).to_socket_addrs() {
 			Ok(mut sockaddrs) => match sockaddrs.next() { Some(sockaddr) => sockaddr, None => return Err(()) },
 			Err(_) => return Err(()),
 		}, Duration::from_secs(1)) {
@@ -249,71 +203,293 @@ impl RESTClient {
 			Err(_) => return Err(()),
 		};
 		stream.set_write_timeout(Some(Duration::from_secs(1))).expect("Host kernel is uselessly old?");
-		stream.set_read_timeout(Some(Duration::from_secs(2))).expect("Host kernel is uselessly old?");
-
-		let req = format!("GET {}/{} HTTP/1.1\nHost: {}\nConnection: keep-alive\n\n", path, req_path, host);
-		match stream.write(req.as_bytes()) {
-			Ok(len) if len == req.len() => {},
-			_ => return Err(()),
-		}
+		stream.set_read_timeout(Some(self.read_timeout)).expect("Host kernel is uselessly old?");
 		#[cfg(feature = "tokio")]
 		let stream = TcpStream::from_std(stream).unwrap();
-		match read_http_resp(stream, 4_000_000).await {
-			Some(r) => Ok(r),
-			None => return Err(()),
+
+		if ssl {
+			#[cfg(feature = "tls")]
+			{
+				let dns_name = webpki::DNSNameRef::try_from_ascii_str(host).map_err(|_| ())?;
+				#[cfg(feature = "tokio")]
+				{
+					let connector = tokio_rustls::TlsConnector::from(Arc::clone(&self.tls_config));
+					let tls_stream = connector.connect(dns_name, stream).await.map_err(|_| ())?;
+					return Ok(HttpStream::Tls(Box::new(tls_stream)));
+				}
+				#[cfg(not(feature = "tokio"))]
+				{
+					let session = rustls::ClientSession::new(&self.tls_config, dns_name);
+					return Ok(HttpStream::Tls(Box::new(rustls::StreamOwned::new(session, stream))));
+				}
+			}
+			#[cfg(not(feature = "tls"))]
+			unreachable!(); // new() refuses https URIs without the tls feature
+		}
+		Ok(HttpStream::Plain(stream))
+	}
+
+	async fn send_on_stream(stream: &mut HttpStream, req: &[u8], max_resp: usize) -> Result<HttpResponse, HttpClientError> {
+		stream.write_all(req).await.map_err(|_| HttpClientError::Transport)?;
+		read_http_resp(stream, max_resp).await
+	}
+
+	/// Sends a full HTTP request and returns the response, reusing an idle connection from our
+	/// pool if we have one. The connection is only returned to the pool if the server allows it.
+	pub(crate) async fn make_request(&self, req: &[u8], max_resp: usize) -> Result<HttpResponse, HttpClientError> {
+		// Don't hold the pool lock across the request so that concurrent requests can proceed.
+		let idle_stream = self.idle_streams.lock().unwrap().pop();
+		let (mut stream, reused) = match idle_stream {
+			Some(stream) => (stream, true),
+			None => (self.connect().await.map_err(|_| HttpClientError::Transport)?, false),
+		};
+		let resp = match Self::send_on_stream(&mut stream, req, max_resp).await {
+			Err(HttpClientError::Transport) if reused => {
+				// The server may have closed our idle keep-alive connection, in which case it has
+				// likely closed the rest of the pool as well (eg because it restarted). Drop them
+				// all and try once more with a fresh connection.
+				self.idle_streams.lock().unwrap().clear();
+				stream = self.connect().await.map_err(|_| HttpClientError::Transport)?;
+				Self::send_on_stream(&mut stream, req, max_resp).await?
+			},
+			res => res?,
+		};
+		if resp.keep_alive {
+			let mut idle_streams = self.idle_streams.lock().unwrap();
+			if idle_streams.len() < MAX_IDLE_CONNECTIONS {
+				idle_streams.push(stream);
+			}
+		}
+		Ok(resp)
+	}
+}
+
+/// Reads an HTTP response from the given stream, never reading past the end of the response so
+/// that the stream may be reused for further requests if the response indicates keep-alive.
+async fn read_http_resp(socket: &mut HttpStream, max_resp: usize) -> Result<HttpResponse, HttpClientError> {
+	let mut parser = HttpResponseParser::new(max_resp);
+	let mut buf = [0; 8192];
+	let mut received_data = false;
+	loop {
+		let bytes_read = match socket.read(&mut buf).await {
+			Ok(0) => {
+				if !received_data { return Err(HttpClientError::Transport); }
+				parser.eof().map_err(HttpClientError::InvalidResponse)?;
+				let mut resp = parser.into_response().unwrap();
+				resp.keep_alive = false;
+				return Ok(resp);
+			},
+			Ok(b) => b,
+			Err(_) => return Err(HttpClientError::Transport),
+		};
+		received_data = true;
+		let consumed = parser.feed(&buf[..bytes_read]).map_err(HttpClientError::InvalidResponse)?;
+		if parser.is_complete() {
+			let mut resp = parser.into_response().unwrap();
+			if consumed != bytes_read {
+				// The server sent something after the response we asked for, we can't trust the
+				// connection to be in a sane state anymore.
+				resp.keep_alive = false;
+			}
+			return Ok(resp);
 		}
 	}
+}
+
+#[cfg(feature = "rest-client")]
+/// A BlockSource which fetches headers and blocks from Bitcoin Core's REST interface (ie
+/// bitcoind run with -rest), keeping connections alive between requests.
+pub struct RESTClient {
+	http: HttpClient,
+}
+
+#[cfg(feature = "rest-client")]
+impl RESTClient {
+	/// Creates a new RESTClient for the given base URI (eg http://127.0.0.1:8332/rest). https
+	/// URIs are only supported with the `tls` feature.
+	pub fn new(uri: String) -> Option<Self> {
+		Some(Self { http: HttpClient::new(uri)? })
+	}
+
+	#[cfg(feature = "tls")]
+	/// Sets the TLS configuration used for https URIs, eg to trust a self-signed certificate on
+	/// a reverse proxy in front of bitcoind. By default we trust the webpki root certificates.
+	pub fn set_tls_config(&mut self, config: Arc<rustls::ClientConfig>) {
+		self.http.tls_config = config;
+	}
 
-	async fn make_rest_call(&self, req_path: &str) -> Result<serde_json::Value, ()> {
+	async fn make_raw_rest_call(&self, req_path: &str) -> Result<Vec<u8>, HttpClientError> {
+		let req = {
+			let (host, path) = self.http.host_path();
+			format!("GET {}/{} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n\r\n", path, req_path, host)
+		};
+		let resp = self.http.make_request(req.as_bytes(), 4_000_000).await?;
+		if resp.status_code != 200 {
+			return Err(HttpClientError::Status(resp.status_code));
+		}
+		Ok(resp.body)
+	}
+
+	async fn make_rest_call(&self, req_path: &str) -> Result<serde_json::Value, HttpClientError> {
 		let resp = self.make_raw_rest_call(req_path).await?;
 		let v: serde_json::Value = match serde_json::from_slice(&resp[..]) {
 			Ok(v) => v,
-			Err(_) => return Err(()),
+			Err(_) => return Err(HttpClientError::InvalidData),
 		};
-		if !v.is_object() {
-			return Err(());
+		if !v.is_object() && !v.is_array() {
+			return Err(HttpClientError::InvalidData);
 		}
 		Ok(v)
 	}
 }
 
 #[cfg(feature = "rpc-client")]
+enum RPCAuth {
+	/// A static "user:password" pair, stored as the full Authorization header value.
+	UserPass(String),
+	/// Bitcoin Core's cookie file, which is rewritten with a fresh password each time bitcoind
+	/// starts. We cache the Authorization header value until we get an HTTP 401.
+	Cookie { path: PathBuf, basic_auth: Mutex<Option<String>> },
+}
+
+/// Reads a Bitcoin Core cookie file (which contains "__cookie__:password") and returns the
+/// corresponding HTTP Basic Authorization header value.
+#[cfg(feature = "rpc-client")]
+fn read_cookie_auth(path: &Path) -> Result<String, ()> {
+	let cookie = std::fs::read_to_string(path).map_err(|_| ())?;
+	let cookie = cookie.trim();
+	if !cookie.contains(':') { return Err(()); }
+	Ok("Basic ".to_string() + &base64::encode(cookie))
+}
+
+#[cfg(feature = "rpc-client")]
+/// A BlockSource which fetches headers and blocks from Bitcoin Core's JSON-RPC interface, keeping
+/// connections alive between requests.
+///
+/// Calls may be made concurrently through a shared reference, each using its own connection.
 pub struct RPCClient {
-	basic_auth: String,
-	uri: String,
+	auth: RPCAuth,
+	http: HttpClient,
 	id: AtomicUsize,
 }
 
 #[cfg(feature = "rpc-client")]
 impl RPCClient {
+	/// Creates a new RPCClient for the given URI which authenticates with a static user_auth
+	/// string of the form "user:password" (ie bitcoind's -rpcuser/-rpcpassword or -rpcauth).
 	pub fn new(user_auth: &str, uri: String) -> Option<Self> {
-		match split_uri(&uri) {
-			Some((ssl, _host, _port, _path)) if !ssl => {
-				Some(Self {
-					basic_auth: "Basic ".to_string() + &base64::encode(user_auth),
-					uri,
-					id: AtomicUsize::new(0),
-				})
+		Some(Self {
+			auth: RPCAuth::UserPass("Basic ".to_string() + &base64::encode(user_auth)),
+			http: HttpClient::new(uri)?,
+			id: AtomicUsize::new(0),
+		})
+	}
+
+	/// Creates a new RPCClient for the given URI which authenticates using bitcoind's cookie
+	/// file (by default .cookie in the data directory). The cookie is read on first use and
+	/// re-read whenever bitcoind rejects our credentials, eg because it restarted.
+	pub fn new_with_cookie(cookie_path: PathBuf, uri: String) -> Option<Self> {
+		Some(Self {
+			auth: RPCAuth::Cookie { path: cookie_path, basic_auth: Mutex::new(None) },
+			http: HttpClient::new(uri)?,
+			id: AtomicUsize::new(0),
+		})
+	}
+
+	#[cfg(feature = "tls")]
+	/// Sets the TLS configuration used for https URIs, eg to trust a self-signed certificate on
+	/// a reverse proxy in front of bitcoind. By default we trust the webpki root certificates.
+	pub fn set_tls_config(&mut self, config: Arc<rustls::ClientConfig>) {
+		self.http.tls_config = config;
+	}
+
+	/// Sets how long we wait for a response before giving up (when not using tokio), eg for
+	/// long-polling RPCs. Defaults to two seconds.
+	pub(crate) fn set_read_timeout(&mut self, timeout: Duration) {
+		self.http.read_timeout = timeout;
+		// Make sure we reconnect with the new timeout
+		self.http.idle_streams.get_mut().unwrap().clear();
+	}
+
+	/// Gets the Authorization header value, (re-)reading the cookie file if required.
+	fn get_basic_auth(&self, reload_cookie: bool) -> Result<String, HttpClientError> {
+		match &self.auth {
+			RPCAuth::UserPass(basic_auth) => Ok(basic_auth.clone()),
+			RPCAuth::Cookie { path, basic_auth } => {
+				let mut basic_auth = basic_auth.lock().unwrap();
+				if reload_cookie || basic_auth.is_none() {
+					*basic_auth = Some(read_cookie_auth(path).map_err(|_| HttpClientError::CookieUnavailable)?);
+				}
+				Ok(basic_auth.as_ref().unwrap().clone())
 			},
-			_ => None,
 		}
 	}
 
+	/// Calls the given JSON-RPC method, returning the "result" field of the response.
+	///
 	/// params entries must be pre-quoted if appropriate
-	async fn make_rpc_call(&self, method: &str, params: &[&str]) -> Result<serde_json::Value, ()> {
-		let (ssl, host, port, path) = split_uri(&self.uri).unwrap();
-		if ssl { unreachable!(); }
+	pub async fn call_method(&self, method: &str, params: &[&str]) -> Result<serde_json::Value, HttpClientError> {
+		let req = self.build_request(method, params).1;
+		let resp = self.post(&req).await?;
+
+		// Bitcoin Core returns JSON-RPC errors with a non-200 status code (eg 404 for unknown
+		// methods and 500 for most other errors), so look for an error object before checking the
+		// status code.
+		let v: serde_json::Value = match serde_json::from_slice(&resp.body[..]) {
+			Ok(v) => v,
+			Err(_) if resp.status_code != 200 => return Err(HttpClientError::Status(resp.status_code)),
+			Err(_) => return Err(HttpClientError::InvalidData),
+		};
+		match v.as_object() {
+			Some(obj) => rpc_result(obj, resp.status_code),
+			None if resp.status_code != 200 => Err(HttpClientError::Status(resp.status_code)),
+			None => Err(HttpClientError::InvalidData),
+		}
+	}
 
-		let mut stream = match std::net::TcpStream::connect_timeout(&match (host, port).to_socket_addrs() {
-			Ok(mut sockaddrs) => match sockaddrs.next() { Some(sockaddr) => sockaddr, None => return Err(()) },
-			Err(_) => return Err(()),
-		}, Duration::from_secs(1)) {
-			Ok(stream) => stream,
-			Err(_) => return Err(()),
+	/// Calls the given JSON-RPC method once for each entry in params_list, all in a single
+	/// JSON-RPC batch request, returning the result of each call in the same order. Only fails as
+	/// a whole if the request itself does.
+	///
+	/// params entries must be pre-quoted if appropriate
+	pub(crate) async fn call_method_batch(&self, method: &str, params_list: &[Vec<&str>]) -> Result<Vec<Result<serde_json::Value, HttpClientError>>, HttpClientError> {
+		if params_list.is_empty() { return Ok(Vec::new()); }
+		let mut ids = Vec::with_capacity(params_list.len());
+		let mut req = "[".to_string();
+		for params in params_list {
+			let (id, call) = self.build_request(method, params);
+			if !ids.is_empty() { req += ","; }
+			req += &call;
+			ids.push(id);
+		}
+		req += "]";
+		let resp = self.post(&req).await?;
+
+		let v: serde_json::Value = match serde_json::from_slice(&resp.body[..]) {
+			Ok(v) => v,
+			Err(_) if resp.status_code != 200 => return Err(HttpClientError::Status(resp.status_code)),
+			Err(_) => return Err(HttpClientError::InvalidData),
 		};
-		stream.set_write_timeout(Some(Duration::from_secs(1))).expect("Host kernel is uselessly old?");
-		stream.set_read_timeout(Some(Duration::from_secs(2))).expect("Host kernel is uselessly old?");
+		let responses = match v {
+			serde_json::Value::Array(responses) => responses,
+			// The batch as a whole was rejected
+			serde_json::Value::Object(obj) => return Err(rpc_result(&obj, resp.status_code).err().unwrap_or(HttpClientError::InvalidData)),
+			_ if resp.status_code != 200 => return Err(HttpClientError::Status(resp.status_code)),
+			_ => return Err(HttpClientError::InvalidData),
+		};
+		// Responses to a batch may come back in any order, so match them up by id.
+		let mut results: Vec<Result<serde_json::Value, HttpClientError>> = ids.iter().map(|_| Err(HttpClientError::InvalidData)).collect();
+		for response in responses {
+			let obj = response.as_object().ok_or(HttpClientError::InvalidData)?;
+			let id = obj.get("id").and_then(|id| id.as_u64()).ok_or(HttpClientError::InvalidData)?;
+			let idx = ids.iter().position(|call_id| *call_id as u64 == id).ok_or(HttpClientError::InvalidData)?;
+			results[idx] = rpc_result(obj, 200);
+		}
+		Ok(results)
+	}
 
+	/// Builds the JSON-RPC request object for a single call, returning it along with its id.
+	fn build_request(&self, method: &str, params: &[&str]) -> (usize, String) {
 		let mut param_str = String::new();
 		for (idx, param) in params.iter().enumerate() {
 			param_str += param;
@@ -321,36 +497,62 @@ impl RPCClient {
 				param_str += ",";
 			}
 		}
-		let req = "{\"method\":\"".to_string() + method + "\",\"params\":[" + &param_str + "],\"id\":" + &self.id.fetch_add(1, Ordering::AcqRel).to_string() + "}";
+		let id = self.id.fetch_add(1, Ordering::AcqRel);
+		(id, "{\"method\":\"".to_string() + method + "\",\"params\":[" + &param_str + "],\"id\":" + &id.to_string() + "}")
+	}
 
-		let req = format!("POST {} HTTP/1.1\r\nHost: {}\r\nAuthorization: {}\r\nConnection: keep-alive\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", path, host, &self.basic_auth, req.len(), req);
-		match stream.write(req.as_bytes()) {
-			Ok(len) if len == req.len() => {},
-			_ => return Err(()),
-		}
-		#[cfg(feature = "tokio")]
-		let stream = TcpStream::from_std(stream).unwrap();
-		let resp = match read_http_resp(stream, 4_000_000).await {
-			Some(r) => r,
-			None => return Err(()),
+	/// POSTs the given JSON-RPC request body, retrying once with a fresh cookie if it is rejected.
+	async fn post(&self, req: &str) -> Result<HttpResponse, HttpClientError> {
+		let mut reloaded_cookie = false;
+		let resp = loop {
+			let basic_auth = self.get_basic_auth(reloaded_cookie)?;
+			let http_req = {
+				let (host, path) = self.http.host_path();
+				format!("POST {} HTTP/1.1\r\nHost: {}\r\nAuthorization: {}\r\nConnection: keep-alive\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", path, host, basic_auth, req.len(), req)
+			};
+			let resp = self.http.make_request(http_req.as_bytes(), 4_000_000).await?;
+			if resp.status_code == 401 && !reloaded_cookie {
+				if let RPCAuth::UserPass(_) = self.auth { return Err(HttpClientError::Status(401)); }
+				// bitcoind writes a fresh cookie each time it starts, re-read it and retry.
+				reloaded_cookie = true;
+				continue;
+			}
+			break resp;
 		};
+		Ok(resp)
+	}
+}
 
-		let v: serde_json::Value = match serde_json::from_slice(&resp[..]) {
-			Ok(v) => v,
-			Err(_) => return Err(()),
-		};
-		if !v.is_object() {
-			return Err(());
-		}
-		let v_obj = v.as_object().unwrap();
-		if v_obj.get("error") != Some(&serde_json::Value::Null) {
-			return Err(());
-		}
-		if let Some(res) = v_obj.get("result") {
-			Ok((*res).clone())
-		} else {
-			Err(())
-		}
+#[cfg(feature = "rpc-client")]
+/// Gets the result of a single JSON-RPC response object, preferring its error object (if any) over
+/// a non-200 status code.
+fn rpc_result(obj: &serde_json::Map<String, serde_json::Value>, status_code: u16) -> Result<serde_json::Value, HttpClientError> {
+	match obj.get("error") {
+		None|Some(serde_json::Value::Null) => {},
+		Some(err) => {
+			return Err(HttpClientError::RPCError {
+				code: err["code"].as_i64().unwrap_or(0),
+				message: err["message"].as_str().unwrap_or("").to_string(),
+			});
+		},
+	}
+	if status_code != 200 {
+		return Err(HttpClientError::Status(status_code));
+	}
+	match obj.get("result") {
+		Some(res) => Ok(res.clone()),
+		None => Err(HttpClientError::InvalidData),
+	}
+}
+
+/// Maps a failed request to the BlockSourceRespErr we should return. A server which answered but
+/// not with what we asked for (eg an HTTP error status or a response we failed to parse) is
+/// usually misconfigured (eg bitcoind was started without -rest) rather than malicious, so, as for
+/// transport failures, we return NoResponse and keep polling it rather than banning it.
+fn resp_err(e: HttpClientError) -> BlockSourceRespErr {
+	match e {
+		HttpClientError::Transport|HttpClientError::InvalidResponse(_)|HttpClientError::Status(_)|
+		HttpClientError::RPCError { .. }|HttpClientError::InvalidData|HttpClientError::CookieUnavailable => BlockSourceRespErr::NoResponse,
 	}
 }
 
@@ -392,27 +594,25 @@ impl BlockSource for RPCClient {
 	fn get_header<'a>(&'a mut self, header_hash: &'a BlockHash, _height: Option<u32>) -> Pin<Box<dyn Future<Output = Result<BlockHeaderData, BlockSourceRespErr>> + 'a + Send>> {
 		let param = "\"".to_string() + &header_hash.to_hex() + "\"";
 		Box::pin(async move {
-			let res = self.make_rpc_call("getblockheader", &[&param]).await;
-			if let Ok(mut v) = res {
-				if v.is_object() {
-					if let None = v.get("previousblockhash") {
-						// Got a request for genesis block, add a dummy previousblockhash
-						v.as_object_mut().unwrap().insert("previousblockhash".to_string(), serde_json::Value::String("".to_string()));
-					}
+			let mut v = self.call_method("getblockheader", &[&param]).await.map_err(resp_err)?;
+			if v.is_object() {
+				if let None = v.get("previousblockhash") {
+					// Got a request for genesis block, add a dummy previousblockhash
+					v.as_object_mut().unwrap().insert("previousblockhash".to_string(), serde_json::Value::String("".to_string()));
 				}
-				let deser_res: Result<GetHeaderResponse, _> = serde_json::from_value(v);
-				match deser_res {
-					Ok(resp) => resp.to_block_header(),
-					Err(_) => Err(BlockSourceRespErr::NoResponse),
-				}
-			} else { Err(BlockSourceRespErr::NoResponse) }
+			}
+			let deser_res: Result<GetHeaderResponse, _> = serde_json::from_value(v);
+			match deser_res {
+				Ok(resp) => resp.to_block_header(),
+				Err(_) => Err(BlockSourceRespErr::NoResponse),
+			}
 		})
 	}
 
 	fn get_block<'a>(&'a mut self, header_hash: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<Block, BlockSourceRespErr>> + 'a + Send>> {
 		let param = "\"".to_string() + &header_hash.to_hex() + "\"";
 		Box::pin(async move {
-			let blockhex = self.make_rpc_call("getblock", &[&param, "0"]).await.map_err(|_| BlockSourceRespErr::NoResponse)?;
+			let blockhex = self.call_method("getblock", &[&param, "0"]).await.map_err(resp_err)?;
 			let blockdata = hex_to_vec(blockhex.as_str().ok_or(BlockSourceRespErr::NoResponse)?).ok_or(BlockSourceRespErr::NoResponse)?;
 			let block: Block = encode::deserialize(&blockdata).map_err(|_| BlockSourceRespErr::NoResponse)?;
 			Ok(block)
@@ -421,12 +621,28 @@ impl BlockSource for RPCClient {
 
 	fn get_best_block<'a>(&'a mut self) -> Pin<Box<dyn Future<Output = Result<(BlockHash, Option<u32>), BlockSourceRespErr>> + 'a + Send>> {
 		Box::pin(async move {
-			if let Ok(v) = self.make_rpc_call("getblockchaininfo", &[]).await {
-				let height = v["blocks"].as_u64().ok_or(BlockSourceRespErr::NoResponse)?
-					.try_into().map_err(|_| BlockSourceRespErr::NoResponse)?;
-				let blockstr = v["bestblockhash"].as_str().ok_or(BlockSourceRespErr::NoResponse)?;
-				Ok((BlockHash::from_hex(blockstr).map_err(|_| BlockSourceRespErr::NoResponse)?, Some(height)))
-			} else { Err(BlockSourceRespErr::NoResponse) }
+			let v = self.call_method("getblockchaininfo", &[]).await.map_err(resp_err)?;
+			let height = v["blocks"].as_u64().ok_or(BlockSourceRespErr::NoResponse)?
+				.try_into().map_err(|_| BlockSourceRespErr::NoResponse)?;
+			let blockstr = v["bestblockhash"].as_str().ok_or(BlockSourceRespErr::NoResponse)?;
+			Ok((BlockHash::from_hex(blockstr).map_err(|_| BlockSourceRespErr::NoResponse)?, Some(height)))
+		})
+	}
+}
+
+#[cfg(feature = "rpc-client")]
+impl BlockHashSource for RPCClient {
+	fn get_block_hash_by_height<'a>(&'a mut self, height: u32) -> Pin<Box<dyn Future<Output = Result<Option<BlockHash>, BlockSourceRespErr>> + 'a + Send>> {
+		Box::pin(async move {
+			match self.call_method("getblockhash", &[&height.to_string()]).await {
+				Ok(v) => {
+					let blockstr = v.as_str().ok_or(BlockSourceRespErr::NoResponse)?;
+					Ok(Some(BlockHash::from_hex(blockstr).map_err(|_| BlockSourceRespErr::NoResponse)?))
+				},
+				// RPC_INVALID_PARAMETER, returned for heights beyond the tip
+				Err(HttpClientError::RPCError { code: -8, .. }) => Ok(None),
+				Err(e) => Err(resp_err(e)),
+			}
 		})
 	}
 }
@@ -436,8 +652,8 @@ impl BlockSource for RESTClient {
 	fn get_header<'a>(&'a mut self, header_hash: &'a BlockHash, _height: Option<u32>) -> Pin<Box<dyn Future<Output = Result<BlockHeaderData, BlockSourceRespErr>> + 'a + Send>> {
 		Box::pin(async move {
 			let reqpath = format!("headers/1/{}.json", header_hash.to_hex());
-			match self.make_rest_call(&reqpath).await {
-				Ok(serde_json::Value::Array(mut v)) if !v.is_empty() => {
+			match self.make_rest_call(&reqpath).await.map_err(resp_err)? {
+				serde_json::Value::Array(mut v) if !v.is_empty() => {
 					let mut header = v.drain(..).next().unwrap();
 					if !header.is_object() { return Err(BlockSourceRespErr::NoResponse); }
 					if let None = header.get("previousblockhash") {
@@ -458,7 +674,7 @@ impl BlockSource for RESTClient {
 	fn get_block<'a>(&'a mut self, header_hash: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<Block, BlockSourceRespErr>> + 'a + Send>> {
 		Box::pin(async move {
 			let reqpath = format!("block/{}.bin", header_hash.to_hex());
-			let blockdata = self.make_raw_rest_call(&reqpath).await.map_err(|_| BlockSourceRespErr::NoResponse)?;
+			let blockdata = self.make_raw_rest_call(&reqpath).await.map_err(resp_err)?;
 			let block: Block = encode::deserialize(&blockdata).map_err(|_| BlockSourceRespErr::NoResponse)?;
 			Ok(block)
 		})
@@ -466,7 +682,7 @@ impl BlockSource for RESTClient {
 
 	fn get_best_block<'a>(&'a mut self) -> Pin<Box<dyn Future<Output = Result<(BlockHash, Option<u32>), BlockSourceRespErr>> + 'a + Send>> {
 		Box::pin(async move {
-			let v = self.make_rest_call("chaininfo.json").await.map_err(|_| BlockSourceRespErr::NoResponse)?;
+			let v = self.make_rest_call("chaininfo.json").await.map_err(resp_err)?;
 			let height = v["blocks"].as_u64().ok_or(BlockSourceRespErr::NoResponse)?
 				.try_into().map_err(|_| BlockSourceRespErr::NoResponse)?;
 			let blockstr = v["bestblockhash"].as_str().ok_or(BlockSourceRespErr::NoResponse)?;
@@ -475,6 +691,21 @@ impl BlockSource for RESTClient {
 	}
 }
 
+#[cfg(feature = "rest-client")]
+impl BlockHashSource for RESTClient {
+	fn get_block_hash_by_height<'a>(&'a mut self, height: u32) -> Pin<Box<dyn Future<Output = Result<Option<BlockHash>, BlockSourceRespErr>> + 'a + Send>> {
+		Box::pin(async move {
+			let reqpath = format!("blockhashbyheight/{}.bin", height);
+			match self.make_raw_rest_call(&reqpath).await {
+				Ok(hashdata) => Ok(Some(encode::deserialize(&hashdata).map_err(|_| BlockSourceRespErr::NoResponse)?)),
+				// Bitcoin Core responds 404 for heights beyond the tip
+				Err(HttpClientError::Status(404)) => Ok(None),
+				Err(e) => Err(resp_err(e)),
+			}
+		})
+	}
+}
+
 #[cfg(test)]
 #[test]
 fn test_split_uri() {
@@ -488,3 +719,166 @@ fn test_split_uri() {
 	assert_eq!(split_uri("ftp://example.com:80/"), None);
 	assert_eq!(split_uri("http://example.com"), Some((false, "example.com", 80, "")));
 }
+
+#[cfg(test)]
+#[test]
+fn test_resp_err() {
+	assert!(match resp_err(HttpClientError::Transport) { BlockSourceRespErr::NoResponse => true, _ => false });
+	assert!(match resp_err(HttpClientError::Status(404)) { BlockSourceRespErr::NoResponse => true, _ => false });
+	assert!(match resp_err(HttpClientError::InvalidData) { BlockSourceRespErr::NoResponse => true, _ => false });
+	assert!(match resp_err(HttpClientError::RPCError { code: -1, message: String::new() }) { BlockSourceRespErr::NoResponse => true, _ => false });
+}
+
+/// Spawns a stand-in HTTP server which answers the given number of requests, closing each
+/// connection after requests_per_connection of them, and returning the URI to point a client at.
+/// The server thread returns the number of connections it accepted.
+///
+/// handler is called with each request's headers and body and returns the response's status
+/// line (eg "200 OK") and body.
+#[cfg(all(test, feature = "rpc-client"))]
+pub(crate) fn spawn_http_server<H>(num_requests: usize, requests_per_connection: usize, handler: H) -> (String, std::thread::JoinHandle<usize>)
+		where H: Fn(&str, &[u8]) -> (&'static str, String) + Send + 'static {
+	// Read and Write may already be imported at the top level depending on features
+	use std::io::{BufRead, BufReader};
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let uri = format!("http://{}/", listener.local_addr().unwrap());
+	let server = std::thread::spawn(move || {
+		let mut served = 0;
+		let mut connections = 0;
+		while served < num_requests {
+			let (stream, _) = listener.accept().unwrap();
+			connections += 1;
+			let mut reader = BufReader::new(stream);
+			let mut served_on_connection = 0;
+			while served < num_requests && served_on_connection < requests_per_connection {
+				let mut line = String::new();
+				if reader.read_line(&mut line).unwrap() == 0 { break; }
+				let mut headers = String::new();
+				let mut content_len = 0;
+				loop {
+					line.clear();
+					reader.read_line(&mut line).unwrap();
+					if line == "\r\n" { break; }
+					if line.to_ascii_lowercase().starts_with("content-length: ") {
+						content_len = line[16..].trim().parse().unwrap();
+					}
+					headers += &line;
+				}
+				let mut body = vec![0; content_len];
+				std::io::Read::read_exact(&mut reader, &mut body).unwrap();
+				let (status, resp) = handler(&headers, &body);
+				let http_resp = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", status, resp.len(), resp);
+				std::io::Write::write_all(reader.get_mut(), http_resp.as_bytes()).unwrap();
+				served += 1;
+				served_on_connection += 1;
+			}
+		}
+		connections
+	});
+	(uri, server)
+}
+
+/// Spawns a stand-in for bitcoind's JSON-RPC server which answers the given number of requests
+/// (over however many connections the client makes), returning the URI to point an RPCClient at.
+///
+/// handler is called with each parsed request (a single call or a batch) and returns the response
+/// body, which is sent with a 500 status if it is an object with a non-null error, as bitcoind
+/// does, and a 200 otherwise.
+#[cfg(all(test, feature = "rpc-client"))]
+pub(crate) fn spawn_rpc_server<H>(num_requests: usize, handler: H) -> (String, std::thread::JoinHandle<usize>)
+		where H: Fn(&serde_json::Value) -> serde_json::Value + Send + 'static {
+	spawn_http_server(num_requests, usize::max_value(), move |_headers, body| {
+		let resp = handler(&serde_json::from_slice(body).unwrap());
+		let status = match resp.get("error") {
+			Some(err) if !err.is_null() => "500 Internal Server Error",
+			_ => "200 OK",
+		};
+		(status, resp.to_string())
+	})
+}
+
+#[cfg(all(test, feature = "rpc-client"))]
+#[tokio::test]
+async fn test_rpc_batch() {
+	let (uri, server) = spawn_rpc_server(2, |req| {
+		match req.as_array() {
+			// Answer out of order, with an error for any odd parameter
+			Some(calls) => serde_json::Value::Array(calls.iter().rev().map(|call| {
+				let param = call["params"][0].as_u64().unwrap();
+				if param % 2 == 1 {
+					serde_json::json!({"result": null, "error": {"code": -5, "message": "odd"}, "id": call["id"]})
+				} else {
+					serde_json::json!({"result": param * 2, "error": null, "id": call["id"]})
+				}
+			}).collect()),
+			None => serde_json::json!({"result": "single", "error": null, "id": req["id"]}),
+		}
+	});
+	let client = RPCClient::new("user:pass", uri).unwrap();
+	let results = client.call_method_batch("double", &[vec!["2"], vec!["3"], vec!["4"]]).await.unwrap();
+	assert_eq!(results, vec![Ok(serde_json::json!(4)), Err(HttpClientError::RPCError { code: -5, message: "odd".to_string() }), Ok(serde_json::json!(8))]);
+	assert_eq!(client.call_method_batch("double", &[]).await, Ok(Vec::new()));
+	assert_eq!(client.call_method("single", &[]).await, Ok(serde_json::json!("single")));
+	server.join().unwrap();
+}
+
+#[cfg(all(test, feature = "rpc-client"))]
+#[test]
+fn test_read_cookie_auth() {
+	let path = std::env::temp_dir().join(format!("lightning-block-sync-cookie-{}", std::process::id()));
+	std::fs::write(&path, "__cookie__:deadbeef\n").unwrap();
+	assert_eq!(read_cookie_auth(&path), Ok("Basic ".to_string() + &base64::encode("__cookie__:deadbeef")));
+	std::fs::write(&path, "garbage").unwrap();
+	assert_eq!(read_cookie_auth(&path), Err(()));
+	std::fs::remove_file(&path).unwrap();
+	assert_eq!(read_cookie_auth(&path), Err(()));
+}
+
+#[cfg(all(test, feature = "rpc-client"))]
+#[tokio::test]
+async fn test_connection_reuse() {
+	let (uri, server) = spawn_rpc_server(3, |req| serde_json::json!({"result": req["params"][0], "error": null, "id": req["id"]}));
+	let client = RPCClient::new("user:pass", uri).unwrap();
+	for i in 0..3 {
+		assert_eq!(client.call_method("echo", &[&i.to_string()]).await, Ok(serde_json::json!(i)));
+	}
+	// All three requests were made over the one pooled connection.
+	assert_eq!(server.join().unwrap(), 1);
+}
+
+#[cfg(all(test, feature = "rpc-client"))]
+#[tokio::test]
+async fn test_stale_connection_retry() {
+	// The server closes each connection after answering a single request without telling us, so
+	// each subsequent request first fails on the stale pooled connection and is retried.
+	let (uri, server) = spawn_http_server(3, 1, |_headers, body| {
+		let req: serde_json::Value = serde_json::from_slice(body).unwrap();
+		("200 OK", serde_json::json!({"result": req["params"][0], "error": null, "id": req["id"]}).to_string())
+	});
+	let client = RPCClient::new("user:pass", uri).unwrap();
+	for i in 0..3 {
+		assert_eq!(client.call_method("echo", &[&i.to_string()]).await, Ok(serde_json::json!(i)));
+	}
+	assert_eq!(server.join().unwrap(), 3);
+}
+
+#[cfg(all(test, feature = "rpc-client"))]
+#[tokio::test]
+async fn test_cookie_reread_on_401() {
+	let path = std::env::temp_dir().join(format!("lightning-block-sync-cookie-401-{}", std::process::id()));
+	let expected_auth = "Authorization: Basic ".to_string() + &base64::encode("__cookie__:new");
+	let (uri, server) = spawn_http_server(4, usize::max_value(), move |headers, body| {
+		if !headers.contains(&expected_auth) { return ("401 Unauthorized", String::new()); }
+		let req: serde_json::Value = serde_json::from_slice(body).unwrap();
+		("200 OK", serde_json::json!({"result": "ok", "error": null, "id": req["id"]}).to_string())
+	});
+	std::fs::write(&path, "__cookie__:old").unwrap();
+	let client = RPCClient::new_with_cookie(path.clone(), uri).unwrap();
+	// We only re-read the cookie once per call, so still-stale credentials are reported as such.
+	assert_eq!(client.call_method("test", &[]).await, Err(HttpClientError::Status(401)));
+	// Once bitcoind has written a new cookie, our cached credentials are rejected and we re-read it.
+	std::fs::write(&path, "__cookie__:new").unwrap();
+	assert_eq!(client.call_method("test", &[]).await, Ok(serde_json::json!("ok")));
+	std::fs::remove_file(&path).unwrap();
+	server.join().unwrap();
+}
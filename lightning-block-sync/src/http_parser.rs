@@ -0,0 +1,418 @@
+//! An incremental HTTP/1.1 response parser. It is fed bytes as they are read off a connection and
+//! never consumes bytes past the end of the response, so that connections can be kept alive and
+//! reused for further requests.
+//!
+//! We support Content-Length-delimited, chunked (including chunk extensions and trailers) and
+//! connection-close-delimited bodies, skip interim 1xx responses, and match header names
+//! case-insensitively.
+
+use std::cmp;
+use std::mem;
+use std::str;
+
+/// The maximum number of bytes we'll accept in the status line and headers, including trailers
+/// and any interim responses.
+const MAX_HEADERS_LEN: usize = 8192;
+/// The maximum length of a chunk-size line, including any chunk extensions.
+const MAX_CHUNK_LINE_LEN: usize = 256;
+
+/// Failure type for parsing an HTTP response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HttpParseError {
+	/// The response does not conform to HTTP/1.x. The string describes what was wrong.
+	Malformed(&'static str),
+	/// The headers or the body exceeded our limits.
+	TooLarge,
+	/// The response uses a feature we don't support, eg a Transfer-Encoding other than chunked.
+	Unsupported(&'static str),
+	/// The connection was closed before the response was complete.
+	UnexpectedEof,
+}
+
+/// A complete HTTP response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+	/// The status code of the (final, non-1xx) response.
+	pub status_code: u16,
+	/// All headers (and trailers) in the order in which they were received.
+	pub headers: Vec<(String, String)>,
+	/// The response body, with any transfer coding removed.
+	pub body: Vec<u8>,
+	/// Whether the server will keep the connection open for further requests.
+	pub keep_alive: bool,
+}
+
+impl HttpResponse {
+	/// Gets the value of the first header with the given name, compared case-insensitively.
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.iter().find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_str())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ParseState {
+	StatusLine,
+	Headers,
+	/// Reading a Content-Length-delimited body with the given number of bytes remaining.
+	FixedBody(usize),
+	ChunkSize,
+	/// Reading chunk data with the given number of bytes remaining in the chunk.
+	ChunkData(usize),
+	/// Expecting the line ending which follows chunk data.
+	ChunkDataEnd,
+	Trailers,
+	/// Reading a body which is delimited by the server closing the connection.
+	UntilClose,
+	Done,
+}
+
+/// An incremental HTTP/1.1 response parser. Create one per response, feed() it bytes as they are
+/// received and, once is_complete() returns true, take the response with into_response().
+pub struct HttpResponseParser {
+	state: ParseState,
+	max_body_len: usize,
+	/// The partial line we're currently reading, in line-oriented states.
+	line: Vec<u8>,
+	header_bytes: usize,
+	status_code: u16,
+	headers: Vec<(String, String)>,
+	content_length: Option<usize>,
+	chunked: bool,
+	keep_alive: bool,
+	body: Vec<u8>,
+}
+
+impl HttpResponseParser {
+	/// Creates a new parser which will fail with TooLarge if the response body is larger than
+	/// max_body_len.
+	pub fn new(max_body_len: usize) -> Self {
+		Self {
+			state: ParseState::StatusLine,
+			max_body_len,
+			line: Vec::new(),
+			header_bytes: 0,
+			status_code: 0,
+			headers: Vec::new(),
+			content_length: None,
+			chunked: false,
+			keep_alive: false,
+			body: Vec::new(),
+		}
+	}
+
+	/// Feeds newly-received bytes into the parser, returning the number of bytes consumed. This
+	/// is always data.len() unless the response completed before the end of data, in which case
+	/// the remaining bytes belong to whatever the server sends next.
+	///
+	/// Once an Err is returned the parser must not be used further.
+	pub fn feed(&mut self, data: &[u8]) -> Result<usize, HttpParseError> {
+		let mut pos = 0;
+		while pos < data.len() {
+			match self.state {
+				ParseState::StatusLine|ParseState::Headers|ParseState::ChunkSize|ParseState::ChunkDataEnd|ParseState::Trailers => {
+					let line = match self.read_line(data, &mut pos)? {
+						Some(line) => line,
+						None => break,
+					};
+					match self.state {
+						ParseState::StatusLine => self.parse_status_line(&line)?,
+						ParseState::Headers => self.parse_header_line(&line)?,
+						ParseState::ChunkSize => self.parse_chunk_size_line(&line)?,
+						ParseState::ChunkDataEnd => {
+							if !line.is_empty() { return Err(HttpParseError::Malformed("missing line ending after chunk data")); }
+							self.state = ParseState::ChunkSize;
+						},
+						ParseState::Trailers => self.parse_trailer_line(&line)?,
+						_ => unreachable!(),
+					}
+				},
+				ParseState::FixedBody(remaining) => {
+					let read_len = cmp::min(remaining, data.len() - pos);
+					self.body.extend_from_slice(&data[pos..pos + read_len]);
+					pos += read_len;
+					self.state = if read_len == remaining { ParseState::Done } else { ParseState::FixedBody(remaining - read_len) };
+				},
+				ParseState::ChunkData(remaining) => {
+					let read_len = cmp::min(remaining, data.len() - pos);
+					self.body.extend_from_slice(&data[pos..pos + read_len]);
+					pos += read_len;
+					self.state = if read_len == remaining { ParseState::ChunkDataEnd } else { ParseState::ChunkData(remaining - read_len) };
+				},
+				ParseState::UntilClose => {
+					if self.body.len() + (data.len() - pos) > self.max_body_len { return Err(HttpParseError::TooLarge); }
+					self.body.extend_from_slice(&data[pos..]);
+					pos = data.len();
+				},
+				ParseState::Done => break,
+			}
+		}
+		Ok(pos)
+	}
+
+	/// Informs the parser that the connection was closed. Returns Ok if the response is complete,
+	/// which may be the case only because the body was delimited by the connection closing.
+	pub fn eof(&mut self) -> Result<(), HttpParseError> {
+		match self.state {
+			ParseState::UntilClose|ParseState::Done => {
+				self.state = ParseState::Done;
+				Ok(())
+			},
+			_ => Err(HttpParseError::UnexpectedEof),
+		}
+	}
+
+	/// Returns true once a full response has been parsed.
+	pub fn is_complete(&self) -> bool {
+		self.state == ParseState::Done
+	}
+
+	/// Returns the parsed response, if it is complete.
+	pub fn into_response(self) -> Option<HttpResponse> {
+		if !self.is_complete() { return None; }
+		Some(HttpResponse {
+			status_code: self.status_code,
+			headers: self.headers,
+			body: self.body,
+			keep_alive: self.keep_alive,
+		})
+	}
+
+	/// Reads bytes into self.line until we reach a '\n', returning the line without its line
+	/// ending once we do. We accept both CRLF and bare LF line endings.
+	fn read_line(&mut self, data: &[u8], pos: &mut usize) -> Result<Option<Vec<u8>>, HttpParseError> {
+		let remaining = &data[*pos..];
+		let (read_len, found_end) = match remaining.iter().position(|c| *c == b'\n') {
+			Some(idx) => (idx + 1, true),
+			None => (remaining.len(), false),
+		};
+		match self.state {
+			ParseState::ChunkSize|ParseState::ChunkDataEnd => {
+				if self.line.len() + read_len > MAX_CHUNK_LINE_LEN { return Err(HttpParseError::TooLarge); }
+			},
+			_ => {
+				if self.header_bytes + read_len > MAX_HEADERS_LEN { return Err(HttpParseError::TooLarge); }
+				self.header_bytes += read_len;
+			},
+		}
+		self.line.extend_from_slice(&remaining[..read_len]);
+		*pos += read_len;
+		if !found_end { return Ok(None); }
+
+		let mut line = mem::replace(&mut self.line, Vec::new());
+		line.pop();
+		if line.last() == Some(&b'\r') { line.pop(); }
+		Ok(Some(line))
+	}
+
+	fn parse_status_line(&mut self, line: &[u8]) -> Result<(), HttpParseError> {
+		// Be lenient and skip empty lines before the status line.
+		if line.is_empty() { return Ok(()); }
+		let line = str::from_utf8(line).map_err(|_| HttpParseError::Malformed("non-UTF-8 status line"))?;
+		let mut parts = line.splitn(3, ' ');
+		self.keep_alive = match parts.next() {
+			Some("HTTP/1.1") => true,
+			Some("HTTP/1.0") => false,
+			_ => return Err(HttpParseError::Malformed("unknown HTTP version")),
+		};
+		self.status_code = match parts.next() {
+			Some(code) if code.len() == 3 && code.bytes().all(|c| c.is_ascii_digit()) => code.parse().unwrap(),
+			_ => return Err(HttpParseError::Malformed("invalid status code")),
+		};
+		// The reason phrase is optional and meaningless, so we ignore it.
+		self.state = ParseState::Headers;
+		Ok(())
+	}
+
+	/// Splits a header (or trailer) line into its name and value.
+	fn split_header_line(line: &[u8]) -> Result<(&str, &str), HttpParseError> {
+		if line[0] == b' ' || line[0] == b'\t' {
+			return Err(HttpParseError::Unsupported("obsolete header line folding"));
+		}
+		let line = str::from_utf8(line).map_err(|_| HttpParseError::Malformed("non-UTF-8 header"))?;
+		let colon = line.find(':').ok_or(HttpParseError::Malformed("header without a colon"))?;
+		let name = &line[..colon];
+		if name.is_empty() || name.bytes().any(|c| c == b' ' || c == b'\t') {
+			return Err(HttpParseError::Malformed("invalid header name"));
+		}
+		let value = line[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+		Ok((name, value))
+	}
+
+	fn parse_header_line(&mut self, line: &[u8]) -> Result<(), HttpParseError> {
+		if line.is_empty() { return self.headers_complete(); }
+		let (name, value) = Self::split_header_line(line)?;
+		if name.eq_ignore_ascii_case("Content-Length") {
+			if value.is_empty() || !value.bytes().all(|c| c.is_ascii_digit()) {
+				return Err(HttpParseError::Malformed("invalid Content-Length"));
+			}
+			let len = value.parse::<usize>().map_err(|_| HttpParseError::TooLarge)?;
+			if self.content_length.is_some() && self.content_length != Some(len) {
+				return Err(HttpParseError::Malformed("conflicting Content-Length headers"));
+			}
+			self.content_length = Some(len);
+		} else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+			for coding in value.split(',').map(|coding| coding.trim()).filter(|coding| !coding.is_empty()) {
+				if self.chunked {
+					return Err(HttpParseError::Malformed("chunked is not the final transfer coding"));
+				}
+				if coding.eq_ignore_ascii_case("chunked") {
+					self.chunked = true;
+				} else if !coding.eq_ignore_ascii_case("identity") {
+					return Err(HttpParseError::Unsupported("transfer coding"));
+				}
+			}
+		} else if name.eq_ignore_ascii_case("Connection") {
+			for option in value.split(',').map(|option| option.trim()) {
+				if option.eq_ignore_ascii_case("close") {
+					self.keep_alive = false;
+				} else if option.eq_ignore_ascii_case("keep-alive") {
+					self.keep_alive = true;
+				}
+			}
+		}
+		self.headers.push((name.to_string(), value.to_string()));
+		Ok(())
+	}
+
+	fn headers_complete(&mut self) -> Result<(), HttpParseError> {
+		if self.status_code / 100 == 1 {
+			// An interim response (eg 100 Continue), the final response follows it.
+			self.headers.clear();
+			self.content_length = None;
+			self.chunked = false;
+			self.state = ParseState::StatusLine;
+			return Ok(());
+		}
+		if self.status_code == 204 || self.status_code == 304 {
+			self.state = ParseState::Done;
+			return Ok(());
+		}
+		if self.chunked {
+			if self.content_length.is_some() {
+				// A response with both is likely an attempt at request smuggling through some
+				// proxy, parse it per RFC 7230 (ignoring Content-Length) but don't reuse the
+				// connection.
+				self.keep_alive = false;
+			}
+			self.state = ParseState::ChunkSize;
+		} else if let Some(len) = self.content_length {
+			if len > self.max_body_len { return Err(HttpParseError::TooLarge); }
+			self.body.reserve(len);
+			self.state = if len == 0 { ParseState::Done } else { ParseState::FixedBody(len) };
+		} else {
+			self.keep_alive = false;
+			self.state = ParseState::UntilClose;
+		}
+		Ok(())
+	}
+
+	fn parse_chunk_size_line(&mut self, line: &[u8]) -> Result<(), HttpParseError> {
+		// Chunk extensions follow a ';' and are ignored.
+		let size_str = match line.iter().position(|c| *c == b';') {
+			Some(idx) => &line[..idx],
+			None => &line[..],
+		};
+		let size_len = size_str.iter().rposition(|c| *c != b' ' && *c != b'\t').map(|idx| idx + 1).unwrap_or(0);
+		if size_len == 0 { return Err(HttpParseError::Malformed("empty chunk size")); }
+		let mut size: usize = 0;
+		for c in size_str[..size_len].iter() {
+			let digit = (*c as char).to_digit(16).ok_or(HttpParseError::Malformed("invalid chunk size"))?;
+			size = size.checked_mul(16).and_then(|size| size.checked_add(digit as usize)).ok_or(HttpParseError::TooLarge)?;
+		}
+		if size == 0 {
+			self.state = ParseState::Trailers;
+		} else {
+			if size > self.max_body_len - self.body.len() { return Err(HttpParseError::TooLarge); }
+			self.state = ParseState::ChunkData(size);
+		}
+		Ok(())
+	}
+
+	fn parse_trailer_line(&mut self, line: &[u8]) -> Result<(), HttpParseError> {
+		if line.is_empty() {
+			self.state = ParseState::Done;
+			return Ok(());
+		}
+		// Trailers never affect message framing, so we just record them.
+		let (name, value) = Self::split_header_line(line)?;
+		self.headers.push((name.to_string(), value.to_string()));
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_all(data: &[u8], max_body_len: usize) -> (Result<usize, HttpParseError>, HttpResponseParser) {
+		let mut parser = HttpResponseParser::new(max_body_len);
+		let res = parser.feed(data);
+		(res, parser)
+	}
+
+	#[test]
+	fn content_length_response() {
+		let data = b"HTTP/1.1 200 OK\r\ncontent-LENGTH: 5\r\nContent-Type: application/json\r\n\r\nhelloHTTP/1.1";
+		let (res, parser) = parse_all(data, 100);
+		assert_eq!(res, Ok(data.len() - 8));
+		let resp = parser.into_response().unwrap();
+		assert_eq!(resp.status_code, 200);
+		assert_eq!(resp.body, b"hello");
+		assert_eq!(resp.header("content-type"), Some("application/json"));
+		assert!(resp.keep_alive);
+	}
+
+	#[test]
+	fn chunked_response_byte_by_byte() {
+		let data = b"HTTP/1.1 500 Internal Server Error\nTransfer-Encoding: chunked\nConnection: close\n\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: yes\r\n\r\n";
+		let mut parser = HttpResponseParser::new(100);
+		for b in data.iter() {
+			assert!(!parser.is_complete());
+			assert_eq!(parser.feed(&[*b]), Ok(1));
+		}
+		let resp = parser.into_response().unwrap();
+		assert_eq!(resp.status_code, 500);
+		assert_eq!(resp.body, b"hello world");
+		assert_eq!(resp.header("x-trailer"), Some("yes"));
+		assert!(!resp.keep_alive);
+	}
+
+	#[test]
+	fn interim_and_empty_responses() {
+		let (res, parser) = parse_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n", 100);
+		assert_eq!(res, Ok(52));
+		let resp = parser.into_response().unwrap();
+		assert_eq!(resp.status_code, 204);
+		assert!(resp.body.is_empty());
+	}
+
+	#[test]
+	fn until_close_response() {
+		let (res, mut parser) = parse_all(b"HTTP/1.0 200 OK\r\n\r\nsome data", 100);
+		assert_eq!(res, Ok(28));
+		assert!(!parser.is_complete());
+		assert_eq!(parser.eof(), Ok(()));
+		let resp = parser.into_response().unwrap();
+		assert_eq!(resp.body, b"some data");
+		assert!(!resp.keep_alive);
+
+		let (_, mut parser) = parse_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort", 100);
+		assert_eq!(parser.eof(), Err(HttpParseError::UnexpectedEof));
+	}
+
+	#[test]
+	fn invalid_responses() {
+		assert_eq!(parse_all(b"HTTP/2 200 OK\r\n", 100).0, Err(HttpParseError::Malformed("unknown HTTP version")));
+		assert_eq!(parse_all(b"HTTP/1.1 20 OK\r\n", 100).0, Err(HttpParseError::Malformed("invalid status code")));
+		assert_eq!(parse_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nContent-Length: 2\r\n", 100).0,
+			Err(HttpParseError::Malformed("conflicting Content-Length headers")));
+		assert_eq!(parse_all(b"HTTP/1.1 200 OK\r\nContent-Length: 101\r\n\r\n", 100).0, Err(HttpParseError::TooLarge));
+		assert_eq!(parse_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n", 100).0,
+			Err(HttpParseError::Unsupported("transfer coding")));
+		assert_eq!(parse_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n65\r\n", 100).0, Err(HttpParseError::TooLarge));
+		assert_eq!(parse_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n", 100).0,
+			Err(HttpParseError::Malformed("invalid chunk size")));
+		assert_eq!(parse_all(&[b'a'; MAX_HEADERS_LEN + 1], 100).0, Err(HttpParseError::TooLarge));
+	}
+}
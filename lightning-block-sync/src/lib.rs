@@ -8,32 +8,119 @@
 //! interface.
 //!
 //! Both provided clients support either blocking TCP reads from std::net::TcpStream or, with
-//! feature `tokio`, tokio::net::TcpStream inside a Tokio runtime.
+//! feature `tokio`, tokio::net::TcpStream inside a Tokio runtime. Both keep a pool of connections
+//! alive across requests, and with feature `tls` they also support https URIs.
+//!
+//! With feature `rpc-client`, the `tip_notifier` module also provides long-polling of Bitcoin
+//! Core's waitfornewblock RPC, which can be used with MicroSPVClient::wait_for_best_tip to learn
+//! about new blocks without delay, as can the always-available ZMQ hashblock subscriber.
+//!
+//! The `mempool` module feeds unconfirmed transactions, from Bitcoin Core's ZMQ rawtx publisher
+//! or, with feature `rpc-client`, its getrawmempool RPC, to ChannelMonitors so that they can learn
+//! payment preimages without waiting for a confirmation.
+//!
+//! With feature `rpc-client`, the `rpc_chain_interface` module also provides a FeeEstimator
+//! backed by Bitcoin Core's estimatesmartfee RPC and a BroadcasterInterface which sends (and
+//! rebroadcasts, until they confirm) transactions via its sendrawtransaction RPC.
+//!
+//! The RPC client can authenticate with either a static user:password pair or Bitcoin Core's
+//! cookie file, which is re-read whenever bitcoind rejects our credentials.
+//!
+//! The `dns_headers` module fetches headers over DNS, and `dns_headers_zone` can generate the
+//! zone files needed to serve them from any block source. Queries can be sent via the system
+//! resolver or, using the `dns_resolver` module, directly to a given DNS server or over
+//! DNS-over-HTTPS.
+//!
+//! The `utxo_lookup` module provides a UtxoLookup which checks announced channels' funding outputs
+//! against a block source, with feature `rpc-client` or `rest-client` using Bitcoin Core's
+//! getblockhash and getblock RPCs or their REST equivalents.
+//!
+//! MicroSPVClient validates headers against the full header consensus rules of a given
+//! bitcoin::Network, and can optionally enforce Bitcoin Core's hard-coded checkpoints.
 
 #[cfg(any(feature = "rest-client", feature = "rpc-client"))]
 mod utils;
 
+#[cfg(any(feature = "rest-client", feature = "rpc-client", feature = "fuzztarget"))]
+pub mod http_parser;
+
 #[cfg(any(feature = "rest-client", feature = "rpc-client"))]
 pub mod http_clients;
 
+#[cfg(feature = "rpc-client")]
+pub mod rpc_chain_interface;
+
 pub mod dns_headers;
 
-use lightning::chain::{chaininterface, keysinterface};
-use lightning::chain::chaininterface::{BlockNotifierArc, ChainListener};
-use lightning::ln::channelmonitor::{ChannelMonitor, ManyChannelMonitor};
-use lightning::ln::channelmanager::SimpleArcChannelManager;
+pub mod dns_resolver;
+
+pub mod dns_headers_zone;
+
+pub mod tip_notifier;
+
+pub mod mempool;
+
+pub mod utxo_lookup;
+
+mod validation;
+pub use validation::default_checkpoints;
+
+mod block_fetch;
 
-use bitcoin::hashes::hex::ToHex;
+mod timer;
+
+macro_rules! log_internal {
+	($logger: expr, $lvl:expr, $($arg:tt)+) => (
+		$logger.log(&lightning::util::logger::Record::new($lvl, format_args!($($arg)+), module_path!(), file!(), line!()));
+	);
+}
+macro_rules! log_error {
+	($logger: expr, $($arg:tt)*) => (
+		log_internal!($logger, lightning::util::logger::Level::Error, $($arg)*);
+	)
+}
+macro_rules! log_warn {
+	($logger: expr, $($arg:tt)*) => (
+		log_internal!($logger, lightning::util::logger::Level::Warn, $($arg)*);
+	)
+}
+macro_rules! log_info {
+	($logger: expr, $($arg:tt)*) => (
+		log_internal!($logger, lightning::util::logger::Level::Info, $($arg)*);
+	)
+}
+macro_rules! log_debug {
+	($logger: expr, $($arg:tt)*) => (
+		log_internal!($logger, lightning::util::logger::Level::Debug, $($arg)*);
+	)
+}
+
+use lightning::chain::chaininterface;
+use lightning::chain::chaininterface::{BlockNotifierArc, ChainListener, ChainWatchInterface};
+use lightning::chain::keysinterface::{ChannelKeys, KeysInterface};
+use lightning::ln::channelmonitor::{ChannelMonitor, ManyChannelMonitor, SimpleManyChannelMonitor};
+use lightning::ln::channelmanager::ChannelManager;
+use lightning::routing::network_graph::NetGraphMsgHandler;
+use lightning::util::logger::Logger;
 
 use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::util::hash::BitcoinHash;
 use bitcoin::util::uint::Uint256;
 use bitcoin::hash_types::BlockHash;
+use bitcoin::network::constants::Network;
 
+use tip_notifier::TipNotifier;
+use validation::ChainValidator;
+use block_fetch::{BlockFetcher, DEFAULT_MAX_PARALLEL_BLOCK_FETCHES};
+use timer::delay_for;
+
+use std::collections::HashMap;
 use std::future::Future;
+use std::hash;
 use std::vec::Vec;
 use std::pin::Pin;
-use std::ops::DerefMut;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, SystemTime};
 
 #[derive(Clone, Debug, PartialEq)]
 /// A block header and some associated data. This information should be available from most block
@@ -97,9 +184,13 @@ fn stateless_check_header(header: &BlockHeader) -> Result<(), BlockSourceRespErr
 }
 
 /// Check that child_header correctly builds on previous_header - the claimed work differential
-/// matches the actual PoW in child_header and the difficulty transition is possible, ie within 4x.
+/// matches the actual PoW in child_header and the difficulty transition is possible on the given
+/// network, ie within 4x and only on retarget boundaries (or, on testnet, a min-difficulty block).
 /// Includes stateless header checks on previous_header.
-fn check_builds_on(child_header: &BlockHeaderData, previous_header: &BlockHeaderData, mainnet: bool) -> Result<(), BlockSourceRespErr> {
+///
+/// Checks which require more than the previous header are done by ChainValidator before we
+/// connect any blocks.
+fn check_builds_on(child_header: &BlockHeaderData, previous_header: &BlockHeaderData, network: Network) -> Result<(), BlockSourceRespErr> {
 	if child_header.header.prev_blockhash != previous_header.header.bitcoin_hash() {
 		return Err(BlockSourceRespErr::BogusData);
 	}
@@ -110,17 +201,7 @@ fn check_builds_on(child_header: &BlockHeaderData, previous_header: &BlockHeader
 			previous_header.chainwork + new_work != child_header.chainwork {
 		return Err(BlockSourceRespErr::BogusData);
 	}
-	if mainnet {
-		if child_header.height % 2016 == 0 {
-			let prev_work = previous_header.header.work();
-			if new_work > prev_work << 2 || new_work < prev_work >> 2 {
-				return Err(BlockSourceRespErr::BogusData)
-			}
-		} else if child_header.header.bits != previous_header.header.bits {
-			return Err(BlockSourceRespErr::BogusData)
-		}
-	}
-	Ok(())
+	validation::check_difficulty_transition(child_header, previous_header, network)
 }
 
 enum ForkStep {
@@ -128,7 +209,7 @@ enum ForkStep {
 	DisconnectBlock(BlockHeaderData),
 	ConnectBlock(BlockHeaderData),
 }
-fn find_fork_step<'a>(steps_tx: &'a mut Vec<ForkStep>, current_header: BlockHeaderData, prev_header: &'a BlockHeaderData, block_source: &'a mut dyn BlockSource, head_blocks: &'a [BlockHeaderData], mainnet: bool) -> Pin<Box<dyn Future<Output=Result<(), BlockSourceRespErr>> + Send + 'a>> {
+fn find_fork_step<'a>(steps_tx: &'a mut Vec<ForkStep>, current_header: BlockHeaderData, prev_header: &'a BlockHeaderData, block_source: &'a mut dyn BlockSource, head_blocks: &'a [BlockHeaderData], network: Network) -> Pin<Box<dyn Future<Output=Result<(), BlockSourceRespErr>> + Send + 'a>> {
 	Box::pin(async move {
 		if prev_header.header.prev_blockhash == current_header.header.prev_blockhash {
 			// Found the fork, get the fork point header and we're done!
@@ -139,7 +220,7 @@ fn find_fork_step<'a>(steps_tx: &'a mut Vec<ForkStep>, current_header: BlockHead
 				steps_tx.push(ForkStep::ForkPoint(new_prev_header.clone()));
 			} else {
 				let new_prev_header = block_source.get_header(&prev_header.header.prev_blockhash, Some(prev_header.height - 1)).await?;
-				check_builds_on(&prev_header, &new_prev_header, mainnet)?;
+				check_builds_on(&prev_header, &new_prev_header, network)?;
 				steps_tx.push(ForkStep::ForkPoint(new_prev_header.clone()));
 			}
 		} else if current_header.height == 0 {
@@ -154,9 +235,9 @@ fn find_fork_step<'a>(steps_tx: &'a mut Vec<ForkStep>, current_header: BlockHead
 				// Current is higher than the prev, walk current down by listing blocks we need to
 				// connect
 				let new_cur_header = block_source.get_header(&current_header.header.prev_blockhash, Some(current_header.height - 1)).await?;
-				check_builds_on(&current_header, &new_cur_header, mainnet)?;
+				check_builds_on(&current_header, &new_cur_header, network)?;
 				steps_tx.push(ForkStep::ConnectBlock(current_header));
-				find_fork_step(steps_tx, new_cur_header, prev_header, block_source, head_blocks, mainnet).await?;
+				find_fork_step(steps_tx, new_cur_header, prev_header, block_source, head_blocks, network).await?;
 			}
 		} else if prev_header.height > current_header.height {
 			// Previous is higher, walk it back and recurse
@@ -164,27 +245,27 @@ fn find_fork_step<'a>(steps_tx: &'a mut Vec<ForkStep>, current_header: BlockHead
 			if !head_blocks.is_empty() {
 				let new_prev_header = head_blocks.last().unwrap();
 				let new_head_blocks = &head_blocks[..head_blocks.len() - 1];
-				find_fork_step(steps_tx, current_header, new_prev_header, block_source, new_head_blocks, mainnet).await?;
+				find_fork_step(steps_tx, current_header, new_prev_header, block_source, new_head_blocks, network).await?;
 			} else {
 				let new_prev_header = block_source.get_header(&prev_header.header.prev_blockhash, Some(prev_header.height - 1)).await?;
-				check_builds_on(&prev_header, &new_prev_header, mainnet)?;
-				find_fork_step(steps_tx, current_header, &new_prev_header, block_source, head_blocks, mainnet).await?;
+				check_builds_on(&prev_header, &new_prev_header, network)?;
+				find_fork_step(steps_tx, current_header, &new_prev_header, block_source, head_blocks, network).await?;
 			}
 		} else {
 			// Target and current are at the same height, but we're not at fork yet, walk
 			// both back and recurse
 			let new_cur_header = block_source.get_header(&current_header.header.prev_blockhash, Some(current_header.height - 1)).await?;
-			check_builds_on(&current_header, &new_cur_header, mainnet)?;
+			check_builds_on(&current_header, &new_cur_header, network)?;
 			steps_tx.push(ForkStep::ConnectBlock(current_header));
 			steps_tx.push(ForkStep::DisconnectBlock(prev_header.clone()));
 			if !head_blocks.is_empty() {
 				let new_prev_header = head_blocks.last().unwrap();
 				let new_head_blocks = &head_blocks[..head_blocks.len() - 1];
-				find_fork_step(steps_tx, new_cur_header, new_prev_header, block_source, new_head_blocks, mainnet).await?;
+				find_fork_step(steps_tx, new_cur_header, new_prev_header, block_source, new_head_blocks, network).await?;
 			} else {
 				let new_prev_header = block_source.get_header(&prev_header.header.prev_blockhash, Some(prev_header.height - 1)).await?;
-				check_builds_on(&prev_header, &new_prev_header, mainnet)?;
-				find_fork_step(steps_tx, new_cur_header, &new_prev_header, block_source, head_blocks, mainnet).await?;
+				check_builds_on(&prev_header, &new_prev_header, network)?;
+				find_fork_step(steps_tx, new_cur_header, &new_prev_header, block_source, head_blocks, network).await?;
 			}
 		}
 		Ok(())
@@ -193,7 +274,7 @@ fn find_fork_step<'a>(steps_tx: &'a mut Vec<ForkStep>, current_header: BlockHead
 /// Walks backwards from current_header and prev_header finding the fork and sending ForkStep events
 /// into the steps_tx Sender. There is no ordering guarantee between different ForkStep types, but
 /// DisconnectBlock and ConnectBlock events are each in reverse, height-descending order.
-async fn find_fork<'a>(current_header: BlockHeaderData, prev_header: &'a BlockHeaderData, block_source: &'a mut dyn BlockSource, mut head_blocks: &'a [BlockHeaderData], mainnet: bool) -> Result<Vec<ForkStep>, BlockSourceRespErr> {
+async fn find_fork<'a>(current_header: BlockHeaderData, prev_header: &'a BlockHeaderData, block_source: &'a mut dyn BlockSource, mut head_blocks: &'a [BlockHeaderData], network: Network) -> Result<Vec<ForkStep>, BlockSourceRespErr> {
 	let mut steps_tx = Vec::new();
 	if current_header.header == prev_header.header { return Ok(steps_tx); }
 
@@ -203,7 +284,7 @@ async fn find_fork<'a>(current_header: BlockHeaderData, prev_header: &'a BlockHe
 		&head_blocks[..head_blocks.len() - 1]
 	} else { head_blocks };
 
-	find_fork_step(&mut steps_tx, current_header, &prev_header, block_source, head_blocks, mainnet).await?;
+	find_fork_step(&mut steps_tx, current_header, &prev_header, block_source, head_blocks, network).await?;
 	Ok(steps_tx)
 }
 
@@ -216,7 +297,7 @@ pub trait AChainListener {
 	fn a_block_disconnected(&mut self, header: &BlockHeader, height: u32);
 }
 
-impl AChainListener for &BlockNotifierArc {
+impl<C: Deref> AChainListener for &BlockNotifierArc<C> where C::Target: ChainWatchInterface {
 	fn a_block_connected(&mut self, block: &Block, height: u32) {
 		self.block_connected(block, height);
 	}
@@ -225,35 +306,88 @@ impl AChainListener for &BlockNotifierArc {
 	}
 }
 
-impl<M, B, F> AChainListener for &SimpleArcChannelManager<M, B, F>
-		where M: ManyChannelMonitor<keysinterface::InMemoryChannelKeys>,
-		      B: chaininterface::BroadcasterInterface, F: chaininterface::FeeEstimator {
+/// Passes a block on to a lightning ChainListener, matching all of its transactions.
+fn connect_block_to_listener<CL: ChainListener + ?Sized>(listener: &CL, block: &Block, height: u32) {
+	let mut txn = Vec::with_capacity(block.txdata.len());
+	let mut idxn = Vec::with_capacity(block.txdata.len());
+	for (i, tx) in block.txdata.iter().enumerate() {
+		txn.push(tx);
+		idxn.push(i as u32);
+	}
+	listener.block_connected(&block.header, height, &txn, &idxn);
+}
+
+impl<ChanSigner, M, T, K, F, L> AChainListener for &ChannelManager<ChanSigner, M, T, K, F, L>
+		where ChanSigner: ChannelKeys,
+		      M: Deref + Sync + Send, T: Deref + Sync + Send, K: Deref + Sync + Send,
+		      F: Deref + Sync + Send, L: Deref + Sync + Send,
+		      M::Target: ManyChannelMonitor<ChanSigner>,
+		      T::Target: chaininterface::BroadcasterInterface,
+		      K::Target: KeysInterface<ChanKeySigner = ChanSigner>,
+		      F::Target: chaininterface::FeeEstimator,
+		      L::Target: Logger {
 	fn a_block_connected(&mut self, block: &Block, height: u32) {
-		let mut txn = Vec::with_capacity(block.txdata.len());
-		let mut idxn = Vec::with_capacity(block.txdata.len());
-		for (i, tx) in block.txdata.iter().enumerate() {
-			txn.push(tx);
-			idxn.push(i as u32);
-		}
-		self.block_connected(&block.header, height, &txn, &idxn);
+		connect_block_to_listener(*self, block, height);
+	}
+	fn a_block_disconnected(&mut self, header: &BlockHeader, height: u32) {
+		self.block_disconnected(header, height);
+	}
+}
+
+impl<C, L> AChainListener for &NetGraphMsgHandler<C, L>
+		where C: Deref + Sync + Send, L: Deref + Sync + Send,
+		      C::Target: ChainWatchInterface,
+		      L::Target: Logger {
+	fn a_block_connected(&mut self, block: &Block, height: u32) {
+		connect_block_to_listener(*self, block, height);
+	}
+	fn a_block_disconnected(&mut self, header: &BlockHeader, height: u32) {
+		self.block_disconnected(header, height);
+	}
+}
+
+impl<Key, ChanSigner, T, F, L, C> AChainListener for &SimpleManyChannelMonitor<Key, ChanSigner, T, F, L, C>
+		where Key: Send + Eq + hash::Hash, ChanSigner: ChannelKeys,
+		      T: Deref + Sync + Send, F: Deref + Sync + Send, L: Deref + Sync + Send, C: Deref + Sync + Send,
+		      T::Target: chaininterface::BroadcasterInterface,
+		      F::Target: chaininterface::FeeEstimator,
+		      L::Target: Logger,
+		      C::Target: ChainWatchInterface {
+	fn a_block_connected(&mut self, block: &Block, height: u32) {
+		connect_block_to_listener(*self, block, height);
 	}
 	fn a_block_disconnected(&mut self, header: &BlockHeader, height: u32) {
 		self.block_disconnected(header, height);
 	}
 }
 
-impl<CS, B, F> AChainListener for (&mut ChannelMonitor<CS>, &B, &F)
-		where CS: keysinterface::ChannelKeys,
-		      B: chaininterface::BroadcasterInterface, F: chaininterface::FeeEstimator {
+impl<CS, B, F, L> AChainListener for (&mut ChannelMonitor<CS>, &B, &F, &L)
+		where CS: ChannelKeys,
+		      B: chaininterface::BroadcasterInterface, F: chaininterface::FeeEstimator, L: Logger {
 	fn a_block_connected(&mut self, block: &Block, height: u32) {
 		let mut txn = Vec::with_capacity(block.txdata.len());
 		for tx in block.txdata.iter() {
 			txn.push(tx);
 		}
-		self.0.block_connected(&txn, height, &block.bitcoin_hash(), self.1, self.2);
+		self.0.block_connected(&txn, height, &block.bitcoin_hash(), self.1, self.2, self.3);
+	}
+	fn a_block_disconnected(&mut self, header: &BlockHeader, height: u32) {
+		self.0.block_disconnected(height, &header.bitcoin_hash(), self.1, self.2, self.3);
+	}
+}
+
+/// Passes block events on to a set of listeners which are all at the same point in the chain.
+struct MultiChainListener<'a>(Vec<Box<dyn AChainListener + 'a>>);
+impl<'a> AChainListener for MultiChainListener<'a> {
+	fn a_block_connected(&mut self, block: &Block, height: u32) {
+		for listener in self.0.iter_mut() {
+			listener.a_block_connected(block, height);
+		}
 	}
 	fn a_block_disconnected(&mut self, header: &BlockHeader, height: u32) {
-		self.0.block_disconnected(height, &header.bitcoin_hash(), self.1, self.2);
+		for listener in self.0.iter_mut() {
+			listener.a_block_disconnected(header, height);
+		}
 	}
 }
 
@@ -264,20 +398,42 @@ impl<CS, B, F> AChainListener for (&mut ChannelMonitor<CS>, &B, &F)
 /// disconnected to the fork point. Thus, we may return an Err() that includes where our tip ended
 /// up which may not be new_header. Note that iff the returned Err has a BlockHeaderData, the
 /// header transition from old_header to new_header is valid.
-async fn sync_chain_monitor<CL : AChainListener + Sized>(new_header: BlockHeaderData, old_header: &BlockHeaderData, block_source: &mut dyn BlockSource, chain_notifier: &mut CL, head_blocks: &mut Vec<BlockHeaderData>, mainnet: bool)
+///
+/// Blocks to connect are fetched up to max_parallel_fetches at a time from block_source and any
+/// helper_sources (see BlockFetcher), but are always connected in order. Failures of the helpers
+/// are otherwise ignored, with the block fetched from block_source instead, but the indexes of
+/// any helpers which served us bogus blocks are added to bogus_helpers.
+async fn sync_chain_monitor<'s, CL : AChainListener + Sized, L: Logger + ?Sized>(new_header: BlockHeaderData, old_header: &BlockHeaderData, block_source: &'s mut dyn BlockSource, helper_sources: Vec<&'s mut dyn BlockSource>, bogus_helpers: &mut Vec<usize>, max_parallel_fetches: usize, chain_notifier: &mut CL, head_blocks: &mut Vec<BlockHeaderData>, validator: &mut ChainValidator, logger: &L)
 		-> Result<(), (BlockSourceRespErr, Option<BlockHeaderData>)> {
-	let mut events = find_fork(new_header, old_header, block_source, &*head_blocks, mainnet).await.map_err(|e| (e, None))?;
+	let mut events = find_fork(new_header, old_header, block_source, &*head_blocks, validator.network()).await.map_err(|e| (e, None))?;
+
+	// Check the new headers against the rules which need more context than find_fork has before we
+	// disconnect anything.
+	{
+		let mut fork_point = old_header;
+		let mut connect_headers = Vec::new();
+		for event in events.iter() {
+			match event {
+				&ForkStep::ForkPoint(ref header) => fork_point = header,
+				&ForkStep::ConnectBlock(ref header) => connect_headers.push(header.clone()),
+				_ => {},
+			}
+		}
+		connect_headers.reverse();
+		validator.check_connected_headers(fork_point, old_header, &connect_headers, block_source).await.map_err(|e| (e, None))?;
+	}
 
 	let mut last_disconnect_tip = None;
 	let mut new_tip = None;
 	for event in events.iter() {
 		match &event {
 			&ForkStep::DisconnectBlock(ref header) => {
-				println!("Disconnecting block {}", header.header.bitcoin_hash());
+				log_info!(logger, "Disconnecting block {} at height {}", header.header.bitcoin_hash(), header.height);
 				if let Some(cached_head) = head_blocks.pop() {
 					assert_eq!(cached_head, *header);
 				}
 				chain_notifier.a_block_disconnected(&header.header, header.height);
+				validator.block_disconnected(header);
 				last_disconnect_tip = Some(header.header.prev_blockhash);
 			},
 			&ForkStep::ForkPoint(ref header) => {
@@ -302,21 +458,28 @@ async fn sync_chain_monitor<CL : AChainListener + Sized>(new_header: BlockHeader
 		new_tip = Some(old_header.clone());
 	}
 
+	let mut connect_headers = Vec::new();
 	for event in events.drain(..).rev() {
 		if let ForkStep::ConnectBlock(header_data) = event {
-			let block = match block_source.get_block(&header_data.header.bitcoin_hash()).await {
-				Err(e) => return Err((e, new_tip)),
-				Ok(b) => b,
-			};
-			if block.header != header_data.header || !block.check_merkle_root() || !block.check_witness_commitment() {
-				return Err((BlockSourceRespErr::BogusData, new_tip));
-			}
-			println!("Connecting block {}", header_data.header.bitcoin_hash().to_hex());
-			chain_notifier.a_block_connected(&block, header_data.height);
-			head_blocks.push(header_data.clone());
-			new_tip = Some(header_data);
+			connect_headers.push(header_data);
 		}
 	}
+	let mut fetcher = BlockFetcher::new(connect_headers, block_source, helper_sources, max_parallel_fetches);
+	while let Some(fetch_res) = fetcher.next_block().await {
+		let (header_data, block) = match fetch_res {
+			Err(e) => {
+				bogus_helpers.extend_from_slice(fetcher.bogus_helpers());
+				return Err((e, new_tip));
+			},
+			Ok(res) => res,
+		};
+		log_info!(logger, "Connecting block {} at height {}", header_data.header.bitcoin_hash(), header_data.height);
+		chain_notifier.a_block_connected(&block, header_data.height);
+		validator.block_connected(&header_data);
+		head_blocks.push(header_data.clone());
+		new_tip = Some(header_data);
+	}
+	bogus_helpers.extend_from_slice(fetcher.bogus_helpers());
 	Ok(())
 }
 
@@ -324,8 +487,12 @@ async fn sync_chain_monitor<CL : AChainListener + Sized>(new_header: BlockHeader
 /// of the latest chain tip from old_block to new_block. This is useful on startup when you need
 /// to bring each ChannelMonitor, as well as the overall ChannelManager, into sync with each other.
 ///
-/// Once you have them all at the same block, you should switch to using MicroSPVClient.
-pub async fn init_sync_chain_monitor<CL : AChainListener + Sized, B: BlockSource>(new_block: BlockHash, old_block: BlockHash, block_source: &mut B, mut chain_notifier: CL) {
+/// Once you have them all at the same block, you should switch to using MicroSPVClient. See
+/// init_sync for a helper which does this for a ChannelManager and all of its ChannelMonitors.
+///
+/// Even though the block source is trusted, headers are checked against the consensus rules of the
+/// given network.
+pub async fn init_sync_chain_monitor<CL : AChainListener + Sized, B: BlockSource, L: Logger + ?Sized>(new_block: BlockHash, old_block: BlockHash, block_source: &mut B, mut chain_notifier: CL, network: Network, logger: &L) {
 	if &old_block[..] == &[0; 32] { return; }
 
 	let new_header = block_source.get_header(&new_block, None).await.unwrap();
@@ -334,7 +501,122 @@ pub async fn init_sync_chain_monitor<CL : AChainListener + Sized, B: BlockSource
 	let old_header = block_source.get_header(&old_block, None).await.unwrap();
 	assert_eq!(old_header.header.bitcoin_hash(), old_block);
 	stateless_check_header(&old_header.header).unwrap();
-	sync_chain_monitor(new_header, &old_header, block_source, &mut chain_notifier, &mut Vec::new(), false).await.unwrap();
+	sync_chain_monitor(new_header, &old_header, block_source, Vec::new(), &mut Vec::new(), DEFAULT_MAX_PARALLEL_BLOCK_FETCHES, &mut chain_notifier, &mut Vec::new(), &mut ChainValidator::new(network), logger).await.unwrap();
+}
+
+/// Bring a freshly deserialized ChannelManager and its ChannelMonitors, each given along with the
+/// last block hash it saw (as returned when reading it), to the current best tip of a single
+/// *trusted* block source, returning the header of that tip.
+///
+/// This should be called after deserialization but before the monitors are handed to the
+/// ManyChannelMonitor, and the returned header can then be used to create a MicroSPVClient.
+/// Listeners which last saw the same block are synced together, so blocks are only fetched once
+/// for all monitors which were persisted at the same point.
+///
+/// If an Err is returned, some listeners may have already been moved to the best tip while others
+/// have not, so they should be dropped and reloaded before trying again.
+pub async fn init_sync<'a, ChanSigner, M, T, K, F, L, BI, FE, LG, B>(block_source: &mut B, network: Network,
+		channel_manager: (BlockHash, &'a ChannelManager<ChanSigner, M, T, K, F, L>),
+		channel_monitors: Vec<(BlockHash, &'a mut ChannelMonitor<ChanSigner>)>,
+		broadcaster: &'a BI, fee_estimator: &'a FE, logger: &'a LG) -> Result<BlockHeaderData, BlockSourceRespErr>
+		where ChanSigner: ChannelKeys + 'a,
+		      M: Deref + Sync + Send + 'a, T: Deref + Sync + Send + 'a, K: Deref + Sync + Send + 'a,
+		      F: Deref + Sync + Send + 'a, L: Deref + Sync + Send + 'a,
+		      M::Target: ManyChannelMonitor<ChanSigner>,
+		      T::Target: chaininterface::BroadcasterInterface,
+		      K::Target: KeysInterface<ChanKeySigner = ChanSigner>,
+		      F::Target: chaininterface::FeeEstimator,
+		      L::Target: Logger,
+		      BI: chaininterface::BroadcasterInterface + 'a, FE: chaininterface::FeeEstimator + 'a, LG: Logger + 'a,
+		      B: BlockSource {
+	let mut listeners: Vec<(BlockHash, Box<dyn AChainListener + 'a>)> = Vec::with_capacity(channel_monitors.len() + 1);
+	listeners.push((channel_manager.0, Box::new(channel_manager.1)));
+	for (last_block_hash, monitor) in channel_monitors {
+		listeners.push((last_block_hash, Box::new((monitor, broadcaster, fee_estimator, logger))));
+	}
+	sync_listeners(block_source, network, listeners, logger).await
+}
+
+/// Brings each of the given listeners from the block it last saw to the best tip of block_source,
+/// syncing listeners which last saw the same block together. See init_sync.
+async fn sync_listeners<'a, B: BlockSource, L: Logger + ?Sized>(block_source: &mut B, network: Network, listeners: Vec<(BlockHash, Box<dyn AChainListener + 'a>)>, logger: &L) -> Result<BlockHeaderData, BlockSourceRespErr> {
+	let (best_hash, height_hint) = block_source.get_best_block().await?;
+	let best_header = block_source.get_header(&best_hash, height_hint).await?;
+	if best_header.header.bitcoin_hash() != best_hash {
+		return Err(BlockSourceRespErr::BogusData);
+	}
+	stateless_check_header(&best_header.header)?;
+
+	let mut groups: HashMap<BlockHash, Vec<Box<dyn AChainListener + 'a>>> = HashMap::new();
+	for (last_block_hash, listener) in listeners {
+		groups.entry(last_block_hash).or_insert_with(Vec::new).push(listener);
+	}
+
+	for (old_block, group) in groups.drain() {
+		if &old_block[..] == &[0; 32] || old_block == best_hash { continue; }
+
+		let old_header = block_source.get_header(&old_block, None).await?;
+		if old_header.header.bitcoin_hash() != old_block {
+			return Err(BlockSourceRespErr::BogusData);
+		}
+		stateless_check_header(&old_header.header)?;
+		let mut listener = MultiChainListener(group);
+		sync_chain_monitor(best_header.clone(), &old_header, block_source, Vec::new(), &mut Vec::new(), DEFAULT_MAX_PARALLEL_BLOCK_FETCHES, &mut listener, &mut Vec::new(), &mut ChainValidator::new(network), logger)
+			.await.map_err(|(e, _)| e)?;
+	}
+	Ok(best_header)
+}
+
+/// Health information about a single block source, as reported by MicroSPVClient::status.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockSourceStatus {
+	/// Whether this is one of the backup block sources, which are only queried when the primary
+	/// sources fail to provide blocks for a better chain.
+	pub backup: bool,
+	/// The best block hash (and height, if provided) this source most recently reported.
+	pub last_tip: Option<(BlockHash, Option<u32>)>,
+	/// The last time this source responded to a request for its best block.
+	pub last_response: Option<SystemTime>,
+	/// The total number of polls in which this source failed to respond to a request.
+	pub no_response_count: u64,
+	/// The number of polls in a row in which this source failed to respond to a request.
+	pub consecutive_no_responses: u32,
+	/// Whether we've given up on this source after it served us invalid data. Banned sources are
+	/// never queried again.
+	pub banned: bool,
+	/// What the invalid data a banned source served us was.
+	pub ban_reason: Option<&'static str>,
+}
+
+/// A snapshot of the state of a MicroSPVClient, as returned by MicroSPVClient::status.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MicroSPVClientStatus {
+	/// The tip of the chain our chain listener is currently on.
+	pub chain_tip: BlockHeaderData,
+	/// The health of each block source, with the primary block sources first, followed by the
+	/// backup block sources, each in the order they were provided to MicroSPVClient::init.
+	pub block_sources: Vec<BlockSourceStatus>,
+	/// The number of times we have disconnected blocks from our chain listener.
+	pub reorg_count: u64,
+	/// The number of blocks disconnected in the most recent reorg, or 0 if there has been none.
+	pub last_reorg_depth: u32,
+	/// The largest number of blocks disconnected in a single reorg.
+	pub max_reorg_depth: u32,
+}
+
+/// Wraps a chain listener to count the number of blocks disconnected in a sync.
+struct DisconnectCounter<'b, CL: AChainListener> {
+	inner: &'b mut CL,
+	disconnected: u32,
+}
+impl<'b, CL: AChainListener> AChainListener for DisconnectCounter<'b, CL> {
+	fn a_block_connected(&mut self, block: &Block, height: u32) {
+		self.inner.a_block_connected(block, height);
+	}
+	fn a_block_disconnected(&mut self, header: &BlockHeader, height: u32) {
+		self.disconnected += 1;
+		self.inner.a_block_disconnected(header, height);
+	}
 }
 
 /// Keep the chain that a chain listener knows about up-to-date with the best chain from any of the
@@ -344,24 +626,44 @@ pub async fn init_sync_chain_monitor<CL : AChainListener + Sized, B: BlockSource
 /// the heaviest chain, but not storing the full header chain, leading to some important
 /// limitations.
 ///
-/// While we never check full difficulty transition logic, the mainnet option enables checking that
-/// difficulty transitions only happen every two weeks and never shift difficulty more than 4x in
-/// either direction, which is sufficient to prevent most minority hashrate attacks.
+/// Headers are checked against the full header consensus rules of the given network, including
+/// exact difficulty retargets, testnet's min-difficulty blocks, median-time-past and the limit on
+/// timestamps in the future. As we do not store the header chain, we fetch any ancestors these
+/// checks require from the block source which served the new headers, which may take a few
+/// thousand requests the first time we cross a retarget after startup. Optionally, checkpoints
+/// (see set_checkpoints) can be used to reject low-work forks from far in the past.
 ///
 /// We cache any headers which we connect until every block source is in agreement on the best tip.
 /// This prevents one block source from being able to orphan us on a fork of its own creation by
 /// not responding to requests for old headers on that fork. However, if one block source is
 /// unreachable this may result in our memory usage growing in accordance with the chain.
-pub struct MicroSPVClient<'a, B: DerefMut<Target=dyn BlockSource + 'a> + Sized + Sync + Send, CL : AChainListener + Sized> {
+///
+/// When connecting several blocks, we fetch blocks from the block source which served us the new
+/// chain and, concurrently, from the other block sources (primary and backup sources helping only
+/// sources of the same kind) which last reported the same best block. Any block another source
+/// fails to provide is fetched from the serving source instead, and we give up on any source which
+/// serves us an invalid block. Blocks are always fully checked and connected in order.
+///
+/// Blocks we [dis]connect and block sources we give up on are logged to the given Logger, and the
+/// health of each block source is available via status().
+pub struct MicroSPVClient<'a, B: DerefMut<Target=dyn BlockSource + 'a> + Sized + Sync + Send, CL : AChainListener + Sized, L: Deref>
+		where L::Target: Logger {
 	chain_tip: (BlockHash, BlockHeaderData),
 	block_sources: Vec<B>,
 	backup_block_sources: Vec<B>,
 	cur_blocks: Vec<Result<BlockHash, BlockSourceRespErr>>,
+	source_status: Vec<BlockSourceStatus>,
 	blocks_past_common_tip: Vec<BlockHeaderData>,
 	chain_notifier: CL,
-	mainnet: bool
+	validator: ChainValidator,
+	reorg_count: u64,
+	last_reorg_depth: u32,
+	max_reorg_depth: u32,
+	max_parallel_block_fetches: usize,
+	logger: L,
 }
-impl<'a, B: DerefMut<Target=dyn BlockSource + 'a> + Sized + Sync + Send, CL : AChainListener + Sized> MicroSPVClient<'a, B, CL> {
+impl<'a, B: DerefMut<Target=dyn BlockSource + 'a> + Sized + Sync + Send, CL : AChainListener + Sized, L: Deref> MicroSPVClient<'a, B, CL, L>
+		where L::Target: Logger {
 	/// Create a new MicroSPVClient with a set of block sources and a chain listener which will
 	/// receive updates of the new tip.
 	///
@@ -374,57 +676,149 @@ impl<'a, B: DerefMut<Target=dyn BlockSource + 'a> + Sized + Sync + Send, CL : AC
 	/// useful when you have a block source which is more censorship-resistant than others but
 	/// which only provides headers. In this case, we can use such source(s) to learn of a censorship
 	/// attack without giving up privacy by querying a privacy-losing block sources.
-	pub fn init(chain_tip: BlockHeaderData, block_sources: Vec<B>, backup_block_sources: Vec<B>, chain_notifier: CL, mainnet: bool) -> Self {
+	pub fn init(chain_tip: BlockHeaderData, block_sources: Vec<B>, backup_block_sources: Vec<B>, chain_notifier: CL, network: Network, logger: L) -> Self {
 		let cur_blocks = vec![Err(BlockSourceRespErr::NoResponse); block_sources.len() + backup_block_sources.len()];
+		let mut source_status = Vec::with_capacity(cur_blocks.len());
+		for idx in 0..cur_blocks.len() {
+			source_status.push(BlockSourceStatus {
+				backup: idx >= block_sources.len(),
+				last_tip: None,
+				last_response: None,
+				no_response_count: 0,
+				consecutive_no_responses: 0,
+				banned: false,
+				ban_reason: None,
+			});
+		}
 		let blocks_past_common_tip = Vec::new();
+		let validator = ChainValidator::new(network);
 		Self {
 			chain_tip: (chain_tip.header.bitcoin_hash(), chain_tip),
-			block_sources, backup_block_sources, cur_blocks, blocks_past_common_tip, chain_notifier, mainnet
+			block_sources, backup_block_sources, cur_blocks, source_status, blocks_past_common_tip, chain_notifier, validator,
+			reorg_count: 0, last_reorg_depth: 0, max_reorg_depth: 0,
+			max_parallel_block_fetches: DEFAULT_MAX_PARALLEL_BLOCK_FETCHES, logger,
 		}
 	}
+
+	/// Gets the current tip of our chain listener, the health of each block source and the reorgs
+	/// we've seen, eg for monitoring for block sources which are lying to us or stuck.
+	pub fn status(&self) -> MicroSPVClientStatus {
+		MicroSPVClientStatus {
+			chain_tip: self.chain_tip.1.clone(),
+			block_sources: self.source_status.clone(),
+			reorg_count: self.reorg_count,
+			last_reorg_depth: self.last_reorg_depth,
+			max_reorg_depth: self.max_reorg_depth,
+		}
+	}
+
+	/// Sets the checkpoints, as (height, block hash) pairs, which any chain we move to must
+	/// include. Any block source which serves us a header at a checkpoint height with a different
+	/// hash, or a chain forking off ours before a checkpoint, is considered to be serving bogus
+	/// data. See default_checkpoints for the checkpoints Bitcoin Core uses.
+	pub fn set_checkpoints(&mut self, checkpoints: Vec<(u32, BlockHash)>) {
+		self.validator.set_checkpoints(checkpoints);
+	}
+
+	/// Sets the maximum number of blocks which we may fetch ahead of the next block to be
+	/// connected, bounding both the number of concurrent block requests and the number of blocks
+	/// held in memory. Defaults to 8. Setting this to 1 fetches blocks one at a time.
+	pub fn set_max_parallel_block_fetches(&mut self, max_parallel_block_fetches: usize) {
+		self.max_parallel_block_fetches = max_parallel_block_fetches;
+	}
 	/// Check each source for a new best tip and update the chain listener accordingly.
 	/// Returns true if some blocks were [dis]connected, false otherwise.
 	pub async fn poll_best_tip(&mut self) -> bool {
 		let mut highest_valid_tip = self.chain_tip.1.chainwork;
 		let mut blocks_connected = false;
 
+		// Splits out the source at $idx in $sources from the rest, of which those we haven't given up
+		// on which last told us their best block is $tip (the tip we're syncing to) can help fetch
+		// its blocks. Also returns the helpers' indexes in $sources.
+		macro_rules! split_sources {
+			($sources: expr, $status: expr, $idx: expr, $tip: expr) => { {
+				let mut source = None;
+				let mut helper_sources: Vec<&mut dyn BlockSource> = Vec::new();
+				let mut helper_idxs = Vec::new();
+				for (helper_idx, helper) in $sources.iter_mut().enumerate() {
+					if helper_idx == $idx {
+						source = Some(helper);
+						continue;
+					}
+					let status = &$status[helper_idx];
+					if !status.banned && status.last_tip.map(|(hash, _)| hash) == Some($tip) {
+						helper_sources.push(&mut **helper);
+						helper_idxs.push(helper_idx);
+					}
+				}
+				(source.unwrap(), helper_sources, helper_idxs)
+			} }
+		}
+
 		macro_rules! process_source {
-			($cur_hash: expr, $source: expr) => { {
+			($cur_hash: expr, $status: expr, $sources: expr, $offset: expr, $idx: expr) => { {
 				if let Err(BlockSourceRespErr::BogusData) = $cur_hash {
 					// We gave up on this provider, move on.
 					continue;
 				}
 				macro_rules! handle_err {
-					($err: expr) => {
+					($err: expr, $reason: expr) => {
 						match $err {
 							Ok(r) => r,
 							Err(BlockSourceRespErr::BogusData) => {
+								log_error!(self.logger, "Giving up on {}block source {} after it served us {}", if $status.backup { "backup " } else { "" }, $idx, $reason);
 								$cur_hash = Err(BlockSourceRespErr::BogusData);
+								$status.banned = true;
+								$status.ban_reason = Some($reason);
 								continue;
 							},
 							Err(BlockSourceRespErr::NoResponse) => {
+								log_debug!(self.logger, "{}lock source {} failed to respond", if $status.backup { "Backup b" } else { "B" }, $idx);
+								$status.no_response_count += 1;
+								$status.consecutive_no_responses += 1;
 								continue;
 							},
 						}
 					}
 				}
-				let (new_hash, height_opt) = handle_err!($source.get_best_block().await);
+				let (new_hash, height_opt) = handle_err!($sources[$idx].get_best_block().await, "an invalid best block");
+				$status.last_tip = Some((new_hash, height_opt));
+				$status.last_response = Some(SystemTime::now());
 				if new_hash == self.chain_tip.0 {
+					$status.consecutive_no_responses = 0;
 					$cur_hash = Ok(new_hash);
 					continue;
 				}
-				let new_header = handle_err!($source.get_header(&new_hash, height_opt).await);
+				let new_header = handle_err!($sources[$idx].get_header(&new_hash, height_opt).await, "an invalid best block header");
 				if new_header.header.bitcoin_hash() != new_hash {
-					$cur_hash = Err(BlockSourceRespErr::BogusData);
-					continue;
+					handle_err!(Err::<(), _>(BlockSourceRespErr::BogusData), "a best block header which did not match the best block hash");
 				}
-				handle_err!(stateless_check_header(&new_header.header));
+				handle_err!(stateless_check_header(&new_header.header), "a best block header with invalid proof-of-work");
 				if new_header.chainwork <= self.chain_tip.1.chainwork {
+					$status.consecutive_no_responses = 0;
 					$cur_hash = Ok(new_hash);
 					continue;
 				}
 
-				let syncres = sync_chain_monitor(new_header.clone(), &self.chain_tip.1, &mut *$source, &mut self.chain_notifier, &mut self.blocks_past_common_tip, self.mainnet).await;
+				let (source, helper_sources, helper_idxs) = split_sources!($sources, self.source_status[$offset..], $idx, new_hash);
+				let mut bogus_helpers = Vec::new();
+				let mut notifier = DisconnectCounter { inner: &mut self.chain_notifier, disconnected: 0 };
+				let syncres = sync_chain_monitor(new_header.clone(), &self.chain_tip.1, &mut **source, helper_sources, &mut bogus_helpers, self.max_parallel_block_fetches,
+					&mut notifier, &mut self.blocks_past_common_tip, &mut self.validator, &*self.logger).await;
+				if notifier.disconnected != 0 {
+					self.reorg_count += 1;
+					self.last_reorg_depth = notifier.disconnected;
+					self.max_reorg_depth = std::cmp::max(self.max_reorg_depth, notifier.disconnected);
+					log_info!(self.logger, "Reorganized {} blocks deep to follow {}block source {}", notifier.disconnected, if $status.backup { "backup " } else { "" }, $idx);
+				}
+				for helper in bogus_helpers {
+					let helper_idx = helper_idxs[helper];
+					let status = &mut self.source_status[$offset + helper_idx];
+					log_error!(self.logger, "Giving up on {}block source {} after it served us an invalid block", if status.backup { "backup " } else { "" }, helper_idx);
+					self.cur_blocks[$offset + helper_idx] = Err(BlockSourceRespErr::BogusData);
+					status.banned = true;
+					status.ban_reason = Some("an invalid block");
+				}
 				if let Err((e, new_tip)) = syncres {
 					if let Some(tip) = new_tip {
 						let tiphash = tip.header.bitcoin_hash();
@@ -439,25 +833,25 @@ impl<'a, B: DerefMut<Target=dyn BlockSource + 'a> + Sized + Sync + Send, CL : AC
 						$cur_hash = Ok(tiphash);
 						highest_valid_tip = std::cmp::max(highest_valid_tip, new_header.chainwork);
 					}
-					handle_err!(Err(e));
+					handle_err!(Err(e), "an invalid header chain or block");
 				} else {
 					highest_valid_tip = std::cmp::max(highest_valid_tip, new_header.chainwork);
 					self.chain_tip = (new_hash, new_header);
+					$status.consecutive_no_responses = 0;
 					$cur_hash = Ok(new_hash);
 					blocks_connected = true;
 				}
 			} }
 		}
 
-		for (cur_hash, source) in self.cur_blocks.iter_mut().take(self.block_sources.len())
-				.zip(self.block_sources.iter_mut()) {
-			process_source!(*cur_hash, *source);
+		let backup_offset = self.block_sources.len();
+		for idx in 0..self.block_sources.len() {
+			process_source!(self.cur_blocks[idx], self.source_status[idx], self.block_sources, 0, idx);
 		}
 
 		if highest_valid_tip != self.chain_tip.1.chainwork {
-			for (cur_hash, source) in self.cur_blocks.iter_mut().skip(self.block_sources.len())
-					.zip(self.backup_block_sources.iter_mut()) {
-				process_source!(*cur_hash, *source);
+			for idx in 0..self.backup_block_sources.len() {
+				process_source!(self.cur_blocks[backup_offset + idx], self.source_status[backup_offset + idx], self.backup_block_sources, backup_offset, idx);
 				if highest_valid_tip == self.chain_tip.1.chainwork { break; }
 			}
 		}
@@ -478,15 +872,63 @@ impl<'a, B: DerefMut<Target=dyn BlockSource + 'a> + Sized + Sync + Send, CL : AC
 		}
 		blocks_connected
 	}
+
+	/// Waits for the given notifier to indicate that a new tip may be available and then checks
+	/// each source for a new best tip as in poll_best_tip. Thus, everything we learn is validated
+	/// exactly as if we had simply polled.
+	///
+	/// If the notifier fails (eg because the ZMQ or RPC connection dropped), we fall back to
+	/// polling after fallback_poll_interval. With feature `tokio`, we also poll if we haven't
+	/// been notified within the larger of fallback_poll_interval and the notifier's max_wait, in
+	/// case the push channel silently stalled.
+	///
+	/// Returns true if some blocks were [dis]connected, false otherwise.
+	pub async fn wait_for_best_tip<N: TipNotifier + ?Sized>(&mut self, notifier: &mut N, fallback_poll_interval: Duration) -> bool {
+		#[cfg(feature = "tokio")]
+		let notified = {
+			// Long-polling notifiers legitimately block for their own timeout, so never cut a
+			// wait short before that.
+			let wait_timeout = match notifier.max_wait() {
+				Some(max_wait) if max_wait > fallback_poll_interval => max_wait,
+				_ => fallback_poll_interval,
+			};
+			match tokio::time::timeout(wait_timeout, notifier.wait_for_new_tip(&self.chain_tip.0)).await {
+				Ok(res) => res,
+				Err(_) => Ok(()),
+			}
+		};
+		#[cfg(not(feature = "tokio"))]
+		let notified = notifier.wait_for_new_tip(&self.chain_tip.0).await;
+
+		if notified.is_err() {
+			log_warn!(self.logger, "Tip notifier failed, falling back to polling in {} seconds", fallback_poll_interval.as_secs());
+			delay_for(fallback_poll_interval).await;
+		}
+		self.poll_best_tip().await
+	}
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
 	use bitcoin::blockdata::block::{Block, BlockHeader};
+	use bitcoin::blockdata::transaction::Transaction;
 	use bitcoin::util::uint::Uint256;
 	use std::collections::HashMap;
 	use std::sync::{Arc, Mutex};
+	use lightning::chain::chaininterface::{BlockNotifier, ChainWatchInterfaceUtil, ConfirmationTarget};
+	use lightning::chain::keysinterface::{InMemoryChannelKeys, KeysManager};
+	use lightning::chain::transaction::OutPoint;
+	use lightning::util::config::UserConfig;
+	use lightning::util::logger::Record;
+	use lightning::util::ser::Writeable;
+
+	struct TestLogger;
+	impl Logger for TestLogger {
+		fn log(&self, record: &Record) {
+			println!("{:<5} [{} : {}, {}] {}", record.level.to_string(), record.module_path, record.file, record.line, record.args);
+		}
+	}
 
 	struct ChainListener {
 		blocks_connected: Mutex<Vec<(BlockHash, u32)>>,
@@ -519,7 +961,7 @@ mod tests {
 			Box::pin(async move {
 				match self.blocks.lock().unwrap().get(header_hash) {
 					Some(block) => {
-						assert_eq!(Some(block.height), height_hint);
+						if let Some(height) = height_hint { assert_eq!(block.height, height); }
 						Ok(BlockHeaderData {
 							chainwork: block.chainwork,
 							height: block.height,
@@ -549,10 +991,13 @@ mod tests {
 		}
 	}
 
-	#[tokio::test]
-	async fn simple_block_connect() {
+	/// Runs through syncing and reorging between the chains 1a-4a, 1b-2b and 4c-5c (forking from
+	/// 3a) on the given network. headers gives the time and nonce of blocks 1a, 2a, 3a, 4a, 1b, 2b,
+	/// 4c and 5c, in that order, which must meet the network's minimum difficulty, and block_work
+	/// the work of each block.
+	async fn do_simple_block_connect(network: Network, block_work: u64, headers: [(u32, u32); 8]) {
 		let genesis = BlockData {
-			block: bitcoin::blockdata::constants::genesis_block(bitcoin::network::constants::Network::Bitcoin),
+			block: bitcoin::blockdata::constants::genesis_block(network),
 			chainwork: Uint256::from_u64(0).unwrap(),
 			height: 0,
 		};
@@ -563,13 +1008,13 @@ mod tests {
 				header: BlockHeader {
 					version: 0,
 					prev_blockhash: genesis.block.bitcoin_hash(),
-					merkle_root: Default::default(), time: 0,
+					merkle_root: Default::default(), time: headers[0].0,
 					bits: genesis.block.header.bits,
-					nonce: 647569994,
+					nonce: headers[0].1,
 				},
 				txdata: Vec::new(),
 			},
-			chainwork: Uint256::from_u64(4295032833).unwrap(),
+			chainwork: Uint256::from_u64(block_work).unwrap(),
 			height: 1
 		};
 		let block_1a_hash = block_1a.block.header.bitcoin_hash();
@@ -578,13 +1023,13 @@ mod tests {
 				header: BlockHeader {
 					version: 0,
 					prev_blockhash: block_1a.block.bitcoin_hash(),
-					merkle_root: Default::default(), time: 4,
+					merkle_root: Default::default(), time: headers[1].0,
 					bits: genesis.block.header.bits,
-					nonce: 1185103332,
+					nonce: headers[1].1,
 				},
 				txdata: Vec::new(),
 			},
-			chainwork: Uint256::from_u64(4295032833 * 2).unwrap(),
+			chainwork: Uint256::from_u64(block_work * 2).unwrap(),
 			height: 2
 		};
 		let block_2a_hash = block_2a.block.header.bitcoin_hash();
@@ -593,13 +1038,13 @@ mod tests {
 				header: BlockHeader {
 					version: 0,
 					prev_blockhash: block_2a.block.bitcoin_hash(),
-					merkle_root: Default::default(), time: 6,
+					merkle_root: Default::default(), time: headers[2].0,
 					bits: genesis.block.header.bits,
-					nonce: 198739431,
+					nonce: headers[2].1,
 				},
 				txdata: Vec::new(),
 			},
-			chainwork: Uint256::from_u64(4295032833 * 3).unwrap(),
+			chainwork: Uint256::from_u64(block_work * 3).unwrap(),
 			height: 3
 		};
 		let block_3a_hash = block_3a.block.header.bitcoin_hash();
@@ -608,13 +1053,13 @@ mod tests {
 				header: BlockHeader {
 					version: 0,
 					prev_blockhash: block_3a.block.bitcoin_hash(),
-					merkle_root: Default::default(), time: 0,
+					merkle_root: Default::default(), time: headers[3].0,
 					bits: genesis.block.header.bits,
-					nonce: 590371681,
+					nonce: headers[3].1,
 				},
 				txdata: Vec::new(),
 			},
-			chainwork: Uint256::from_u64(4295032833 * 4).unwrap(),
+			chainwork: Uint256::from_u64(block_work * 4).unwrap(),
 			height: 4
 		};
 		let block_4a_hash = block_4a.block.header.bitcoin_hash();
@@ -625,13 +1070,13 @@ mod tests {
 				header: BlockHeader {
 					version: 0,
 					prev_blockhash: genesis.block.bitcoin_hash(),
-					merkle_root: Default::default(), time: 6,
+					merkle_root: Default::default(), time: headers[4].0,
 					bits: genesis.block.header.bits,
-					nonce: 1347696353,
+					nonce: headers[4].1,
 				},
 				txdata: Vec::new(),
 			},
-			chainwork: Uint256::from_u64(4295032833).unwrap(),
+			chainwork: Uint256::from_u64(block_work).unwrap(),
 			height: 1
 		};
 		let block_1b_hash = block_1b.block.header.bitcoin_hash();
@@ -640,13 +1085,13 @@ mod tests {
 				header: BlockHeader {
 					version: 0,
 					prev_blockhash: block_1b.block.bitcoin_hash(),
-					merkle_root: Default::default(), time: 5,
+					merkle_root: Default::default(), time: headers[5].0,
 					bits: genesis.block.header.bits,
-					nonce: 144775545,
+					nonce: headers[5].1,
 				},
 				txdata: Vec::new(),
 			},
-			chainwork: Uint256::from_u64(4295032833 * 2).unwrap(),
+			chainwork: Uint256::from_u64(block_work * 2).unwrap(),
 			height: 2
 		};
 		let block_2b_hash = block_2b.block.header.bitcoin_hash();
@@ -657,13 +1102,13 @@ mod tests {
 				header: BlockHeader {
 					version: 0,
 					prev_blockhash: block_3a.block.bitcoin_hash(),
-					merkle_root: Default::default(), time: 17,
+					merkle_root: Default::default(), time: headers[6].0,
 					bits: genesis.block.header.bits,
-					nonce: 316634915,
+					nonce: headers[6].1,
 				},
 				txdata: Vec::new(),
 			},
-			chainwork: Uint256::from_u64(4295032833 * 4).unwrap(),
+			chainwork: Uint256::from_u64(block_work * 4).unwrap(),
 			height: 4
 		};
 		let block_4c_hash = block_4c.block.header.bitcoin_hash();
@@ -672,13 +1117,13 @@ mod tests {
 				header: BlockHeader {
 					version: 0,
 					prev_blockhash: block_4c.block.bitcoin_hash(),
-					merkle_root: Default::default(), time: 3,
+					merkle_root: Default::default(), time: headers[7].0,
 					bits: genesis.block.header.bits,
-					nonce: 218413871,
+					nonce: headers[7].1,
 				},
 				txdata: Vec::new(),
 			},
-			chainwork: Uint256::from_u64(4295032833 * 5).unwrap(),
+			chainwork: Uint256::from_u64(block_work * 5).unwrap(),
 			height: 5
 		};
 		let block_5c_hash = block_5c.block.header.bitcoin_hash();
@@ -735,7 +1180,7 @@ mod tests {
 		let mut client = MicroSPVClient::init((&chain_one).get_header(&block_1a_hash, Some(1)).await.unwrap(),
 			vec![&mut source_one as &mut dyn BlockSource, &mut source_two as &mut dyn BlockSource, &mut source_three as &mut dyn BlockSource],
 			vec![&mut source_four as &mut dyn BlockSource],
-			Arc::clone(&chain_notifier), true);
+			Arc::clone(&chain_notifier), network, &TestLogger);
 
 		// Test that we will reorg onto 2b because chain_one knows about 1b + 2b
 		assert!(client.poll_best_tip().await);
@@ -744,6 +1189,13 @@ mod tests {
 		assert_eq!(client.blocks_past_common_tip.len(), 2);
 		assert_eq!(client.blocks_past_common_tip[0].header.bitcoin_hash(), block_1b_hash);
 		assert_eq!(client.blocks_past_common_tip[1].header.bitcoin_hash(), block_2b_hash);
+		let status = client.status();
+		assert_eq!(status.chain_tip.header.bitcoin_hash(), block_2b_hash);
+		assert_eq!((status.reorg_count, status.last_reorg_depth, status.max_reorg_depth), (1, 1, 1));
+		assert_eq!(status.block_sources[0].last_tip, Some((block_2b_hash, Some(2))));
+		assert_eq!(status.block_sources[1].last_tip, Some((block_1a_hash, Some(1))));
+		assert!(status.block_sources.iter().all(|source| !source.banned));
+		assert!(status.block_sources[3].backup && status.block_sources[3].last_tip.is_none());
 
 		// Test that even if chain_one (which we just got blocks from) stops responding to block or
 		// header requests we can still reorg back because we never wiped our block cache as
@@ -766,6 +1218,8 @@ mod tests {
 		assert!(client.poll_best_tip().await);
 		assert_eq!(&chain_notifier.blocks_disconnected.lock().unwrap()[..], &[(block_2b_hash, 2), (block_1b_hash, 1)][..]);
 		assert_eq!(&chain_notifier.blocks_connected.lock().unwrap()[..], &[(block_1a_hash, 1), (block_2a_hash, 2), (block_3a_hash, 3)][..]);
+		let status = client.status();
+		assert_eq!((status.reorg_count, status.last_reorg_depth, status.max_reorg_depth), (2, 2, 2));
 
 		// Note that blocks_past_common_tip is not wiped as chain_one still returns 2a as its tip
 		// (though a smarter MicroSPVClient may wipe 1a and 2a from the set eventually.
@@ -839,4 +1293,291 @@ mod tests {
 		assert!(chain_notifier.blocks_disconnected.lock().unwrap().is_empty());
 		assert_eq!(&chain_notifier.blocks_connected.lock().unwrap()[..], &[(block_4a_hash, 4)][..]);
 	}
+
+	#[tokio::test]
+	async fn simple_block_connect() {
+		// Headers mined at mainnet's minimum difficulty on top of the mainnet genesis block, each
+		// at least ten minutes after its parent so that they pass the median-time-past check.
+		do_simple_block_connect(Network::Bitcoin, 4295032833, [
+			(1231007106, 2150894026), (1231007705, 2511311306), (1231008305, 3816047282), (1231008908, 1536535970),
+			(1231007206, 566662455), (1231007805, 2498891755),
+			(1231009005, 3985074402), (1231009605, 907621019),
+		]).await;
+	}
+
+	#[tokio::test]
+	async fn simple_block_connect_regtest() {
+		let genesis_time = bitcoin::blockdata::constants::genesis_block(Network::Regtest).header.time;
+		do_simple_block_connect(Network::Regtest, 2, [
+			(genesis_time + 600, 2), (genesis_time + 1200, 2), (genesis_time + 1800, 1), (genesis_time + 2400, 0),
+			(genesis_time + 700, 0), (genesis_time + 1300, 0),
+			(genesis_time + 2500, 1), (genesis_time + 3100, 4),
+		]).await;
+	}
+
+	/// A TipNotifier which either fails, notifies immediately, or never notifies at all.
+	struct TestNotifier {
+		fail: bool,
+		never_notify: bool,
+		max_wait: Option<Duration>,
+		calls: usize,
+	}
+	impl TipNotifier for TestNotifier {
+		fn wait_for_new_tip<'a>(&'a mut self, _current_tip: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<(), ()>> + 'a + Send>> {
+			self.calls += 1;
+			let (fail, never_notify) = (self.fail, self.never_notify);
+			Box::pin(async move {
+				if never_notify { pending_forever().await; }
+				if fail { Err(()) } else { Ok(()) }
+			})
+		}
+		fn max_wait(&self) -> Option<Duration> { self.max_wait }
+	}
+	async fn pending_forever() {
+		struct Never;
+		impl Future for Never {
+			type Output = ();
+			fn poll(self: Pin<&mut Self>, _cx: &mut std::task::Context) -> std::task::Poll<()> { std::task::Poll::Pending }
+		}
+		Never.await
+	}
+
+	#[tokio::test]
+	async fn wait_for_best_tip() {
+		let genesis = BlockData {
+			block: bitcoin::blockdata::constants::genesis_block(Network::Regtest),
+			chainwork: Uint256::from_u64(0).unwrap(),
+			height: 0,
+		};
+		let block_1 = BlockData {
+			block: Block {
+				header: BlockHeader {
+					version: 0,
+					prev_blockhash: genesis.block.bitcoin_hash(),
+					merkle_root: Default::default(), time: genesis.block.header.time + 600,
+					bits: genesis.block.header.bits,
+					nonce: 2,
+				},
+				txdata: Vec::new(),
+			},
+			chainwork: Uint256::from_u64(2).unwrap(),
+			height: 1
+		};
+		let block_1_hash = block_1.block.bitcoin_hash();
+		let genesis_hash = genesis.block.bitcoin_hash();
+
+		let mut blocks = HashMap::new();
+		blocks.insert(genesis_hash, genesis);
+		blocks.insert(block_1_hash, block_1);
+		let chain = Blockchain {
+			blocks: Mutex::new(blocks), best_block: Mutex::new((genesis_hash, Some(0))),
+			headers_only: false, disallowed: Mutex::new(false)
+		};
+
+		let chain_notifier = Arc::new(ChainListener {
+			blocks_connected: Mutex::new(Vec::new()), blocks_disconnected: Mutex::new(Vec::new())
+		});
+		let mut source = &chain;
+		let mut client = MicroSPVClient::init((&chain).get_header(&genesis_hash, Some(0)).await.unwrap(),
+			vec![&mut source as &mut dyn BlockSource], vec![], Arc::clone(&chain_notifier), Network::Regtest, &TestLogger);
+
+		// A notification results in an immediate poll, which picks up the new block.
+		let mut notifier = TestNotifier { fail: false, never_notify: false, max_wait: None, calls: 0 };
+		*chain.best_block.lock().unwrap() = (block_1_hash, Some(1));
+		assert!(client.wait_for_best_tip(&mut notifier, Duration::from_secs(60)).await);
+		assert_eq!(&chain_notifier.blocks_connected.lock().unwrap()[..], &[(block_1_hash, 1)][..]);
+		assert_eq!(notifier.calls, 1);
+
+		// If the notifier fails we still poll, but only after the fallback interval.
+		notifier.fail = true;
+		let start = std::time::Instant::now();
+		assert!(!client.wait_for_best_tip(&mut notifier, Duration::from_millis(50)).await);
+		assert!(start.elapsed() >= Duration::from_millis(50));
+		assert_eq!(notifier.calls, 2);
+
+		// With tokio, a stalled notifier is given up on after the larger of the fallback interval
+		// and its own max_wait.
+		#[cfg(feature = "tokio")]
+		{
+			let mut notifier = TestNotifier { fail: false, never_notify: true, max_wait: Some(Duration::from_millis(100)), calls: 0 };
+			let start = std::time::Instant::now();
+			assert!(!client.wait_for_best_tip(&mut notifier, Duration::from_millis(10)).await);
+			assert!(start.elapsed() >= Duration::from_millis(100));
+
+			notifier.max_wait = None;
+			let start = std::time::Instant::now();
+			assert!(!client.wait_for_best_tip(&mut notifier, Duration::from_millis(10)).await);
+			let elapsed = start.elapsed();
+			assert!(elapsed >= Duration::from_millis(10) && elapsed < Duration::from_millis(100));
+		}
+	}
+
+	struct TestFeeEstimator;
+	impl chaininterface::FeeEstimator for TestFeeEstimator {
+		fn get_est_sat_per_1000_weight(&self, _: ConfirmationTarget) -> u64 { 253 }
+	}
+	struct TestBroadcaster;
+	impl chaininterface::BroadcasterInterface for TestBroadcaster {
+		fn broadcast_transaction(&self, _tx: &Transaction) {}
+	}
+
+	/// A lightning ChainListener which records the heights it was called with.
+	struct HeightListener {
+		connected: Mutex<Vec<u32>>,
+		disconnected: Mutex<Vec<u32>>,
+	}
+	impl lightning::chain::chaininterface::ChainListener for HeightListener {
+		fn block_connected(&self, _header: &BlockHeader, height: u32, _txn_matched: &[&Transaction], _indexes_of_txn_matched: &[u32]) {
+			self.connected.lock().unwrap().push(height);
+		}
+		fn block_disconnected(&self, _header: &BlockHeader, height: u32) {
+			self.disconnected.lock().unwrap().push(height);
+		}
+	}
+
+	/// Builds a block on top of prev, grinding the nonce until it has valid (regtest)
+	/// proof-of-work. time_offset is used to build distinct blocks on different forks.
+	fn mine_block(prev: &BlockData, time_offset: u32) -> BlockData {
+		let mut header = BlockHeader {
+			version: 0, prev_blockhash: prev.block.bitcoin_hash(), merkle_root: Default::default(),
+			time: prev.block.header.time + 600 + time_offset, bits: prev.block.header.bits, nonce: 0,
+		};
+		while header.validate_pow(&header.target()).is_err() { header.nonce += 1; }
+		BlockData {
+			chainwork: prev.chainwork + header.work(),
+			height: prev.height + 1,
+			block: Block { header, txdata: Vec::new() },
+		}
+	}
+
+	#[tokio::test]
+	async fn init_sync_across_reorg() {
+		let genesis = BlockData {
+			block: bitcoin::blockdata::constants::genesis_block(Network::Regtest),
+			chainwork: Uint256::from_u64(0).unwrap(),
+			height: 0,
+		};
+		// Our listeners last saw a chain ending in 3a, but the best chain now forks off after 1 and
+		// ends at 4b.
+		let block_1 = mine_block(&genesis, 0);
+		let block_2a = mine_block(&block_1, 0);
+		let block_3a = mine_block(&block_2a, 0);
+		let block_2b = mine_block(&block_1, 1);
+		let block_3b = mine_block(&block_2b, 1);
+		let block_4b = mine_block(&block_3b, 1);
+		let (hash_1, hash_2a, hash_3a) = (block_1.block.bitcoin_hash(), block_2a.block.bitcoin_hash(), block_3a.block.bitcoin_hash());
+		let (hash_2b, hash_3b, hash_4b) = (block_2b.block.bitcoin_hash(), block_3b.block.bitcoin_hash(), block_4b.block.bitcoin_hash());
+
+		let mut blocks = HashMap::new();
+		for block in [genesis, block_1, block_2a, block_3a, block_2b, block_3b, block_4b.clone()].iter() {
+			blocks.insert(block.block.bitcoin_hash(), block.clone());
+		}
+		let chain = Blockchain {
+			blocks: Mutex::new(blocks), best_block: Mutex::new((hash_4b, Some(4))),
+			headers_only: false, disallowed: Mutex::new(false)
+		};
+
+		let logger = TestLogger;
+		let fee_estimator = TestFeeEstimator;
+		let broadcaster = TestBroadcaster;
+		let chain_watch = Arc::new(ChainWatchInterfaceUtil::new(Network::Regtest));
+		let monitor: SimpleManyChannelMonitor<OutPoint, InMemoryChannelKeys, &TestBroadcaster, &TestFeeEstimator, &TestLogger, Arc<ChainWatchInterfaceUtil>> =
+			SimpleManyChannelMonitor::new(Arc::clone(&chain_watch), &broadcaster, &logger, &fee_estimator);
+		let keys_manager = KeysManager::new(&[42; 32], Network::Regtest, 42, 42);
+		let channel_manager = ChannelManager::new(Network::Regtest, &fee_estimator, &monitor, &broadcaster, &logger, &keys_manager, UserConfig::default(), 3).unwrap();
+
+		let block_notifier: BlockNotifierArc<Arc<ChainWatchInterfaceUtil>> = Arc::new(BlockNotifier::new(Arc::clone(&chain_watch)));
+		let notified = Arc::new(HeightListener { connected: Mutex::new(Vec::new()), disconnected: Mutex::new(Vec::new()) });
+		block_notifier.register_listener(Arc::clone(&notified) as Arc<dyn lightning::chain::chaininterface::ChainListener>);
+
+		let new_listener = || Arc::new(ChainListener { blocks_connected: Mutex::new(Vec::new()), blocks_disconnected: Mutex::new(Vec::new()) });
+		let (at_3a, at_1, at_tip) = (new_listener(), new_listener(), new_listener());
+
+		let listeners: Vec<(BlockHash, Box<dyn AChainListener>)> = vec![
+			(hash_3a, Box::new(&channel_manager)),
+			(hash_3a, Box::new(&block_notifier)),
+			(hash_3a, Box::new(Arc::clone(&at_3a))),
+			(hash_1, Box::new(Arc::clone(&at_1))),
+			(hash_4b, Box::new(Arc::clone(&at_tip))),
+		];
+		let mut source = &chain;
+		let best_header = sync_listeners(&mut source, Network::Regtest, listeners, &logger).await.unwrap();
+		assert_eq!(best_header, (&chain).get_header(&hash_4b, Some(4)).await.unwrap());
+
+		// Listeners on the stale fork are walked back to the fork point and then forward...
+		assert_eq!(&at_3a.blocks_disconnected.lock().unwrap()[..], &[(hash_3a, 3), (hash_2a, 2)][..]);
+		assert_eq!(&at_3a.blocks_connected.lock().unwrap()[..], &[(hash_2b, 2), (hash_3b, 3), (hash_4b, 4)][..]);
+		assert_eq!(&notified.disconnected.lock().unwrap()[..], &[3, 2][..]);
+		assert_eq!(&notified.connected.lock().unwrap()[..], &[2, 3, 4][..]);
+		// ...while those below the fork only need blocks connected, and those at the tip are left
+		// alone.
+		assert!(at_1.blocks_disconnected.lock().unwrap().is_empty());
+		assert_eq!(&at_1.blocks_connected.lock().unwrap()[..], &[(hash_2b, 2), (hash_3b, 3), (hash_4b, 4)][..]);
+		assert!(at_tip.blocks_disconnected.lock().unwrap().is_empty());
+		assert!(at_tip.blocks_connected.lock().unwrap().is_empty());
+
+		// The ChannelManager serializes the best block it has seen right after its genesis hash.
+		let manager_bytes = channel_manager.encode();
+		assert_eq!(&manager_bytes[34..38], &4u32.to_be_bytes()[..]);
+		assert_eq!(&manager_bytes[38..70], &hash_4b[..]);
+	}
+
+	#[tokio::test]
+	async fn bogus_helper_is_banned() {
+		let genesis = BlockData {
+			block: bitcoin::blockdata::constants::genesis_block(Network::Regtest),
+			chainwork: Uint256::from_u64(0).unwrap(),
+			height: 0,
+		};
+		let block_1 = mine_block(&genesis, 0);
+		let block_2 = mine_block(&block_1, 0);
+		let block_3 = mine_block(&block_2, 0);
+		let (hash_1, hash_2, hash_3) = (block_1.block.bitcoin_hash(), block_2.block.bitcoin_hash(), block_3.block.bitcoin_hash());
+
+		let mut good_blocks = HashMap::new();
+		for block in [genesis.clone(), block_1.clone(), block_2.clone(), block_3.clone()].iter() {
+			good_blocks.insert(block.block.bitcoin_hash(), block.clone());
+		}
+		let good_chain = Blockchain {
+			blocks: Mutex::new(good_blocks), best_block: Mutex::new((hash_3, Some(3))),
+			headers_only: false, disallowed: Mutex::new(false)
+		};
+
+		// The liar claims 3 as its tip but can't serve the headers to get there, so it can't sync us
+		// itself, and serves a block 3 whose transactions don't match the header.
+		let mut liar_blocks = HashMap::new();
+		liar_blocks.insert(genesis.block.bitcoin_hash(), genesis);
+		liar_blocks.insert(hash_1, block_1.clone());
+		let mut bogus_block_3 = block_3.clone();
+		bogus_block_3.block.txdata.push(Transaction { version: 1, lock_time: 0, input: Vec::new(), output: Vec::new() });
+		liar_blocks.insert(hash_3, bogus_block_3);
+		let liar_chain = Blockchain {
+			blocks: Mutex::new(liar_blocks), best_block: Mutex::new((hash_3, Some(3))),
+			headers_only: false, disallowed: Mutex::new(false)
+		};
+
+		let chain_notifier = Arc::new(ChainListener {
+			blocks_connected: Mutex::new(Vec::new()), blocks_disconnected: Mutex::new(Vec::new())
+		});
+		let mut liar_source = &liar_chain;
+		let mut good_source = &good_chain;
+		let mut client = MicroSPVClient::init((&good_chain).get_header(&hash_1, Some(1)).await.unwrap(),
+			vec![&mut liar_source as &mut dyn BlockSource, &mut good_source as &mut dyn BlockSource], Vec::new(),
+			Arc::clone(&chain_notifier), Network::Regtest, &TestLogger);
+
+		// The liar fails to sync us, but as it's on the tip the good source serves us, it helps fetch
+		// blocks. It's handed block 3 while the good source fetches block 2, and is banned once it
+		// serves the bogus block, which is then fetched from the good source.
+		assert!(client.poll_best_tip().await);
+		assert_eq!(&chain_notifier.blocks_connected.lock().unwrap()[..], &[(hash_2, 2), (hash_3, 3)][..]);
+		let status = client.status();
+		assert_eq!(status.chain_tip.header.bitcoin_hash(), hash_3);
+		assert!(status.block_sources[0].banned);
+		assert_eq!(status.block_sources[0].ban_reason, Some("an invalid block"));
+		assert!(!status.block_sources[1].banned);
+
+		// Banned helpers are never queried again.
+		*liar_chain.disallowed.lock().unwrap() = true;
+		assert!(!client.poll_best_tip().await);
+	}
 }
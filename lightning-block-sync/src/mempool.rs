@@ -0,0 +1,331 @@
+//! Feeds of unconfirmed transactions, allowing ChannelMonitors to learn payment preimages from
+//! HTLC claims as soon as they are broadcast rather than once they confirm (see
+//! ChannelMonitor::transaction_seen_in_mempool).
+//!
+//! We provide a MempoolSource which subscribes to Bitcoin Core's ZMQ `rawtx` feed (enabled with
+//! -zmqpubrawtx) and, with feature `rpc-client`, one which polls the `getrawmempool` RPC. As ZMQ
+//! silently drops messages while we are disconnected, the two may be combined, eg by polling the
+//! RPC each time a new block is connected.
+//!
+//! Transactions are only ever used to learn preimages, which are checked against their payment
+//! hash, so a mempool source need not be trusted.
+
+use crate::tip_notifier::ZMQConnection;
+
+use lightning::chain::chaininterface;
+use lightning::chain::chaininterface::ChainWatchInterface;
+use lightning::chain::keysinterface::ChannelKeys;
+use lightning::ln::channelmonitor::{ChannelMonitor, SimpleManyChannelMonitor};
+use lightning::util::logger::Logger;
+
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode;
+
+use std::future::Future;
+use std::hash;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::pin::Pin;
+
+#[cfg(feature = "rpc-client")]
+use crate::http_clients::{HttpClientError, RPCClient};
+#[cfg(feature = "rpc-client")]
+use bitcoin::hash_types::Txid;
+#[cfg(feature = "rpc-client")]
+use bitcoin::hashes::hex::FromHex;
+#[cfg(feature = "rpc-client")]
+use std::collections::HashSet;
+
+/// A source of transactions which have entered the mempool.
+pub trait MempoolSource : Send {
+	/// Gets transactions which have entered the mempool since the last call. Depending on the
+	/// source this may wait for a new transaction to arrive or return immediately, possibly with
+	/// no transactions. Transactions may occasionally be returned more than once.
+	///
+	/// Returns Err if the underlying connection failed. The next call will attempt to
+	/// re-establish it.
+	///
+	/// Sadly rust's trait system hasn't grown the ability to take impl/differentially-sized return
+	/// values yet, so we have to Box + dyn the future.
+	fn get_new_transactions<'a>(&'a mut self) -> Pin<Box<dyn Future<Output = Result<Vec<Transaction>, ()>> + 'a + Send>>;
+}
+
+/// Something which wants to hear about unconfirmed transactions, implemented for
+/// SimpleManyChannelMonitor and for a ChannelMonitor along with a logger.
+pub trait MempoolListener {
+	/// Notifies the listener of a transaction which has not yet been confirmed.
+	fn transaction_seen_in_mempool(&mut self, tx: &Transaction);
+}
+
+impl<Key, ChanSigner, T, F, L, C> MempoolListener for &SimpleManyChannelMonitor<Key, ChanSigner, T, F, L, C>
+		where Key: Send + Eq + hash::Hash + 'static, ChanSigner: ChannelKeys,
+		      T: Deref, F: Deref, L: Deref, C: Deref,
+		      T::Target: chaininterface::BroadcasterInterface,
+		      F::Target: chaininterface::FeeEstimator,
+		      L::Target: Logger,
+		      C::Target: ChainWatchInterface {
+	fn transaction_seen_in_mempool(&mut self, tx: &Transaction) {
+		SimpleManyChannelMonitor::transaction_seen_in_mempool(*self, tx);
+	}
+}
+
+impl<CS: ChannelKeys, L: Logger> MempoolListener for (&mut ChannelMonitor<CS>, &L) {
+	fn transaction_seen_in_mempool(&mut self, tx: &Transaction) {
+		self.0.transaction_seen_in_mempool(tx, self.1);
+	}
+}
+
+/// Fetches any new transactions from the given source and passes them to the given listener,
+/// returning the number of transactions passed on.
+pub async fn sync_mempool<S: MempoolSource + ?Sized, ML: MempoolListener + ?Sized>(source: &mut S, listener: &mut ML) -> Result<usize, ()> {
+	let txn = source.get_new_transactions().await?;
+	for tx in txn.iter() {
+		listener.transaction_seen_in_mempool(tx);
+	}
+	Ok(txn.len())
+}
+
+const RAWTX_TOPIC: &[u8] = b"rawtx";
+/// Transactions are limited to the 4M weight units of a block, and thus 4MB.
+const MAX_RAWTX_FRAME_LEN: u64 = 4_000_000;
+
+/// A MempoolSource which subscribes to Bitcoin Core's ZMQ rawtx publisher, ie the address passed
+/// to bitcoind as -zmqpubrawtx=tcp://address:port. Each call to get_new_transactions waits for the
+/// next transaction.
+///
+/// Note that bitcoind also publishes transactions as they are included in blocks (which are then
+/// harmlessly handled as if they were unconfirmed).
+pub struct ZMQMempoolClient {
+	addr: SocketAddr,
+	conn: Option<ZMQConnection>,
+}
+
+impl ZMQMempoolClient {
+	/// Creates a new ZMQMempoolClient for the given address. We don't connect until the first
+	/// call to get_new_transactions.
+	pub fn new(addr: SocketAddr) -> Self {
+		Self { addr, conn: None }
+	}
+}
+
+impl MempoolSource for ZMQMempoolClient {
+	fn get_new_transactions<'a>(&'a mut self) -> Pin<Box<dyn Future<Output = Result<Vec<Transaction>, ()>> + 'a + Send>> {
+		Box::pin(async move {
+			if self.conn.is_none() {
+				self.conn = Some(ZMQConnection::connect(&self.addr, RAWTX_TOPIC, MAX_RAWTX_FRAME_LEN).await?);
+			}
+			let body = match self.conn.as_mut().unwrap().next_message().await {
+				Ok(body) => body,
+				Err(()) => {
+					self.conn = None;
+					return Err(());
+				},
+			};
+			// A message we can't parse is bitcoind's problem, not the connection's.
+			match encode::deserialize(&body) {
+				Ok(tx) => Ok(vec![tx]),
+				Err(_) => Ok(Vec::new()),
+			}
+		})
+	}
+}
+
+/// The maximum number of getrawtransaction calls we put in a single JSON-RPC batch request.
+#[cfg(feature = "rpc-client")]
+const RPC_BATCH_SIZE: usize = 25;
+/// The maximum number of transactions we fetch per call to RPCMempoolClient::get_new_transactions.
+#[cfg(feature = "rpc-client")]
+const MAX_TXN_PER_POLL: usize = 500;
+
+#[cfg(feature = "rpc-client")]
+/// A MempoolSource which polls Bitcoin Core's getrawmempool RPC, fetching each transaction we
+/// haven't seen before with (batched) getrawtransaction calls.
+///
+/// At most 500 transactions are fetched per call, so on startup (or after a burst of new
+/// transactions) it may take several calls to work through the full mempool, but no single call
+/// blocks for too long.
+pub struct RPCMempoolClient {
+	client: RPCClient,
+	/// The txids which were in the mempool as of our last poll.
+	seen_txids: HashSet<Txid>,
+}
+
+#[cfg(feature = "rpc-client")]
+impl RPCMempoolClient {
+	/// Creates a new RPCMempoolClient using the given RPCClient.
+	pub fn new(client: RPCClient) -> Self {
+		Self { client, seen_txids: HashSet::new() }
+	}
+}
+
+#[cfg(feature = "rpc-client")]
+impl MempoolSource for RPCMempoolClient {
+	fn get_new_transactions<'a>(&'a mut self) -> Pin<Box<dyn Future<Output = Result<Vec<Transaction>, ()>> + 'a + Send>> {
+		Box::pin(async move {
+			let mempool = self.client.call_method("getrawmempool", &[]).await.map_err(|_| ())?;
+			let mut mempool_txids = HashSet::new();
+			for txid in mempool.as_array().ok_or(())? {
+				mempool_txids.insert(Txid::from_hex(txid.as_str().ok_or(())?).map_err(|_| ())?);
+			}
+
+			let mut new_txids: Vec<Txid> = mempool_txids.iter().filter(|txid| !self.seen_txids.contains(*txid)).cloned().collect();
+			new_txids.truncate(MAX_TXN_PER_POLL);
+
+			let mut txn = Vec::new();
+			for batch in new_txids.chunks(RPC_BATCH_SIZE) {
+				let params: Vec<String> = batch.iter().map(|txid| format!("\"{}\"", txid)).collect();
+				let params_list: Vec<Vec<&str>> = params.iter().map(|param| vec![&param[..]]).collect();
+				let results = match self.client.call_method_batch("getrawtransaction", &params_list).await {
+					Ok(results) => results,
+					Err(HttpClientError::Transport) => return Err(()),
+					// The batch may simply have been too large a response (eg if it contained some
+					// huge transactions), so try each transaction on its own.
+					Err(_) => {
+						let mut results = Vec::with_capacity(params_list.len());
+						for params in params_list.iter() {
+							results.push(self.client.call_method("getrawtransaction", params).await);
+						}
+						results
+					},
+				};
+				for result in results {
+					let tx_hex = match result {
+						Ok(tx_hex) => tx_hex,
+						// The transaction may have left the mempool since getrawmempool.
+						Err(HttpClientError::RPCError { .. }) => continue,
+						Err(_) => return Err(()),
+					};
+					let tx_bytes = Vec::<u8>::from_hex(tx_hex.as_str().ok_or(())?).map_err(|_| ())?;
+					txn.push(encode::deserialize(&tx_bytes).map_err(|_| ())?);
+				}
+			}
+			// Forget transactions which have left the mempool and remember the ones we just
+			// fetched, leaving any beyond MAX_TXN_PER_POLL for the next call.
+			self.seen_txids.retain(|txid| mempool_txids.contains(txid));
+			self.seen_txids.extend(new_txids);
+			Ok(txn)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tip_notifier::{zmtp_greeting, zmtp_ready_command, ZMTP_FLAG_MORE};
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
+	use std::io::{Read, Write};
+	use std::net::TcpListener;
+
+	struct TxRecorder(Vec<Transaction>);
+	impl MempoolListener for TxRecorder {
+		fn transaction_seen_in_mempool(&mut self, tx: &Transaction) {
+			self.0.push(tx.clone());
+		}
+	}
+
+	#[tokio::test]
+	async fn zmq_rawtx_subscription() {
+		let tx = genesis_block(Network::Bitcoin).txdata[0].clone();
+		let tx_bytes = encode::serialize(&tx);
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let publisher_tx_bytes = tx_bytes.clone();
+		let publisher = std::thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut greeting = [0; 64];
+			stream.read_exact(&mut greeting).unwrap();
+			stream.write_all(&zmtp_greeting()).unwrap();
+			let mut ready = vec![0; zmtp_ready_command().len()];
+			stream.read_exact(&mut ready).unwrap();
+			stream.write_all(&zmtp_ready_command()).unwrap();
+
+			let mut subscribe = [0; 8];
+			stream.read_exact(&mut subscribe).unwrap();
+			assert_eq!(&subscribe[..], b"\x00\x06\x01rawtx");
+
+			// A message which isn't a transaction is skipped, followed by a real one.
+			let mut message = vec![ZMTP_FLAG_MORE, 5];
+			message.extend_from_slice(b"rawtx");
+			message.extend_from_slice(&[ZMTP_FLAG_MORE, 1, 0]);
+			message.extend_from_slice(&[0, 4, 0, 0, 0, 0]);
+			message.extend_from_slice(&[ZMTP_FLAG_MORE, 5]);
+			message.extend_from_slice(b"rawtx");
+			message.extend_from_slice(&[ZMTP_FLAG_MORE, publisher_tx_bytes.len() as u8]);
+			message.extend_from_slice(&publisher_tx_bytes);
+			message.extend_from_slice(&[0, 4, 1, 0, 0, 0]);
+			stream.write_all(&message).unwrap();
+		});
+
+		let mut client = ZMQMempoolClient::new(addr);
+		let mut recorder = TxRecorder(Vec::new());
+		assert_eq!(sync_mempool(&mut client, &mut recorder).await, Ok(0));
+		assert_eq!(sync_mempool(&mut client, &mut recorder).await, Ok(1));
+		assert_eq!(recorder.0, vec![tx]);
+		publisher.join().unwrap();
+
+		// Once the publisher goes away we should fail and then try to reconnect.
+		assert_eq!(sync_mempool(&mut client, &mut recorder).await, Err(()));
+		assert!(client.conn.is_none());
+	}
+
+	#[cfg(feature = "rpc-client")]
+	#[tokio::test]
+	async fn rpc_mempool_polling() {
+		use crate::http_clients::spawn_rpc_server;
+		use bitcoin::hashes::hex::ToHex;
+		use bitcoin::hashes::Hash;
+		use std::sync::{Arc, Mutex};
+
+		let tx = genesis_block(Network::Bitcoin).txdata[0].clone();
+		let tx_hex = encode::serialize(&tx).to_hex();
+		// One real transaction and more bogus ones than we fetch in one go, which bitcoind will
+		// claim have already left the mempool.
+		let mut mempool = vec![tx.txid().to_string()];
+		for i in 0..MAX_TXN_PER_POLL as u32 {
+			mempool.push(Txid::from_slice(&[&i.to_be_bytes()[..], &[0x42; 28][..]].concat()).unwrap().to_string());
+		}
+
+		let batch_sizes = Arc::new(Mutex::new(Vec::new()));
+		let server_batch_sizes = Arc::clone(&batch_sizes);
+		let real_txid = tx.txid().to_string();
+		// Two polls, each a getrawmempool and then the getrawtransaction batches, plus a final
+		// getrawmempool with nothing new.
+		let num_batches = (MAX_TXN_PER_POLL + RPC_BATCH_SIZE - 1) / RPC_BATCH_SIZE;
+		let (uri, server) = spawn_rpc_server(num_batches + 4, move |req| {
+			match req.as_array() {
+				Some(calls) => {
+					server_batch_sizes.lock().unwrap().push(calls.len());
+					serde_json::Value::Array(calls.iter().map(|call| {
+						assert_eq!(call["method"], "getrawtransaction");
+						if call["params"][0] == real_txid.as_str() {
+							serde_json::json!({"result": tx_hex, "error": null, "id": call["id"]})
+						} else {
+							serde_json::json!({"result": null, "error": {"code": -5, "message": "No such mempool transaction"}, "id": call["id"]})
+						}
+					}).collect())
+				},
+				None => {
+					assert_eq!(req["method"], "getrawmempool");
+					serde_json::json!({"result": mempool, "error": null, "id": req["id"]})
+				},
+			}
+		});
+
+		let mut client = RPCMempoolClient::new(RPCClient::new("user:pass", uri).unwrap());
+		let mut recorder = TxRecorder(Vec::new());
+		let first = sync_mempool(&mut client, &mut recorder).await.unwrap();
+		let second = sync_mempool(&mut client, &mut recorder).await.unwrap();
+		assert_eq!(first + second, 1);
+		assert_eq!(recorder.0, vec![tx]);
+		assert_eq!(sync_mempool(&mut client, &mut recorder).await, Ok(0));
+		server.join().unwrap();
+
+		// The first poll is capped, leaving the last transaction for the second one.
+		let batch_sizes = batch_sizes.lock().unwrap();
+		assert_eq!(batch_sizes.iter().sum::<usize>(), MAX_TXN_PER_POLL + 1);
+		assert_eq!(batch_sizes.len(), num_batches + 1);
+		assert!(batch_sizes.iter().all(|size| *size <= RPC_BATCH_SIZE));
+	}
+}
@@ -0,0 +1,353 @@
+//! Implementations of rust-lightning's FeeEstimator and BroadcasterInterface which are backed by
+//! Bitcoin Core's JSON-RPC interface.
+//!
+//! As both traits are synchronous while RPC calls are not, neither makes requests when called.
+//! Instead, RPCFeeEstimator answers from a cache of estimatesmartfee results which is refreshed
+//! by update_estimates, and RPCBroadcaster queues transactions which are sent by send_pending.
+//! Both provide a method which loops forever doing so, suitable for spawning as a background
+//! task.
+
+use crate::AChainListener;
+use crate::http_clients::{HttpClientError, RPCClient};
+use crate::timer::delay_for;
+
+use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::Txid;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The lowest feerate rust-lightning accepts, ie 1 sat/vbyte rounded up.
+const MIN_FEERATE_SAT_PER_KW: u64 = 253;
+
+/// The feerates we use until we get an estimate for a target, in sat/kW.
+const DEFAULT_BACKGROUND_SAT_PER_KW: u64 = MIN_FEERATE_SAT_PER_KW;
+const DEFAULT_NORMAL_SAT_PER_KW: u64 = 2000;
+const DEFAULT_HIGH_PRIORITY_SAT_PER_KW: u64 = 5000;
+
+/// Gets the estimatesmartfee conf_target and estimate_mode parameters for a ConfirmationTarget.
+fn estimate_params(target: &ConfirmationTarget) -> (u16, &'static str) {
+	match target {
+		ConfirmationTarget::Background => (144, "\"ECONOMICAL\""),
+		ConfirmationTarget::Normal => (18, "\"ECONOMICAL\""),
+		ConfirmationTarget::HighPriority => (6, "\"CONSERVATIVE\""),
+	}
+}
+
+/// Converts a feerate in BTC/kvbyte, as returned by estimatesmartfee, to sat/kW, rounding up and
+/// never going below MIN_FEERATE_SAT_PER_KW.
+fn btc_per_kvb_to_sat_per_kw(btc_per_kvb: f64) -> Option<u64> {
+	if !btc_per_kvb.is_finite() || btc_per_kvb < 0.0 { return None; }
+	let sat_per_kvb = (btc_per_kvb * 100_000_000.0).round() as u64;
+	Some(std::cmp::max((sat_per_kvb + 3) / 4, MIN_FEERATE_SAT_PER_KW))
+}
+
+/// A FeeEstimator which caches Bitcoin Core's estimatesmartfee results for each
+/// ConfirmationTarget:
+///  * Background: 144 blocks, ECONOMICAL mode
+///  * Normal: 18 blocks, ECONOMICAL mode
+///  * HighPriority: 6 blocks, CONSERVATIVE mode
+///
+/// Until bitcoind has an estimate for a target (eg because it hasn't seen enough blocks), we use
+/// a default of 253, 2000 or 5000 sat/kW respectively. Estimates are never below 253 sat/kW.
+pub struct RPCFeeEstimator {
+	background: AtomicU64,
+	normal: AtomicU64,
+	high_priority: AtomicU64,
+}
+
+impl RPCFeeEstimator {
+	/// Creates a new RPCFeeEstimator using the default feerates until update_estimates is called.
+	pub fn new() -> Self {
+		Self {
+			background: AtomicU64::new(DEFAULT_BACKGROUND_SAT_PER_KW),
+			normal: AtomicU64::new(DEFAULT_NORMAL_SAT_PER_KW),
+			high_priority: AtomicU64::new(DEFAULT_HIGH_PRIORITY_SAT_PER_KW),
+		}
+	}
+
+	fn cached_estimate(&self, target: ConfirmationTarget) -> &AtomicU64 {
+		match target {
+			ConfirmationTarget::Background => &self.background,
+			ConfirmationTarget::Normal => &self.normal,
+			ConfirmationTarget::HighPriority => &self.high_priority,
+		}
+	}
+
+	/// Refreshes the cached estimate for each ConfirmationTarget. Targets for which bitcoind has
+	/// no estimate keep their previous value.
+	///
+	/// Returns Err if any request failed, though estimates fetched before the failure are still
+	/// updated.
+	pub async fn update_estimates(&self, client: &RPCClient) -> Result<(), HttpClientError> {
+		self.update_estimate(client, ConfirmationTarget::Background).await?;
+		self.update_estimate(client, ConfirmationTarget::Normal).await?;
+		self.update_estimate(client, ConfirmationTarget::HighPriority).await
+	}
+
+	async fn update_estimate(&self, client: &RPCClient, target: ConfirmationTarget) -> Result<(), HttpClientError> {
+		let (conf_target, mode) = estimate_params(&target);
+		let resp = client.call_method("estimatesmartfee", &[&conf_target.to_string(), mode]).await?;
+		// If bitcoind can't give an estimate it omits feerate and sets errors instead.
+		if let Some(sat_per_kw) = resp["feerate"].as_f64().and_then(btc_per_kvb_to_sat_per_kw) {
+			self.cached_estimate(target).store(sat_per_kw, Ordering::Release);
+		}
+		Ok(())
+	}
+
+	/// Calls update_estimates every interval, forever. Failures are ignored, leaving the previous
+	/// estimates in place until the next attempt.
+	pub async fn update_estimates_periodically(&self, client: RPCClient, interval: Duration) {
+		loop {
+			let _ = self.update_estimates(&client).await;
+			delay_for(interval).await;
+		}
+	}
+}
+
+impl FeeEstimator for RPCFeeEstimator {
+	fn get_est_sat_per_1000_weight(&self, confirmation_target: ConfirmationTarget) -> u64 {
+		self.cached_estimate(confirmation_target).load(Ordering::Acquire)
+	}
+}
+
+/// Bitcoin Core's RPC_VERIFY_ALREADY_IN_CHAIN error code, returned by sendrawtransaction when the
+/// transaction has already been confirmed.
+const RPC_VERIFY_ALREADY_IN_CHAIN: i64 = -27;
+
+/// The number of blocks we keep rebroadcasting a transaction for before giving up on it, eg
+/// because a conflicting transaction confirmed instead.
+const MAX_REBROADCAST_BLOCKS: u32 = 2016;
+
+struct PendingBroadcast {
+	tx: Transaction,
+	/// Whether we need to (re-)send the transaction on the next call to send_pending.
+	needs_send: bool,
+	/// The number of blocks which have been connected since the transaction was first broadcast.
+	blocks_unconfirmed: u32,
+}
+
+/// A BroadcasterInterface which sends transactions to Bitcoin Core's sendrawtransaction RPC.
+///
+/// Transactions are queued by broadcast_transaction and sent by the next call to send_pending.
+/// If bitcoind can't be reached, the transaction remains queued until a later call succeeds.
+///
+/// To ensure transactions eventually confirm even if they were rejected (eg because their
+/// locktime had not yet passed) or fell out of the mempool, the broadcaster should also be
+/// notified of new blocks as an AChainListener. Each time a block is connected any transaction
+/// which remains unconfirmed is queued to be sent again, until it confirms or 2016 blocks pass.
+pub struct RPCBroadcaster {
+	pending: Mutex<HashMap<Txid, PendingBroadcast>>,
+}
+
+impl RPCBroadcaster {
+	/// Creates a new RPCBroadcaster with no queued transactions.
+	pub fn new() -> Self {
+		Self { pending: Mutex::new(HashMap::new()) }
+	}
+
+	/// Sends each queued transaction to bitcoind.
+	///
+	/// Transactions which bitcoind rejects are not retried until the next block is connected, as
+	/// they are unlikely to be accepted before then. Returns Err if we failed to reach bitcoind,
+	/// leaving any unsent transactions queued.
+	pub async fn send_pending(&self, client: &RPCClient) -> Result<(), HttpClientError> {
+		let to_send: Vec<(Txid, Transaction)> = self.pending.lock().unwrap().iter()
+			.filter(|(_, pending)| pending.needs_send)
+			.map(|(txid, pending)| (*txid, pending.tx.clone()))
+			.collect();
+		for (txid, tx) in to_send {
+			let tx_hex = format!("\"{}\"", encode::serialize_hex(&tx));
+			match client.call_method("sendrawtransaction", &[&tx_hex]).await {
+				Err(HttpClientError::RPCError { code: RPC_VERIFY_ALREADY_IN_CHAIN, .. }) => {
+					self.pending.lock().unwrap().remove(&txid);
+				},
+				Ok(_) | Err(HttpClientError::RPCError { .. }) => {
+					if let Some(pending) = self.pending.lock().unwrap().get_mut(&txid) {
+						pending.needs_send = false;
+					}
+				},
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(())
+	}
+
+	/// Calls send_pending every interval, forever, ignoring failures.
+	pub async fn send_pending_periodically(&self, client: RPCClient, interval: Duration) {
+		loop {
+			let _ = self.send_pending(&client).await;
+			delay_for(interval).await;
+		}
+	}
+}
+
+impl BroadcasterInterface for RPCBroadcaster {
+	fn broadcast_transaction(&self, tx: &Transaction) {
+		let mut pending = self.pending.lock().unwrap();
+		let entry = pending.entry(tx.txid()).or_insert_with(|| PendingBroadcast {
+			tx: tx.clone(), needs_send: true, blocks_unconfirmed: 0,
+		});
+		entry.needs_send = true;
+	}
+}
+
+impl AChainListener for &RPCBroadcaster {
+	fn a_block_connected(&mut self, block: &Block, _height: u32) {
+		let mut pending = self.pending.lock().unwrap();
+		for tx in block.txdata.iter() {
+			pending.remove(&tx.txid());
+		}
+		pending.retain(|_, pending| {
+			pending.blocks_unconfirmed += 1;
+			pending.needs_send = true;
+			pending.blocks_unconfirmed <= MAX_REBROADCAST_BLOCKS
+		});
+	}
+	fn a_block_disconnected(&mut self, _header: &BlockHeader, _height: u32) {
+		// Transactions which were confirmed in the disconnected block are returned to bitcoind's
+		// mempool, and rust-lightning will broadcast them again if they don't reconfirm.
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::http_clients::spawn_rpc_server;
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
+
+	#[test]
+	fn test_feerate_conversion() {
+		// 0.00001 BTC/kvB is 1 sat/vB, which rounds up to our floor.
+		assert_eq!(btc_per_kvb_to_sat_per_kw(0.00001), Some(MIN_FEERATE_SAT_PER_KW));
+		assert_eq!(btc_per_kvb_to_sat_per_kw(0.0), Some(MIN_FEERATE_SAT_PER_KW));
+		assert_eq!(btc_per_kvb_to_sat_per_kw(0.0002), Some(5000));
+		assert_eq!(btc_per_kvb_to_sat_per_kw(0.00012345), Some(3087));
+		assert_eq!(btc_per_kvb_to_sat_per_kw(-1.0), None);
+
+		let estimator = RPCFeeEstimator::new();
+		assert_eq!(estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background), 253);
+		assert_eq!(estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal), 2000);
+		assert_eq!(estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority), 5000);
+	}
+
+	#[test]
+	fn test_rebroadcast_until_confirmed() {
+		let block = genesis_block(Network::Regtest);
+		let confirmed_tx = block.txdata[0].clone();
+		let mut unconfirmed_tx = confirmed_tx.clone();
+		unconfirmed_tx.lock_time = 1;
+
+		let broadcaster = RPCBroadcaster::new();
+		broadcaster.broadcast_transaction(&confirmed_tx);
+		broadcaster.broadcast_transaction(&unconfirmed_tx);
+		for pending in broadcaster.pending.lock().unwrap().values_mut() {
+			pending.needs_send = false;
+		}
+
+		(&broadcaster).a_block_connected(&block, 1);
+		{
+			let pending = broadcaster.pending.lock().unwrap();
+			assert_eq!(pending.len(), 1);
+			let entry = &pending[&unconfirmed_tx.txid()];
+			assert!(entry.needs_send);
+			assert_eq!(entry.blocks_unconfirmed, 1);
+		}
+
+		// Eventually we give up on transactions which never confirm.
+		let empty_block = Block { header: block.header, txdata: Vec::new() };
+		for height in 2..MAX_REBROADCAST_BLOCKS + 2 {
+			(&broadcaster).a_block_connected(&empty_block, height);
+		}
+		assert!(broadcaster.pending.lock().unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_update_estimates() {
+		let requests = std::sync::atomic::AtomicUsize::new(0);
+		let (uri, server) = spawn_rpc_server(6, move |req| {
+			assert_eq!(req["method"], "estimatesmartfee");
+			let result = if requests.fetch_add(1, Ordering::Relaxed) >= 3 {
+				serde_json::json!({"feerate": "garbage", "blocks": 2})
+			} else { match (req["params"][0].as_u64().unwrap(), req["params"][1].as_str().unwrap()) {
+				(144, "ECONOMICAL") => serde_json::json!({"feerate": 0.00002, "blocks": 144}),
+				// bitcoind omits the feerate if it has no estimate yet
+				(18, "ECONOMICAL") => serde_json::json!({"errors": ["Insufficient data or no feerate found"], "blocks": 0}),
+				(6, "CONSERVATIVE") => serde_json::json!({"feerate": 0.00012345, "blocks": 6}),
+				_ => panic!(),
+			} };
+			serde_json::json!({"result": result, "error": null, "id": req["id"]})
+		});
+		let client = RPCClient::new("user:pass", uri).unwrap();
+		let estimator = RPCFeeEstimator::new();
+		estimator.update_estimates(&client).await.unwrap();
+		assert_eq!(estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background), 500);
+		assert_eq!(estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal), DEFAULT_NORMAL_SAT_PER_KW);
+		assert_eq!(estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority), 3087);
+
+		// A second update with unparseable feerates leaves the cached values alone.
+		estimator.update_estimates(&client).await.unwrap();
+		assert_eq!(estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background), 500);
+		assert_eq!(estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority), 3087);
+		server.join().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_send_pending() {
+		let block = genesis_block(Network::Regtest);
+		let confirmed_tx = block.txdata[0].clone();
+		let mut missing_inputs_tx = confirmed_tx.clone();
+		missing_inputs_tx.lock_time = 1;
+		let mut accepted_tx = confirmed_tx.clone();
+		accepted_tx.lock_time = 2;
+
+		let confirmed_hex = encode::serialize_hex(&confirmed_tx);
+		let missing_inputs_hex = encode::serialize_hex(&missing_inputs_tx);
+		let (uri, server) = spawn_rpc_server(3, move |req| {
+			assert_eq!(req["method"], "sendrawtransaction");
+			let tx_hex = req["params"][0].as_str().unwrap();
+			if tx_hex == confirmed_hex {
+				serde_json::json!({"result": null, "error": {"code": RPC_VERIFY_ALREADY_IN_CHAIN, "message": "Transaction already in block chain"}, "id": req["id"]})
+			} else if tx_hex == missing_inputs_hex {
+				serde_json::json!({"result": null, "error": {"code": -25, "message": "bad-txns-inputs-missingorspent"}, "id": req["id"]})
+			} else {
+				serde_json::json!({"result": "00", "error": null, "id": req["id"]})
+			}
+		});
+
+		let broadcaster = RPCBroadcaster::new();
+		broadcaster.broadcast_transaction(&confirmed_tx);
+		broadcaster.broadcast_transaction(&missing_inputs_tx);
+		broadcaster.broadcast_transaction(&accepted_tx);
+		let client = RPCClient::new("user:pass", uri).unwrap();
+		broadcaster.send_pending(&client).await.unwrap();
+		server.join().unwrap();
+
+		// Transactions which are already confirmed are forgotten, while those which were rejected
+		// (eg because their inputs are missing) or accepted are only sent again once a block
+		// connects without them.
+		{
+			let pending = broadcaster.pending.lock().unwrap();
+			assert_eq!(pending.len(), 2);
+			assert!(!pending[&missing_inputs_tx.txid()].needs_send);
+			assert!(!pending[&accepted_tx.txid()].needs_send);
+		}
+		// With nothing left to send we don't even connect to bitcoind.
+		broadcaster.send_pending(&client).await.unwrap();
+
+		// Unsent transactions stay queued if bitcoind can't be reached.
+		broadcaster.broadcast_transaction(&accepted_tx);
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let dead_client = RPCClient::new("user:pass", format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+		drop(listener);
+		assert_eq!(broadcaster.send_pending(&dead_client).await, Err(HttpClientError::Transport));
+		assert!(broadcaster.pending.lock().unwrap()[&accepted_tx.txid()].needs_send);
+	}
+}
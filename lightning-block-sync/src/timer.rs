@@ -0,0 +1,74 @@
+//! A minimal timer so that our async loops never block the thread they're polled on, whether or
+//! not we're running inside a tokio runtime.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[cfg(not(feature = "tokio"))]
+use std::sync::{Arc, Mutex};
+#[cfg(not(feature = "tokio"))]
+use std::task::Waker;
+
+/// Completes once the given duration has passed. With feature `tokio` this is tokio's timer,
+/// otherwise a thread is spawned which wakes the task once the duration has passed.
+pub(crate) fn delay_for(duration: Duration) -> Delay {
+	#[cfg(feature = "tokio")]
+	{ Delay { delay: tokio::time::delay_for(duration) } }
+	#[cfg(not(feature = "tokio"))]
+	{ Delay { duration, state: None } }
+}
+
+pub(crate) struct Delay {
+	#[cfg(feature = "tokio")]
+	delay: tokio::time::Delay,
+	#[cfg(not(feature = "tokio"))]
+	duration: Duration,
+	/// Whether the timer thread has fired, and the waker to wake once it does. None until we're
+	/// first polled.
+	#[cfg(not(feature = "tokio"))]
+	state: Option<Arc<Mutex<(bool, Option<Waker>)>>>,
+}
+
+impl Future for Delay {
+	type Output = ();
+
+	#[cfg(feature = "tokio")]
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+		Pin::new(&mut self.delay).poll(cx)
+	}
+
+	#[cfg(not(feature = "tokio"))]
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+		let duration = self.duration;
+		let state = self.state.get_or_insert_with(|| {
+			let state = Arc::new(Mutex::new((false, None::<Waker>)));
+			let thread_state = Arc::clone(&state);
+			std::thread::spawn(move || {
+				std::thread::sleep(duration);
+				let mut state = thread_state.lock().unwrap();
+				state.0 = true;
+				if let Some(waker) = state.1.take() { waker.wake(); }
+			});
+			state
+		});
+		let mut state = state.lock().unwrap();
+		if state.0 { return Poll::Ready(()); }
+		state.1 = Some(cx.waker().clone());
+		Poll::Pending
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Instant;
+
+	#[tokio::test]
+	async fn delay_waits() {
+		let start = Instant::now();
+		delay_for(Duration::from_millis(50)).await;
+		assert!(start.elapsed() >= Duration::from_millis(50));
+	}
+}
@@ -0,0 +1,368 @@
+//! Push-based notifications of new blocks, allowing a MicroSPVClient to learn about a new tip as
+//! soon as it is available instead of at its next poll (see MicroSPVClient::wait_for_best_tip).
+//!
+//! We provide a TipNotifier which subscribes to Bitcoin Core's ZMQ `hashblock` feed (enabled with
+//! -zmqpubhashblock) and, with feature `rpc-client`, one which long-polls the `waitfornewblock`
+//! RPC. Notifications only ever trigger a poll of the MicroSPVClient's block sources, so a
+//! notifier is never trusted for anything but timing.
+
+use bitcoin::hash_types::BlockHash;
+use bitcoin::hashes::Hash;
+
+use std::future::Future;
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+#[cfg(feature = "rpc-client")]
+use crate::http_clients::RPCClient;
+#[cfg(feature = "rpc-client")]
+use bitcoin::hashes::hex::FromHex;
+
+#[cfg(feature = "tokio")]
+use tokio::net::TcpStream;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[cfg(not(feature = "tokio"))]
+use std::net::TcpStream;
+#[cfg(not(feature = "tokio"))]
+use std::io::{Read, Write};
+
+/// A source of notifications that a new best block may be available.
+pub trait TipNotifier : Send {
+	/// Waits until a block other than current_tip may be the best block. Spurious wakeups are
+	/// fine as each one only results in the block sources being polled.
+	///
+	/// Returns Err if the underlying push channel failed, in which case the caller should fall back
+	/// to polling. The next call should attempt to re-establish the channel.
+	///
+	/// Sadly rust's trait system hasn't grown the ability to take impl/differentially-sized return
+	/// values yet, so we have to Box + dyn the future.
+	fn wait_for_new_tip<'a>(&'a mut self, current_tip: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<(), ()>> + 'a + Send>>;
+
+	/// If this notifier bounds how long a single wait_for_new_tip call may block for (eg the
+	/// waitfornewblock timeout when long-polling), returns that bound. A call which is still
+	/// pending after this long is assumed to have stalled.
+	fn max_wait(&self) -> Option<Duration> { None }
+}
+
+pub(crate) const ZMTP_FLAG_MORE: u8 = 0x01;
+const ZMTP_FLAG_LONG: u8 = 0x02;
+const ZMTP_FLAG_COMMAND: u8 = 0x04;
+/// hashblock messages are tiny, so anything larger than this is nonsense.
+const MAX_ZMTP_FRAME_LEN: u64 = 4096;
+const HASHBLOCK_TOPIC: &[u8] = b"hashblock";
+/// Without tokio we can't time out a wait from the outside, so we instead reconnect (and have the
+/// client poll) if we haven't heard anything for this long.
+#[cfg(not(feature = "tokio"))]
+const ZMQ_READ_TIMEOUT: Duration = Duration::from_secs(60 * 30);
+
+/// The ZMTP 3.0 greeting for a client using the NULL security mechanism.
+pub(crate) fn zmtp_greeting() -> [u8; 64] {
+	let mut greeting = [0; 64];
+	greeting[0] = 0xff; // Signature, with 8 bytes of padding
+	greeting[9] = 0x7f;
+	greeting[10] = 3; // Version 3.0
+	greeting[12..16].copy_from_slice(b"NULL");
+	// as-server and filler are all 0s
+	greeting
+}
+
+/// A ZMTP READY command identifying us as a SUB socket.
+pub(crate) fn zmtp_ready_command() -> Vec<u8> {
+	let mut body = Vec::new();
+	body.push(5);
+	body.extend_from_slice(b"READY");
+	body.push(11);
+	body.extend_from_slice(b"Socket-Type");
+	body.extend_from_slice(&[0, 0, 0, 3]);
+	body.extend_from_slice(b"SUB");
+	let mut frame = vec![ZMTP_FLAG_COMMAND, body.len() as u8];
+	frame.extend_from_slice(&body);
+	frame
+}
+
+/// Parses a single ZMTP frame from the front of buf, returning its flags, its body and the number
+/// of bytes it occupies in buf, or None if buf doesn't yet contain a full frame. Frames with a body
+/// longer than max_frame_len are rejected.
+fn parse_zmtp_frame(buf: &[u8], max_frame_len: u64) -> Result<Option<(u8, &[u8], usize)>, ()> {
+	if buf.is_empty() { return Ok(None); }
+	let flags = buf[0];
+	if flags & !(ZMTP_FLAG_MORE | ZMTP_FLAG_LONG | ZMTP_FLAG_COMMAND) != 0 { return Err(()); }
+	let (body_len, header_len) = if flags & ZMTP_FLAG_LONG != 0 {
+		if buf.len() < 9 { return Ok(None); }
+		let mut len_bytes = [0; 8];
+		len_bytes.copy_from_slice(&buf[1..9]);
+		(u64::from_be_bytes(len_bytes), 9)
+	} else {
+		if buf.len() < 2 { return Ok(None); }
+		(buf[1] as u64, 2)
+	};
+	if body_len > max_frame_len { return Err(()); }
+	let frame_len = header_len + body_len as usize;
+	if buf.len() < frame_len { return Ok(None); }
+	Ok(Some((flags, &buf[header_len..frame_len], frame_len)))
+}
+
+/// A ZMTP connection to a ZMQ PUB socket, subscribed to a single topic. We keep all partially-read
+/// data in buf and parts, so a read may be cancelled (eg by a timeout) without leaving the
+/// connection in an invalid state.
+pub(crate) struct ZMQConnection {
+	stream: TcpStream,
+	buf: Vec<u8>,
+	parts: Vec<Vec<u8>>,
+	topic: &'static [u8],
+	max_frame_len: u64,
+}
+
+impl ZMQConnection {
+	async fn read_more(&mut self) -> Result<(), ()> {
+		let mut read_buf = [0; 1024];
+		#[cfg(feature = "tokio")]
+		let res = self.stream.read(&mut read_buf).await;
+		#[cfg(not(feature = "tokio"))]
+		let res = self.stream.read(&mut read_buf);
+		match res {
+			Ok(0) => Err(()),
+			Ok(len) => {
+				self.buf.extend_from_slice(&read_buf[..len]);
+				Ok(())
+			},
+			Err(_) => Err(()),
+		}
+	}
+
+	async fn write_all(&mut self, data: &[u8]) -> Result<(), ()> {
+		#[cfg(feature = "tokio")]
+		let res = self.stream.write_all(data).await;
+		#[cfg(not(feature = "tokio"))]
+		let res = self.stream.write_all(data);
+		res.map_err(|_| ())
+	}
+
+	/// Connects to the given ZMQ PUB socket, completes the ZMTP handshake and subscribes to
+	/// messages with the given topic, whose frames may be up to max_frame_len bytes.
+	pub(crate) async fn connect(addr: &SocketAddr, topic: &'static [u8], max_frame_len: u64) -> Result<Self, ()> {
+		let stream = std::net::TcpStream::connect_timeout(addr, Duration::from_secs(1)).map_err(|_| ())?;
+		stream.set_write_timeout(Some(Duration::from_secs(1))).expect("Host kernel is uselessly old?");
+		#[cfg(not(feature = "tokio"))]
+		stream.set_read_timeout(Some(ZMQ_READ_TIMEOUT)).expect("Host kernel is uselessly old?");
+		#[cfg(feature = "tokio")]
+		let stream = TcpStream::from_std(stream).map_err(|_| ())?;
+		let mut conn = Self { stream, buf: Vec::new(), parts: Vec::new(), topic, max_frame_len };
+
+		conn.write_all(&zmtp_greeting()).await?;
+		while conn.buf.len() < 64 { conn.read_more().await?; }
+		{
+			let greeting = &conn.buf[..64];
+			if greeting[0] != 0xff || greeting[9] != 0x7f || greeting[10] < 3 || &greeting[12..16] != b"NULL" {
+				return Err(());
+			}
+		}
+		conn.buf.drain(..64);
+
+		conn.write_all(&zmtp_ready_command()).await?;
+		loop {
+			if let Some((flags, body, frame_len)) = parse_zmtp_frame(&conn.buf, MAX_ZMTP_FRAME_LEN)? {
+				if flags & ZMTP_FLAG_COMMAND == 0 || !body.starts_with(b"\x05READY") { return Err(()); }
+				conn.buf.drain(..frame_len);
+				break;
+			}
+			conn.read_more().await?;
+		}
+
+		// In ZMTP 3.0, subscriptions are sent as a message starting with a 1 byte.
+		let mut subscribe = vec![0, 1 + topic.len() as u8, 1];
+		subscribe.extend_from_slice(topic);
+		conn.write_all(&subscribe).await?;
+		Ok(conn)
+	}
+
+	/// Reads messages until we get one with our topic, returning its body.
+	pub(crate) async fn next_message(&mut self) -> Result<Vec<u8>, ()> {
+		loop {
+			while let Some((flags, body, frame_len)) = parse_zmtp_frame(&self.buf, self.max_frame_len)? {
+				// Commands (eg ZMTP 3.1 PINGs) are ignored, everything else is a message part.
+				let is_message = flags & ZMTP_FLAG_COMMAND == 0;
+				if is_message {
+					// Bitcoin Core's messages have three parts: the topic, the body and a sequence
+					// number.
+					if self.parts.len() >= 3 { return Err(()); }
+					self.parts.push(body.to_vec());
+				}
+				self.buf.drain(..frame_len);
+				if is_message && flags & ZMTP_FLAG_MORE == 0 {
+					let mut parts = mem::replace(&mut self.parts, Vec::new());
+					if parts.len() >= 2 && &parts[0][..] == self.topic {
+						return Ok(parts.swap_remove(1));
+					}
+				}
+			}
+			self.read_more().await?;
+		}
+	}
+
+	/// Reads messages until we get a hashblock message, returning its block hash.
+	async fn next_block_hash(&mut self) -> Result<BlockHash, ()> {
+		loop {
+			let body = self.next_message().await?;
+			if body.len() == 32 {
+				// Bitcoin Core sends the hash in the usual (reversed) display byte order.
+				let mut hash = [0; 32];
+				hash.copy_from_slice(&body);
+				hash.reverse();
+				return Ok(BlockHash::from_slice(&hash).unwrap());
+			}
+		}
+	}
+}
+
+/// A TipNotifier which subscribes to Bitcoin Core's ZMQ hashblock publisher, ie the address
+/// passed to bitcoind as -zmqpubhashblock=tcp://address:port.
+///
+/// We implement the (tiny) subset of ZMTP 3.0 required for this directly, so no ZMQ library is
+/// needed.
+pub struct ZMQTipNotifier {
+	addr: SocketAddr,
+	conn: Option<ZMQConnection>,
+}
+
+impl ZMQTipNotifier {
+	/// Creates a new ZMQTipNotifier for the given address. We don't connect until the first call
+	/// to wait_for_new_tip.
+	pub fn new(addr: SocketAddr) -> Self {
+		Self { addr, conn: None }
+	}
+}
+
+impl TipNotifier for ZMQTipNotifier {
+	fn wait_for_new_tip<'a>(&'a mut self, _current_tip: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<(), ()>> + 'a + Send>> {
+		Box::pin(async move {
+			if self.conn.is_none() {
+				self.conn = Some(ZMQConnection::connect(&self.addr, HASHBLOCK_TOPIC, MAX_ZMTP_FRAME_LEN).await?);
+				// We may have missed blocks while we were disconnected, so have the client poll
+				// immediately.
+				return Ok(());
+			}
+			match self.conn.as_mut().unwrap().next_block_hash().await {
+				Ok(_) => Ok(()),
+				Err(()) => {
+					self.conn = None;
+					Err(())
+				},
+			}
+		})
+	}
+}
+
+#[cfg(feature = "rpc-client")]
+/// A TipNotifier which long-polls Bitcoin Core's waitfornewblock RPC.
+///
+/// As each request blocks for up to the configured timeout, the RPCClient used here should not be
+/// one of the MicroSPVClient's block sources.
+pub struct RPCLongPollTipNotifier {
+	client: RPCClient,
+	timeout: Duration,
+	last_notified: Option<BlockHash>,
+}
+
+#[cfg(feature = "rpc-client")]
+impl RPCLongPollTipNotifier {
+	/// Creates a new RPCLongPollTipNotifier, with each waitfornewblock call returning after at
+	/// most timeout if no new block arrives.
+	pub fn new(mut client: RPCClient, timeout: Duration) -> Self {
+		client.set_read_timeout(timeout + Duration::from_secs(5));
+		Self { client, timeout, last_notified: None }
+	}
+}
+
+#[cfg(feature = "rpc-client")]
+impl TipNotifier for RPCLongPollTipNotifier {
+	fn wait_for_new_tip<'a>(&'a mut self, current_tip: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<(), ()>> + 'a + Send>> {
+		Box::pin(async move {
+			// waitfornewblock only returns once the tip changes from the one bitcoind had when the
+			// call started, so first check we didn't miss a block since the client last polled.
+			// We only do so once per new tip in case the client is unable to sync to it.
+			let best = self.client.call_method("getbestblockhash", &[]).await.map_err(|_| ())?;
+			let best_hash = BlockHash::from_hex(best.as_str().ok_or(())?).map_err(|_| ())?;
+			if best_hash != *current_tip && self.last_notified != Some(best_hash) {
+				self.last_notified = Some(best_hash);
+				return Ok(());
+			}
+
+			let timeout_ms = (self.timeout.as_secs() * 1000 + self.timeout.subsec_millis() as u64).to_string();
+			let res = self.client.call_method("waitfornewblock", &[&timeout_ms]).await.map_err(|_| ())?;
+			if let Some(hash) = res["hash"].as_str().and_then(|hash| BlockHash::from_hex(hash).ok()) {
+				self.last_notified = Some(hash);
+			}
+			Ok(())
+		})
+	}
+
+	fn max_wait(&self) -> Option<Duration> {
+		// Matches the read timeout we set on the client, giving bitcoind some slack to respond
+		// once waitfornewblock times out.
+		Some(self.timeout + Duration::from_secs(5))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::{Read, Write};
+	use std::net::TcpListener;
+
+	#[test]
+	fn zmtp_frame_parsing() {
+		assert_eq!(parse_zmtp_frame(&[], MAX_ZMTP_FRAME_LEN), Ok(None));
+		assert_eq!(parse_zmtp_frame(&[0x01], MAX_ZMTP_FRAME_LEN), Ok(None));
+		assert_eq!(parse_zmtp_frame(&[0x01, 2, 42], MAX_ZMTP_FRAME_LEN), Ok(None));
+		assert_eq!(parse_zmtp_frame(&[0x01, 2, 42, 43, 44], MAX_ZMTP_FRAME_LEN), Ok(Some((0x01, &[42, 43][..], 4))));
+		assert_eq!(parse_zmtp_frame(&[0x02, 0, 0, 0, 0, 0, 0, 0, 1, 42], MAX_ZMTP_FRAME_LEN), Ok(Some((0x02, &[42][..], 10))));
+		assert_eq!(parse_zmtp_frame(&[0x02, 0, 0, 0, 0, 0, 0, 1, 0], MAX_ZMTP_FRAME_LEN), Ok(None));
+		assert_eq!(parse_zmtp_frame(&[0x02, 0, 0, 0, 0, 0, 0, 1, 0], 255), Err(()));
+		assert_eq!(parse_zmtp_frame(&[0x08, 0], MAX_ZMTP_FRAME_LEN), Err(()));
+	}
+
+	#[tokio::test]
+	async fn zmq_hashblock_notification() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let publisher = std::thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut greeting = [0; 64];
+			stream.read_exact(&mut greeting).unwrap();
+			assert_eq!(&greeting[..], &zmtp_greeting()[..]);
+			stream.write_all(&zmtp_greeting()).unwrap();
+
+			let mut ready = vec![0; zmtp_ready_command().len()];
+			stream.read_exact(&mut ready).unwrap();
+			assert_eq!(ready, zmtp_ready_command());
+			stream.write_all(&zmtp_ready_command()).unwrap();
+
+			let mut subscribe = [0; 12];
+			stream.read_exact(&mut subscribe).unwrap();
+			assert_eq!(&subscribe[..], b"\x00\x0a\x01hashblock");
+
+			let mut message = vec![ZMTP_FLAG_MORE, 9];
+			message.extend_from_slice(b"hashblock");
+			message.extend_from_slice(&[ZMTP_FLAG_MORE, 32]);
+			message.extend_from_slice(&[42; 32]);
+			message.extend_from_slice(&[0, 4, 0, 0, 0, 0]);
+			stream.write_all(&message).unwrap();
+		});
+
+		let mut notifier = ZMQTipNotifier::new(addr);
+		let tip = BlockHash::from_slice(&[0; 32]).unwrap();
+		// The first call connects and asks for an immediate poll, after which we get the hashblock.
+		assert_eq!(notifier.wait_for_new_tip(&tip).await, Ok(()));
+		assert_eq!(notifier.conn.as_mut().unwrap().next_block_hash().await, Ok(BlockHash::from_slice(&[42; 32]).unwrap()));
+		publisher.join().unwrap();
+
+		// Once the publisher goes away we should fail and then try to reconnect.
+		assert_eq!(notifier.wait_for_new_tip(&tip).await, Err(()));
+		assert!(notifier.conn.is_none());
+	}
+}
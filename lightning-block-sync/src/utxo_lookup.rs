@@ -0,0 +1,323 @@
+//! A UtxoLookup which checks the funding outputs of announced channels against a BlockSource,
+//! allowing NetGraphMsgHandler to reject channel_announcements for channels which don't exist.
+//!
+//! UtxoLookup::get_utxo is synchronous, so BlockSourceUtxoLookup only queues the lookup and
+//! returns UtxoLookupResult::Async. The queue is worked through by process_pending, which fetches
+//! the hash of the block at the height given in the short_channel_id and then the block itself,
+//! and hands the funding output to NetGraphMsgHandler::utxo_lookup_completed.
+//!
+//! With feature `rpc-client` or `rest-client`, Bitcoin Core's RPC (getblockhash and getblock) or
+//! REST (blockhashbyheight and block) interfaces can be used as the BlockHashSource.
+//!
+//! Channels whose funding output was found this way are removed once it is spent, as long as the
+//! NetGraphMsgHandler is notified of new blocks, eg by including it in the chain listener passed
+//! to MicroSPVClient. The funding outputs being watched are not persisted, so this only applies to
+//! channels looked up since the NetGraphMsgHandler was last created.
+
+use crate::{BlockSource, BlockSourceRespErr};
+
+use lightning::chain::chaininterface::{ChainError, ChainWatchInterface, UtxoLookup, UtxoLookupResult};
+use lightning::chain::transaction::OutPoint;
+use lightning::routing::network_graph::NetGraphMsgHandler;
+use lightning::util::logger::Logger;
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::transaction::TxOut;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::network::constants::Network;
+use bitcoin::util::hash::BitcoinHash;
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A BlockSource which can also look up blocks on its best chain by height.
+pub trait BlockHashSource : BlockSource {
+	/// Gets the hash of the block at the given height in the best chain, or None if the best
+	/// chain isn't that long.
+	///
+	/// Sadly rust's trait system hasn't grown the ability to take impl/differentially-sized return
+	/// values yet, so we have to Box + dyn the future.
+	fn get_block_hash_by_height<'a>(&'a mut self, height: u32) -> Pin<Box<dyn Future<Output = Result<Option<BlockHash>, BlockSourceRespErr>> + 'a + Send>>;
+}
+
+/// Finds the output identified by a short_channel_id in the block at the height it gives.
+fn find_funding_output(block: &Block, short_channel_id: u64) -> Result<(OutPoint, TxOut), ChainError> {
+	let tx_index = ((short_channel_id >> 16) & 0xff_ffff) as usize;
+	let output_index = (short_channel_id & 0xffff) as u16;
+	let tx = block.txdata.get(tx_index).ok_or(ChainError::UnknownTx)?;
+	let txout = tx.output.get(output_index as usize).ok_or(ChainError::UnknownTx)?;
+	Ok((OutPoint::new(tx.txid(), output_index), txout.clone()))
+}
+
+/// The number of times we try to look up a funding output before giving up on it when the source
+/// fails to respond.
+const MAX_LOOKUP_ATTEMPTS: u8 = 3;
+
+/// A UtxoLookup which queues lookups to be done against a BlockHashSource by process_pending.
+///
+/// Note that we only check that the funding output exists, not that it is unspent, as neither
+/// getblock nor the REST block endpoint tell us. Channels whose funding output was already spent
+/// are removed as soon as the NetGraphMsgHandler sees a block spending it, which for outputs
+/// spent before we started means never - such channels are left to be pruned once they stop
+/// receiving channel_updates.
+pub struct BlockSourceUtxoLookup {
+	genesis_hash: BlockHash,
+	/// Queued lookups, by short_channel_id, with the number of failed attempts at each.
+	pending: Mutex<VecDeque<(u64, u8)>>,
+}
+
+impl BlockSourceUtxoLookup {
+	/// Creates a new BlockSourceUtxoLookup for channels on the given network. Announcements for
+	/// channels on other chains are rejected immediately.
+	pub fn new(network: Network) -> Self {
+		Self {
+			genesis_hash: genesis_block(network).header.bitcoin_hash(),
+			pending: Mutex::new(VecDeque::new()),
+		}
+	}
+
+	/// Looks up the funding output of each queued channel, passing the results to
+	/// net_graph_msg_handler.
+	///
+	/// If the source doesn't respond, the lookup is moved to the back of the queue and Err is
+	/// returned, leaving the rest of the queue for the next call. After MAX_LOOKUP_ATTEMPTS such
+	/// failures the output is treated as if it doesn't exist, as is one in a block which doesn't
+	/// match the requested hash.
+	pub async fn process_pending<B, C, L>(&self, source: &mut B, net_graph_msg_handler: &NetGraphMsgHandler<C, L>) -> Result<(), BlockSourceRespErr>
+			where B: BlockHashSource + ?Sized,
+			      C: Deref + Sync + Send, L: Deref + Sync + Send,
+			      C::Target: ChainWatchInterface, L::Target: Logger {
+		loop {
+			let (short_channel_id, failed_attempts) = match self.pending.lock().unwrap().pop_front() {
+				Some(lookup) => lookup,
+				None => return Ok(()),
+			};
+			let result = match Self::look_up(source, short_channel_id).await {
+				Ok(result) => result,
+				Err(BlockSourceRespErr::BogusData) => Err(ChainError::UnknownTx),
+				Err(e) => {
+					if failed_attempts + 1 < MAX_LOOKUP_ATTEMPTS {
+						self.pending.lock().unwrap().push_back((short_channel_id, failed_attempts + 1));
+					} else {
+						let _ = net_graph_msg_handler.utxo_lookup_completed(short_channel_id, Err(ChainError::UnknownTx));
+					}
+					return Err(e);
+				},
+			};
+			// If the announcement was rejected, or had been given up on, there's nothing to do.
+			let _ = net_graph_msg_handler.utxo_lookup_completed(short_channel_id, result);
+		}
+	}
+
+	async fn look_up<B: BlockHashSource + ?Sized>(source: &mut B, short_channel_id: u64) -> Result<Result<(OutPoint, TxOut), ChainError>, BlockSourceRespErr> {
+		let height = (short_channel_id >> 40) as u32;
+		let block_hash = match source.get_block_hash_by_height(height).await? {
+			Some(block_hash) => block_hash,
+			None => return Ok(Err(ChainError::UnknownTx)),
+		};
+		let block = source.get_block(&block_hash).await?;
+		if block.bitcoin_hash() != block_hash || !block.check_merkle_root() {
+			return Err(BlockSourceRespErr::BogusData);
+		}
+		Ok(find_funding_output(&block, short_channel_id))
+	}
+
+	/// Calls process_pending every interval, forever, ignoring failures.
+	pub async fn process_pending_periodically<B, C, L>(&self, mut source: B, net_graph_msg_handler: &NetGraphMsgHandler<C, L>, interval: Duration)
+			where B: BlockHashSource,
+			      C: Deref + Sync + Send, L: Deref + Sync + Send,
+			      C::Target: ChainWatchInterface, L::Target: Logger {
+		loop {
+			let _ = self.process_pending(&mut source, net_graph_msg_handler).await;
+			crate::timer::delay_for(interval).await;
+		}
+	}
+}
+
+impl UtxoLookup for BlockSourceUtxoLookup {
+	fn get_utxo(&self, genesis_hash: &BlockHash, short_channel_id: u64) -> UtxoLookupResult {
+		if *genesis_hash != self.genesis_hash {
+			return UtxoLookupResult::Sync(Err(ChainError::NotWatched));
+		}
+		// NetGraphMsgHandler bounds the number of outstanding lookups, but may give up on one and
+		// then request it again while it is still queued here.
+		let mut pending = self.pending.lock().unwrap();
+		if !pending.iter().any(|&(queued_id, _)| queued_id == short_channel_id) {
+			pending.push_back((short_channel_id, 0));
+		}
+		UtxoLookupResult::Async
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use lightning::chain::chaininterface::ChainWatchInterfaceUtil;
+	use lightning::ln::features::ChannelFeatures;
+	use lightning::ln::msgs::{ChannelAnnouncement, RoutingMessageHandler};
+	use lightning::util::logger::Record;
+	use lightning::util::ser::{Readable, Writeable};
+
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::{Transaction, TxIn};
+	use bitcoin::hashes::Hash;
+	use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin::secp256k1::{Message, Secp256k1, SecretKey, PublicKey};
+
+	use std::sync::Arc;
+
+	struct NullLogger {}
+	impl Logger for NullLogger {
+		fn log(&self, _record: &Record) {}
+	}
+
+	/// A source with the blocks of a chain (starting at height 0), which fails after serving
+	/// failures_after requests.
+	struct BlockHeights {
+		blocks: Vec<Block>,
+		failures_after: usize,
+	}
+	impl BlockHeights {
+		fn serve(&mut self) -> Result<(), BlockSourceRespErr> {
+			if self.failures_after == 0 { return Err(BlockSourceRespErr::NoResponse); }
+			self.failures_after -= 1;
+			Ok(())
+		}
+	}
+	impl BlockSource for BlockHeights {
+		fn get_header<'a>(&'a mut self, _header_hash: &'a BlockHash, _height_hint: Option<u32>) -> Pin<Box<dyn Future<Output = Result<crate::BlockHeaderData, BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async { Err(BlockSourceRespErr::NoResponse) })
+		}
+		fn get_block<'a>(&'a mut self, header_hash: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<Block, BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async move {
+				self.serve()?;
+				self.blocks.iter().find(|block| block.bitcoin_hash() == *header_hash).cloned().ok_or(BlockSourceRespErr::NoResponse)
+			})
+		}
+		fn get_best_block<'a>(&'a mut self) -> Pin<Box<dyn Future<Output = Result<(BlockHash, Option<u32>), BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async { Err(BlockSourceRespErr::NoResponse) })
+		}
+	}
+	impl BlockHashSource for BlockHeights {
+		fn get_block_hash_by_height<'a>(&'a mut self, height: u32) -> Pin<Box<dyn Future<Output = Result<Option<BlockHash>, BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async move {
+				self.serve()?;
+				Ok(self.blocks.get(height as usize).map(|block| block.bitcoin_hash()))
+			})
+		}
+	}
+
+	fn funding_script(secp_ctx: &Secp256k1<bitcoin::secp256k1::All>, btckey_1: &SecretKey, btckey_2: &SecretKey) -> Script {
+		Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+			.push_slice(&PublicKey::from_secret_key(secp_ctx, btckey_1).serialize())
+			.push_slice(&PublicKey::from_secret_key(secp_ctx, btckey_2).serialize())
+			.push_opcode(opcodes::all::OP_PUSHNUM_2)
+			.push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh()
+	}
+
+	fn block_with_outputs(prev: &Block, outputs: Vec<TxOut>) -> Block {
+		let tx = Transaction {
+			version: 2, lock_time: 0,
+			input: vec![TxIn { previous_output: Default::default(), script_sig: Script::new(), sequence: 0xffffffff, witness: Vec::new() }],
+			output: outputs,
+		};
+		let mut block = Block {
+			header: BlockHeader {
+				version: 1, prev_blockhash: prev.bitcoin_hash(), merkle_root: Default::default(),
+				time: prev.header.time + 600, bits: prev.header.bits, nonce: 0,
+			},
+			txdata: vec![tx],
+		};
+		block.header.merkle_root = block.merkle_root();
+		block
+	}
+
+	#[test]
+	fn test_find_funding_output() {
+		let genesis = genesis_block(Network::Regtest);
+		let block = block_with_outputs(&genesis, vec![TxOut { value: 1, script_pubkey: Script::new() }, TxOut { value: 2, script_pubkey: Script::new() }]);
+		let (outpoint, txout) = find_funding_output(&block, (1 << 40) | 1).ok().unwrap();
+		assert_eq!(outpoint, OutPoint::new(block.txdata[0].txid(), 1));
+		assert_eq!(txout.value, 2);
+		assert!(find_funding_output(&block, (1 << 40) | 2).is_err());
+		assert!(find_funding_output(&block, (1 << 40) | (1 << 16)).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_process_pending() {
+		let secp_ctx = Secp256k1::new();
+		let node_1_privkey = SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_2_privkey = SecretKey::from_slice(&[41; 32]).unwrap();
+		let btckey_1 = SecretKey::from_slice(&[40; 32]).unwrap();
+		let btckey_2 = SecretKey::from_slice(&[39; 32]).unwrap();
+
+		let genesis = genesis_block(Network::Regtest);
+		let block = block_with_outputs(&genesis, vec![TxOut { value: 100_000, script_pubkey: funding_script(&secp_ctx, &btckey_1, &btckey_2) }]);
+		let mut source = BlockHeights { blocks: vec![genesis.clone(), block.clone()], failures_after: 0 };
+
+		// The message fields are only visible within the lightning crate, so build the
+		// announcement from its serialization instead.
+		let announcement = |short_channel_id: u64| {
+			let mut contents = ChannelFeatures::known().encode();
+			contents.extend_from_slice(&genesis.bitcoin_hash()[..]);
+			contents.extend_from_slice(&short_channel_id.to_be_bytes());
+			for key in [&node_1_privkey, &node_2_privkey, &btckey_1, &btckey_2].iter() {
+				contents.extend_from_slice(&PublicKey::from_secret_key(&secp_ctx, key).serialize());
+			}
+			let msghash = Message::from_slice(&Sha256dHash::hash(&contents[..])[..]).unwrap();
+			let mut msg = Vec::new();
+			for key in [&node_1_privkey, &node_2_privkey, &btckey_1, &btckey_2].iter() {
+				msg.extend_from_slice(&secp_ctx.sign(&msghash, key).serialize_compact());
+			}
+			msg.extend_from_slice(&contents);
+			let res: ChannelAnnouncement = Readable::read(&mut std::io::Cursor::new(&msg)).unwrap();
+			res
+		};
+
+		let utxo_lookup = Arc::new(BlockSourceUtxoLookup::new(Network::Regtest));
+		let chain_monitor = Arc::new(ChainWatchInterfaceUtil::new(Network::Regtest));
+		let logger: Arc<dyn Logger> = Arc::new(NullLogger {});
+		let mut net_graph_msg_handler = NetGraphMsgHandler::new(chain_monitor, logger);
+		net_graph_msg_handler.set_utxo_lookup(utxo_lookup.clone());
+
+		// The real funding output, an output which doesn't exist and a block we don't have yet.
+		let valid_id = 1 << 40;
+		for short_channel_id in [valid_id, valid_id | 1, 2 << 40].iter() {
+			assert_eq!(net_graph_msg_handler.handle_channel_announcement(&announcement(*short_channel_id)).unwrap(), false);
+		}
+		assert_eq!(utxo_lookup.pending.lock().unwrap().len(), 3);
+
+		// Nothing is lost if the source goes away part way through, and a lookup which failed is
+		// retried after the rest of the queue rather than holding it up.
+		assert!(utxo_lookup.process_pending(&mut source, &net_graph_msg_handler).await.is_err());
+		assert_eq!(*utxo_lookup.pending.lock().unwrap(), vec![(valid_id | 1, 0), (2 << 40, 0), (valid_id, 1)]);
+		source.failures_after = 3;
+		assert!(utxo_lookup.process_pending(&mut source, &net_graph_msg_handler).await.is_err());
+		assert_eq!(*utxo_lookup.pending.lock().unwrap(), vec![(valid_id, 2)]);
+
+		source.failures_after = usize::max_value();
+		utxo_lookup.process_pending(&mut source, &net_graph_msg_handler).await.unwrap();
+		assert!(utxo_lookup.pending.lock().unwrap().is_empty());
+		assert_eq!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().keys().cloned().collect::<Vec<_>>(), vec![valid_id]);
+
+		// A lookup is given up on once the source has failed it MAX_LOOKUP_ATTEMPTS times, after
+		// which the channel may be announced (and looked up) again.
+		let retried_id = valid_id | 2;
+		assert_eq!(net_graph_msg_handler.handle_channel_announcement(&announcement(retried_id)).unwrap(), false);
+		source.failures_after = 0;
+		for _ in 0..MAX_LOOKUP_ATTEMPTS {
+			assert!(utxo_lookup.process_pending(&mut source, &net_graph_msg_handler).await.is_err());
+		}
+		assert!(utxo_lookup.pending.lock().unwrap().is_empty());
+		assert_eq!(net_graph_msg_handler.handle_channel_announcement(&announcement(retried_id)).unwrap(), false);
+		assert_eq!(*utxo_lookup.pending.lock().unwrap(), vec![(retried_id, 0)]);
+		assert_eq!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().len(), 1);
+	}
+}
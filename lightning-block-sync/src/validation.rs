@@ -0,0 +1,541 @@
+//! Network-aware header validation for MicroSPVClient.
+//!
+//! check_builds_on only sees a header and its immediate predecessor, which is enough to check the
+//! header commits to the claimed chain work and that mainnet difficulty only shifts on retarget
+//! boundaries. The remaining consensus rules for headers need more context - the timestamps of the
+//! previous 11 blocks for median-time-past, the first block of the retarget window to calculate
+//! the exact new difficulty, and, on testnet, the last block mined without the 20-minute
+//! min-difficulty exception. ChainValidator tracks the little state we need to avoid refetching
+//! these on every poll and fetches whatever is missing from the BlockSource which served us the
+//! new headers.
+//!
+//! Note that signet is not yet representable in the version of bitcoin::Network we depend on. A
+//! signet chain uses mainnet difficulty rules with a different proof-of-work limit, so it cannot
+//! currently be validated here.
+
+use crate::{BlockHeaderData, BlockSource, BlockSourceRespErr};
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::network::constants::Network;
+use bitcoin::util::hash::BitcoinHash;
+use bitcoin::util::uint::Uint256;
+
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of blocks between difficulty retargets.
+pub(crate) const RETARGET_INTERVAL: u32 = 2016;
+/// The amount of time we expect a retarget window to take, ie two weeks.
+const TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+/// The spacing after which testnet allows a block to be mined at the minimum difficulty.
+const TESTNET_MIN_DIFFICULTY_SPACING: u32 = 20 * 60;
+/// Headers whose time is more than this far in our future are not (yet) valid.
+const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+/// The number of previous blocks whose median time a new header's time must exceed.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// The compact proof-of-work limit for the given network.
+pub(crate) fn pow_limit_bits(network: Network) -> u32 {
+	match network {
+		Network::Bitcoin|Network::Testnet => 0x1d00ffff,
+		Network::Regtest => 0x207fffff,
+	}
+}
+
+/// Whether the network allows blocks at the minimum difficulty if none have been found for 20
+/// minutes.
+fn allows_min_difficulty_blocks(network: Network) -> bool {
+	match network {
+		Network::Bitcoin => false,
+		Network::Testnet|Network::Regtest => true,
+	}
+}
+
+/// Whether the network never adjusts difficulty at all.
+fn no_retargeting(network: Network) -> bool {
+	match network {
+		Network::Bitcoin|Network::Testnet => false,
+		Network::Regtest => true,
+	}
+}
+
+/// Expands a compact nBits value into the full target.
+pub(crate) fn bits_to_target(bits: u32) -> Uint256 {
+	BlockHeader { version: 0, prev_blockhash: Default::default(), merkle_root: Default::default(),
+		time: 0, bits, nonce: 0 }.target()
+}
+
+/// Encodes a target in compact nBits form, matching Bitcoin Core's arith_uint256::GetCompact for
+/// the (non-negative) targets we deal with.
+pub(crate) fn target_to_bits(target: &Uint256) -> u32 {
+	let mut size = (target.bits() + 7) / 8;
+	let mut compact = if size <= 3 {
+		target.low_u32() << (8 * (3 - size))
+	} else {
+		(*target >> (8 * (size - 3))).low_u32()
+	};
+	// The top bit of the mantissa is a sign bit, so shift right if it would be set.
+	if compact & 0x00800000 != 0 {
+		compact >>= 8;
+		size += 1;
+	}
+	compact | ((size as u32) << 24)
+}
+
+/// Calculates the nBits a block at a retarget height must have, given the nBits and time of the
+/// last block before the retarget and the time of the first block in the retarget window (ie the
+/// block 2016 blocks before the retarget).
+pub(crate) fn calculate_next_work_required(last_bits: u32, first_block_time: u32, last_block_time: u32, network: Network) -> u32 {
+	if no_retargeting(network) {
+		return last_bits;
+	}
+
+	// Note that we intentionally replicate the off-by-one in Bitcoin Core here - the window
+	// measures only 2015 block intervals.
+	let mut actual_timespan = last_block_time as i64 - first_block_time as i64;
+	if actual_timespan < (TARGET_TIMESPAN / 4) as i64 {
+		actual_timespan = (TARGET_TIMESPAN / 4) as i64;
+	}
+	if actual_timespan > (TARGET_TIMESPAN * 4) as i64 {
+		actual_timespan = (TARGET_TIMESPAN * 4) as i64;
+	}
+
+	let pow_limit = bits_to_target(pow_limit_bits(network));
+	let new_target = bits_to_target(last_bits).mul_u32(actual_timespan as u32) / Uint256::from_u64(TARGET_TIMESPAN as u64).unwrap();
+	if new_target > pow_limit {
+		target_to_bits(&pow_limit)
+	} else {
+		target_to_bits(&new_target)
+	}
+}
+
+/// Checks the parts of the difficulty rules which only require the immediate predecessor of a
+/// header - the target is below the network's limit and mainnet difficulty only changes on
+/// retarget boundaries and then by no more than 4x in either direction.
+///
+/// On testnet we cannot tell whether a block following a min-difficulty block returned to the
+/// right difficulty without walking back further, so ChainValidator checks that later.
+pub(crate) fn check_difficulty_transition(child_header: &BlockHeaderData, previous_header: &BlockHeaderData, network: Network) -> Result<(), BlockSourceRespErr> {
+	let pow_limit_bits = pow_limit_bits(network);
+	if child_header.header.target() > bits_to_target(pow_limit_bits) {
+		return Err(BlockSourceRespErr::BogusData);
+	}
+	if no_retargeting(network) {
+		return Ok(());
+	}
+
+	if child_header.height % RETARGET_INTERVAL == 0 {
+		let new_work = child_header.header.work();
+		let prev_work = previous_header.header.work();
+		if new_work > prev_work << 2 || new_work < prev_work >> 2 {
+			return Err(BlockSourceRespErr::BogusData)
+		}
+	} else if child_header.header.bits != previous_header.header.bits {
+		if !allows_min_difficulty_blocks(network) {
+			return Err(BlockSourceRespErr::BogusData)
+		}
+		let is_min_difficulty = child_header.header.bits == pow_limit_bits &&
+			child_header.header.time > previous_header.header.time.saturating_add(TESTNET_MIN_DIFFICULTY_SPACING);
+		if !is_min_difficulty && previous_header.header.bits != pow_limit_bits {
+			return Err(BlockSourceRespErr::BogusData)
+		}
+	}
+	Ok(())
+}
+
+/// The hard-coded checkpoints from Bitcoin Core for the given network, as (height, block hash)
+/// pairs.
+///
+/// Pass these to MicroSPVClient::set_checkpoints to reject any BlockSource which serves a chain
+/// forking off before a checkpoint, which prevents a source from feeding us a low-work chain
+/// starting deep in the past (where difficulty was low) while we are still syncing.
+pub fn default_checkpoints(network: Network) -> Vec<(u32, BlockHash)> {
+	let checkpoints: &[(u32, &str)] = match network {
+		Network::Bitcoin => &[
+			( 11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+			( 33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+			( 74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+			(105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+			(134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+			(168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+			(193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+			(210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+			(216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+			(225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+			(250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+			(279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+			(295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+		],
+		Network::Testnet => &[
+			(546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+		],
+		Network::Regtest => &[],
+	};
+	checkpoints.iter().map(|(height, hash)| (*height, BlockHash::from_hex(hash).unwrap())).collect()
+}
+
+fn median_time_past(times: &VecDeque<u32>) -> u32 {
+	let mut sorted: Vec<u32> = times.iter().cloned().collect();
+	sorted.sort_unstable();
+	sorted[sorted.len() / 2]
+}
+
+/// Tracks the state required to check header chains against the full set of header consensus
+/// rules for a network, see the module-level documentation for more.
+pub(crate) struct ChainValidator {
+	network: Network,
+	checkpoints: Vec<(u32, BlockHash)>,
+	/// The last few headers on our best chain, so that we don't have to refetch them to calculate
+	/// median-time-past when connecting the next block.
+	recent_headers: VecDeque<BlockHeaderData>,
+	/// The first header of the current retarget window on our best chain, if we've connected it.
+	/// This saves fetching 2016 headers from the BlockSource every time we cross a retarget.
+	retarget_window_start: Option<BlockHeaderData>,
+}
+
+impl ChainValidator {
+	pub(crate) fn new(network: Network) -> Self {
+		Self { network, checkpoints: Vec::new(), recent_headers: VecDeque::new(), retarget_window_start: None }
+	}
+
+	pub(crate) fn network(&self) -> Network {
+		self.network
+	}
+
+	pub(crate) fn set_checkpoints(&mut self, checkpoints: Vec<(u32, BlockHash)>) {
+		self.checkpoints = checkpoints;
+	}
+
+	/// Informs the validator that the given header is now the tip of our best chain.
+	pub(crate) fn block_connected(&mut self, header: &BlockHeaderData) {
+		if header.height % RETARGET_INTERVAL == 0 {
+			self.retarget_window_start = Some(header.clone());
+		}
+		self.recent_headers.push_back(header.clone());
+		if self.recent_headers.len() > MEDIAN_TIME_SPAN {
+			self.recent_headers.pop_front();
+		}
+	}
+
+	/// Informs the validator that the given header is no longer part of our best chain.
+	pub(crate) fn block_disconnected(&mut self, header: &BlockHeaderData) {
+		if self.retarget_window_start.as_ref().map(|start| start.height >= header.height).unwrap_or(false) {
+			self.retarget_window_start = None;
+		}
+		if self.recent_headers.back().map(|recent| recent.height >= header.height).unwrap_or(false) {
+			self.recent_headers.pop_back();
+		}
+	}
+
+	/// Gets the header which new_header builds on, which must be either fork_point, one of
+	/// new_headers, or an ancestor of fork_point (which we may have to fetch from block_source).
+	async fn get_prev_header(&self, header: &BlockHeaderData, fork_point: &BlockHeaderData, new_headers: &[BlockHeaderData], block_source: &mut dyn BlockSource) -> Result<BlockHeaderData, BlockSourceRespErr> {
+		if header.height == 0 {
+			// Only the genesis block has no previous header, and we never validate it.
+			return Err(BlockSourceRespErr::BogusData);
+		}
+		let prev_height = header.height - 1;
+		if prev_height > fork_point.height {
+			return Ok(new_headers[(prev_height - fork_point.height - 1) as usize].clone());
+		}
+		if prev_height == fork_point.height {
+			return Ok(fork_point.clone());
+		}
+		let prev_hash = header.header.prev_blockhash;
+		if let Some(recent) = self.recent_headers.iter().find(|recent| recent.header.bitcoin_hash() == prev_hash) {
+			return Ok(recent.clone());
+		}
+		let prev_header = block_source.get_header(&prev_hash, Some(prev_height)).await?;
+		if prev_header.header.bitcoin_hash() != prev_hash || prev_header.height != prev_height {
+			return Err(BlockSourceRespErr::BogusData);
+		}
+		Ok(prev_header)
+	}
+
+	/// Gets the ancestor of new_headers at the given height, walking back from fork_point if it
+	/// isn't one we have handy.
+	async fn get_ancestor(&self, height: u32, fork_point: &BlockHeaderData, new_headers: &[BlockHeaderData], block_source: &mut dyn BlockSource) -> Result<BlockHeaderData, BlockSourceRespErr> {
+		if height > fork_point.height {
+			return Ok(new_headers[(height - fork_point.height - 1) as usize].clone());
+		}
+		if let Some(start) = &self.retarget_window_start {
+			// retarget_window_start is always on our best chain at or below fork_point as we
+			// forget it when it is disconnected.
+			if start.height == height {
+				return Ok(start.clone());
+			}
+		}
+		let mut header = fork_point.clone();
+		while header.height > height {
+			header = self.get_prev_header(&header, fork_point, new_headers, block_source).await?;
+		}
+		Ok(header)
+	}
+
+	/// Checks new_headers (which must be sorted by height and build on fork_point, having been
+	/// checked by check_builds_on) against the rules check_builds_on cannot check, as well as
+	/// against any configured checkpoints. old_tip is the tip of our best chain, which must also
+	/// descend from fork_point.
+	///
+	/// Returns NoResponse if a header's time is too far in the future, as it may become valid later.
+	pub(crate) async fn check_connected_headers(&self, fork_point: &BlockHeaderData, old_tip: &BlockHeaderData, new_headers: &[BlockHeaderData], block_source: &mut dyn BlockSource) -> Result<(), BlockSourceRespErr> {
+		// If our best chain includes a checkpoint, refuse any chain which forks off before it.
+		if self.checkpoints.iter().any(|(height, _)| *height > fork_point.height && *height <= old_tip.height) {
+			return Err(BlockSourceRespErr::BogusData);
+		}
+
+		let mut recent_times = VecDeque::with_capacity(MEDIAN_TIME_SPAN);
+		let mut ancestor = fork_point.clone();
+		loop {
+			recent_times.push_front(ancestor.header.time);
+			if recent_times.len() == MEDIAN_TIME_SPAN || ancestor.height == 0 { break; }
+			ancestor = self.get_prev_header(&ancestor, fork_point, new_headers, block_source).await?;
+		}
+
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+		let pow_limit_bits = pow_limit_bits(self.network);
+		let mut previous_header = fork_point;
+		for header in new_headers.iter() {
+			debug_assert_eq!(header.height, previous_header.height + 1);
+			if let Some((_, hash)) = self.checkpoints.iter().find(|(height, _)| *height == header.height) {
+				if *hash != header.header.bitcoin_hash() {
+					return Err(BlockSourceRespErr::BogusData);
+				}
+			}
+
+			if header.header.time <= median_time_past(&recent_times) {
+				return Err(BlockSourceRespErr::BogusData);
+			}
+			if header.header.time as u64 > now + MAX_FUTURE_BLOCK_TIME {
+				return Err(BlockSourceRespErr::NoResponse);
+			}
+
+			let expected_bits = if no_retargeting(self.network) {
+				previous_header.header.bits
+			} else if header.height % RETARGET_INTERVAL == 0 {
+				let window_start = self.get_ancestor(header.height - RETARGET_INTERVAL, fork_point, new_headers, block_source).await?;
+				calculate_next_work_required(previous_header.header.bits, window_start.header.time, previous_header.header.time, self.network)
+			} else if allows_min_difficulty_blocks(self.network) {
+				if header.header.time > previous_header.header.time.saturating_add(TESTNET_MIN_DIFFICULTY_SPACING) {
+					pow_limit_bits
+				} else {
+					// Walk back to the last block which was not mined under the min-difficulty
+					// exception (or the start of the retarget window) and use its difficulty.
+					let mut last_regular = previous_header.clone();
+					while last_regular.height % RETARGET_INTERVAL != 0 && last_regular.header.bits == pow_limit_bits {
+						last_regular = self.get_prev_header(&last_regular, fork_point, new_headers, block_source).await?;
+					}
+					last_regular.header.bits
+				}
+			} else {
+				previous_header.header.bits
+			};
+			if header.header.bits != expected_bits {
+				return Err(BlockSourceRespErr::BogusData);
+			}
+
+			if recent_times.len() == MEDIAN_TIME_SPAN {
+				recent_times.pop_front();
+			}
+			recent_times.push_back(header.header.time);
+			previous_header = header;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::blockdata::block::Block;
+
+	use std::collections::HashMap;
+	use std::future::Future;
+	use std::pin::Pin;
+
+	/// A BlockSource which serves the headers it was given, and nothing else.
+	struct HeaderSource(HashMap<BlockHash, BlockHeaderData>);
+	impl BlockSource for HeaderSource {
+		fn get_header<'a>(&'a mut self, header_hash: &'a BlockHash, _height_hint: Option<u32>) -> Pin<Box<dyn Future<Output = Result<BlockHeaderData, BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async move { self.0.get(header_hash).cloned().ok_or(BlockSourceRespErr::NoResponse) })
+		}
+		fn get_block<'a>(&'a mut self, _header_hash: &'a BlockHash) -> Pin<Box<dyn Future<Output = Result<Block, BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async { Err(BlockSourceRespErr::NoResponse) })
+		}
+		fn get_best_block<'a>(&'a mut self) -> Pin<Box<dyn Future<Output = Result<(BlockHash, Option<u32>), BlockSourceRespErr>> + 'a + Send>> {
+			Box::pin(async { Err(BlockSourceRespErr::NoResponse) })
+		}
+	}
+
+	/// Builds a header on top of prev (or a genesis header at height 0 if prev is None). We never
+	/// check proof-of-work here, so the nonce is only used to make forks distinct.
+	fn header_on(prev: Option<&BlockHeaderData>, time: u32, bits: u32, nonce: u32) -> BlockHeaderData {
+		BlockHeaderData {
+			chainwork: Uint256::from_u64(0).unwrap(),
+			height: prev.map(|prev| prev.height + 1).unwrap_or(0),
+			header: BlockHeader {
+				version: 1, prev_blockhash: prev.map(|prev| prev.header.bitcoin_hash()).unwrap_or(Default::default()),
+				merkle_root: Default::default(), time, bits, nonce,
+			},
+		}
+	}
+
+	/// Builds a chain of len headers starting at genesis, with a block every spacing seconds.
+	fn build_chain(len: u32, start_time: u32, spacing: u32, bits: u32) -> Vec<BlockHeaderData> {
+		let mut chain = vec![header_on(None, start_time, bits, 0)];
+		for i in 1..len {
+			let header = header_on(chain.last(), start_time + i * spacing, bits, 0);
+			chain.push(header);
+		}
+		chain
+	}
+
+	fn source_for(chain: &[BlockHeaderData]) -> HeaderSource {
+		HeaderSource(chain.iter().map(|header| (header.header.bitcoin_hash(), header.clone())).collect())
+	}
+
+	#[tokio::test]
+	async fn test_checkpoint_enforcement() {
+		let chain = build_chain(11, 1_500_000_000, 600, 0x207fffff);
+		let mut source = source_for(&chain);
+		let mut validator = ChainValidator::new(Network::Regtest);
+		validator.set_checkpoints(vec![(5, chain[5].header.bitcoin_hash())]);
+
+		// Extending our best chain past the checkpoint is fine.
+		let next = header_on(Some(&chain[10]), chain[10].header.time + 600, 0x207fffff, 0);
+		assert!(validator.check_connected_headers(&chain[10], &chain[10], &[next], &mut source).await.is_ok());
+
+		// But a fork from below the checkpoint is refused once our best chain includes it, even if
+		// it would otherwise be valid.
+		let fork = header_on(Some(&chain[3]), chain[3].header.time + 1, 0x207fffff, 1);
+		match validator.check_connected_headers(&chain[3], &chain[10], &[fork.clone()], &mut source).await {
+			Err(BlockSourceRespErr::BogusData) => {},
+			_ => panic!(),
+		}
+
+		// Before we reach the checkpoint, a chain including it is accepted as long as it has the
+		// checkpointed block at the checkpoint height.
+		assert!(validator.check_connected_headers(&chain[3], &chain[4], &[chain[4].clone(), chain[5].clone()], &mut source).await.is_ok());
+		let fork_2 = header_on(Some(&fork), fork.header.time + 600, 0x207fffff, 1);
+		match validator.check_connected_headers(&chain[3], &chain[3], &[fork, fork_2], &mut source).await {
+			Err(BlockSourceRespErr::BogusData) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_median_time_past() {
+		let chain = build_chain(11, 1_500_000_000, 600, 0x207fffff);
+		let mut source = source_for(&chain);
+		let validator = ChainValidator::new(Network::Regtest);
+
+		// The median of the last 11 blocks' times is that of block 5, so a new block must have a
+		// time after it, even though it may be before its immediate predecessor's.
+		let median = chain[5].header.time;
+		let at_median = header_on(Some(&chain[10]), median, 0x207fffff, 0);
+		match validator.check_connected_headers(&chain[10], &chain[10], &[at_median], &mut source).await {
+			Err(BlockSourceRespErr::BogusData) => {},
+			_ => panic!(),
+		}
+		let after_median = header_on(Some(&chain[10]), median + 1, 0x207fffff, 0);
+		assert!(validator.check_connected_headers(&chain[10], &chain[10], &[after_median.clone()], &mut source).await.is_ok());
+
+		// The window moves with each new header, including ones we are connecting in the same
+		// batch, making after_median the new median.
+		let next = header_on(Some(&after_median), median + 1, 0x207fffff, 0);
+		match validator.check_connected_headers(&chain[10], &chain[10], &[after_median, next], &mut source).await {
+			Err(BlockSourceRespErr::BogusData) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_future_timestamp() {
+		let chain = build_chain(11, 1_500_000_000, 600, 0x207fffff);
+		let mut source = source_for(&chain);
+		let validator = ChainValidator::new(Network::Regtest);
+
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+		let near_future = header_on(Some(&chain[10]), now + MAX_FUTURE_BLOCK_TIME as u32 - 60, 0x207fffff, 0);
+		assert!(validator.check_connected_headers(&chain[10], &chain[10], &[near_future], &mut source).await.is_ok());
+
+		// A header too far in the future may become valid later, so is NoResponse, not BogusData.
+		let far_future = header_on(Some(&chain[10]), now + MAX_FUTURE_BLOCK_TIME as u32 + 60, 0x207fffff, 0);
+		match validator.check_connected_headers(&chain[10], &chain[10], &[far_future], &mut source).await {
+			Err(BlockSourceRespErr::NoResponse) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_low_work_fork() {
+		// A mainnet chain which mined its first retarget window twice as fast as targeted, so the
+		// block at the retarget must be (about) twice as hard.
+		let chain = build_chain(RETARGET_INTERVAL, 1_500_000_000, 300, 0x1c0168fd);
+		let mut source = source_for(&chain);
+		let validator = ChainValidator::new(Network::Bitcoin);
+		let tip = &chain[RETARGET_INTERVAL as usize - 1];
+
+		let expected_bits = calculate_next_work_required(0x1c0168fd, chain[0].header.time, tip.header.time, Network::Bitcoin);
+		assert!(bits_to_target(expected_bits) < bits_to_target(0x1c0168fd));
+		let retarget = header_on(Some(tip), tip.header.time + 300, expected_bits, 0);
+		assert!(validator.check_connected_headers(tip, tip, &[retarget], &mut source).await.is_ok());
+
+		// A fork which keeps the old (easier) difficulty across the retarget is refused.
+		let low_work = header_on(Some(tip), tip.header.time + 300, 0x1c0168fd, 1);
+		match validator.check_connected_headers(tip, tip, &[low_work], &mut source).await {
+			Err(BlockSourceRespErr::BogusData) => {},
+			_ => panic!(),
+		}
+
+		// As is a fork which drops to the minimum difficulty outside of a retarget, which is only
+		// allowed on testnet.
+		let prev = &chain[100];
+		let min_difficulty = header_on(Some(prev), prev.header.time + 20 * 60 + 1, pow_limit_bits(Network::Bitcoin), 1);
+		match validator.check_connected_headers(prev, tip, &[min_difficulty], &mut source).await {
+			Err(BlockSourceRespErr::BogusData) => {},
+			_ => panic!(),
+		}
+	}
+
+	// Test vectors from Bitcoin Core's pow_tests.cpp
+	#[test]
+	fn test_retarget_calculation() {
+		// Blocks 30240 through 32255
+		assert_eq!(calculate_next_work_required(0x1d00ffff, 1261130161, 1262152739, Network::Bitcoin), 0x1d00d86a);
+		// Blocks 0 through 2015, which are limited by the proof-of-work limit
+		assert_eq!(calculate_next_work_required(0x1d00ffff, 1231006505, 1233061996, Network::Bitcoin), 0x1d00ffff);
+		// Blocks 66528 through 68543, which are limited by the 4x lower bound on the timespan
+		assert_eq!(calculate_next_work_required(0x1c05a3f4, 1279008237, 1279297671, Network::Bitcoin), 0x1c0168fd);
+		// Blocks 46368 through 48383, which are limited by the 4x upper bound on the timespan
+		assert_eq!(calculate_next_work_required(0x1c387f6f, 1263163443, 1269211443, Network::Bitcoin), 0x1d00e1fd);
+		// Regtest never retargets
+		assert_eq!(calculate_next_work_required(0x207fffff, 0, 1, Network::Regtest), 0x207fffff);
+	}
+
+	#[test]
+	fn test_compact_round_trip() {
+		for bits in [0x1d00ffff, 0x1d00d86a, 0x1c0168fd, 0x207fffff, 0x1b0404cb, 0x03123456].iter() {
+			assert_eq!(target_to_bits(&bits_to_target(*bits)), *bits);
+		}
+	}
+
+	#[test]
+	fn test_testnet_min_difficulty_transition() {
+		let header_at = |height, time, bits| BlockHeaderData {
+			chainwork: Uint256::from_u64(0).unwrap(), height,
+			header: BlockHeader { version: 0, prev_blockhash: Default::default(), merkle_root: Default::default(), time, bits, nonce: 0 },
+		};
+		let regular = header_at(1, 1000, 0x1c0168fd);
+		// A min-difficulty block may only follow a 20-minute gap on testnet
+		assert!(check_difficulty_transition(&header_at(2, 1000 + 20 * 60 + 1, 0x1d00ffff), &regular, Network::Testnet).is_ok());
+		assert!(check_difficulty_transition(&header_at(2, 1000 + 20 * 60, 0x1d00ffff), &regular, Network::Testnet).is_err());
+		assert!(check_difficulty_transition(&header_at(2, 1000 + 20 * 60 + 1, 0x1d00ffff), &regular, Network::Bitcoin).is_err());
+		// Targets above the network's proof-of-work limit are never valid
+		assert!(check_difficulty_transition(&header_at(2, 1001, 0x1d01ffff), &header_at(1, 1000, 0x1d01ffff), Network::Bitcoin).is_err());
+		assert!(check_difficulty_transition(&header_at(2, 1001, 0x207fffff), &header_at(1, 1000, 0x207fffff), Network::Regtest).is_ok());
+	}
+}
@@ -0,0 +1,578 @@
+//! A socket handling library for those who wish to use rust-lightning with native
+//! std::net::TcpStreams but can't (or don't want to) use Tokio.
+//!
+//! The high-level usage mirrors lightning-net-tokio: hand over a TcpStream and a reference to a
+//! PeerManager and the rest is handled, except for the
+//! [Event](../lightning/util/events/enum.Event.html) handling mechanism, see below.
+//!
+//! The PeerHandler must be an Arc, and must use the SocketDescriptor provided here as the
+//! PeerHandler's SocketDescriptor.
+//!
+//! Each connection is handled by two threads - one which blocks reading from the socket and one
+//! which blocks writing to it. Data passed to send_data is queued in a bounded buffer which the
+//! writer thread drains, so send_data never blocks. Once the buffer is full, send_data accepts
+//! only part of the data, reads are paused, and write_buffer_space_avail is called once the
+//! buffer has drained, as PeerManager expects.
+//!
+//! Three methods are exposed to register a new connection for handling, see their individual docs
+//! for more. All three take a
+//! [mpsc::SyncSender<()>](https://doc.rust-lang.org/std/sync/mpsc/struct.SyncSender.html) which is
+//! sent into every time something occurs which may result in lightning
+//! [Events](../lightning/util/events/enum.Event.html). The call site should, thus, look something
+//! like this:
+//! ```
+//! use bitcoin::secp256k1::key::PublicKey;
+//! use lightning::util::events::EventsProvider;
+//! use std::net::{SocketAddr, TcpStream};
+//! use std::sync::Arc;
+//! use std::sync::mpsc;
+//!
+//! // Define concrete types for our high-level objects:
+//! type TxBroadcaster = dyn lightning::chain::chaininterface::BroadcasterInterface;
+//! type FeeEstimator = dyn lightning::chain::chaininterface::FeeEstimator;
+//! type Logger = dyn lightning::util::logger::Logger;
+//! type ChainWatchInterface = dyn lightning::chain::chaininterface::ChainWatchInterface;
+//! type ChannelMonitor = lightning::ln::channelmonitor::SimpleManyChannelMonitor<lightning::chain::transaction::OutPoint, lightning::chain::keysinterface::InMemoryChannelKeys, Arc<TxBroadcaster>, Arc<FeeEstimator>, Arc<Logger>, Arc<ChainWatchInterface>>;
+//! type ChannelManager = lightning::ln::channelmanager::SimpleArcChannelManager<ChannelMonitor, TxBroadcaster, FeeEstimator, Logger>;
+//! type PeerManager = lightning::ln::peer_handler::SimpleArcPeerManager<lightning_net_std::SocketDescriptor, ChannelMonitor, TxBroadcaster, FeeEstimator, Logger>;
+//!
+//! // Connect to node with pubkey their_node_id at addr:
+//! fn connect_to_node(peer_manager: PeerManager, channel_monitor: Arc<ChannelMonitor>, channel_manager: ChannelManager, their_node_id: PublicKey, addr: SocketAddr) {
+//!     let (sender, receiver) = mpsc::sync_channel(1);
+//!     lightning_net_std::connect_outbound(peer_manager, sender, their_node_id, addr);
+//!     while let Ok(()) = receiver.recv() {
+//!         for _event in channel_manager.get_and_clear_pending_events().drain(..) {
+//!             // Handle the event!
+//!         }
+//!         for _event in channel_monitor.get_and_clear_pending_events().drain(..) {
+//!             // Handle the event!
+//!         }
+//!     }
+//! }
+//!
+//! // Begin reading from a newly accepted socket and talk to the peer:
+//! fn accept_socket(peer_manager: PeerManager, channel_monitor: Arc<ChannelMonitor>, channel_manager: ChannelManager, socket: TcpStream) {
+//!     let (sender, receiver) = mpsc::sync_channel(1);
+//!     lightning_net_std::setup_inbound(peer_manager, sender, socket);
+//!     while let Ok(()) = receiver.recv() {
+//!         for _event in channel_manager.get_and_clear_pending_events().drain(..) {
+//!             // Handle the event!
+//!         }
+//!         for _event in channel_monitor.get_and_clear_pending_events().drain(..) {
+//!             // Handle the event!
+//!         }
+//!     }
+//! }
+//! ```
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use lightning::ln::peer_handler;
+use lightning::ln::peer_handler::SocketDescriptor as LnSocketTrait;
+use lightning::ln::msgs::{ChannelMessageHandler, NetAddress};
+use lightning::util::logger::Logger;
+
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The most data we will queue for the writer thread before applying backpressure.
+const OUTBOUND_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// The most data we hand to a single write call, so that we can release the lock on our state
+/// and let send_data queue more in the meantime.
+const WRITE_CHUNK_SIZE: usize = 8192;
+
+/// Connection contains all our internal state for a connection - we hold a reference to it (in an
+/// Arc) in each SocketDescriptor we create as well as in the reader and writer threads.
+struct Connection {
+	state: Mutex<ConnectionState>,
+	/// Notified whenever anything in state changes which a thread may be waiting on.
+	state_changed: Condvar,
+	/// A handle to the socket which is only used to shut it down, waking up the reader and writer.
+	stream: TcpStream,
+	remote_addr: Option<NetAddress>,
+	event_notify: mpsc::SyncSender<()>,
+	id: u64,
+}
+
+struct ConnectionState {
+	/// Data passed to send_data which the writer thread has yet to write.
+	outbound_data: VecDeque<u8>,
+	/// Set when send_data could not queue all the data it was given, indicating that we must call
+	/// write_buffer_space_avail once the buffer has drained.
+	write_space_requested: bool,
+	read_paused: bool,
+	/// Set once rust-lightning told us to disconnect, either by returning an Err or by calling
+	/// SocketDescriptor::disconnect_socket, after which we must not call into the PeerManager
+	/// again.
+	rl_requested_disconnect: bool,
+	/// Set once the reader thread is exiting, telling the writer thread to exit as well.
+	shutting_down: bool,
+	/// The number of PeerManager calls currently being made by our threads. disconnect_socket
+	/// must wait for this to reach 0 before returning.
+	pm_calls_in_progress: usize,
+}
+
+impl Connection {
+	fn new(event_notify: mpsc::SyncSender<()>, stream: TcpStream) -> Result<Arc<Self>, ()> {
+		// Our threads block on the socket, so make sure it wasn't set non-blocking by the user.
+		stream.set_nonblocking(false).map_err(|_| ())?;
+		let _ = stream.set_nodelay(true);
+		Ok(Arc::new(Self {
+			state: Mutex::new(ConnectionState {
+				outbound_data: VecDeque::new(),
+				write_space_requested: false,
+				read_paused: false,
+				rl_requested_disconnect: false,
+				shutting_down: false,
+				pm_calls_in_progress: 0,
+			}),
+			state_changed: Condvar::new(),
+			// Loopback peers are likely forwarded by a local proxy, so don't group them by address
+			remote_addr: stream.peer_addr().ok()
+				.filter(|addr| !addr.ip().is_loopback() && !addr.ip().is_unspecified())
+				.map(NetAddress::from),
+			stream,
+			event_notify,
+			id: ID_COUNTER.fetch_add(1, Ordering::AcqRel),
+		}))
+	}
+
+	fn event_trigger(&self) {
+		// Ignore full errors as we just need the user to poll after this point, so if they haven't
+		// received the last send yet, it doesn't matter. If the user dropped the receiver they
+		// evidently don't care about events.
+		let _ = self.event_notify.try_send(());
+	}
+
+	/// Checks that we may call into the PeerManager, ie that rust-lightning hasn't told us to
+	/// disconnect, and if so notes that a call is in progress. Must be followed by end_pm_call.
+	fn start_pm_call(&self) -> bool {
+		let mut state = self.state.lock().unwrap();
+		if state.rl_requested_disconnect { return false; }
+		state.pm_calls_in_progress += 1;
+		true
+	}
+
+	fn end_pm_call(&self, mut state: MutexGuard<ConnectionState>) {
+		state.pm_calls_in_progress -= 1;
+		self.state_changed.notify_all();
+	}
+
+	/// Marks the connection as closed by rust-lightning and shuts down the socket, waking up both
+	/// threads.
+	fn close(&self, state: &mut MutexGuard<ConnectionState>) {
+		state.rl_requested_disconnect = true;
+		let _ = self.stream.shutdown(Shutdown::Both);
+		self.state_changed.notify_all();
+	}
+
+	fn run_writer<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, us: Arc<Self>, mut writer: TcpStream) {
+		let mut our_descriptor = SocketDescriptor::new(Arc::clone(&us));
+		let mut chunk = Vec::with_capacity(WRITE_CHUNK_SIZE);
+		loop {
+			{
+				let mut state = us.state.lock().unwrap();
+				while state.outbound_data.is_empty() && !state.shutting_down && !state.rl_requested_disconnect {
+					state = us.state_changed.wait(state).unwrap();
+				}
+				if state.shutting_down || state.rl_requested_disconnect { return; }
+				let len = std::cmp::min(state.outbound_data.len(), WRITE_CHUNK_SIZE);
+				chunk.clear();
+				chunk.extend(state.outbound_data.drain(..len));
+			}
+			if writer.write_all(&chunk).is_err() {
+				// Shutting down the socket will cause the reader thread to notice the connection
+				// is gone and inform the PeerManager.
+				let _ = us.stream.shutdown(Shutdown::Both);
+				return;
+			}
+
+			let space_avail = {
+				let mut state = us.state.lock().unwrap();
+				if state.write_space_requested && state.outbound_data.len() < OUTBOUND_BUFFER_LIMIT / 2 {
+					state.write_space_requested = false;
+					true
+				} else { false }
+			};
+			if space_avail {
+				if !us.start_pm_call() { return; }
+				let res = peer_manager.write_buffer_space_avail(&mut our_descriptor);
+				let mut state = us.state.lock().unwrap();
+				if res.is_err() {
+					us.close(&mut state);
+				}
+				us.end_pm_call(state);
+				us.event_trigger();
+			}
+		}
+	}
+
+	fn run_reader<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, us: Arc<Self>, mut reader: TcpStream, writer_thread: thread::JoinHandle<()>) {
+		let mut our_descriptor = SocketDescriptor::new(Arc::clone(&us));
+		// 8KB is nice and big but also should never cause any issues with stack overflowing.
+		let mut buf = [0; 8192];
+
+		// Whether the connection was closed by the peer (or the network), in which case we need
+		// to call peer_manager.socket_disconnected(). If rust-lightning told us to disconnect,
+		// either by returning an Err or by calling SocketDescriptor::disconnect_socket, it
+		// already knows we're disconnected.
+		let peer_disconnected = loop {
+			{
+				let mut state = us.state.lock().unwrap();
+				while state.read_paused && !state.rl_requested_disconnect {
+					state = us.state_changed.wait(state).unwrap();
+				}
+				if state.rl_requested_disconnect { break false; }
+			}
+			match reader.read(&mut buf) {
+				Ok(0) | Err(_) => break !us.state.lock().unwrap().rl_requested_disconnect,
+				Ok(len) => {
+					{
+						// We may have been paused while blocked reading, in which case we hold on
+						// to the data until reads are resumed.
+						let mut state = us.state.lock().unwrap();
+						while state.read_paused && !state.rl_requested_disconnect {
+							state = us.state_changed.wait(state).unwrap();
+						}
+						if state.rl_requested_disconnect { break false; }
+						state.pm_calls_in_progress += 1;
+					}
+					let res = peer_manager.read_event(&mut our_descriptor, &buf[0..len]);
+					let mut state = us.state.lock().unwrap();
+					match res {
+						Ok(pause_read) => {
+							if pause_read { state.read_paused = true; }
+						},
+						Err(_) => us.close(&mut state),
+					}
+					us.end_pm_call(state);
+					us.event_trigger();
+				},
+			}
+		};
+
+		{
+			let mut state = us.state.lock().unwrap();
+			state.shutting_down = true;
+			us.state_changed.notify_all();
+		}
+		let _ = us.stream.shutdown(Shutdown::Both);
+		// Make sure the writer thread won't call into the PeerManager again before we tell it the
+		// socket is gone.
+		let _ = writer_thread.join();
+		if peer_disconnected {
+			peer_manager.socket_disconnected(&our_descriptor);
+			us.event_trigger();
+		}
+	}
+
+	/// Spawns the reader and writer threads for a connection, returning the reader's handle.
+	fn spawn_threads<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, us: Arc<Self>, reader: TcpStream, writer: TcpStream) -> thread::JoinHandle<()> {
+		let writer_peer_manager = Arc::clone(&peer_manager);
+		let writer_us = Arc::clone(&us);
+		let writer_thread = thread::spawn(move || Self::run_writer(writer_peer_manager, writer_us, writer));
+		thread::spawn(move || Self::run_reader(peer_manager, us, reader, writer_thread))
+	}
+}
+
+/// Clones the stream for the reader and writer threads.
+fn split_stream(stream: &TcpStream) -> Option<(TcpStream, TcpStream)> {
+	Some((stream.try_clone().ok()?, stream.try_clone().ok()?))
+}
+
+/// Process incoming messages and feed outgoing messages on the provided socket generated by
+/// accepting an incoming connection.
+///
+/// Returns a handle to a thread which completes when the peer is disconnected, or None if the
+/// PeerManager refused the connection (in which case the socket is simply dropped). Processing
+/// happens in background threads, so the handle need not be joined.
+///
+/// See the module-level documentation for how to handle the event_notify mpsc::SyncSender.
+pub fn setup_inbound<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, event_notify: mpsc::SyncSender<()>, stream: TcpStream) -> Option<thread::JoinHandle<()>> {
+	let (reader, writer) = split_stream(&stream)?;
+	let us = Connection::new(event_notify, stream).ok()?;
+	if let Ok(_) = peer_manager.new_inbound_connection(SocketDescriptor::new(Arc::clone(&us))) {
+		Some(Connection::spawn_threads(peer_manager, us, reader, writer))
+	} else {
+		// Note that we will skip socket_disconnected here, in accordance with the PeerManager
+		// requirements.
+		None
+	}
+}
+
+/// Process incoming messages and feed outgoing messages on the provided socket generated by
+/// making an outbound connection which is expected to be accepted by a peer with the given
+/// public key.
+///
+/// Returns a handle to a thread which completes when the peer is disconnected, or None if the
+/// PeerManager refused the connection (in which case the socket is simply dropped). Processing
+/// happens in background threads, so the handle need not be joined.
+///
+/// See the module-level documentation for how to handle the event_notify mpsc::SyncSender.
+pub fn setup_outbound<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, event_notify: mpsc::SyncSender<()>, their_node_id: PublicKey, stream: TcpStream) -> Option<thread::JoinHandle<()>> {
+	let (reader, writer) = split_stream(&stream)?;
+	let us = Connection::new(event_notify, stream).ok()?;
+	if let Ok(initial_send) = peer_manager.new_outbound_connection(their_node_id, SocketDescriptor::new(Arc::clone(&us))) {
+		// The initial message always fits in our (empty) buffer.
+		let sent = SocketDescriptor::new(Arc::clone(&us)).send_data(&initial_send, true);
+		debug_assert_eq!(sent, initial_send.len());
+		Some(Connection::spawn_threads(peer_manager, us, reader, writer))
+	} else {
+		// Note that we will skip socket_disconnected here, in accordance with the PeerManager
+		// requirements.
+		None
+	}
+}
+
+/// Process incoming messages and feed outgoing messages on a new connection made to the given
+/// socket address which is expected to be accepted by a peer with the given public key.
+///
+/// Shorthand for TcpStream::connect_timeout(addr) followed by setup_outbound(). Blocks until the
+/// connection is established or fails.
+///
+/// See the module-level documentation for how to handle the event_notify mpsc::SyncSender.
+pub fn connect_outbound<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, event_notify: mpsc::SyncSender<()>, their_node_id: PublicKey, addr: SocketAddr) -> Option<thread::JoinHandle<()>> {
+	let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(10)).ok()?;
+	setup_outbound(peer_manager, event_notify, their_node_id, stream)
+}
+
+/// The SocketDescriptor used to refer to sockets by a PeerHandler. This is pub only as it is a
+/// type in the template of PeerHandler.
+pub struct SocketDescriptor {
+	conn: Arc<Connection>,
+	id: u64,
+}
+impl SocketDescriptor {
+	fn new(conn: Arc<Connection>) -> Self {
+		let id = conn.id;
+		Self { conn, id }
+	}
+}
+impl peer_handler::SocketDescriptor for SocketDescriptor {
+	fn send_data(&mut self, data: &[u8], resume_read: bool) -> usize {
+		let mut state = self.conn.state.lock().unwrap();
+		if state.rl_requested_disconnect || state.shutting_down {
+			return 0;
+		}
+		if resume_read && state.read_paused {
+			state.read_paused = false;
+			self.conn.state_changed.notify_all();
+		}
+		if data.is_empty() { return 0; }
+
+		let space = OUTBOUND_BUFFER_LIMIT.saturating_sub(state.outbound_data.len());
+		let queued_len = std::cmp::min(space, data.len());
+		state.outbound_data.extend(&data[..queued_len]);
+		if queued_len < data.len() {
+			// In accordance with the send_data() docs, we must call write_buffer_space_avail
+			// once there is room, and not read in the meantime.
+			state.write_space_requested = true;
+			state.read_paused = true;
+		}
+		if queued_len > 0 {
+			self.conn.state_changed.notify_all();
+		}
+		queued_len
+	}
+
+	fn disconnect_socket(&mut self) {
+		let mut state = self.conn.state.lock().unwrap();
+		self.conn.close(&mut state);
+		// We can't return until our threads are done calling into the PeerManager with this
+		// descriptor.
+		while state.pm_calls_in_progress != 0 {
+			state = self.conn.state_changed.wait(state).unwrap();
+		}
+	}
+
+	fn remote_addr(&self) -> Option<NetAddress> {
+		self.conn.remote_addr.clone()
+	}
+}
+impl Clone for SocketDescriptor {
+	fn clone(&self) -> Self {
+		Self {
+			conn: Arc::clone(&self.conn),
+			id: self.id,
+		}
+	}
+}
+impl Eq for SocketDescriptor {}
+impl PartialEq for SocketDescriptor {
+	fn eq(&self, o: &Self) -> bool {
+		self.id == o.id
+	}
+}
+impl Hash for SocketDescriptor {
+	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+		self.id.hash(state);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use lightning::ln::features::*;
+	use lightning::ln::msgs::*;
+	use lightning::ln::peer_handler::{IgnoringCustomMessageHandler, MessageHandler, PeerManager};
+	use lightning::util::events::*;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey, PublicKey};
+
+	use std::mem;
+	use std::sync::{Arc, Mutex};
+	use std::sync::mpsc;
+	use std::time::Duration;
+
+	pub struct TestLogger();
+	impl lightning::util::logger::Logger for TestLogger {
+		fn log(&self, record: &lightning::util::logger::Record) {
+			println!("{:<5} [{} : {}, {}] {}", record.level.to_string(), record.module_path, record.file, record.line, record.args);
+		}
+	}
+
+	struct MsgHandler{
+		expected_pubkey: PublicKey,
+		pubkey_connected: Mutex<mpsc::Sender<()>>,
+		pubkey_disconnected: Mutex<mpsc::Sender<()>>,
+		msg_events: Mutex<Vec<MessageSendEvent>>,
+	}
+	impl RoutingMessageHandler for MsgHandler {
+		fn handle_node_announcement(&self, _msg: &NodeAnnouncement) -> Result<bool, LightningError> { Ok(false) }
+		fn handle_channel_announcement(&self, _msg: &ChannelAnnouncement) -> Result<bool, LightningError> { Ok(false) }
+		fn handle_channel_update(&self, _msg: &ChannelUpdate) -> Result<bool, LightningError> { Ok(false) }
+		fn handle_htlc_fail_channel_update(&self, _update: &HTLCFailChannelUpdate) { }
+		fn get_next_channel_announcements(&self, _starting_point: u64, _batch_amount: u8) -> Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)> { Vec::new() }
+		fn get_next_node_announcements(&self, _starting_point: Option<&PublicKey>, _batch_amount: u8) -> Vec<NodeAnnouncement> { Vec::new() }
+		fn should_request_full_sync(&self, _node_id: &PublicKey) -> bool { false }
+	}
+	impl ChannelMessageHandler for MsgHandler {
+		fn handle_open_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &OpenChannel) {}
+		fn handle_accept_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &AcceptChannel) {}
+		fn handle_funding_created(&self, _their_node_id: &PublicKey, _msg: &FundingCreated) {}
+		fn handle_funding_signed(&self, _their_node_id: &PublicKey, _msg: &FundingSigned) {}
+		fn handle_funding_locked(&self, _their_node_id: &PublicKey, _msg: &FundingLocked) {}
+		fn handle_shutdown(&self, _their_node_id: &PublicKey, _msg: &Shutdown) {}
+		fn handle_closing_signed(&self, _their_node_id: &PublicKey, _msg: &ClosingSigned) {}
+		fn handle_update_add_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateAddHTLC) {}
+		fn handle_update_fulfill_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFulfillHTLC) {}
+		fn handle_update_fail_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFailHTLC) {}
+		fn handle_update_fail_malformed_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFailMalformedHTLC) {}
+		fn handle_commitment_signed(&self, _their_node_id: &PublicKey, _msg: &CommitmentSigned) {}
+		fn handle_revoke_and_ack(&self, _their_node_id: &PublicKey, _msg: &RevokeAndACK) {}
+		fn handle_update_fee(&self, _their_node_id: &PublicKey, _msg: &UpdateFee) {}
+		fn handle_announcement_signatures(&self, _their_node_id: &PublicKey, _msg: &AnnouncementSignatures) {}
+		fn peer_disconnected(&self, their_node_id: &PublicKey, _no_connection_possible: bool) {
+			if *their_node_id == self.expected_pubkey {
+				self.pubkey_disconnected.lock().unwrap().send(()).unwrap();
+			}
+		}
+		fn peer_connected(&self, their_node_id: &PublicKey, _msg: &Init) {
+			if *their_node_id == self.expected_pubkey {
+				self.pubkey_connected.lock().unwrap().send(()).unwrap();
+			}
+		}
+		fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &ChannelReestablish) {}
+		fn handle_error(&self, _their_node_id: &PublicKey, _msg: &ErrorMessage) {}
+	}
+	impl MessageSendEventsProvider for MsgHandler {
+		fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+			let mut ret = Vec::new();
+			mem::swap(&mut *self.msg_events.lock().unwrap(), &mut ret);
+			ret
+		}
+	}
+
+	#[test]
+	fn basic_connection_test() {
+		let secp_ctx = Secp256k1::new();
+		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
+		let b_key = SecretKey::from_slice(&[2; 32]).unwrap();
+		let a_pub = PublicKey::from_secret_key(&secp_ctx, &a_key);
+		let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
+
+		let (a_connected_sender, a_connected) = mpsc::channel();
+		let (a_disconnected_sender, a_disconnected) = mpsc::channel();
+		let a_handler = Arc::new(MsgHandler {
+			expected_pubkey: b_pub,
+			pubkey_connected: Mutex::new(a_connected_sender),
+			pubkey_disconnected: Mutex::new(a_disconnected_sender),
+			msg_events: Mutex::new(Vec::new()),
+		});
+		let a_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::clone(&a_handler),
+			route_handler: Arc::clone(&a_handler) as Arc<dyn RoutingMessageHandler>,
+			custom_message_handler: Arc::new(IgnoringCustomMessageHandler {}),
+		}, a_key.clone(), &[1; 32], Arc::new(TestLogger())));
+
+		let (b_connected_sender, b_connected) = mpsc::channel();
+		let (b_disconnected_sender, b_disconnected) = mpsc::channel();
+		let b_handler = Arc::new(MsgHandler {
+			expected_pubkey: a_pub,
+			pubkey_connected: Mutex::new(b_connected_sender),
+			pubkey_disconnected: Mutex::new(b_disconnected_sender),
+			msg_events: Mutex::new(Vec::new()),
+		});
+		let b_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::clone(&b_handler),
+			route_handler: Arc::clone(&b_handler) as Arc<dyn RoutingMessageHandler>,
+			custom_message_handler: Arc::new(IgnoringCustomMessageHandler {}),
+		}, b_key.clone(), &[2; 32], Arc::new(TestLogger())));
+
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let (sender, _receiver) = mpsc::sync_channel(1);
+		let a_thread = super::connect_outbound(Arc::clone(&a_manager), sender.clone(), b_pub, addr).unwrap();
+		let b_thread = super::setup_inbound(b_manager, sender, listener.accept().unwrap().0).unwrap();
+
+		a_connected.recv_timeout(Duration::from_secs(10)).unwrap();
+		b_connected.recv_timeout(Duration::from_secs(1)).unwrap();
+
+		a_handler.msg_events.lock().unwrap().push(MessageSendEvent::HandleError {
+			node_id: b_pub, action: ErrorAction::DisconnectPeer { msg: None }
+		});
+		assert!(a_disconnected.try_recv().is_err());
+		assert!(b_disconnected.try_recv().is_err());
+
+		a_manager.process_events();
+		a_disconnected.recv_timeout(Duration::from_secs(10)).unwrap();
+		b_disconnected.recv_timeout(Duration::from_secs(1)).unwrap();
+
+		a_thread.join().unwrap();
+		b_thread.join().unwrap();
+	}
+
+	#[test]
+	fn send_data_backpressure() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (_remote, _) = listener.accept().unwrap();
+		let (sender, _receiver) = mpsc::sync_channel(1);
+		let mut descriptor = super::SocketDescriptor::new(super::Connection::new(sender, stream).unwrap());
+
+		// With no writer thread draining the buffer, we accept data until it is full, then pause
+		// reads until rust-lightning resumes them.
+		let data = vec![0; super::OUTBOUND_BUFFER_LIMIT - 10];
+		assert_eq!(super::LnSocketTrait::send_data(&mut descriptor, &data, false), data.len());
+		assert_eq!(super::LnSocketTrait::send_data(&mut descriptor, &[0; 20], false), 10);
+		{
+			let state = descriptor.conn.state.lock().unwrap();
+			assert!(state.write_space_requested);
+			assert!(state.read_paused);
+		}
+		assert_eq!(super::LnSocketTrait::send_data(&mut descriptor, &[], true), 0);
+		assert!(!descriptor.conn.state.lock().unwrap().read_paused);
+
+		super::LnSocketTrait::disconnect_socket(&mut descriptor);
+		assert_eq!(super::LnSocketTrait::send_data(&mut descriptor, &[0; 20], false), 0);
+	}
+}
@@ -0,0 +1,377 @@
+//! A ConnectionManager which keeps us connected to a set of desired peers, reconnecting with
+//! exponential backoff whenever a connection fails or is lost.
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use tokio::sync::mpsc;
+
+use lightning::ln::channelmanager::ChannelDetails;
+use lightning::ln::msgs::{ChannelMessageHandler, NetAddress};
+use lightning::ln::peer_handler;
+use lightning::routing::network_graph::NetworkGraph;
+use lightning::util::logger::Logger;
+
+use super::{connect_stream, setup_outbound, SocketDescriptor, Socks5Proxy};
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The delay before our first reconnection attempt, which doubles with each failed attempt.
+const BASE_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The longest we will ever wait between reconnection attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(600);
+/// How often we check whether an outbound connection has completed its handshake.
+const HANDSHAKE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Gets the delay before the next connection attempt after the given number of consecutive failed
+/// attempts. The delay is picked uniformly from the upper half of the backoff window so that many
+/// peers lost at once (eg when our own connectivity drops) aren't all retried in lockstep.
+fn reconnect_delay(failed_attempts: u32) -> Duration {
+	let shift = std::cmp::min(failed_attempts.saturating_sub(1), 16);
+	let window_ms = std::cmp::min(BASE_RECONNECT_DELAY.as_millis() << shift, MAX_RECONNECT_DELAY.as_millis()) as u64;
+	// RandomState is randomly keyed, which is plenty of randomness for jitter.
+	let jitter = RandomState::new().build_hasher().finish() % (window_ms / 2 + 1);
+	Duration::from_millis(window_ms - window_ms / 2 + jitter)
+}
+
+/// The state of our connection to a peer tracked by a ConnectionManager.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerConnectionState {
+	/// The peer is connected and has completed the initial handshake (the connection may be
+	/// either inbound or outbound).
+	Connected,
+	/// We are currently attempting to connect to the peer.
+	Connecting,
+	/// We are not connected to the peer and will try again at next_attempt.
+	Disconnected {
+		/// The number of connection attempts which have failed since we were last connected.
+		failed_attempts: u32,
+		/// When the next connection attempt will be made.
+		next_attempt: Instant,
+	},
+	/// We don't know of any address we can connect to the peer on. We will look again (eg in case
+	/// the peer has since broadcast a node_announcement) at next_attempt.
+	NoKnownAddresses {
+		/// When we will next look for an address for the peer.
+		next_attempt: Instant,
+	},
+}
+
+struct PeerInfo {
+	/// Addresses provided via add_peer, which are tried before any from the NetworkGraph.
+	addresses: Vec<NetAddress>,
+	/// Whether the peer was added via add_peer, rather than only because we have a channel with
+	/// it.
+	added_explicitly: bool,
+	/// Whether the peer was in the channel list last passed to update_channel_peers.
+	has_channels: bool,
+	attempt_in_flight: bool,
+	no_known_addresses: bool,
+	failed_attempts: u32,
+	next_attempt: Instant,
+}
+
+impl PeerInfo {
+	fn new() -> Self {
+		Self {
+			addresses: Vec::new(),
+			added_explicitly: false,
+			has_channels: false,
+			attempt_in_flight: false,
+			no_known_addresses: false,
+			failed_attempts: 0,
+			next_attempt: Instant::now(),
+		}
+	}
+}
+
+/// Keeps us connected to a set of desired peers - those added with add_peer and, via
+/// update_channel_peers, any peer we have a channel with.
+///
+/// Connections are made from reconnect_tick, which should be called regularly (eg every second
+/// from a tokio::time::interval) from within a Tokio runtime. Each tick, we start a connection
+/// attempt to any desired peer which is not connected and whose backoff has expired, trying
+/// addresses passed to add_peer first and then any addresses from the peer's node_announcement in
+/// the given NetworkGraph (skipping onion addresses unless a proxy has been set with set_proxy). After each failed attempt or lost connection we
+/// wait for an exponentially increasing (and randomly jittered) delay of up to ten minutes before
+/// trying again.
+///
+/// Inbound connections from a desired peer are noticed on the next tick, after which we won't
+/// attempt to connect to it until it disconnects.
+pub struct ConnectionManager<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized> {
+	peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>,
+	event_notify: mpsc::Sender<()>,
+	proxy: Option<Socks5Proxy>,
+	peers: Arc<Mutex<HashMap<PublicKey, PeerInfo>>>,
+}
+
+impl<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized> ConnectionManager<CMH, L> {
+	/// Creates a new ConnectionManager which makes connections using the given PeerManager,
+	/// initially with no desired peers.
+	///
+	/// event_notify is handed to each connection, see the module-level documentation of this
+	/// crate for how to handle it.
+	pub fn new(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, event_notify: mpsc::Sender<()>) -> Self {
+		Self { peer_manager, event_notify, proxy: None, peers: Arc::new(Mutex::new(HashMap::new())) }
+	}
+
+	/// Sets a SOCKS5 proxy (eg Tor) to make connections via. Without one, onion addresses are
+	/// ignored.
+	pub fn set_proxy(&mut self, proxy: Socks5Proxy) {
+		self.proxy = Some(proxy);
+	}
+
+	/// Adds a peer we wish to stay connected to, along with any addresses it can be reached at in
+	/// addition to those in its node_announcement. If the peer is already tracked, the given
+	/// addresses replace any previously given ones.
+	pub fn add_peer(&self, their_node_id: PublicKey, addresses: Vec<NetAddress>) {
+		let mut peers = self.peers.lock().unwrap();
+		let peer = peers.entry(their_node_id).or_insert_with(PeerInfo::new);
+		peer.addresses = addresses;
+		peer.added_explicitly = true;
+	}
+
+	/// Stops trying to connect to a peer added with add_peer, unless we still have a channel with
+	/// it. Note that this does not disconnect the peer if it is currently connected.
+	pub fn remove_peer(&self, their_node_id: &PublicKey) {
+		let mut peers = self.peers.lock().unwrap();
+		let remove = match peers.get_mut(their_node_id) {
+			Some(peer) => {
+				peer.added_explicitly = false;
+				peer.addresses.clear();
+				!peer.has_channels
+			},
+			None => false,
+		};
+		if remove {
+			peers.remove(their_node_id);
+		}
+	}
+
+	/// Updates the set of peers we have channels with (and thus wish to remain connected to) to
+	/// the counterparties of the given channels, ie the result of ChannelManager::list_channels.
+	///
+	/// This should be called whenever channels are opened or closed, or simply before each call
+	/// to reconnect_tick.
+	pub fn update_channel_peers(&self, channels: &[ChannelDetails]) {
+		let mut peers = self.peers.lock().unwrap();
+		for peer in peers.values_mut() {
+			peer.has_channels = false;
+		}
+		for chan in channels {
+			peers.entry(chan.remote_network_id).or_insert_with(PeerInfo::new).has_channels = true;
+		}
+		peers.retain(|_, peer| peer.added_explicitly || peer.has_channels);
+	}
+
+	/// Gets the state of our connection to the given peer, or None if it isn't a desired peer.
+	pub fn peer_state(&self, their_node_id: &PublicKey) -> Option<PeerConnectionState> {
+		let connected_peers = self.peer_manager.get_peer_node_ids();
+		let peers = self.peers.lock().unwrap();
+		peers.get(their_node_id).map(|peer| Self::connection_state(peer, connected_peers.contains(their_node_id)))
+	}
+
+	/// Gets the state of our connection to each desired peer.
+	pub fn peer_states(&self) -> Vec<(PublicKey, PeerConnectionState)> {
+		let connected_peers = self.peer_manager.get_peer_node_ids();
+		let peers = self.peers.lock().unwrap();
+		peers.iter().map(|(node_id, peer)| (*node_id, Self::connection_state(peer, connected_peers.contains(node_id)))).collect()
+	}
+
+	fn connection_state(peer: &PeerInfo, connected: bool) -> PeerConnectionState {
+		if connected {
+			PeerConnectionState::Connected
+		} else if peer.attempt_in_flight {
+			PeerConnectionState::Connecting
+		} else if peer.no_known_addresses {
+			PeerConnectionState::NoKnownAddresses { next_attempt: peer.next_attempt }
+		} else {
+			PeerConnectionState::Disconnected { failed_attempts: peer.failed_attempts, next_attempt: peer.next_attempt }
+		}
+	}
+
+	/// Starts a connection attempt (via tokio::spawn) to each desired peer which is not connected
+	/// and is due for one. Addresses from the peer's node_announcement are only used if a
+	/// network_graph is provided.
+	///
+	/// Must be called from within a Tokio runtime.
+	pub fn reconnect_tick(&self, network_graph: Option<&NetworkGraph>) {
+		let connected_peers = self.peer_manager.get_peer_node_ids();
+		let now = Instant::now();
+		let mut peers = self.peers.lock().unwrap();
+		for (node_id, peer) in peers.iter_mut() {
+			if connected_peers.contains(node_id) {
+				peer.failed_attempts = 0;
+				continue;
+			}
+			if peer.attempt_in_flight || now < peer.next_attempt { continue; }
+
+			let mut addresses = peer.addresses.clone();
+			if let Some(announced_addresses) = network_graph.and_then(|graph| graph.get_addresses(node_id)) {
+				for address in announced_addresses {
+					if !addresses.contains(address) {
+						addresses.push(address.clone());
+					}
+				}
+			}
+			// We can only connect to onion addresses via a proxy.
+			if self.proxy.is_none() {
+				addresses.retain(|address| match address {
+					NetAddress::OnionV2 { .. } | NetAddress::OnionV3 { .. } => false,
+					NetAddress::IPv4 { .. } | NetAddress::IPv6 { .. } => true,
+				});
+			}
+			if addresses.is_empty() {
+				peer.no_known_addresses = true;
+				peer.failed_attempts += 1;
+				peer.next_attempt = now + reconnect_delay(peer.failed_attempts);
+				continue;
+			}
+
+			peer.no_known_addresses = false;
+			peer.attempt_in_flight = true;
+			let peer_manager = Arc::clone(&self.peer_manager);
+			let event_notify = self.event_notify.clone();
+			let proxy = self.proxy.clone();
+			let peers_ref = Arc::clone(&self.peers);
+			let their_node_id = *node_id;
+			tokio::spawn(async move {
+				for addr in addresses {
+					if let Ok(stream) = connect_stream(&addr, proxy.as_ref()).await {
+						// Wait for the connection to close before scheduling our next attempt,
+						// resetting our backoff once the handshake completes (rather than relying
+						// on reconnect_tick happening to run while we're connected).
+						let mut connection = Box::pin(setup_outbound(Arc::clone(&peer_manager), event_notify, their_node_id, stream));
+						let mut handshake_complete = false;
+						loop {
+							tokio::select! {
+								_ = &mut connection => break,
+								_ = tokio::time::delay_for(HANDSHAKE_CHECK_INTERVAL), if !handshake_complete => {
+									if peer_manager.get_peer_node_ids().contains(&their_node_id) {
+										handshake_complete = true;
+										if let Some(peer) = peers_ref.lock().unwrap().get_mut(&their_node_id) {
+											peer.failed_attempts = 0;
+										}
+									}
+								},
+							}
+						}
+						break;
+					}
+				}
+				// If we completed the handshake failed_attempts was reset above, so we start again
+				// from the shortest delay.
+				if let Some(peer) = peers_ref.lock().unwrap().get_mut(&their_node_id) {
+					peer.attempt_in_flight = false;
+					peer.failed_attempts += 1;
+					peer.next_attempt = Instant::now() + reconnect_delay(peer.failed_attempts);
+				}
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::make_node;
+
+	use lightning::ln::msgs::ErrorAction;
+	use lightning::util::events::MessageSendEvent;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	#[test]
+	fn test_reconnect_delay() {
+		for failed_attempts in 1..40 {
+			let delay = reconnect_delay(failed_attempts);
+			assert!(delay <= MAX_RECONNECT_DELAY);
+			assert!(delay >= std::cmp::min(BASE_RECONNECT_DELAY * 2u32.pow(std::cmp::min(failed_attempts - 1, 16)), MAX_RECONNECT_DELAY) / 2);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_reconnect_after_disconnect() {
+		let secp_ctx = Secp256k1::new();
+		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
+		let b_key = SecretKey::from_slice(&[2; 32]).unwrap();
+		let a_pub = PublicKey::from_secret_key(&secp_ctx, &a_key);
+		let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
+		let (a_handler, a_manager, mut a_connected, mut a_disconnected) = make_node(a_key, 1, b_pub);
+		let (_b_handler, b_manager, mut b_connected, mut b_disconnected) = make_node(b_key, 2, a_pub);
+
+		let (sender, _receiver) = mpsc::channel(2);
+		let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0".parse::<std::net::SocketAddr>().unwrap()).await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let b_sender = sender.clone();
+		tokio::spawn(async move {
+			loop {
+				let (stream, _) = listener.accept().await.unwrap();
+				tokio::spawn(crate::setup_inbound(Arc::clone(&b_manager), b_sender.clone(), stream));
+			}
+		});
+
+		// An address nothing is listening on, so that our first attempt fails.
+		let dead_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+		let connection_manager = ConnectionManager::new(Arc::clone(&a_manager), sender);
+		let unknown_pub = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[3; 32]).unwrap());
+		connection_manager.add_peer(unknown_pub, Vec::new());
+		connection_manager.add_peer(b_pub, vec![dead_addr.into()]);
+		connection_manager.reconnect_tick(None);
+		match connection_manager.peer_state(&unknown_pub) {
+			Some(PeerConnectionState::NoKnownAddresses { .. }) => {},
+			state => panic!("Unexpected state {:?}", state),
+		}
+		let wait_for_disconnected = || async {
+			tokio::time::timeout(Duration::from_secs(10), async {
+				loop {
+					if let Some(PeerConnectionState::Disconnected { failed_attempts, .. }) = connection_manager.peer_state(&b_pub) {
+						break failed_attempts;
+					}
+					tokio::time::delay_for(Duration::from_millis(10)).await;
+				}
+			}).await.unwrap()
+		};
+		assert_eq!(wait_for_disconnected().await, 1);
+
+		connection_manager.add_peer(b_pub, vec![addr.into()]);
+		tokio::time::timeout(Duration::from_secs(10), async {
+			loop {
+				connection_manager.reconnect_tick(None);
+				if let Ok(()) = a_connected.try_recv() { break; }
+				tokio::time::delay_for(Duration::from_millis(50)).await;
+			}
+		}).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(1), b_connected.recv()).await.unwrap();
+		assert_eq!(connection_manager.peer_state(&b_pub), Some(PeerConnectionState::Connected));
+
+		// Once the connection drops we should reconnect after a short delay. Even though
+		// reconnect_tick didn't run while we were connected, the successful connection reset our
+		// backoff, so only the lost connection counts as a failed attempt.
+		a_handler.msg_events.lock().unwrap().push(MessageSendEvent::HandleError {
+			node_id: b_pub, action: ErrorAction::DisconnectPeer { msg: None }
+		});
+		a_manager.process_events();
+		tokio::time::timeout(Duration::from_secs(10), a_disconnected.recv()).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(1), b_disconnected.recv()).await.unwrap();
+		assert_eq!(wait_for_disconnected().await, 1);
+
+		tokio::time::timeout(Duration::from_secs(10), async {
+			loop {
+				connection_manager.reconnect_tick(None);
+				if let Ok(()) = a_connected.try_recv() { break; }
+				tokio::time::delay_for(Duration::from_millis(50)).await;
+			}
+		}).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(1), b_connected.recv()).await.unwrap();
+		assert_eq!(connection_manager.peer_state(&b_pub), Some(PeerConnectionState::Connected));
+
+		// Once we no longer want the peer we stop tracking it.
+		connection_manager.remove_peer(&b_pub);
+		assert_eq!(connection_manager.peer_state(&b_pub), None);
+		assert_eq!(connection_manager.peer_states().len(), 1);
+	}
+}
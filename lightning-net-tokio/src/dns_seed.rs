@@ -0,0 +1,375 @@
+//! BOLT 10 DNS seed bootstrapping, allowing a new node with an empty NetworkGraph to find peers to
+//! connect to.
+//!
+//! A DNS seed serves SRV records for a set of nodes, each pointing at a "virtual hostname" of the
+//! form <bech32-encoded node_id>.<seed root>, which in turn resolves (via A/AAAA records) to the
+//! addresses the node can be reached at. Queries may be narrowed by prefixing the seed root with
+//! conditions, eg "a2.n10.<seed root>" for at most 10 nodes with IPv4 addresses.
+//!
+//! Resolution is done via a DNSSeedResolver, with UDPSeedResolver sending queries directly to a
+//! recursive DNS server (as the system resolver cannot be used for SRV lookups).
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use bech32::{FromBase32, ToBase32};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+
+use lightning::ln::msgs::NetAddress;
+use lightning::util::dns::{self, QueryIdSource, TYPE_A, TYPE_AAAA, TYPE_SRV};
+
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::time::Duration;
+
+/// Some well-known DNS seeds for Bitcoin mainnet.
+pub const MAINNET_DNS_SEEDS: &[&str] = &["nodes.lightning.directory", "lseed.bitcoinstats.com"];
+
+/// An SRV record, as returned by a DNSSeedResolver.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SrvRecord {
+	/// The priority of the target host, lower values being preferred.
+	pub priority: u16,
+	/// The relative weight of records with the same priority.
+	pub weight: u16,
+	/// The port the target host is listening on.
+	pub port: u16,
+	/// The target hostname, without a trailing '.'.
+	pub target: String,
+}
+
+/// A DNS resolver capable of the lookups required to bootstrap from a DNS seed.
+pub trait DNSSeedResolver: Send {
+	/// Looks up the SRV records for the given name.
+	fn resolve_srv<'a>(&'a mut self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<SrvRecord>, ()>> + 'a + Send>>;
+
+	/// Looks up the A records (if ipv4 is set) and AAAA records (if ipv6 is set) for the given name.
+	///
+	/// Should only return Err if none of the lookups succeeded.
+	fn resolve_host<'a>(&'a mut self, name: &'a str, ipv4: bool, ipv6: bool) -> Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, ()>> + 'a + Send>>;
+}
+
+/// The BOLT 10 query conditions which may be used to narrow the set of nodes a seed returns.
+#[derive(Clone, Default)]
+pub struct SeedQueryConditions {
+	/// A bitfield of the BOLT 7 address types we want nodes to have, where bit n is set for address
+	/// type n (ie 2 for IPv4, 4 for IPv6, 8 for Tor v2 and 16 for Tor v3). The seed's default is 6,
+	/// ie IPv4 or IPv6.
+	pub address_types: Option<u8>,
+	/// Only look up a specific node.
+	pub node_id: Option<PublicKey>,
+	/// The maximum number of nodes to return.
+	pub num_results: Option<u8>,
+}
+
+/// Gets the name to query SRV records for given a seed root and query conditions. We always ask
+/// for realm 0 (Bitcoin), explicitly so that seeds serving several realms don't have to guess.
+pub fn seed_query_name(seed_root: &str, conditions: &SeedQueryConditions) -> String {
+	let mut name = "r0.".to_string();
+	if let Some(node_id) = &conditions.node_id {
+		name += &format!("l{}.", encode_node_id(node_id));
+	}
+	if let Some(address_types) = conditions.address_types {
+		name += &format!("a{}.", address_types);
+	}
+	if let Some(num_results) = conditions.num_results {
+		name += &format!("n{}.", num_results);
+	}
+	name + seed_root
+}
+
+/// Encodes a node_id as it appears in a seed's virtual hostnames, ie bech32 with the "ln" prefix.
+fn encode_node_id(node_id: &PublicKey) -> String {
+	bech32::encode("ln", node_id.serialize().to_base32()).unwrap()
+}
+
+/// Decodes the node_id from a virtual hostname returned by a seed.
+fn decode_virtual_hostname(hostname: &str) -> Option<PublicKey> {
+	let label = hostname.split('.').next()?;
+	let (hrp, data) = bech32::decode(label).ok()?;
+	if hrp != "ln" { return None; }
+	let node_id = Vec::<u8>::from_base32(&data).ok()?;
+	PublicKey::from_slice(&node_id).ok()
+}
+
+/// Queries the given DNS seed for nodes matching the given conditions, returning each node_id along
+/// with an address it can be reached at (possibly multiple times for nodes with several addresses),
+/// ready to be passed to connect_outbound.
+///
+/// SRV records which do not point to a valid virtual hostname, or whose hostname fails to resolve,
+/// are skipped. Returns Err only if the SRV lookup itself fails.
+pub async fn query_dns_seed<R: DNSSeedResolver + ?Sized>(resolver: &mut R, seed_root: &str, conditions: &SeedQueryConditions) -> Result<Vec<(PublicKey, NetAddress)>, ()> {
+	let mut records = resolver.resolve_srv(&seed_query_name(seed_root, conditions)).await?;
+	records.sort_by_key(|record| record.priority);
+	// Only look up the address families we asked the seed for, defaulting to both as it does.
+	let address_types = conditions.address_types.unwrap_or(6);
+	let (want_ipv4, want_ipv6) = (address_types & 2 != 0, address_types & 4 != 0);
+	if !want_ipv4 && !want_ipv6 { return Ok(Vec::new()); }
+
+	let mut nodes = Vec::new();
+	for record in records {
+		let node_id = match decode_virtual_hostname(&record.target) {
+			Some(node_id) => node_id,
+			None => continue,
+		};
+		let addrs = match resolver.resolve_host(&record.target, want_ipv4, want_ipv6).await {
+			Ok(addrs) => addrs,
+			Err(()) => continue,
+		};
+		for addr in addrs {
+			nodes.push((node_id, SocketAddr::new(addr, record.port).into()));
+		}
+	}
+	Ok(nodes)
+}
+
+/// The UDP payload size we advertise via EDNS, large enough for most seeds' responses.
+const EDNS_PAYLOAD_SIZE: u16 = 4096;
+
+/// A DNSSeedResolver which sends queries to a given recursive DNS server, over UDP and falling back
+/// to TCP if a response is truncated.
+pub struct UDPSeedResolver {
+	server: SocketAddr,
+	query_ids: QueryIdSource,
+}
+
+impl UDPSeedResolver {
+	/// Creates a new UDPSeedResolver which queries the recursive resolver at the given address (eg
+	/// your local resolver or a public one at port 53).
+	pub fn new(server: SocketAddr) -> Self {
+		Self { server, query_ids: QueryIdSource::new() }
+	}
+
+	async fn query_udp(&self, query: &[u8]) -> Result<(Vec<u8>, dns::Response), ()> {
+		let local_addr: SocketAddr = if self.server.is_ipv4() {
+			(Ipv4Addr::UNSPECIFIED, 0).into()
+		} else {
+			(Ipv6Addr::UNSPECIFIED, 0).into()
+		};
+		let mut socket = UdpSocket::bind(local_addr).await.map_err(|_| ())?;
+		socket.connect(self.server).await.map_err(|_| ())?;
+		socket.send(query).await.map_err(|_| ())?;
+		let mut buf = vec![0; EDNS_PAYLOAD_SIZE as usize];
+		// Ignore (a few) datagrams which don't match our query, eg late responses to old queries
+		for _ in 0..4 {
+			let len = socket.recv(&mut buf).await.map_err(|_| ())?;
+			if let Ok(parsed) = dns::parse_response(query, &buf[..len]) {
+				buf.truncate(len);
+				return Ok((buf, parsed));
+			}
+		}
+		Err(())
+	}
+
+	async fn query_tcp(&self, query: &[u8]) -> Result<(Vec<u8>, dns::Response), ()> {
+		let mut stream = TcpStream::connect(&self.server).await.map_err(|_| ())?;
+		let mut msg = Vec::with_capacity(query.len() + 2);
+		msg.extend_from_slice(&(query.len() as u16).to_be_bytes());
+		msg.extend_from_slice(query);
+		stream.write_all(&msg).await.map_err(|_| ())?;
+		let mut len = [0; 2];
+		stream.read_exact(&mut len).await.map_err(|_| ())?;
+		let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+		stream.read_exact(&mut buf).await.map_err(|_| ())?;
+		let parsed = dns::parse_response(query, &buf)?;
+		Ok((buf, parsed))
+	}
+
+	/// Sends a query for the given name and type, returning the record data of each answer of that
+	/// type along with the full response (which is needed to decompress names in the data).
+	async fn query(&mut self, name: &str, qtype: u16) -> Result<(Vec<u8>, Vec<(usize, usize)>), ()> {
+		let query = dns::build_query(self.query_ids.next_id(), name, qtype, EDNS_PAYLOAD_SIZE, false).ok_or(())?;
+		let (resp, parsed) = match time::timeout(Duration::from_secs(5), self.query_udp(&query)).await {
+			Ok(Ok(res)) => res,
+			_ => return Err(()),
+		};
+		if !parsed.truncated { return Ok((resp, parsed.answers)); }
+		let (resp, parsed) = match time::timeout(Duration::from_secs(5), self.query_tcp(&query)).await {
+			Ok(Ok(res)) => res,
+			_ => return Err(()),
+		};
+		Ok((resp, parsed.answers))
+	}
+
+	async fn query_addrs(&mut self, name: &str, qtype: u16) -> Result<Vec<IpAddr>, ()> {
+		let (resp, answers) = self.query(name, qtype).await?;
+		let mut addrs = Vec::with_capacity(answers.len());
+		for (start, len) in answers {
+			let data = &resp[start..start + len];
+			match (qtype, len) {
+				(TYPE_A, 4) => {
+					let mut addr = [0; 4];
+					addr.copy_from_slice(data);
+					addrs.push(IpAddr::V4(addr.into()));
+				},
+				(TYPE_AAAA, 16) => {
+					let mut addr = [0; 16];
+					addr.copy_from_slice(data);
+					addrs.push(IpAddr::V6(addr.into()));
+				},
+				_ => return Err(()),
+			}
+		}
+		Ok(addrs)
+	}
+}
+
+impl DNSSeedResolver for UDPSeedResolver {
+	fn resolve_srv<'a>(&'a mut self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<SrvRecord>, ()>> + 'a + Send>> {
+		Box::pin(async move {
+			let (resp, answers) = self.query(name, TYPE_SRV).await?;
+			let mut records = Vec::with_capacity(answers.len());
+			for (start, len) in answers {
+				if len < 7 { return Err(()); }
+				let field = |pos: usize| ((resp[start + pos] as u16) << 8) | resp[start + pos + 1] as u16;
+				let (target, _) = dns::read_name(&resp, start + 6)?;
+				records.push(SrvRecord { priority: field(0), weight: field(2), port: field(4), target });
+			}
+			Ok(records)
+		})
+	}
+
+	fn resolve_host<'a>(&'a mut self, name: &'a str, ipv4: bool, ipv6: bool) -> Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, ()>> + 'a + Send>> {
+		Box::pin(async move {
+			let v4_res = if ipv4 { Some(self.query_addrs(name, TYPE_A).await) } else { None };
+			let v6_res = if ipv6 { Some(self.query_addrs(name, TYPE_AAAA).await) } else { None };
+			// Many hosts only have one kind of address, and some servers fail rather than returning
+			// an empty answer for the other, so only fail if none of our queries succeeded.
+			let mut addrs = Vec::new();
+			let mut any_succeeded = false;
+			for res in v4_res.into_iter().chain(v6_res.into_iter()) {
+				if let Ok(res) = res {
+					addrs.extend(res);
+					any_succeeded = true;
+				}
+			}
+			if any_succeeded { Ok(addrs) } else { Err(()) }
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use std::collections::HashMap;
+
+	/// A DNSSeedResolver which answers from fixed maps, recording the SRV names queried.
+	struct FakeResolver {
+		srv: HashMap<String, Vec<SrvRecord>>,
+		hosts: HashMap<String, Vec<IpAddr>>,
+		srv_queries: Vec<String>,
+		host_queries: Vec<(String, bool, bool)>,
+	}
+	impl DNSSeedResolver for FakeResolver {
+		fn resolve_srv<'a>(&'a mut self, name: &'a str) -> Pin<Box<dyn Future<Output = Result<Vec<SrvRecord>, ()>> + 'a + Send>> {
+			Box::pin(async move {
+				self.srv_queries.push(name.to_string());
+				self.srv.get(name).cloned().ok_or(())
+			})
+		}
+		fn resolve_host<'a>(&'a mut self, name: &'a str, ipv4: bool, ipv6: bool) -> Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, ()>> + 'a + Send>> {
+			Box::pin(async move {
+				self.host_queries.push((name.to_string(), ipv4, ipv6));
+				self.hosts.get(name).cloned().ok_or(())
+			})
+		}
+	}
+
+	#[tokio::test]
+	async fn test_query_dns_seed() {
+		let secp_ctx = Secp256k1::new();
+		let node_a = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[1; 32]).unwrap());
+		let node_b = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let host_a = format!("{}.seed.example.com", encode_node_id(&node_a));
+		let host_b = format!("{}.seed.example.com", encode_node_id(&node_b));
+		assert!(host_a.starts_with("ln1"));
+		assert_eq!(decode_virtual_hostname(&host_a), Some(node_a));
+		assert_eq!(decode_virtual_hostname("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4.seed.example.com"), None);
+
+		let mut resolver = FakeResolver { srv: HashMap::new(), hosts: HashMap::new(), srv_queries: Vec::new(), host_queries: Vec::new() };
+		resolver.srv.insert("r0.a2.n3.seed.example.com".to_string(), vec![
+			SrvRecord { priority: 10, weight: 1, port: 9736, target: host_b.clone() },
+			SrvRecord { priority: 0, weight: 1, port: 9735, target: host_a.clone() },
+			SrvRecord { priority: 0, weight: 1, port: 9735, target: "garbage.seed.example.com".to_string() },
+		]);
+		resolver.hosts.insert(host_a, vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+		resolver.hosts.insert(host_b, vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]);
+
+		let conditions = SeedQueryConditions { address_types: Some(2), node_id: None, num_results: Some(3) };
+		let nodes = query_dns_seed(&mut resolver, "seed.example.com", &conditions).await.unwrap();
+		assert_eq!(nodes, vec![
+			(node_a, NetAddress::IPv4 { addr: [10, 0, 0, 1], port: 9735 }),
+			(node_a, NetAddress::IPv6 { addr: Ipv6Addr::LOCALHOST.octets(), port: 9735 }),
+			(node_b, NetAddress::IPv4 { addr: [10, 0, 0, 2], port: 9736 }),
+		]);
+		// Only IPv4 addresses were asked for, so we shouldn't have looked up AAAA records.
+		assert_eq!(resolver.host_queries.len(), 2);
+		assert!(resolver.host_queries.iter().all(|&(_, ipv4, ipv6)| ipv4 && !ipv6));
+
+		let conditions = SeedQueryConditions { node_id: Some(node_a), ..Default::default() };
+		assert!(query_dns_seed(&mut resolver, "seed.example.com", &conditions).await.is_err());
+		assert_eq!(resolver.srv_queries[1], format!("r0.l{}.seed.example.com", encode_node_id(&node_a)));
+	}
+
+	#[test]
+	fn test_parse_srv_response() {
+		let query = dns::build_query(42, "seed.example.com", TYPE_SRV, EDNS_PAYLOAD_SIZE, false).unwrap();
+		let mut resp = query[..query.len() - 11].to_vec();
+		resp[2] = 0x81;
+		resp[3] = 0x80;
+		resp[7] = 1;
+		resp[11] = 0;
+		// An SRV answer whose owner name and target both point back into the question.
+		resp.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0, 13]);
+		resp.extend_from_slice(&[0, 1, 0, 2, 0x26, 0x07, 4]);
+		resp.extend_from_slice(b"node");
+		resp.extend_from_slice(&[0xc0, 12]);
+
+		let parsed = dns::parse_response(&query, &resp).unwrap();
+		assert!(!parsed.truncated);
+		assert_eq!(parsed.answers.len(), 1);
+		let (start, len) = parsed.answers[0];
+		assert_eq!(len, 13);
+		assert_eq!(dns::read_name(&resp, start + 6).unwrap(), ("node.seed.example.com".to_string(), resp.len()));
+
+		// Responses to a query with another ID or for another name are rejected.
+		let other_id = dns::build_query(43, "seed.example.com", TYPE_SRV, EDNS_PAYLOAD_SIZE, false).unwrap();
+		assert!(dns::parse_response(&other_id, &resp).is_err());
+		let other_name = dns::build_query(42, "seed.example.org", TYPE_SRV, EDNS_PAYLOAD_SIZE, false).unwrap();
+		assert!(dns::parse_response(&other_name, &resp).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_resolve_host_aaaa_failure() {
+		let mut server = UdpSocket::bind::<SocketAddr>((Ipv4Addr::LOCALHOST, 0).into()).await.unwrap();
+		let server_addr = server.local_addr().unwrap();
+		let server_thread = tokio::spawn(async move {
+			let mut buf = [0; 512];
+			for _ in 0..2 {
+				let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+				// Echo the question back (dropping the OPT record), answering A queries with a
+				// single address and failing AAAA queries with SERVFAIL.
+				let mut resp = buf[..len - 11].to_vec();
+				resp[2] = 0x81;
+				resp[11] = 0;
+				if resp[resp.len() - 3] == TYPE_A as u8 {
+					resp[3] = 0x80;
+					resp[7] = 1;
+					resp.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+				} else {
+					resp[3] = 0x82;
+				}
+				server.send_to(&resp, &peer).await.unwrap();
+			}
+		});
+
+		let mut resolver = UDPSeedResolver::new(server_addr);
+		assert_eq!(resolver.resolve_host("node.seed.example.com", true, true).await,
+			Ok(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]));
+		server_thread.await.unwrap();
+	}
+}
@@ -8,8 +8,8 @@
 //! The PeerHandler, due to the fire-and-forget nature of this logic, must be an Arc, and must use
 //! the SocketDescriptor provided here as the PeerHandler's SocketDescriptor.
 //!
-//! Three methods are exposed to register a new connection for handling in tokio::spawn calls, see
-//! their individual docs for more. All three take a
+//! Four methods are exposed to register a new connection for handling in tokio::spawn calls, see
+//! their individual docs for more. All four take a
 //! [mpsc::Sender<()>](../tokio/sync/mpsc/struct.Sender.html) which is sent into every time
 //! something occurs which may result in lightning [Events](../lightning/util/events/enum.Event.html).
 //! The call site should, thus, look something like this:
@@ -33,7 +33,7 @@
 //! // Connect to node with pubkey their_node_id at addr:
 //! async fn connect_to_node(peer_manager: PeerManager, channel_monitor: Arc<ChannelMonitor>, channel_manager: ChannelManager, their_node_id: PublicKey, addr: SocketAddr) {
 //!     let (sender, mut receiver) = mpsc::channel(2);
-//!     lightning_net_tokio::connect_outbound(peer_manager, sender, their_node_id, addr).await;
+//!     let _ = lightning_net_tokio::connect_outbound(peer_manager, sender, their_node_id, addr).await;
 //!     loop {
 //!         receiver.recv().await;
 //!         for _event in channel_manager.get_and_clear_pending_events().drain(..) {
@@ -60,6 +60,15 @@
 //!     }
 //! }
 //! ```
+//!
+//! To stay connected to a set of peers (eg everyone we have a channel with), reconnecting with
+//! backoff whenever a connection is lost, see [ConnectionManager](connection_manager/struct.ConnectionManager.html).
+//!
+//! Peers which are only reachable via Tor (or which you wish to reach via Tor) can be connected to
+//! through a SOCKS5 proxy, see [Socks5Proxy](socks5/struct.Socks5Proxy.html).
+//!
+//! A new node which doesn't yet know of any peers can find some via BOLT 10 DNS seeds, see
+//! [query_dns_seed](dns_seed/fn.query_dns_seed.html).
 
 use bitcoin::secp256k1::key::PublicKey;
 
@@ -70,16 +79,20 @@ use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use lightning::ln::peer_handler;
 use lightning::ln::peer_handler::SocketDescriptor as LnSocketTrait;
-use lightning::ln::msgs::ChannelMessageHandler;
+use lightning::ln::msgs::{ChannelMessageHandler, NetAddress};
 use lightning::util::logger::Logger;
 
 use std::{task, thread};
-use std::net::SocketAddr;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use std::hash::Hash;
 
+pub mod connection_manager;
+pub mod dns_seed;
+pub mod socks5;
+pub use socks5::Socks5Proxy;
+
 static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 /// Connection contains all our internal state for a connection - we hold a reference to the
@@ -111,6 +124,7 @@ struct Connection {
 	block_disconnect_socket: bool,
 	read_paused: bool,
 	rl_requested_disconnect: bool,
+	remote_addr: Option<NetAddress>,
 	id: u64,
 }
 impl Connection {
@@ -215,12 +229,16 @@ impl Connection {
 		// we shove a value into the channel which comes after we've reset the read_paused bool to
 		// false.
 		let (read_waker, read_receiver) = mpsc::channel(1);
+		// Loopback peers are likely forwarded by a local proxy, so don't group them by address
+		let remote_addr = stream.peer_addr().ok()
+			.filter(|addr| !addr.ip().is_loopback() && !addr.ip().is_unspecified())
+			.map(NetAddress::from);
 		let (reader, writer) = io::split(stream);
 
 		(reader, write_receiver, read_receiver,
 		Arc::new(Mutex::new(Self {
 			writer: Some(writer), event_notify, write_avail, read_waker, read_paused: false,
-			block_disconnect_socket: false, rl_requested_disconnect: false,
+			block_disconnect_socket: false, rl_requested_disconnect: false, remote_addr,
 			id: ID_COUNTER.fetch_add(1, Ordering::AcqRel)
 		})))
 	}
@@ -329,11 +347,42 @@ pub fn setup_outbound<CMH: ChannelMessageHandler + 'static, L: Logger + 'static
 	}
 }
 
+/// The reason connect_outbound or connect_outbound_via_proxy failed to make a connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectError {
+	/// The address is an onion address, which can only be reached via a SOCKS5 proxy, but no proxy
+	/// was configured.
+	NoProxyForOnion,
+	/// We (or the proxy, on our behalf) failed to connect to the address before timing out.
+	ConnectionFailed,
+}
+
+/// Connects to the given address, via the given proxy if it should be used for the address.
+async fn connect_stream(addr: &NetAddress, proxy: Option<&Socks5Proxy>) -> Result<TcpStream, ConnectError> {
+	match proxy {
+		// Connections via Tor can take a while to establish, so we're more patient here.
+		Some(proxy) if proxy.should_proxy(addr) => {
+			if let Ok(Ok(stream)) = time::timeout(Duration::from_secs(30), proxy.connect(addr)).await {
+				Ok(stream)
+			} else { Err(ConnectError::ConnectionFailed) }
+		},
+		_ => {
+			let addr = socks5::socket_addr(addr).ok_or(ConnectError::NoProxyForOnion)?;
+			if let Ok(Ok(stream)) = time::timeout(Duration::from_secs(10), TcpStream::connect(&addr)).await {
+				Ok(stream)
+			} else { Err(ConnectError::ConnectionFailed) }
+		},
+	}
+}
+
 /// Process incoming messages and feed outgoing messages on a new connection made to the given
-/// socket address which is expected to be accepted by a peer with the given public key (by
-/// scheduling futures with tokio::spawn).
+/// address which is expected to be accepted by a peer with the given public key (by scheduling
+/// futures with tokio::spawn).
 ///
-/// Shorthand for TcpStream::connect(addr) with a timeout followed by setup_outbound().
+/// Shorthand for TcpStream::connect(addr) with a timeout followed by setup_outbound(). addr may be
+/// a std::net::SocketAddr or an IPv4/IPv6 NetAddress - onion addresses can only be reached via a
+/// proxy (see connect_outbound_via_proxy), and ConnectError::NoProxyForOnion is always returned
+/// for them here.
 ///
 /// Returns a future (as the fn is async) which needs to be polled to complete the connection and
 /// connection setup. That future then returns a future which will complete when the peer is
@@ -342,10 +391,19 @@ pub fn setup_outbound<CMH: ChannelMessageHandler + 'static, L: Logger + 'static
 /// make progress.
 ///
 /// See the module-level documentation for how to handle the event_notify mpsc::Sender.
-pub async fn connect_outbound<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, event_notify: mpsc::Sender<()>, their_node_id: PublicKey, addr: SocketAddr) -> Option<impl std::future::Future<Output=()>> {
-	if let Ok(Ok(stream)) = time::timeout(Duration::from_secs(10), TcpStream::connect(&addr)).await {
-		Some(setup_outbound(peer_manager, event_notify, their_node_id, stream))
-	} else { None }
+pub async fn connect_outbound<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized, A: Into<NetAddress>>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, event_notify: mpsc::Sender<()>, their_node_id: PublicKey, addr: A) -> Result<impl std::future::Future<Output=()>, ConnectError> {
+	let stream = connect_stream(&addr.into(), None).await?;
+	Ok(setup_outbound(peer_manager, event_notify, their_node_id, stream))
+}
+
+/// Identical to connect_outbound, except that the connection is made via the given SOCKS5 proxy
+/// (eg Tor) if the address is an onion address or the proxy is configured to be used for all
+/// connections (see Socks5Proxy::set_proxy_clearnet).
+///
+/// See the module-level documentation for how to handle the event_notify mpsc::Sender.
+pub async fn connect_outbound_via_proxy<CMH: ChannelMessageHandler + 'static, L: Logger + 'static + ?Sized, A: Into<NetAddress>>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, Arc<CMH>, Arc<L>>>, event_notify: mpsc::Sender<()>, their_node_id: PublicKey, addr: A, proxy: &Socks5Proxy) -> Result<impl std::future::Future<Output=()>, ConnectError> {
+	let stream = connect_stream(&addr.into(), Some(proxy)).await?;
+	Ok(setup_outbound(peer_manager, event_notify, their_node_id, stream))
 }
 
 const SOCK_WAKER_VTABLE: task::RawWakerVTable =
@@ -383,11 +441,15 @@ fn write_avail_to_waker(sender: *const mpsc::Sender<()>) -> task::RawWaker {
 pub struct SocketDescriptor {
 	conn: Arc<Mutex<Connection>>,
 	id: u64,
+	remote_addr: Option<NetAddress>,
 }
 impl SocketDescriptor {
 	fn new(conn: Arc<Mutex<Connection>>) -> Self {
-		let id = conn.lock().unwrap().id;
-		Self { conn, id }
+		let (id, remote_addr) = {
+			let us = conn.lock().unwrap();
+			(us.id, us.remote_addr.clone())
+		};
+		Self { conn, id, remote_addr }
 	}
 }
 impl peer_handler::SocketDescriptor for SocketDescriptor {
@@ -457,12 +519,17 @@ impl peer_handler::SocketDescriptor for SocketDescriptor {
 			thread::yield_now();
 		}
 	}
+
+	fn remote_addr(&self) -> Option<NetAddress> {
+		self.remote_addr.clone()
+	}
 }
 impl Clone for SocketDescriptor {
 	fn clone(&self) -> Self {
 		Self {
 			conn: Arc::clone(&self.conn),
 			id: self.id,
+			remote_addr: self.remote_addr.clone(),
 		}
 	}
 }
@@ -482,7 +549,7 @@ impl Hash for SocketDescriptor {
 mod tests {
 	use lightning::ln::features::*;
 	use lightning::ln::msgs::*;
-	use lightning::ln::peer_handler::{MessageHandler, PeerManager};
+	use lightning::ln::peer_handler::{IgnoringCustomMessageHandler, MessageHandler, PeerManager};
 	use lightning::util::events::*;
 	use bitcoin::secp256k1::{Secp256k1, SecretKey, PublicKey};
 
@@ -499,11 +566,11 @@ mod tests {
 		}
 	}
 
-	struct MsgHandler{
-		expected_pubkey: PublicKey,
-		pubkey_connected: mpsc::Sender<()>,
-		pubkey_disconnected: mpsc::Sender<()>,
-		msg_events: Mutex<Vec<MessageSendEvent>>,
+	pub(crate) struct MsgHandler{
+		pub(crate) expected_pubkey: PublicKey,
+		pub(crate) pubkey_connected: mpsc::Sender<()>,
+		pub(crate) pubkey_disconnected: mpsc::Sender<()>,
+		pub(crate) msg_events: Mutex<Vec<MessageSendEvent>>,
 	}
 	impl RoutingMessageHandler for MsgHandler {
 		fn handle_node_announcement(&self, _msg: &NodeAnnouncement) -> Result<bool, LightningError> { Ok(false) }
@@ -551,6 +618,25 @@ mod tests {
 		}
 	}
 
+	/// Creates a PeerManager (and the MsgHandler behind it) which expects to talk to the peer with
+	/// their_pubkey, along with receivers notified when that peer connects and disconnects.
+	pub(crate) fn make_node(our_key: SecretKey, ephemeral_seed: u8, their_pubkey: PublicKey) -> (Arc<MsgHandler>, Arc<PeerManager<super::SocketDescriptor, Arc<MsgHandler>, Arc<TestLogger>>>, mpsc::Receiver<()>, mpsc::Receiver<()>) {
+		let (connected_sender, connected) = mpsc::channel(1);
+		let (disconnected_sender, disconnected) = mpsc::channel(1);
+		let handler = Arc::new(MsgHandler {
+			expected_pubkey: their_pubkey,
+			pubkey_connected: connected_sender,
+			pubkey_disconnected: disconnected_sender,
+			msg_events: Mutex::new(Vec::new()),
+		});
+		let manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::clone(&handler),
+			route_handler: Arc::clone(&handler) as Arc<dyn RoutingMessageHandler>,
+			custom_message_handler: Arc::new(IgnoringCustomMessageHandler {}),
+		}, our_key, &[ephemeral_seed; 32], Arc::new(TestLogger())));
+		(handler, manager, connected, disconnected)
+	}
+
 	async fn do_basic_connection_test() {
 		let secp_ctx = Secp256k1::new();
 		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
@@ -569,6 +655,7 @@ mod tests {
 		let a_manager = Arc::new(PeerManager::new(MessageHandler {
 			chan_handler: Arc::clone(&a_handler),
 			route_handler: Arc::clone(&a_handler) as Arc<dyn RoutingMessageHandler>,
+			custom_message_handler: Arc::new(IgnoringCustomMessageHandler {}),
 		}, a_key.clone(), &[1; 32], Arc::new(TestLogger())));
 
 		let (b_connected_sender, mut b_connected) = mpsc::channel(1);
@@ -582,6 +669,7 @@ mod tests {
 		let b_manager = Arc::new(PeerManager::new(MessageHandler {
 			chan_handler: Arc::clone(&b_handler),
 			route_handler: Arc::clone(&b_handler) as Arc<dyn RoutingMessageHandler>,
+			custom_message_handler: Arc::new(IgnoringCustomMessageHandler {}),
 		}, b_key.clone(), &[2; 32], Arc::new(TestLogger())));
 
 		// We bind on localhost, hoping the environment is properly configured with a local
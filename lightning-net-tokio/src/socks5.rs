@@ -0,0 +1,268 @@
+//! A minimal SOCKS5 (RFC 1928) client, allowing connections to be made via Tor, including to
+//! onion-only peers.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use lightning::ln::msgs::NetAddress;
+
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0;
+const AUTH_USERNAME_PASSWORD: u8 = 2;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+
+/// A SOCKS5 proxy, such as Tor's SocksPort, via which outbound connections can be made.
+///
+/// Connections to onion addresses are always made via the proxy, while by default connections to
+/// IPv4/IPv6 addresses are made directly. Use set_proxy_clearnet to route those via the proxy as
+/// well, eg to avoid revealing our IP address to peers.
+#[derive(Clone)]
+pub struct Socks5Proxy {
+	addr: SocketAddr,
+	credentials: Option<(String, String)>,
+	proxy_clearnet: bool,
+}
+
+impl Socks5Proxy {
+	/// Creates a new Socks5Proxy for a proxy listening on the given address, which we connect to
+	/// without authentication.
+	pub fn new(addr: SocketAddr) -> Self {
+		Self { addr, credentials: None, proxy_clearnet: false }
+	}
+
+	/// Sets the username and password we authenticate to the proxy with. Tor does not check
+	/// these, but uses them for stream isolation - connections made with different credentials are
+	/// never sent over the same circuit. Each must be no longer than 255 bytes.
+	pub fn set_credentials(&mut self, username: String, password: String) {
+		self.credentials = Some((username, password));
+	}
+
+	/// Sets whether connections to IPv4/IPv6 addresses are also made via the proxy.
+	pub fn set_proxy_clearnet(&mut self, proxy_clearnet: bool) {
+		self.proxy_clearnet = proxy_clearnet;
+	}
+
+	/// Returns true if connections to the given address should be made via this proxy.
+	pub(crate) fn should_proxy(&self, addr: &NetAddress) -> bool {
+		match addr {
+			NetAddress::IPv4 { .. } | NetAddress::IPv6 { .. } => self.proxy_clearnet,
+			NetAddress::OnionV2 { .. } | NetAddress::OnionV3 { .. } => true,
+		}
+	}
+
+	/// Connects to the given address via the proxy, returning the stream once the proxy reports
+	/// that the connection has been established.
+	pub(crate) async fn connect(&self, addr: &NetAddress) -> Result<TcpStream, ()> {
+		let mut stream = TcpStream::connect(&self.addr).await.map_err(|_| ())?;
+
+		let method = if self.credentials.is_some() { AUTH_USERNAME_PASSWORD } else { AUTH_NONE };
+		stream.write_all(&[SOCKS_VERSION, 1, method]).await.map_err(|_| ())?;
+		let mut method_resp = [0; 2];
+		stream.read_exact(&mut method_resp).await.map_err(|_| ())?;
+		if method_resp[0] != SOCKS_VERSION || method_resp[1] != method {
+			return Err(());
+		}
+
+		if let Some((username, password)) = &self.credentials {
+			if username.len() > 255 || password.len() > 255 { return Err(()); }
+			let mut auth = Vec::with_capacity(3 + username.len() + password.len());
+			auth.push(USERNAME_PASSWORD_VERSION);
+			auth.push(username.len() as u8);
+			auth.extend_from_slice(username.as_bytes());
+			auth.push(password.len() as u8);
+			auth.extend_from_slice(password.as_bytes());
+			stream.write_all(&auth).await.map_err(|_| ())?;
+			let mut auth_resp = [0; 2];
+			stream.read_exact(&mut auth_resp).await.map_err(|_| ())?;
+			if auth_resp[1] != 0 { return Err(()); }
+		}
+
+		stream.write_all(&connect_request(addr)).await.map_err(|_| ())?;
+		let mut reply = [0; 4];
+		stream.read_exact(&mut reply).await.map_err(|_| ())?;
+		if reply[0] != SOCKS_VERSION || reply[1] != REPLY_SUCCEEDED { return Err(()); }
+		// Skip the bound address and port, which we don't care about.
+		let bound_addr_len = match reply[3] {
+			ATYP_IPV4 => 4,
+			ATYP_IPV6 => 16,
+			ATYP_DOMAIN => {
+				let mut len = [0; 1];
+				stream.read_exact(&mut len).await.map_err(|_| ())?;
+				len[0] as usize
+			},
+			_ => return Err(()),
+		};
+		let mut bound_addr = vec![0; bound_addr_len + 2];
+		stream.read_exact(&mut bound_addr).await.map_err(|_| ())?;
+		Ok(stream)
+	}
+}
+
+/// Encodes data as RFC 4648 base32, lowercase and without padding, as used in onion addresses.
+fn base32_encode(data: &[u8]) -> String {
+	const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+	let mut ret = String::with_capacity((data.len() * 8 + 4) / 5);
+	let mut buffer: u32 = 0;
+	let mut bits = 0;
+	for byte in data {
+		buffer = (buffer << 8) | *byte as u32;
+		bits += 8;
+		while bits >= 5 {
+			ret.push(ALPHABET[((buffer >> (bits - 5)) & 31) as usize] as char);
+			bits -= 5;
+		}
+	}
+	if bits > 0 {
+		ret.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+	}
+	ret
+}
+
+/// Gets the hostname (ie "<base32>.onion") for an onion address, or None for IPv4/IPv6 addresses.
+pub(crate) fn onion_hostname(addr: &NetAddress) -> Option<String> {
+	match addr {
+		NetAddress::OnionV2 { addr, .. } => Some(base32_encode(addr) + ".onion"),
+		NetAddress::OnionV3 { ed25519_pubkey, checksum, version, .. } => {
+			let mut onion = Vec::with_capacity(35);
+			onion.extend_from_slice(ed25519_pubkey);
+			onion.extend_from_slice(&checksum.to_be_bytes());
+			onion.push(*version);
+			Some(base32_encode(&onion) + ".onion")
+		},
+		NetAddress::IPv4 { .. } | NetAddress::IPv6 { .. } => None,
+	}
+}
+
+/// Gets the SocketAddr for an IPv4/IPv6 address, or None for onion addresses.
+pub(crate) fn socket_addr(addr: &NetAddress) -> Option<SocketAddr> {
+	match addr {
+		NetAddress::IPv4 { addr, port } => Some(SocketAddr::V4(SocketAddrV4::new((*addr).into(), *port))),
+		NetAddress::IPv6 { addr, port } => Some(SocketAddr::V6(SocketAddrV6::new((*addr).into(), *port, 0, 0))),
+		NetAddress::OnionV2 { .. } | NetAddress::OnionV3 { .. } => None,
+	}
+}
+
+/// Builds a SOCKS5 CONNECT request for the given address. Onion addresses are sent as domain
+/// names, which the proxy resolves itself.
+fn connect_request(addr: &NetAddress) -> Vec<u8> {
+	let mut req = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+	let port = match addr {
+		NetAddress::IPv4 { addr, port } => {
+			req.push(ATYP_IPV4);
+			req.extend_from_slice(addr);
+			*port
+		},
+		NetAddress::IPv6 { addr, port } => {
+			req.push(ATYP_IPV6);
+			req.extend_from_slice(addr);
+			*port
+		},
+		NetAddress::OnionV2 { port, .. } | NetAddress::OnionV3 { port, .. } => {
+			let hostname = onion_hostname(addr).unwrap();
+			req.push(ATYP_DOMAIN);
+			req.push(hostname.len() as u8);
+			req.extend_from_slice(hostname.as_bytes());
+			*port
+		},
+	};
+	req.extend_from_slice(&port.to_be_bytes());
+	req
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ConnectError;
+	use crate::tests::make_node;
+
+	use bitcoin::secp256k1::{Secp256k1, SecretKey, PublicKey};
+
+	use tokio::sync::mpsc;
+
+	use std::sync::Arc;
+	use std::time::Duration;
+
+	#[test]
+	fn test_onion_hostnames() {
+		assert_eq!(onion_hostname(&NetAddress::OnionV2 { addr: [0xff; 10], port: 9735 }).unwrap(), "7777777777777777.onion");
+		let v3 = NetAddress::OnionV3 { ed25519_pubkey: [0; 32], checksum: 0, version: 3, port: 9735 };
+		assert_eq!(onion_hostname(&v3).unwrap(), "a".repeat(55) + "d.onion");
+		assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+		assert!(onion_hostname(&NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }).is_none());
+
+		let req = connect_request(&v3);
+		assert_eq!(&req[..5], &[5, 1, 0, 3, 62]);
+		assert_eq!(&req[req.len() - 2..], &[0x26, 0x07]);
+		assert_eq!(connect_request(&NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }), vec![5, 1, 0, 1, 127, 0, 0, 1, 0x26, 0x07]);
+	}
+
+	#[tokio::test]
+	async fn test_connect_to_onion_via_proxy() {
+		let secp_ctx = Secp256k1::new();
+		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
+		let b_key = SecretKey::from_slice(&[2; 32]).unwrap();
+		let a_pub = PublicKey::from_secret_key(&secp_ctx, &a_key);
+		let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
+		let (_a_handler, a_manager, mut a_connected, _a_disconnected) = make_node(a_key, 1, b_pub);
+		let (_b_handler, b_manager, mut b_connected, _b_disconnected) = make_node(b_key, 2, a_pub);
+		let (sender, _receiver) = mpsc::channel(2);
+
+		let onion = NetAddress::OnionV3 { ed25519_pubkey: [42; 32], checksum: 0xbeef, version: 3, port: 9735 };
+		let expected_hostname = onion_hostname(&onion).unwrap();
+
+		// Without a proxy we can't reach the onion address at all.
+		assert_eq!(crate::connect_outbound(Arc::clone(&a_manager), sender.clone(), b_pub, onion.clone()).await.err(), Some(ConnectError::NoProxyForOnion));
+
+		let mut b_listener = tokio::net::TcpListener::bind("127.0.0.1:0".parse::<std::net::SocketAddr>().unwrap()).await.unwrap();
+		let b_addr = b_listener.local_addr().unwrap();
+		let b_sender = sender.clone();
+		tokio::spawn(async move {
+			let (stream, _) = b_listener.accept().await.unwrap();
+			crate::setup_inbound(b_manager, b_sender, stream).await;
+		});
+
+		// A SOCKS5 stand-in which requires our credentials and "resolves" the onion address to B.
+		let mut proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0".parse::<std::net::SocketAddr>().unwrap()).await.unwrap();
+		let proxy_addr = proxy_listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			let (mut inbound, _) = proxy_listener.accept().await.unwrap();
+			let mut greeting = [0; 3];
+			inbound.read_exact(&mut greeting).await.unwrap();
+			assert_eq!(greeting, [5, 1, AUTH_USERNAME_PASSWORD]);
+			inbound.write_all(&[5, AUTH_USERNAME_PASSWORD]).await.unwrap();
+			let mut auth = [0; 11];
+			inbound.read_exact(&mut auth).await.unwrap();
+			assert_eq!(&auth, b"\x01\x04user\x04pass");
+			inbound.write_all(&[1, 0]).await.unwrap();
+
+			let mut req = [0; 5];
+			inbound.read_exact(&mut req).await.unwrap();
+			assert_eq!(req, [5, CMD_CONNECT, 0, ATYP_DOMAIN, 62]);
+			let mut hostname = [0; 62];
+			inbound.read_exact(&mut hostname).await.unwrap();
+			assert_eq!(&hostname[..], expected_hostname.as_bytes());
+			let mut port = [0; 2];
+			inbound.read_exact(&mut port).await.unwrap();
+			assert_eq!(u16::from_be_bytes(port), 9735);
+			inbound.write_all(&[5, REPLY_SUCCEEDED, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 0]).await.unwrap();
+
+			let mut outbound = TcpStream::connect(&b_addr).await.unwrap();
+			let (mut inbound_read, mut inbound_write) = inbound.split();
+			let (mut outbound_read, mut outbound_write) = outbound.split();
+			let _ = tokio::join!(tokio::io::copy(&mut inbound_read, &mut outbound_write), tokio::io::copy(&mut outbound_read, &mut inbound_write));
+		});
+
+		let mut proxy = Socks5Proxy::new(proxy_addr);
+		proxy.set_credentials("user".to_string(), "pass".to_string());
+		let _connection = crate::connect_outbound_via_proxy(a_manager, sender, b_pub, onion, &proxy).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(10), a_connected.recv()).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(1), b_connected.recv()).await.unwrap();
+	}
+}
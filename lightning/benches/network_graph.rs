@@ -0,0 +1,244 @@
+//! Measures the heap memory a NetworkGraph uses and the time get_route takes over it, with and
+//! without the graph retaining the gossip messages it was built from, as well as the memory its
+//! node ids take up interned in a node table, compared to storing NodeIds or PublicKeys in each
+//! channel as the graph used to.
+//!
+//! Run with `cargo bench --bench network_graph` from the lightning directory. This is a plain
+//! binary rather than a libtest benchmark so that it can run on stable and so that its counting
+//! allocator doesn't affect anything else.
+
+extern crate bitcoin;
+extern crate lightning;
+
+use lightning::chain::chaininterface::ChainWatchInterfaceUtil;
+use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement, RoutingMessageHandler};
+use lightning::routing::network_graph::{NetGraphMsgHandler, NodeId};
+use lightning::routing::router::get_route;
+use lightning::util::logger::{Logger, Record};
+use lightning::util::ser::{Readable, Writeable};
+
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::Network;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::util::hash::BitcoinHash;
+
+use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+use bitcoin::secp256k1::{Message, Secp256k1, Signature};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const NODE_COUNT: usize = 1000;
+const CHANNELS_PER_NODE: usize = 4;
+const ROUTE_COUNT: usize = 1000;
+
+/// Tracks the heap memory in use, so that we can tell how much a network graph takes up.
+struct CountingAllocator;
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+		System.alloc(layout)
+	}
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+		System.dealloc(ptr, layout)
+	}
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Gets the heap memory allocated (and not yet freed) by f's result.
+fn heap_usage<T, F: FnOnce() -> T>(f: F) -> usize {
+	let allocated_before = ALLOCATED.load(Ordering::Relaxed);
+	let res = f();
+	let allocated = ALLOCATED.load(Ordering::Relaxed) - allocated_before;
+	mem::drop(res);
+	allocated
+}
+
+struct NullLogger;
+impl Logger for NullLogger {
+	fn log(&self, _record: &Record) {}
+}
+
+type BenchNetGraphMsgHandler = NetGraphMsgHandler<Arc<ChainWatchInterfaceUtil>, Arc<NullLogger>>;
+
+struct GraphMessages {
+	node_ids: Vec<PublicKey>,
+	channel_announcements: Vec<ChannelAnnouncement>,
+	channel_updates: Vec<ChannelUpdate>,
+	node_announcements: Vec<NodeAnnouncement>,
+}
+
+/// Signs the serialized contents of a message with each of the given keys and reads the message
+/// from the signatures followed by the contents, as the message fields aren't public.
+fn sign_message<M: Readable>(secp_ctx: &Secp256k1<bitcoin::secp256k1::All>, contents: Vec<u8>, keys: &[&SecretKey]) -> M {
+	let msghash = Message::from_slice(&Sha256dHash::hash(&contents[..])[..]).unwrap();
+	let mut msg = Vec::new();
+	for key in keys.iter() {
+		let sig: Signature = secp_ctx.sign(&msghash, key);
+		msg.extend_from_slice(&sig.serialize_compact());
+	}
+	msg.extend_from_slice(&contents);
+	Readable::read(&mut Cursor::new(&msg)).unwrap()
+}
+
+/// Generates the gossip for a random-looking (but deterministic) graph, with each node opening
+/// CHANNELS_PER_NODE channels to others and all channels having an update in each direction.
+fn generate_graph_messages() -> GraphMessages {
+	let secp_ctx = Secp256k1::new();
+	let chain_hash = genesis_block(Network::Testnet).header.bitcoin_hash();
+	let privkeys: Vec<SecretKey> = (0..NODE_COUNT).map(|i| {
+		let mut key = [0x42; 32];
+		key[0] = (i >> 8) as u8 + 1;
+		key[1] = i as u8;
+		SecretKey::from_slice(&key).unwrap()
+	}).collect();
+	let node_ids: Vec<PublicKey> = privkeys.iter().map(|key| PublicKey::from_secret_key(&secp_ctx, key)).collect();
+
+	let mut channel_announcements = Vec::with_capacity(NODE_COUNT * CHANNELS_PER_NODE);
+	let mut channel_updates = Vec::with_capacity(NODE_COUNT * CHANNELS_PER_NODE * 2);
+	// channel_updates older than two weeks are rejected as stale, so date them now.
+	let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+	let mut rng_state: u64 = 0x5eed;
+	for node in 0..NODE_COUNT {
+		for _ in 0..CHANNELS_PER_NODE {
+			rng_state ^= rng_state << 13;
+			rng_state ^= rng_state >> 7;
+			rng_state ^= rng_state << 17;
+			let peer = (node + 1 + (rng_state as usize) % (NODE_COUNT - 1)) % NODE_COUNT;
+			let short_channel_id = channel_announcements.len() as u64 + 1;
+
+			// Using the same keys for LN and BTC ids, as in the router tests.
+			let mut contents = ChannelFeatures::empty().encode();
+			contents.extend_from_slice(&chain_hash[..]);
+			contents.extend_from_slice(&short_channel_id.encode());
+			for &key in [node, peer, node, peer].iter() {
+				contents.extend_from_slice(&node_ids[key].serialize());
+			}
+			let keys = [&privkeys[node], &privkeys[peer], &privkeys[node], &privkeys[peer]];
+			channel_announcements.push(sign_message(&secp_ctx, contents, &keys));
+
+			for &(signer, flags) in [(node, 0u16), (peer, 1u16)].iter() {
+				let mut contents = chain_hash[..].to_vec();
+				contents.extend_from_slice(&short_channel_id.encode());
+				contents.extend_from_slice(&timestamp.encode());
+				contents.extend_from_slice(&flags.encode());
+				contents.extend_from_slice(&((rng_state % 144) as u16 + 6).encode()); // cltv_expiry_delta
+				contents.extend_from_slice(&0u64.encode()); // htlc_minimum_msat
+				contents.extend_from_slice(&((rng_state % 1000) as u32).encode()); // fee_base_msat
+				contents.extend_from_slice(&((rng_state % 500) as u32).encode()); // fee_proportional_millionths
+				channel_updates.push(sign_message(&secp_ctx, contents, &[&privkeys[signer]]));
+			}
+		}
+	}
+
+	let node_announcements = privkeys.iter().zip(node_ids.iter()).map(|(privkey, node_id)| {
+		let mut contents = NodeFeatures::known().encode();
+		contents.extend_from_slice(&1u32.encode()); // timestamp
+		contents.extend_from_slice(&node_id.serialize());
+		contents.extend_from_slice(&[0; 3 + 32]); // rgb and alias
+		contents.extend_from_slice(&0u16.encode()); // no addresses
+		sign_message(&secp_ctx, contents, &[privkey])
+	}).collect();
+
+	GraphMessages { node_ids, channel_announcements, channel_updates, node_announcements }
+}
+
+fn build_graph(messages: &GraphMessages, retain_announcements: bool) -> BenchNetGraphMsgHandler {
+	let chain_monitor = Arc::new(ChainWatchInterfaceUtil::new(Network::Testnet));
+	let net_graph_msg_handler = NetGraphMsgHandler::new(chain_monitor, Arc::new(NullLogger));
+	net_graph_msg_handler.network_graph.write().unwrap().set_retain_announcements(retain_announcements);
+	for msg in messages.channel_announcements.iter() {
+		net_graph_msg_handler.handle_channel_announcement(msg).unwrap();
+	}
+	for msg in messages.channel_updates.iter() {
+		net_graph_msg_handler.handle_channel_update(msg).unwrap();
+	}
+	for msg in messages.node_announcements.iter() {
+		net_graph_msg_handler.handle_node_announcement(msg).unwrap();
+	}
+	net_graph_msg_handler
+}
+
+/// Builds the node map and channel endpoints of the graph with node ids of type K stored in each
+/// channel, as the graph used to, so that we can measure what each type of node id costs. The
+/// graph itself is dropped before we return, so only the tables are left allocated.
+fn build_node_ids<K: Ord + Copy, F: Fn(&PublicKey) -> K>(messages: &GraphMessages, to_node_id: F) -> (BTreeMap<K, ()>, Vec<(K, K)>) {
+	let graph = build_graph(messages, false);
+	let network_graph = graph.network_graph.read().unwrap();
+	let mut nodes = BTreeMap::new();
+	let mut channel_endpoints = Vec::with_capacity(network_graph.get_channels().len());
+	for chan in network_graph.get_channels().values() {
+		let node_one = to_node_id(&network_graph.get_node_id(chan.node_one).as_pubkey().unwrap());
+		let node_two = to_node_id(&network_graph.get_node_id(chan.node_two).as_pubkey().unwrap());
+		nodes.insert(node_one, ());
+		nodes.insert(node_two, ());
+		channel_endpoints.push((node_one, node_two));
+	}
+	(nodes, channel_endpoints)
+}
+
+/// Builds the node map, node table and channel endpoints of the graph as the graph stores them,
+/// with each channel holding indices into the node table.
+fn build_interned_node_ids(messages: &GraphMessages) -> (BTreeMap<NodeId, u32>, Vec<NodeId>, Vec<(u32, u32)>) {
+	let graph = build_graph(messages, false);
+	let network_graph = graph.network_graph.read().unwrap();
+	let mut nodes = BTreeMap::new();
+	let mut node_table = Vec::new();
+	let mut channel_endpoints = Vec::with_capacity(network_graph.get_channels().len());
+	for chan in network_graph.get_channels().values() {
+		let mut intern = |node_id: &NodeId| {
+			*nodes.entry(*node_id).or_insert_with(|| {
+				node_table.push(*node_id);
+				node_table.len() as u32 - 1
+			})
+		};
+		let node_one = intern(network_graph.get_node_id(chan.node_one));
+		let node_two = intern(network_graph.get_node_id(chan.node_two));
+		channel_endpoints.push((node_one, node_two));
+	}
+	(nodes, node_table, channel_endpoints)
+}
+
+/// Gets the average time, in microseconds, get_route takes over the graph.
+fn time_get_route(messages: &GraphMessages, net_graph_msg_handler: &BenchNetGraphMsgHandler) -> u64 {
+	let logger = Arc::new(NullLogger);
+	let start = Instant::now();
+	for route_idx in 0..ROUTE_COUNT {
+		let src = &messages.node_ids[route_idx % NODE_COUNT];
+		let dst = &messages.node_ids[(route_idx * 7 + NODE_COUNT / 2) % NODE_COUNT];
+		let _ = get_route(src, net_graph_msg_handler, dst, None, &[], 100_000, 42, Arc::clone(&logger));
+	}
+	let elapsed = start.elapsed();
+	(elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64) / ROUTE_COUNT as u64
+}
+
+fn main() {
+	let messages = generate_graph_messages();
+	println!("Network graph of {} nodes and {} channels:", NODE_COUNT, messages.channel_announcements.len());
+
+	for &retain_announcements in [true, false].iter() {
+		let heap = heap_usage(|| build_graph(&messages, retain_announcements));
+		let net_graph_msg_handler = build_graph(&messages, retain_announcements);
+		let route_time = time_get_route(&messages, &net_graph_msg_handler);
+		println!("  {} announcements: {} bytes of heap, {} us per get_route",
+			if retain_announcements { "retaining" } else { "without" }, heap, route_time);
+	}
+
+	let interned = heap_usage(|| build_interned_node_ids(&messages));
+	let with_node_ids = heap_usage(|| build_node_ids(&messages, NodeId::from_pubkey));
+	let with_pubkeys = heap_usage(|| build_node_ids(&messages, |pubkey| *pubkey));
+	println!("  node ids take up {} bytes interned, {} bytes as NodeIds and {} bytes as PublicKeys in each channel",
+		interned, with_node_ids, with_pubkeys);
+}
@@ -5,13 +5,15 @@
 //! disconnections, transaction broadcasting, and feerate information requests.
 
 use bitcoin::blockdata::block::{Block, BlockHeader};
-use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
 use bitcoin::blockdata::script::Script;
 use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::util::hash::BitcoinHash;
 use bitcoin::network::constants::Network;
 use bitcoin::hash_types::{Txid, BlockHash};
 
+use chain::transaction::OutPoint;
+
 use std::sync::{Mutex, MutexGuard, Arc};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::collections::HashSet;
@@ -63,6 +65,30 @@ pub trait ChainWatchInterface: Sync + Send {
 	fn reentered(&self) -> usize;
 }
 
+/// The result of a UtxoLookup::get_utxo call.
+pub enum UtxoLookupResult {
+	/// The lookup completed immediately, giving either the output and where it is or the reason
+	/// it couldn't be found.
+	Sync(Result<(OutPoint, TxOut), ChainError>),
+	/// The output is being fetched in the background, and the result will be provided by calling
+	/// NetGraphMsgHandler::utxo_lookup_completed once it is available.
+	Async,
+}
+
+/// An interface to look up the funding output of an announced channel which, unlike
+/// ChainWatchInterface::get_chain_utxo, may defer the lookup, eg to fetch the block containing the
+/// output from a remote Bitcoin Core instance.
+///
+/// Note that all of the functions implemented here *must* be reentrant-safe, and that
+/// get_utxo may be called again for the same short_channel_id while a previous lookup is
+/// outstanding if that lookup was given up on (see NetGraphMsgHandler::set_utxo_lookup).
+pub trait UtxoLookup: Sync + Send {
+	/// Looks up the transaction output identified by the given short_channel_id (see
+	/// ChainWatchInterface::get_chain_utxo for its format) on the chain with the given genesis
+	/// block hash, returning its outpoint so that the channel can be removed once it is spent.
+	fn get_utxo(&self, genesis_hash: &BlockHash, short_channel_id: u64) -> UtxoLookupResult;
+}
+
 /// An interface to send a transaction to the Bitcoin network.
 pub trait BroadcasterInterface: Sync + Send {
 	/// Sends a transaction out to (hopefully) be mined.
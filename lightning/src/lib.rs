@@ -20,6 +20,7 @@
 extern crate bitcoin;
 #[cfg(test)] extern crate rand;
 #[cfg(test)] extern crate hex;
+#[cfg(test)] extern crate crossbeam;
 #[cfg(all(test, feature = "mutation_testing"))] extern crate mutagen;
 
 #[macro_use]
@@ -34,6 +34,8 @@ use std;
 use std::default::Default;
 use std::{cmp,mem,fmt};
 use std::ops::Deref;
+#[cfg(not(feature = "fuzztarget"))]
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[cfg(all(test, feature = "mutation_testing"))]
 use mutagen::mutate;
@@ -385,6 +387,20 @@ const ANCHOR_VALUE_SATOSHIS: u64 = 330;
 /// it's 2^24.
 pub const MAX_FUNDING_SATOSHIS: u64 = 1 << 24;
 
+/// Gets the timestamp our first channel_update for a new channel should carry. We use the current
+/// time so that the update isn't considered stale by peers (or our own router) before we've seen
+/// a block to take the time from, except when fuzzing, where we need to be deterministic.
+fn initial_update_time_counter() -> u32 {
+	#[cfg(not(feature = "fuzztarget"))]
+	{
+		SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(1)
+	}
+	#[cfg(feature = "fuzztarget")]
+	{
+		1
+	}
+}
+
 /// Used to return a simple Error back to ChannelManager. Will get converted to a
 /// msgs::ErrorAction::SendErrorMessage or msgs::ErrorAction::IgnoreError as appropriate with our
 /// channel_id in ChannelManager.
@@ -485,7 +501,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			holding_cell_update_fee: None,
 			next_local_htlc_id: 0,
 			next_remote_htlc_id: 0,
-			update_time_counter: 1,
+			update_time_counter: initial_update_time_counter(),
 
 			resend_order: RAACommitmentOrder::CommitmentFirst,
 
@@ -708,7 +724,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			holding_cell_update_fee: None,
 			next_local_htlc_id: 0,
 			next_remote_htlc_id: 0,
-			update_time_counter: 1,
+			update_time_counter: initial_update_time_counter(),
 
 			resend_order: RAACommitmentOrder::CommitmentFirst,
 
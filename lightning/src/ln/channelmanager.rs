@@ -285,26 +285,101 @@ pub(super) enum RAACommitmentOrder {
 	RevokeAndACKFirst,
 }
 
+/// A single channel in ChannelHolder::by_id. Each channel has its own lock, which is held for the
+/// full duration of any operation on the channel, including the ChannelMonitor update it
+/// generates, so that operations on different channels can proceed in parallel while monitor
+/// updates for any one channel are still generated and handed to the ManyChannelMonitor in order.
+///
+/// The slot is emptied (set to None) at the same time the channel is removed from by_id, so that
+/// anyone who looked the channel up before it was removed notices once they get the lock.
+pub(super) type ChannelSlot<ChanSigner> = Arc<Mutex<Option<Channel<ChanSigner>>>>;
+
 // Note this is only exposed in cfg(test):
+//
+// The ChannelHolder lock is only ever held briefly, to look up or update the index of channels
+// and the other shared state here. Channel locks are always taken before the ChannelHolder lock,
+// and no thread ever holds more than one channel lock at once, except ChannelManager::write, which
+// takes all of them, and ChannelManager::claim_funds, which takes all those an MPP payment is
+// claimed from. Both take them in channel_id order, and never while holding the ChannelHolder lock.
 pub(super) struct ChannelHolder<ChanSigner: ChannelKeys> {
-	pub(super) by_id: HashMap<[u8; 32], Channel<ChanSigner>>,
+	pub(super) by_id: HashMap<[u8; 32], ChannelSlot<ChanSigner>>,
 	pub(super) short_to_id: HashMap<u64, [u8; 32]>,
 	/// short channel id -> forward infos. Key of 0 means payments received
-	/// Note that while this is held in the same mutex as the channels themselves, no consistency
-	/// guarantees are made about the existence of a channel with the short id here, nor the short
-	/// ids in the PendingHTLCInfo!
+	/// Note that no consistency guarantees are made about the existence of a channel with the
+	/// short id here, nor the short ids in the PendingHTLCInfo!
 	pub(super) forward_htlcs: HashMap<u64, Vec<HTLCForwardInfo>>,
 	/// (payment_hash, payment_secret) -> Vec<HTLCs> for tracking HTLCs that
 	/// were to us and can be failed/claimed by the user
-	/// Note that while this is held in the same mutex as the channels themselves, no consistency
-	/// guarantees are made about the channels given here actually existing anymore by the time you
-	/// go to read them!
+	/// Note that no consistency guarantees are made about the channels given here actually
+	/// existing anymore by the time you go to read them!
 	claimable_htlcs: HashMap<(PaymentHash, Option<PaymentSecret>), Vec<ClaimableHTLC>>,
-	/// Messages to send to peers - pushed to in the same lock that they are generated in (except
-	/// for broadcast messages, where ordering isn't as strict).
+	/// Messages to send to peers - pushed to while holding the lock of the channel they were
+	/// generated in (except for broadcast messages, where ordering isn't as strict).
 	pub(super) pending_msg_events: Vec<events::MessageSendEvent>,
 }
 
+/// A locked channel which is (still) present in ChannelHolder::by_id. Mirrors the parts of
+/// hash_map::OccupiedEntry which we use, so that removing the channel also removes it from the
+/// index (and short_to_id) and empties its slot.
+struct ChannelEntry<'a, ChanSigner: ChannelKeys + 'a> {
+	channel_id: [u8; 32],
+	channel: MutexGuard<'a, Option<Channel<ChanSigner>>>,
+	holder: &'a Mutex<ChannelHolder<ChanSigner>>,
+}
+
+impl<'a, ChanSigner: ChannelKeys> ChannelEntry<'a, ChanSigner> {
+	/// Locks the given channel, returning None if it was removed while we waited for the lock.
+	/// Must not be called while holding the ChannelHolder lock.
+	fn lock(slot: &'a ChannelSlot<ChanSigner>, channel_id: [u8; 32], holder: &'a Mutex<ChannelHolder<ChanSigner>>) -> Option<Self> {
+		let channel = slot.lock().unwrap();
+		if channel.is_none() { return None; }
+		#[cfg(debug_assertions)]
+		CHANNEL_LOCKS_HELD.with(|held| held.set(held.get() + 1));
+		Some(ChannelEntry { channel_id, channel, holder })
+	}
+
+	fn key(&self) -> &[u8; 32] {
+		&self.channel_id
+	}
+
+	fn get(&self) -> &Channel<ChanSigner> {
+		self.channel.as_ref().unwrap()
+	}
+
+	fn get_mut(&mut self) -> &mut Channel<ChanSigner> {
+		self.channel.as_mut().unwrap()
+	}
+
+	/// Removes the channel from ChannelHolder::by_id (and short_to_id), returning it.
+	fn remove_entry(&mut self) -> ([u8; 32], Channel<ChanSigner>) {
+		let chan = self.channel.take().unwrap();
+		let mut channel_state = self.holder.lock().unwrap();
+		channel_state.by_id.remove(&self.channel_id);
+		if let Some(short_id) = chan.get_short_channel_id() {
+			channel_state.short_to_id.remove(&short_id);
+		}
+		(self.channel_id, chan)
+	}
+
+	fn remove(&mut self) -> Channel<ChanSigner> {
+		self.remove_entry().1
+	}
+}
+
+#[cfg(debug_assertions)]
+impl<'a, ChanSigner: ChannelKeys> Drop for ChannelEntry<'a, ChanSigner> {
+	fn drop(&mut self) {
+		CHANNEL_LOCKS_HELD.with(|held| held.set(held.get() - 1));
+	}
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+	// The number of ChannelEntrys (ie channel locks) the current thread holds, so that we can check
+	// that none are held where taking another lock could deadlock.
+	static CHANNEL_LOCKS_HELD: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0);
+}
+
 /// State we hold per-peer. In the future we should put channels in here, but for now we only hold
 /// the latest Init features we heard from the peer.
 struct PeerState {
@@ -364,6 +439,10 @@ pub type SimpleRefChannelManager<'a, 'b, 'c, 'd, 'e, M, T, F, L> = ChannelManage
 /// offline for a full minute. In order to track this, you must call
 /// timer_chan_freshness_every_min roughly once per minute, though it doesn't have to be perfect.
 ///
+/// Each channel is protected by its own lock, with only a brief global lock around the index of
+/// channels, so messages and HTLCs for different channels may be handled from different threads
+/// in parallel.
+///
 /// Rather than using a plain ChannelManager, it is preferable to use either a SimpleArcChannelManager
 /// a SimpleRefChannelManager, for conciseness. See their documentation for more details, but
 /// essentially you should default to using a SimpleRefChannelManager, and use a
@@ -525,11 +604,14 @@ macro_rules! handle_error {
 		match $internal {
 			Ok(msg) => Ok(msg),
 			Err(MsgHandleErrInternal { err, shutdown_finish }) => {
+				// Note that neither the channel_state lock nor any channel lock may be held upon
+				// entering the macro, as finish_force_close_channel may need to take them.
 				#[cfg(debug_assertions)]
 				{
-					// In testing, ensure there are no deadlocks where the lock is already held upon
-					// entering the macro.
-					assert!($self.channel_state.try_lock().is_ok());
+					// In testing, ensure there are no deadlocks where a channel lock is already held
+					// upon entering the macro. We can't check the channel_state lock with try_lock
+					// as we used to, as other threads may now briefly hold it at any time.
+					assert_eq!(CHANNEL_LOCKS_HELD.with(|held| held.get()), 0);
 				}
 
 				let mut msg_events = Vec::with_capacity(2);
@@ -564,7 +646,7 @@ macro_rules! handle_error {
 }
 
 macro_rules! break_chan_entry {
-	($self: ident, $res: expr, $channel_state: expr, $entry: expr) => {
+	($self: ident, $res: expr, $entry: expr) => {
 		match $res {
 			Ok(res) => res,
 			Err(ChannelError::Ignore(msg)) => {
@@ -573,9 +655,6 @@ macro_rules! break_chan_entry {
 			Err(ChannelError::Close(msg)) => {
 				log_trace!($self.logger, "Closing channel {} due to Close-required error: {}", log_bytes!($entry.key()[..]), msg);
 				let (channel_id, mut chan) = $entry.remove_entry();
-				if let Some(short_id) = chan.get_short_channel_id() {
-					$channel_state.short_to_id.remove(&short_id);
-				}
 				break Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, chan.force_shutdown(true), $self.get_channel_update(&chan).ok()))
 			},
 			Err(ChannelError::CloseDelayBroadcast(_)) => { panic!("Wait is only generated on receipt of channel_reestablish, which is handled by try_chan_entry, we don't bother to support it here"); }
@@ -584,7 +663,7 @@ macro_rules! break_chan_entry {
 }
 
 macro_rules! try_chan_entry {
-	($self: ident, $res: expr, $channel_state: expr, $entry: expr) => {
+	($self: ident, $res: expr, $entry: expr) => {
 		match $res {
 			Ok(res) => res,
 			Err(ChannelError::Ignore(msg)) => {
@@ -593,17 +672,11 @@ macro_rules! try_chan_entry {
 			Err(ChannelError::Close(msg)) => {
 				log_trace!($self.logger, "Closing channel {} due to Close-required error: {}", log_bytes!($entry.key()[..]), msg);
 				let (channel_id, mut chan) = $entry.remove_entry();
-				if let Some(short_id) = chan.get_short_channel_id() {
-					$channel_state.short_to_id.remove(&short_id);
-				}
 				return Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, chan.force_shutdown(true), $self.get_channel_update(&chan).ok()))
 			},
 			Err(ChannelError::CloseDelayBroadcast(msg)) => {
 				log_error!($self.logger, "Channel {} need to be shutdown but closing transactions not broadcast due to {}", log_bytes!($entry.key()[..]), msg);
 				let (channel_id, mut chan) = $entry.remove_entry();
-				if let Some(short_id) = chan.get_short_channel_id() {
-					$channel_state.short_to_id.remove(&short_id);
-				}
 				let shutdown_res = chan.force_shutdown(false);
 				return Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, shutdown_res, $self.get_channel_update(&chan).ok()))
 			}
@@ -612,17 +685,14 @@ macro_rules! try_chan_entry {
 }
 
 macro_rules! handle_monitor_err {
-	($self: ident, $err: expr, $channel_state: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr) => {
-		handle_monitor_err!($self, $err, $channel_state, $entry, $action_type, $resend_raa, $resend_commitment, Vec::new(), Vec::new())
+	($self: ident, $err: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr) => {
+		handle_monitor_err!($self, $err, $entry, $action_type, $resend_raa, $resend_commitment, Vec::new(), Vec::new())
 	};
-	($self: ident, $err: expr, $channel_state: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr, $failed_forwards: expr, $failed_fails: expr) => {
+	($self: ident, $err: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr, $failed_forwards: expr, $failed_fails: expr) => {
 		match $err {
 			ChannelMonitorUpdateErr::PermanentFailure => {
 				log_error!($self.logger, "Closing channel {} due to monitor update PermanentFailure", log_bytes!($entry.key()[..]));
 				let (channel_id, mut chan) = $entry.remove_entry();
-				if let Some(short_id) = chan.get_short_channel_id() {
-					$channel_state.short_to_id.remove(&short_id);
-				}
 				// TODO: $failed_fails is dropped here, which will cause other channels to hit the
 				// chain in a confused state! We need to move them into the ChannelMonitor which
 				// will be responsible for failing backwards once things confirm on-chain.
@@ -662,18 +732,18 @@ macro_rules! handle_monitor_err {
 }
 
 macro_rules! return_monitor_err {
-	($self: ident, $err: expr, $channel_state: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr) => {
-		return handle_monitor_err!($self, $err, $channel_state, $entry, $action_type, $resend_raa, $resend_commitment);
+	($self: ident, $err: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr) => {
+		return handle_monitor_err!($self, $err, $entry, $action_type, $resend_raa, $resend_commitment);
 	};
-	($self: ident, $err: expr, $channel_state: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr, $failed_forwards: expr, $failed_fails: expr) => {
-		return handle_monitor_err!($self, $err, $channel_state, $entry, $action_type, $resend_raa, $resend_commitment, $failed_forwards, $failed_fails);
+	($self: ident, $err: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr, $failed_forwards: expr, $failed_fails: expr) => {
+		return handle_monitor_err!($self, $err, $entry, $action_type, $resend_raa, $resend_commitment, $failed_forwards, $failed_fails);
 	}
 }
 
 // Does not break in case of TemporaryFailure!
 macro_rules! maybe_break_monitor_err {
-	($self: ident, $err: expr, $channel_state: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr) => {
-		match (handle_monitor_err!($self, $err, $channel_state, $entry, $action_type, $resend_raa, $resend_commitment), $err) {
+	($self: ident, $err: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr) => {
+		match (handle_monitor_err!($self, $err, $entry, $action_type, $resend_raa, $resend_commitment), $err) {
 			(e, ChannelMonitorUpdateErr::PermanentFailure) => {
 				break e;
 			},
@@ -746,6 +816,27 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		Ok(res)
 	}
 
+	/// Looks up the channel with the given id. The returned slot should be locked with
+	/// lock_channel after the channel_state lock has been released.
+	fn get_channel_slot(&self, channel_id: &[u8; 32]) -> Option<ChannelSlot<ChanSigner>> {
+		self.channel_state.lock().unwrap().by_id.get(channel_id).cloned()
+	}
+
+	/// Locks a channel looked up with get_channel_slot, returning None if there was no such channel
+	/// or it has since been removed.
+	fn lock_channel<'a>(&'a self, slot: &'a Option<ChannelSlot<ChanSigner>>, channel_id: [u8; 32]) -> Option<ChannelEntry<'a, ChanSigner>> {
+		match slot {
+			&Some(ref slot) => ChannelEntry::lock(slot, channel_id, &self.channel_state),
+			&None => None,
+		}
+	}
+
+	/// Gets all of our channels, so that each can be locked in turn without holding the
+	/// channel_state lock.
+	fn get_channel_slots(&self) -> Vec<([u8; 32], ChannelSlot<ChanSigner>)> {
+		self.channel_state.lock().unwrap().by_id.iter().map(|(id, slot)| (*id, slot.clone())).collect()
+	}
+
 	/// Creates a new outbound channel to the given remote node and with the given value.
 	///
 	/// user_id will be provided back as user_channel_id in FundingGenerationReady and
@@ -767,7 +858,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		let channel = Channel::new_outbound(&self.fee_estimator, &self.keys_manager, their_network_key, channel_value_satoshis, push_msat, user_id, config)?;
 		let res = channel.get_open_channel(self.genesis_hash.clone(), &self.fee_estimator);
 
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let mut channel_state = self.channel_state.lock().unwrap();
 		match channel_state.by_id.entry(channel.channel_id()) {
 			hash_map::Entry::Occupied(_) => {
@@ -777,7 +868,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					panic!("RNG is bad???");
 				}
 			},
-			hash_map::Entry::Vacant(entry) => { entry.insert(channel); }
+			hash_map::Entry::Vacant(entry) => { entry.insert(Arc::new(Mutex::new(Some(channel)))); }
 		}
 		channel_state.pending_msg_events.push(events::MessageSendEvent::SendOpenChannel {
 			node_id: their_network_key,
@@ -786,12 +877,18 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		Ok(())
 	}
 
-	fn list_channels_with_filter<Fn: FnMut(&(&[u8; 32], &Channel<ChanSigner>)) -> bool>(&self, f: Fn) -> Vec<ChannelDetails> {
+	fn list_channels_with_filter<Fn: FnMut(&(&[u8; 32], &Channel<ChanSigner>)) -> bool>(&self, mut f: Fn) -> Vec<ChannelDetails> {
 		let mut res = Vec::new();
 		{
-			let channel_state = self.channel_state.lock().unwrap();
-			res.reserve(channel_state.by_id.len());
-			for (channel_id, channel) in channel_state.by_id.iter().filter(f) {
+			let channel_slots = self.get_channel_slots();
+			res.reserve(channel_slots.len());
+			for &(ref channel_id, ref slot) in channel_slots.iter() {
+				let chan = match ChannelEntry::lock(slot, *channel_id, &self.channel_state) {
+					Some(chan) => chan,
+					None => continue,
+				};
+				let channel = chan.get();
+				if !f(&(channel_id, channel)) { continue; }
 				let (inbound_capacity_msat, outbound_capacity_msat) = channel.get_inbound_outbound_available_balance_msat();
 				res.push(ChannelDetails {
 					channel_id: (*channel_id).clone(),
@@ -839,26 +936,23 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	///
 	/// May generate a SendShutdown message event on success, which should be relayed.
 	pub fn close_channel(&self, channel_id: &[u8; 32]) -> Result<(), APIError> {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		let (mut failed_htlcs, chan_option) = {
-			let mut channel_state_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_state_lock;
-			match channel_state.by_id.entry(channel_id.clone()) {
-				hash_map::Entry::Occupied(mut chan_entry) => {
+			let chan_slot = self.get_channel_slot(channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, *channel_id);
+			match chan_opt {
+				Some(mut chan_entry) => {
 					let (shutdown_msg, failed_htlcs) = chan_entry.get_mut().get_shutdown()?;
-					channel_state.pending_msg_events.push(events::MessageSendEvent::SendShutdown {
+					self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::SendShutdown {
 						node_id: chan_entry.get().get_their_node_id(),
 						msg: shutdown_msg
 					});
 					if chan_entry.get().is_shutdown() {
-						if let Some(short_id) = chan_entry.get().get_short_channel_id() {
-							channel_state.short_to_id.remove(&short_id);
-						}
 						(failed_htlcs, Some(chan_entry.remove_entry().1))
 					} else { (failed_htlcs, None) }
 				},
-				hash_map::Entry::Vacant(_) => return Err(APIError::ChannelUnavailable{err: "No such channel"})
+				None => return Err(APIError::ChannelUnavailable{err: "No such channel"})
 			}
 		};
 		for htlc_source in failed_htlcs.drain(..) {
@@ -899,16 +993,13 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	/// Force closes a channel, immediately broadcasting the latest local commitment transaction to
 	/// the chain and rejecting new HTLCs on the given channel.
 	pub fn force_close_channel(&self, channel_id: &[u8; 32]) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		let mut chan = {
-			let mut channel_state_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_state_lock;
-			if let Some(chan) = channel_state.by_id.remove(channel_id) {
-				if let Some(short_id) = chan.get_short_channel_id() {
-					channel_state.short_to_id.remove(&short_id);
-				}
-				chan
+			let chan_slot = self.get_channel_slot(channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, *channel_id);
+			if let Some(mut chan_entry) = chan_opt {
+				chan_entry.remove()
 			} else {
 				return;
 			}
@@ -931,17 +1022,19 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		}
 	}
 
-	fn decode_update_add_htlc_onion(&self, msg: &msgs::UpdateAddHTLC) -> (PendingHTLCStatus, MutexGuard<ChannelHolder<ChanSigner>>) {
+	/// Must not be called while holding the channel_state lock or any channel lock, as the
+	/// channel we'd forward over will be locked to check the HTLC against its parameters.
+	fn decode_update_add_htlc_onion(&self, msg: &msgs::UpdateAddHTLC) -> PendingHTLCStatus {
 		macro_rules! return_malformed_err {
 			($msg: expr, $err_code: expr) => {
 				{
 					log_info!(self.logger, "Failed to accept/forward incoming HTLC: {}", $msg);
-					return (PendingHTLCStatus::Fail(HTLCFailureMsg::Malformed(msgs::UpdateFailMalformedHTLC {
+					return PendingHTLCStatus::Fail(HTLCFailureMsg::Malformed(msgs::UpdateFailMalformedHTLC {
 						channel_id: msg.channel_id,
 						htlc_id: msg.htlc_id,
 						sha256_of_onion: Sha256::hash(&msg.onion_routing_packet.hop_data).into_inner(),
 						failure_code: $err_code,
-					})), self.channel_state.lock().unwrap());
+					}));
 				}
 			}
 		}
@@ -974,19 +1067,15 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			return_malformed_err!("HMAC Check failed", 0x8000 | 0x4000 | 5);
 		}
 
-		let mut channel_state = None;
 		macro_rules! return_err {
 			($msg: expr, $err_code: expr, $data: expr) => {
 				{
 					log_info!(self.logger, "Failed to accept/forward incoming HTLC: {}", $msg);
-					if channel_state.is_none() {
-						channel_state = Some(self.channel_state.lock().unwrap());
-					}
-					return (PendingHTLCStatus::Fail(HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
+					return PendingHTLCStatus::Fail(HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
 						channel_id: msg.channel_id,
 						htlc_id: msg.htlc_id,
 						reason: onion_utils::build_first_hop_failure_packet(&shared_secret, $err_code, $data),
-					})), channel_state.unwrap());
+					}));
 				}
 			}
 		}
@@ -1127,21 +1216,27 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				})
 			};
 
-		channel_state = Some(self.channel_state.lock().unwrap());
 		if let &PendingHTLCStatus::Forward(PendingHTLCInfo { ref routing, ref amt_to_forward, ref outgoing_cltv_value, .. }) = &pending_forward_info {
 			// If short_channel_id is 0 here, we'll reject the HTLC as there cannot be a channel
 			// with a short_channel_id of 0. This is important as various things later assume
 			// short_channel_id is non-0 in any ::Forward.
 			if let &PendingHTLCRouting::Forward { ref short_channel_id, .. } = routing {
-				let id_option = channel_state.as_ref().unwrap().short_to_id.get(&short_channel_id).cloned();
+				let id_option = self.channel_state.lock().unwrap().short_to_id.get(&short_channel_id).cloned();
 				let forwarding_id = match id_option {
 					None => { // unknown_next_peer
 						return_err!("Don't have available channel for forwarding as requested.", 0x4000 | 10, &[0;0]);
 					},
 					Some(id) => id.clone(),
 				};
+				let forwarding_slot = self.get_channel_slot(&forwarding_id);
+				let forwarding_chan = match self.lock_channel(&forwarding_slot, forwarding_id) {
+					None => { // unknown_next_peer, the channel was closed since we looked up its id
+						return_err!("Don't have available channel for forwarding as requested.", 0x4000 | 10, &[0;0]);
+					},
+					Some(chan) => chan,
+				};
 				if let Some((err, code, chan_update)) = loop {
-					let chan = channel_state.as_mut().unwrap().by_id.get_mut(&forwarding_id).unwrap();
+					let chan = forwarding_chan.get();
 
 					// Note that we could technically not return an error yet here and just hope
 					// that the connection is reestablished or monitor updated by the time we get
@@ -1197,11 +1292,11 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			}
 		}
 
-		(pending_forward_info, channel_state.unwrap())
+		pending_forward_info
 	}
 
 	/// only fails if the channel does not yet have an assigned short_id
-	/// May be called with channel_state already locked!
+	/// Does not take any locks, so may be called with the channel (and/or channel_state) locked.
 	fn get_channel_update(&self, chan: &Channel<ChanSigner>) -> Result<msgs::ChannelUpdate, LightningError> {
 		let short_channel_id = match chan.get_short_channel_id() {
 			None => return Err(LightningError{err: "Channel not yet established", action: msgs::ErrorAction::IgnoreError}),
@@ -1244,17 +1339,17 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		}
 		let onion_packet = onion_utils::construct_onion_packet(onion_payloads, onion_keys, prng_seed, payment_hash);
 
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		let err: Result<(), _> = loop {
-			let mut channel_lock = self.channel_state.lock().unwrap();
-			let id = match channel_lock.short_to_id.get(&path.first().unwrap().short_channel_id) {
+			let id = match self.channel_state.lock().unwrap().short_to_id.get(&path.first().unwrap().short_channel_id) {
 				None => return Err(APIError::ChannelUnavailable{err: "No channel available with first hop!"}),
 				Some(id) => id.clone(),
 			};
 
-			let channel_state = &mut *channel_lock;
-			if let hash_map::Entry::Occupied(mut chan) = channel_state.by_id.entry(id) {
+			let chan_slot = self.get_channel_slot(&id);
+			let chan_opt = self.lock_channel(&chan_slot, id);
+			if let Some(mut chan) = chan_opt {
 				match {
 					if chan.get().get_their_node_id() != path.first().unwrap().pubkey {
 						return Err(APIError::RouteError{err: "Node ID mismatch on first hop!"});
@@ -1266,11 +1361,11 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						path: path.clone(),
 						session_priv: session_priv.clone(),
 						first_hop_htlc_msat: htlc_msat,
-					}, onion_packet, &self.logger), channel_state, chan)
+					}, onion_packet, &self.logger), chan)
 				} {
 					Some((update_add, commitment_signed, monitor_update)) => {
 						if let Err(e) = self.monitor.update_monitor(chan.get().get_funding_txo().unwrap(), monitor_update) {
-							maybe_break_monitor_err!(self, e, channel_state, chan, RAACommitmentOrder::CommitmentFirst, false, true);
+							maybe_break_monitor_err!(self, e, chan, RAACommitmentOrder::CommitmentFirst, false, true);
 							// Note that MonitorUpdateFailed here indicates (per function docs)
 							// that we will resend the commitment update once monitor updating
 							// is restored. Therefore, we must return an error indicating that
@@ -1279,7 +1374,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 							return Err(APIError::MonitorUpdateFailed);
 						}
 
-						channel_state.pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
+						self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
 							node_id: path.first().unwrap().pubkey,
 							updates: msgs::CommitmentUpdate {
 								update_add_htlcs: vec![update_add],
@@ -1293,7 +1388,10 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					},
 					None => {},
 				}
-			} else { unreachable!(); }
+			} else {
+				// The channel was closed after we looked up its id
+				return Err(APIError::ChannelUnavailable{err: "No channel available with first hop!"});
+			}
 			return Ok(());
 		};
 
@@ -1414,19 +1512,23 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	/// May panic if the funding_txo is duplicative with some other channel (note that this should
 	/// be trivially prevented by using unique funding transaction keys per-channel).
 	pub fn funding_transaction_generated(&self, temporary_channel_id: &[u8; 32], funding_txo: OutPoint) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		let (chan, msg) = {
-			let (res, chan) = match self.channel_state.lock().unwrap().by_id.remove(temporary_channel_id) {
-				Some(mut chan) => {
-					(chan.get_outbound_funding_created(funding_txo, &self.logger)
-						.map_err(|e| if let ChannelError::Close(msg) = e {
-							MsgHandleErrInternal::from_finish_shutdown(msg, chan.channel_id(), chan.force_shutdown(true), None)
-						} else { unreachable!(); })
-					, chan)
-				},
-				None => return
+			let mut chan = {
+				let chan_slot = self.get_channel_slot(temporary_channel_id);
+				let chan_opt = self.lock_channel(&chan_slot, *temporary_channel_id);
+				match chan_opt {
+					Some(mut chan_entry) => chan_entry.remove(),
+					None => return
+				}
 			};
+			// The channel is no longer in by_id, so we can generate the (signed) funding_created
+			// without holding any locks.
+			let res = chan.get_outbound_funding_created(funding_txo, &self.logger)
+				.map_err(|e| if let ChannelError::Close(msg) = e {
+					MsgHandleErrInternal::from_finish_shutdown(msg, chan.channel_id(), chan.force_shutdown(true), None)
+				} else { unreachable!(); });
 			match handle_error!(self, res, chan.get_their_node_id()) {
 				Ok(funding_msg) => {
 					(chan, funding_msg)
@@ -1445,7 +1547,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				panic!("Generated duplicate funding txid?");
 			},
 			hash_map::Entry::Vacant(e) => {
-				e.insert(chan);
+				e.insert(Arc::new(Mutex::new(Some(chan))));
 			}
 		}
 	}
@@ -1497,7 +1599,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	///
 	/// Panics if addresses is absurdly large (more than 500).
 	pub fn broadcast_node_announcement(&self, rgb: [u8; 3], alias: [u8; 32], addresses: Vec<msgs::NetAddress>) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		if addresses.len() > 500 {
 			panic!("More than half the message size was taken up by public addresses!");
@@ -1527,19 +1629,26 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	/// Should only really ever be called in response to a PendingHTLCsForwardable event.
 	/// Will likely generate further events.
 	pub fn process_pending_htlc_forwards(&self) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		let mut new_events = Vec::new();
 		let mut failed_forwards = Vec::new();
 		let mut handle_errors = Vec::new();
 		{
-			let mut channel_state_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_state_lock;
+			// Take the pending forwards out of channel_state so that we can handle each outbound
+			// channel in turn under its own lock.
+			let forward_htlcs = mem::replace(&mut self.channel_state.lock().unwrap().forward_htlcs, HashMap::new());
 
-			for (short_chan_id, mut pending_forwards) in channel_state.forward_htlcs.drain() {
+			for (short_chan_id, mut pending_forwards) in forward_htlcs {
 				if short_chan_id != 0 {
-					let forward_chan_id = match channel_state.short_to_id.get(&short_chan_id) {
-						Some(chan_id) => chan_id.clone(),
+					let forward_chan_id = self.channel_state.lock().unwrap().short_to_id.get(&short_chan_id).cloned();
+					let chan_slot = forward_chan_id.and_then(|chan_id| self.get_channel_slot(&chan_id));
+					let chan_opt = match forward_chan_id {
+						Some(chan_id) => self.lock_channel(&chan_slot, chan_id),
+						None => None,
+					};
+					let mut chan = match chan_opt {
+						Some(chan) => chan,
 						None => {
 							failed_forwards.reserve(pending_forwards.len());
 							for forward_info in pending_forwards.drain(..) {
@@ -1565,7 +1674,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 							continue;
 						}
 					};
-					if let hash_map::Entry::Occupied(mut chan) = channel_state.by_id.entry(forward_chan_id) {
+					{
 						let mut add_htlc_msgs = Vec::new();
 						let mut fail_htlc_msgs = Vec::new();
 						for forward_info in pending_forwards.drain(..) {
@@ -1656,9 +1765,6 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 										ChannelError::Close(msg) => {
 											log_trace!(self.logger, "Closing channel {} due to Close-required error: {}", log_bytes!(chan.key()[..]), msg);
 											let (channel_id, mut channel) = chan.remove_entry();
-											if let Some(short_id) = channel.get_short_channel_id() {
-												channel_state.short_to_id.remove(&short_id);
-											}
 											Err(MsgHandleErrInternal::from_finish_shutdown(msg, channel_id, channel.force_shutdown(true), self.get_channel_update(&channel).ok()))
 										},
 										ChannelError::CloseDelayBroadcast(_) => { panic!("Wait is only generated on receipt of channel_reestablish, which is handled by try_chan_entry, we don't bother to support it here"); }
@@ -1668,10 +1774,10 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 								}
 							};
 							if let Err(e) = self.monitor.update_monitor(chan.get().get_funding_txo().unwrap(), monitor_update) {
-								handle_errors.push((chan.get().get_their_node_id(), handle_monitor_err!(self, e, channel_state, chan, RAACommitmentOrder::CommitmentFirst, false, true)));
+								handle_errors.push((chan.get().get_their_node_id(), handle_monitor_err!(self, e, chan, RAACommitmentOrder::CommitmentFirst, false, true)));
 								continue;
 							}
-							channel_state.pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
+							self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
 								node_id: chan.get().get_their_node_id(),
 								updates: msgs::CommitmentUpdate {
 									update_add_htlcs: add_htlc_msgs,
@@ -1683,10 +1789,9 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 								},
 							});
 						}
-					} else {
-						unreachable!();
 					}
 				} else {
+					let mut channel_state = self.channel_state.lock().unwrap();
 					for forward_info in pending_forwards.drain(..) {
 						match forward_info {
 							HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
@@ -1780,13 +1885,16 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	///
 	/// This method handles all the details, and must be called roughly once per minute.
 	pub fn timer_chan_freshness_every_min(&self) {
-		let _ = self.total_consistency_lock.read().unwrap();
-		let mut channel_state_lock = self.channel_state.lock().unwrap();
-		let channel_state = &mut *channel_state_lock;
-		for (_, chan) in channel_state.by_id.iter_mut() {
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
+		for (channel_id, slot) in self.get_channel_slots() {
+			let mut chan_entry = match ChannelEntry::lock(&slot, channel_id, &self.channel_state) {
+				Some(chan_entry) => chan_entry,
+				None => continue,
+			};
+			let chan = chan_entry.get_mut();
 			if chan.is_disabled_staged() && !chan.is_live() {
 				if let Ok(update) = self.get_channel_update(&chan) {
-					channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+					self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 						msg: update
 					});
 				}
@@ -1805,7 +1913,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	/// Returns false if no payment was found to fail backwards, true if the process of failing the
 	/// HTLC backwards has been started.
 	pub fn fail_htlc_backwards(&self, payment_hash: &PaymentHash, payment_secret: &Option<PaymentSecret>) -> bool {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		let mut channel_state = Some(self.channel_state.lock().unwrap());
 		let removed_source = channel_state.as_mut().unwrap().claimable_htlcs.remove(&(*payment_hash, *payment_secret));
@@ -1946,15 +2054,15 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	pub fn claim_funds(&self, payment_preimage: PaymentPreimage, payment_secret: &Option<PaymentSecret>, expected_amount: u64) -> bool {
 		let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0).into_inner());
 
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
-		let mut channel_state = Some(self.channel_state.lock().unwrap());
-		let removed_source = channel_state.as_mut().unwrap().claimable_htlcs.remove(&(payment_hash, *payment_secret));
+		let removed_source = self.channel_state.lock().unwrap().claimable_htlcs.remove(&(payment_hash, *payment_secret));
 		if let Some(mut sources) = removed_source {
 			assert!(!sources.is_empty());
 
 			// If we are claiming an MPP payment, we have to take special care to ensure that each
-			// channel exists before claiming all of the payments (inside one lock).
+			// channel exists before claiming any of the payments, and that none are closed until
+			// we've claimed from all of them, so we lock all of them first.
 			// Note that channel existance is sufficient as we should always get a monitor update
 			// which will take care of the real HTLC claim enforcement.
 			//
@@ -1973,27 +2081,74 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				(false, false)
 			};
 
-			for htlc in sources.iter() {
-				if !is_mpp || !valid_mpp { break; }
-				if let None = channel_state.as_ref().unwrap().short_to_id.get(&htlc.prev_hop.short_channel_id) {
-					valid_mpp = false;
+			let mut errs = Vec::new();
+			let mut claimed_any_htlcs = false;
+			if is_mpp && valid_mpp {
+				let mut chan_ids = Vec::with_capacity(sources.len());
+				{
+					let channel_state = self.channel_state.lock().unwrap();
+					for htlc in sources.iter() {
+						match channel_state.short_to_id.get(&htlc.prev_hop.short_channel_id) {
+							Some(chan_id) => chan_ids.push(*chan_id),
+							None => {
+								valid_mpp = false;
+								break;
+							},
+						}
+					}
+				}
+				if valid_mpp {
+					// As we hold more than one channel lock at once we must take them in channel_id
+					// order, as ChannelManager::write does.
+					let mut locking_order = chan_ids.clone();
+					locking_order.sort();
+					locking_order.dedup();
+					let chan_slots: Vec<_> = locking_order.iter().map(|chan_id| (*chan_id, self.get_channel_slot(chan_id))).collect();
+					let mut chans = Vec::with_capacity(chan_slots.len());
+					for &(chan_id, ref chan_slot) in chan_slots.iter() {
+						match self.lock_channel(chan_slot, chan_id) {
+							Some(chan) => chans.push(chan),
+							None => {
+								valid_mpp = false;
+								break;
+							},
+						}
+					}
+					if valid_mpp {
+						for (htlc, chan_id) in sources.drain(..).zip(chan_ids.iter()) {
+							let chan = chans.iter_mut().find(|chan| chan.key() == chan_id).unwrap();
+							if chan.channel.is_none() {
+								// A previous claim from the same channel hit a permanent monitor
+								// failure and closed it, which will be handled on-chain.
+								continue;
+							}
+							match self.claim_funds_from_channel(chan, htlc.prev_hop.htlc_id, payment_preimage) {
+								Err(Some(e)) => {
+									if let msgs::ErrorAction::IgnoreError = e.1.err.action {
+										// As below, the HTLC will be claimed once the monitor is restored.
+										log_error!(self.logger, "Temporary failure claiming HTLC, treating as success: {}", e.1.err.err);
+										claimed_any_htlcs = true;
+									} else { errs.push(e); }
+								},
+								Err(None) => {},
+								Ok(()) => claimed_any_htlcs = true,
+							}
+						}
+					}
 				}
 			}
 
-			let mut errs = Vec::new();
-			let mut claimed_any_htlcs = false;
 			for htlc in sources.drain(..) {
-				if channel_state.is_none() { channel_state = Some(self.channel_state.lock().unwrap()); }
 				if (is_mpp && !valid_mpp) || (!is_mpp && (htlc.value < expected_amount || htlc.value > expected_amount * 2)) {
 					let mut htlc_msat_height_data = byte_utils::be64_to_array(htlc.value).to_vec();
 					htlc_msat_height_data.extend_from_slice(&byte_utils::be32_to_array(
 						self.latest_block_height.load(Ordering::Acquire) as u32,
 					));
-					self.fail_htlc_backwards_internal(channel_state.take().unwrap(),
+					self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(),
 									 HTLCSource::PreviousHopData(htlc.prev_hop), &payment_hash,
 									 HTLCFailReason::Reason { failure_code: 0x4000|15, data: htlc_msat_height_data });
 				} else {
-					match self.claim_funds_from_hop(channel_state.as_mut().unwrap(), htlc.prev_hop, payment_preimage) {
+					match self.claim_funds_from_hop(htlc.prev_hop, payment_preimage) {
 						Err(Some(e)) => {
 							if let msgs::ErrorAction::IgnoreError = e.1.err.action {
 								// We got a temporary failure updating monitor, but will claim the
@@ -2002,7 +2157,6 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 								claimed_any_htlcs = true;
 							} else { errs.push(e); }
 						},
-						Err(None) if is_mpp => unreachable!("We already checked for channel existence, we can't fail here!"),
 						Err(None) => {
 							log_warn!(self.logger, "Channel we expected to claim an HTLC from was closed.");
 						},
@@ -2011,10 +2165,8 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				}
 			}
 
-			// Now that we've done the entire above loop in one lock, we can handle any errors
-			// which were generated.
-			channel_state.take();
-
+			// Now that we've done the entire above loop without holding any channel locks, we can
+			// handle any errors which were generated.
 			for (their_node_id, err) in errs.drain(..) {
 				let res: Result<(), _> = Err(err);
 				let _ = handle_error!(self, res, their_node_id);
@@ -2024,70 +2176,80 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		} else { false }
 	}
 
-	fn claim_funds_from_hop(&self, channel_state_lock: &mut MutexGuard<ChannelHolder<ChanSigner>>, prev_hop: HTLCPreviousHopData, payment_preimage: PaymentPreimage) -> Result<(), Option<(PublicKey, MsgHandleErrInternal)>> {
+	/// Must not be called while holding the channel_state lock or any channel lock.
+	fn claim_funds_from_hop(&self, prev_hop: HTLCPreviousHopData, payment_preimage: PaymentPreimage) -> Result<(), Option<(PublicKey, MsgHandleErrInternal)>> {
 		//TODO: Delay the claimed_funds relaying just like we do outbound relay!
-		let channel_state = &mut **channel_state_lock;
-		let chan_id = match channel_state.short_to_id.get(&prev_hop.short_channel_id) {
+		let chan_id = match self.channel_state.lock().unwrap().short_to_id.get(&prev_hop.short_channel_id) {
 			Some(chan_id) => chan_id.clone(),
 			None => {
 				return Err(None)
 			}
 		};
 
-		if let hash_map::Entry::Occupied(mut chan) = channel_state.by_id.entry(chan_id) {
-			let was_frozen_for_monitor = chan.get().is_awaiting_monitor_update();
-			match chan.get_mut().get_update_fulfill_htlc_and_commit(prev_hop.htlc_id, payment_preimage, &self.logger) {
-				Ok((msgs, monitor_option)) => {
-					if let Some(monitor_update) = monitor_option {
-						if let Err(e) = self.monitor.update_monitor(chan.get().get_funding_txo().unwrap(), monitor_update) {
-							if was_frozen_for_monitor {
-								assert!(msgs.is_none());
-							} else {
-								return Err(Some((chan.get().get_their_node_id(), handle_monitor_err!(self, e, channel_state, chan, RAACommitmentOrder::CommitmentFirst, false, msgs.is_some()).unwrap_err())));
-							}
+		let chan_slot = self.get_channel_slot(&chan_id);
+		let chan_opt = self.lock_channel(&chan_slot, chan_id);
+		if let Some(mut chan) = chan_opt {
+			self.claim_funds_from_channel(&mut chan, prev_hop.htlc_id, payment_preimage)
+		} else {
+			// The channel was closed after we looked up its id
+			return Err(None)
+		}
+	}
+
+	/// Claims the given HTLC from a channel we've locked. Must not be called while holding the
+	/// channel_state lock.
+	fn claim_funds_from_channel(&self, chan: &mut ChannelEntry<ChanSigner>, htlc_id: u64, payment_preimage: PaymentPreimage) -> Result<(), Option<(PublicKey, MsgHandleErrInternal)>> {
+		let was_frozen_for_monitor = chan.get().is_awaiting_monitor_update();
+		match chan.get_mut().get_update_fulfill_htlc_and_commit(htlc_id, payment_preimage, &self.logger) {
+			Ok((msgs, monitor_option)) => {
+				if let Some(monitor_update) = monitor_option {
+					if let Err(e) = self.monitor.update_monitor(chan.get().get_funding_txo().unwrap(), monitor_update) {
+						if was_frozen_for_monitor {
+							assert!(msgs.is_none());
+						} else {
+							return Err(Some((chan.get().get_their_node_id(), handle_monitor_err!(self, e, chan, RAACommitmentOrder::CommitmentFirst, false, msgs.is_some()).unwrap_err())));
 						}
 					}
-					if let Some((msg, commitment_signed)) = msgs {
-						channel_state.pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
-							node_id: chan.get().get_their_node_id(),
-							updates: msgs::CommitmentUpdate {
-								update_add_htlcs: Vec::new(),
-								update_fulfill_htlcs: vec![msg],
-								update_fail_htlcs: Vec::new(),
-								update_fail_malformed_htlcs: Vec::new(),
-								update_fee: None,
-								commitment_signed,
-							}
-						});
-					}
-					return Ok(())
-				},
-				Err(e) => {
-					// TODO: Do something with e?
-					// This should only occur if we are claiming an HTLC at the same time as the
-					// HTLC is being failed (eg because a block is being connected and this caused
-					// an HTLC to time out). This should, of course, only occur if the user is the
-					// one doing the claiming (as it being a part of a peer claim would imply we're
-					// about to lose funds) and only if the lock in claim_funds was dropped as a
-					// previous HTLC was failed (thus not for an MPP payment).
-					debug_assert!(false, "This shouldn't be reachable except in absurdly rare cases between monitor updates and HTLC timeouts: {:?}", e);
-					return Err(None)
-				},
-			}
-		} else { unreachable!(); }
+				}
+				if let Some((msg, commitment_signed)) = msgs {
+					self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
+						node_id: chan.get().get_their_node_id(),
+						updates: msgs::CommitmentUpdate {
+							update_add_htlcs: Vec::new(),
+							update_fulfill_htlcs: vec![msg],
+							update_fail_htlcs: Vec::new(),
+							update_fail_malformed_htlcs: Vec::new(),
+							update_fee: None,
+							commitment_signed,
+						}
+					});
+				}
+				return Ok(())
+			},
+			Err(e) => {
+				// TODO: Do something with e?
+				// This should only occur if we are claiming an HTLC at the same time as the
+				// HTLC is being failed (eg because a block is being connected and this caused
+				// an HTLC to time out). This should, of course, only occur if the user is the
+				// one doing the claiming (as it being a part of a peer claim would imply we're
+				// about to lose funds).
+				debug_assert!(false, "This shouldn't be reachable except in absurdly rare cases between monitor updates and HTLC timeouts: {:?}", e);
+				return Err(None)
+			},
+		}
 	}
 
-	fn claim_funds_internal(&self, mut channel_state_lock: MutexGuard<ChannelHolder<ChanSigner>>, source: HTLCSource, payment_preimage: PaymentPreimage) {
+	/// Must not be called while holding the channel_state lock or any channel lock.
+	fn claim_funds_internal(&self, source: HTLCSource, payment_preimage: PaymentPreimage) {
 		match source {
 			HTLCSource::OutboundRoute { .. } => {
-				mem::drop(channel_state_lock);
 				let mut pending_events = self.pending_events.lock().unwrap();
 				pending_events.push(events::Event::PaymentSent {
 					payment_preimage
 				});
 			},
 			HTLCSource::PreviousHopData(hop_data) => {
-				if let Err((their_node_id, err)) = match self.claim_funds_from_hop(&mut channel_state_lock, hop_data, payment_preimage) {
+				if let Err((their_node_id, err)) = match self.claim_funds_from_hop(hop_data, payment_preimage) {
 					Ok(()) => Ok(()),
 					Err(None) => {
 						// TODO: There is probably a channel monitor somewhere that needs to
@@ -2097,7 +2259,6 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					},
 					Err(Some(res)) => Err(res),
 				} {
-					mem::drop(channel_state_lock);
 					let res: Result<(), _> = Err(err);
 					let _ = handle_error!(self, res, their_node_id);
 				}
@@ -2131,7 +2292,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	///  4) once all remote copies are updated, you call this function with the update_id that
 	///     completed, and once it is the latest the Channel will be re-enabled.
 	pub fn channel_monitor_updated(&self, funding_txo: &OutPoint, highest_applied_update_id: u64) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		let mut close_results = Vec::new();
 		let mut htlc_forwards = Vec::new();
@@ -2139,19 +2300,23 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		let mut pending_events = Vec::new();
 
 		{
-			let mut channel_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_lock;
-			let short_to_id = &mut channel_state.short_to_id;
-			let pending_msg_events = &mut channel_state.pending_msg_events;
-			let channel = match channel_state.by_id.get_mut(&funding_txo.to_channel_id()) {
+			let chan_slot = self.get_channel_slot(&funding_txo.to_channel_id());
+			let chan_opt = self.lock_channel(&chan_slot, funding_txo.to_channel_id());
+			let mut chan = match chan_opt {
 				Some(chan) => chan,
 				None => return,
 			};
+			let channel = chan.get_mut();
 			if !channel.is_awaiting_monitor_update() || channel.get_latest_monitor_update_id() != highest_applied_update_id {
 				return;
 			}
 
 			let (raa, commitment_update, order, pending_forwards, mut pending_failures, needs_broadcast_safe, funding_locked) = channel.monitor_updating_restored(&self.logger);
+
+			let mut channel_lock = self.channel_state.lock().unwrap();
+			let channel_state = &mut *channel_lock;
+			let short_to_id = &mut channel_state.short_to_id;
+			let pending_msg_events = &mut channel_state.pending_msg_events;
 			if !pending_forwards.is_empty() {
 				htlc_forwards.push((channel.get_short_channel_id().expect("We can't have pending forwards before funding confirmation"), pending_forwards));
 			}
@@ -2232,7 +2397,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					node_id: their_node_id.clone(),
 					msg: channel.get_accept_channel(),
 				});
-				entry.insert(channel);
+				entry.insert(Arc::new(Mutex::new(Some(channel))));
 			}
 		}
 		Ok(())
@@ -2240,17 +2405,17 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 	fn internal_accept_channel(&self, their_node_id: &PublicKey, their_features: InitFeatures, msg: &msgs::AcceptChannel) -> Result<(), MsgHandleErrInternal> {
 		let (value, output_script, user_id) = {
-			let mut channel_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_lock;
-			match channel_state.by_id.entry(msg.temporary_channel_id) {
-				hash_map::Entry::Occupied(mut chan) => {
+			let chan_slot = self.get_channel_slot(&msg.temporary_channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, msg.temporary_channel_id);
+			match chan_opt {
+				Some(mut chan) => {
 					if chan.get().get_their_node_id() != *their_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.temporary_channel_id));
 					}
-					try_chan_entry!(self, chan.get_mut().accept_channel(&msg, &self.default_configuration, their_features), channel_state, chan);
+					try_chan_entry!(self, chan.get_mut().accept_channel(&msg, &self.default_configuration, their_features), chan);
 					(chan.get().get_value_satoshis(), chan.get().get_funding_redeemscript().to_v0_p2wsh(), chan.get().get_user_id())
 				},
-				hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.temporary_channel_id))
+				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.temporary_channel_id))
 			}
 		};
 		let mut pending_events = self.pending_events.lock().unwrap();
@@ -2265,19 +2430,19 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 	fn internal_funding_created(&self, their_node_id: &PublicKey, msg: &msgs::FundingCreated) -> Result<(), MsgHandleErrInternal> {
 		let ((funding_msg, monitor_update), mut chan) = {
-			let mut channel_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_lock;
-			match channel_state.by_id.entry(msg.temporary_channel_id.clone()) {
-				hash_map::Entry::Occupied(mut chan) => {
+			let chan_slot = self.get_channel_slot(&msg.temporary_channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, msg.temporary_channel_id);
+			match chan_opt {
+				Some(mut chan) => {
 					if chan.get().get_their_node_id() != *their_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.temporary_channel_id));
 					}
-					(try_chan_entry!(self, chan.get_mut().funding_created(msg, &self.logger), channel_state, chan), chan.remove())
+					(try_chan_entry!(self, chan.get_mut().funding_created(msg, &self.logger), chan), chan.remove())
 				},
-				hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.temporary_channel_id))
+				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.temporary_channel_id))
 			}
 		};
-		// Because we have exclusive ownership of the channel here we can release the channel_state
+		// Because we have exclusive ownership of the channel here we can release the channel's
 		// lock before add_monitor
 		if let Err(e) = self.monitor.add_monitor(monitor_update.get_funding_txo(), monitor_update) {
 			match e {
@@ -2308,7 +2473,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					node_id: their_node_id.clone(),
 					msg: funding_msg,
 				});
-				e.insert(chan);
+				e.insert(Arc::new(Mutex::new(Some(chan))));
 			}
 		}
 		Ok(())
@@ -2316,23 +2481,23 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 	fn internal_funding_signed(&self, their_node_id: &PublicKey, msg: &msgs::FundingSigned) -> Result<(), MsgHandleErrInternal> {
 		let (funding_txo, user_id) = {
-			let mut channel_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_lock;
-			match channel_state.by_id.entry(msg.channel_id) {
-				hash_map::Entry::Occupied(mut chan) => {
+			let chan_slot = self.get_channel_slot(&msg.channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+			match chan_opt {
+				Some(mut chan) => {
 					if chan.get().get_their_node_id() != *their_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 					}
 					let monitor = match chan.get_mut().funding_signed(&msg, &self.logger) {
 						Ok(update) => update,
-						Err(e) => try_chan_entry!(self, Err(e), channel_state, chan),
+						Err(e) => try_chan_entry!(self, Err(e), chan),
 					};
 					if let Err(e) = self.monitor.add_monitor(chan.get().get_funding_txo().unwrap(), monitor) {
-						return_monitor_err!(self, e, channel_state, chan, RAACommitmentOrder::RevokeAndACKFirst, false, false);
+						return_monitor_err!(self, e, chan, RAACommitmentOrder::RevokeAndACKFirst, false, false);
 					}
 					(chan.get().get_funding_txo().unwrap(), chan.get().get_user_id())
 				},
-				hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 			}
 		};
 		let mut pending_events = self.pending_events.lock().unwrap();
@@ -2344,14 +2509,14 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	}
 
 	fn internal_funding_locked(&self, their_node_id: &PublicKey, msg: &msgs::FundingLocked) -> Result<(), MsgHandleErrInternal> {
-		let mut channel_state_lock = self.channel_state.lock().unwrap();
-		let channel_state = &mut *channel_state_lock;
-		match channel_state.by_id.entry(msg.channel_id) {
-			hash_map::Entry::Occupied(mut chan) => {
+		let chan_slot = self.get_channel_slot(&msg.channel_id);
+		let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+		match chan_opt {
+			Some(mut chan) => {
 				if chan.get().get_their_node_id() != *their_node_id {
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
-				try_chan_entry!(self, chan.get_mut().funding_locked(&msg), channel_state, chan);
+				try_chan_entry!(self, chan.get_mut().funding_locked(&msg), chan);
 				if let Some(announcement_sigs) = self.get_announcement_sigs(chan.get()) {
 					log_trace!(self.logger, "Sending announcement_signatures for {} in response to funding_locked", log_bytes!(chan.get().channel_id()));
 					// If we see locking block before receiving remote funding_locked, we broadcast our
@@ -2363,48 +2528,44 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					// connection in the future if simultaneous misses by both peers due to network/hardware
 					// failures is an issue. Note, to achieve its goal, only one of the announcement_sigs needs
 					// to be received, from then sigs are going to be flood to the whole network.
-					channel_state.pending_msg_events.push(events::MessageSendEvent::SendAnnouncementSignatures {
+					self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::SendAnnouncementSignatures {
 						node_id: their_node_id.clone(),
 						msg: announcement_sigs,
 					});
 				}
 				Ok(())
 			},
-			hash_map::Entry::Vacant(_) => Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+			None => Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		}
 	}
 
 	fn internal_shutdown(&self, their_node_id: &PublicKey, msg: &msgs::Shutdown) -> Result<(), MsgHandleErrInternal> {
 		let (mut dropped_htlcs, chan_option) = {
-			let mut channel_state_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_state_lock;
-
-			match channel_state.by_id.entry(msg.channel_id.clone()) {
-				hash_map::Entry::Occupied(mut chan_entry) => {
+			let chan_slot = self.get_channel_slot(&msg.channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+			match chan_opt {
+				Some(mut chan_entry) => {
 					if chan_entry.get().get_their_node_id() != *their_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 					}
-					let (shutdown, closing_signed, dropped_htlcs) = try_chan_entry!(self, chan_entry.get_mut().shutdown(&self.fee_estimator, &msg), channel_state, chan_entry);
+					let (shutdown, closing_signed, dropped_htlcs) = try_chan_entry!(self, chan_entry.get_mut().shutdown(&self.fee_estimator, &msg), chan_entry);
 					if let Some(msg) = shutdown {
-						channel_state.pending_msg_events.push(events::MessageSendEvent::SendShutdown {
+						self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::SendShutdown {
 							node_id: their_node_id.clone(),
 							msg,
 						});
 					}
 					if let Some(msg) = closing_signed {
-						channel_state.pending_msg_events.push(events::MessageSendEvent::SendClosingSigned {
+						self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::SendClosingSigned {
 							node_id: their_node_id.clone(),
 							msg,
 						});
 					}
 					if chan_entry.get().is_shutdown() {
-						if let Some(short_id) = chan_entry.get().get_short_channel_id() {
-							channel_state.short_to_id.remove(&short_id);
-						}
 						(dropped_htlcs, Some(chan_entry.remove_entry().1))
 					} else { (dropped_htlcs, None) }
 				},
-				hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 			}
 		};
 		for htlc_source in dropped_htlcs.drain(..) {
@@ -2423,16 +2584,16 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 	fn internal_closing_signed(&self, their_node_id: &PublicKey, msg: &msgs::ClosingSigned) -> Result<(), MsgHandleErrInternal> {
 		let (tx, chan_option) = {
-			let mut channel_state_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_state_lock;
-			match channel_state.by_id.entry(msg.channel_id.clone()) {
-				hash_map::Entry::Occupied(mut chan_entry) => {
+			let chan_slot = self.get_channel_slot(&msg.channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+			match chan_opt {
+				Some(mut chan_entry) => {
 					if chan_entry.get().get_their_node_id() != *their_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 					}
-					let (closing_signed, tx) = try_chan_entry!(self, chan_entry.get_mut().closing_signed(&self.fee_estimator, &msg), channel_state, chan_entry);
+					let (closing_signed, tx) = try_chan_entry!(self, chan_entry.get_mut().closing_signed(&self.fee_estimator, &msg), chan_entry);
 					if let Some(msg) = closing_signed {
-						channel_state.pending_msg_events.push(events::MessageSendEvent::SendClosingSigned {
+						self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::SendClosingSigned {
 							node_id: their_node_id.clone(),
 							msg,
 						});
@@ -2443,13 +2604,10 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						// also implies there are no pending HTLCs left on the channel, so we can
 						// fully delete it from tracking (the channel monitor is still around to
 						// watch for old state broadcasts)!
-						if let Some(short_id) = chan_entry.get().get_short_channel_id() {
-							channel_state.short_to_id.remove(&short_id);
-						}
 						(tx, Some(chan_entry.remove_entry().1))
 					} else { (tx, None) }
 				},
-				hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 			}
 		};
 		if let Some(broadcast_tx) = tx {
@@ -2477,11 +2635,14 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		//encrypted with the same key. It's not immediately obvious how to usefully exploit that,
 		//but we should prevent it anyway.
 
-		let (mut pending_forward_info, mut channel_state_lock) = self.decode_update_add_htlc_onion(msg);
-		let channel_state = &mut *channel_state_lock;
+		// Note that this must happen before we lock the inbound channel, as the outbound channel is
+		// locked while checking the HTLC's forwarding parameters.
+		let mut pending_forward_info = self.decode_update_add_htlc_onion(msg);
 
-		match channel_state.by_id.entry(msg.channel_id) {
-			hash_map::Entry::Occupied(mut chan) => {
+		let chan_slot = self.get_channel_slot(&msg.channel_id);
+		let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+		match chan_opt {
+			Some(mut chan) => {
 				if chan.get().get_their_node_id() != *their_node_id {
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
@@ -2517,88 +2678,89 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						}));
 					}
 				}
-				try_chan_entry!(self, chan.get_mut().update_add_htlc(&msg, pending_forward_info), channel_state, chan);
+				try_chan_entry!(self, chan.get_mut().update_add_htlc(&msg, pending_forward_info), chan);
 			},
-			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		}
 		Ok(())
 	}
 
 	fn internal_update_fulfill_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFulfillHTLC) -> Result<(), MsgHandleErrInternal> {
-		let mut channel_lock = self.channel_state.lock().unwrap();
 		let htlc_source = {
-			let channel_state = &mut *channel_lock;
-			match channel_state.by_id.entry(msg.channel_id) {
-				hash_map::Entry::Occupied(mut chan) => {
+			let chan_slot = self.get_channel_slot(&msg.channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+			match chan_opt {
+				Some(mut chan) => {
 					if chan.get().get_their_node_id() != *their_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 					}
-					try_chan_entry!(self, chan.get_mut().update_fulfill_htlc(&msg), channel_state, chan)
+					try_chan_entry!(self, chan.get_mut().update_fulfill_htlc(&msg), chan)
 				},
-				hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 			}
 		};
-		self.claim_funds_internal(channel_lock, htlc_source, msg.payment_preimage.clone());
+		self.claim_funds_internal(htlc_source, msg.payment_preimage.clone());
 		Ok(())
 	}
 
 	fn internal_update_fail_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFailHTLC) -> Result<(), MsgHandleErrInternal> {
-		let mut channel_lock = self.channel_state.lock().unwrap();
-		let channel_state = &mut *channel_lock;
-		match channel_state.by_id.entry(msg.channel_id) {
-			hash_map::Entry::Occupied(mut chan) => {
+		let chan_slot = self.get_channel_slot(&msg.channel_id);
+		let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+		match chan_opt {
+			Some(mut chan) => {
 				if chan.get().get_their_node_id() != *their_node_id {
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
-				try_chan_entry!(self, chan.get_mut().update_fail_htlc(&msg, HTLCFailReason::LightningError { err: msg.reason.clone() }), channel_state, chan);
+				try_chan_entry!(self, chan.get_mut().update_fail_htlc(&msg, HTLCFailReason::LightningError { err: msg.reason.clone() }), chan);
 			},
-			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		}
 		Ok(())
 	}
 
 	fn internal_update_fail_malformed_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFailMalformedHTLC) -> Result<(), MsgHandleErrInternal> {
-		let mut channel_lock = self.channel_state.lock().unwrap();
-		let channel_state = &mut *channel_lock;
-		match channel_state.by_id.entry(msg.channel_id) {
-			hash_map::Entry::Occupied(mut chan) => {
+		let chan_slot = self.get_channel_slot(&msg.channel_id);
+		let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+		match chan_opt {
+			Some(mut chan) => {
 				if chan.get().get_their_node_id() != *their_node_id {
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
 				if (msg.failure_code & 0x8000) == 0 {
 					let chan_err: ChannelError = ChannelError::Close("Got update_fail_malformed_htlc with BADONION not set");
-					try_chan_entry!(self, Err(chan_err), channel_state, chan);
+					try_chan_entry!(self, Err(chan_err), chan);
 				}
-				try_chan_entry!(self, chan.get_mut().update_fail_malformed_htlc(&msg, HTLCFailReason::Reason { failure_code: msg.failure_code, data: Vec::new() }), channel_state, chan);
+				try_chan_entry!(self, chan.get_mut().update_fail_malformed_htlc(&msg, HTLCFailReason::Reason { failure_code: msg.failure_code, data: Vec::new() }), chan);
 				Ok(())
 			},
-			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		}
 	}
 
 	fn internal_commitment_signed(&self, their_node_id: &PublicKey, msg: &msgs::CommitmentSigned) -> Result<(), MsgHandleErrInternal> {
-		let mut channel_state_lock = self.channel_state.lock().unwrap();
-		let channel_state = &mut *channel_state_lock;
-		match channel_state.by_id.entry(msg.channel_id) {
-			hash_map::Entry::Occupied(mut chan) => {
+		let chan_slot = self.get_channel_slot(&msg.channel_id);
+		let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+		match chan_opt {
+			Some(mut chan) => {
 				if chan.get().get_their_node_id() != *their_node_id {
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
 				let (revoke_and_ack, commitment_signed, closing_signed, monitor_update) =
 					match chan.get_mut().commitment_signed(&msg, &self.fee_estimator, &self.logger) {
-						Err((None, e)) => try_chan_entry!(self, Err(e), channel_state, chan),
+						Err((None, e)) => try_chan_entry!(self, Err(e), chan),
 						Err((Some(update), e)) => {
 							assert!(chan.get().is_awaiting_monitor_update());
 							let _ = self.monitor.update_monitor(chan.get().get_funding_txo().unwrap(), update);
-							try_chan_entry!(self, Err(e), channel_state, chan);
+							try_chan_entry!(self, Err(e), chan);
 							unreachable!();
 						},
 						Ok(res) => res
 					};
 				if let Err(e) = self.monitor.update_monitor(chan.get().get_funding_txo().unwrap(), monitor_update) {
-					return_monitor_err!(self, e, channel_state, chan, RAACommitmentOrder::RevokeAndACKFirst, true, commitment_signed.is_some());
+					return_monitor_err!(self, e, chan, RAACommitmentOrder::RevokeAndACKFirst, true, commitment_signed.is_some());
 					//TODO: Rebroadcast closing_signed if present on monitor update restoration
 				}
+				let mut channel_state = self.channel_state.lock().unwrap();
 				channel_state.pending_msg_events.push(events::MessageSendEvent::SendRevokeAndACK {
 					node_id: their_node_id.clone(),
 					msg: revoke_and_ack,
@@ -2624,7 +2786,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				}
 				Ok(())
 			},
-			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		}
 	}
 
@@ -2665,24 +2827,25 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 	fn internal_revoke_and_ack(&self, their_node_id: &PublicKey, msg: &msgs::RevokeAndACK) -> Result<(), MsgHandleErrInternal> {
 		let (pending_forwards, mut pending_failures, short_channel_id) = {
-			let mut channel_state_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_state_lock;
-			match channel_state.by_id.entry(msg.channel_id) {
-				hash_map::Entry::Occupied(mut chan) => {
+			let chan_slot = self.get_channel_slot(&msg.channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+			match chan_opt {
+				Some(mut chan) => {
 					if chan.get().get_their_node_id() != *their_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 					}
 					let was_frozen_for_monitor = chan.get().is_awaiting_monitor_update();
 					let (commitment_update, pending_forwards, pending_failures, closing_signed, monitor_update) =
-						try_chan_entry!(self, chan.get_mut().revoke_and_ack(&msg, &self.fee_estimator, &self.logger), channel_state, chan);
+						try_chan_entry!(self, chan.get_mut().revoke_and_ack(&msg, &self.fee_estimator, &self.logger), chan);
 					if let Err(e) = self.monitor.update_monitor(chan.get().get_funding_txo().unwrap(), monitor_update) {
 						if was_frozen_for_monitor {
 							assert!(commitment_update.is_none() && closing_signed.is_none() && pending_forwards.is_empty() && pending_failures.is_empty());
 							return Err(MsgHandleErrInternal::ignore_no_close("Previous monitor update failure prevented responses to RAA"));
 						} else {
-							return_monitor_err!(self, e, channel_state, chan, RAACommitmentOrder::CommitmentFirst, false, commitment_update.is_some(), pending_forwards, pending_failures);
+							return_monitor_err!(self, e, chan, RAACommitmentOrder::CommitmentFirst, false, commitment_update.is_some(), pending_forwards, pending_failures);
 						}
 					}
+					let mut channel_state = self.channel_state.lock().unwrap();
 					if let Some(updates) = commitment_update {
 						channel_state.pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
 							node_id: their_node_id.clone(),
@@ -2697,7 +2860,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					}
 					(pending_forwards, pending_failures, chan.get().get_short_channel_id().expect("RAA should only work on a short-id-available channel"))
 				},
-				hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+				None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 			}
 		};
 		for failure in pending_failures.drain(..) {
@@ -2709,26 +2872,25 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	}
 
 	fn internal_update_fee(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFee) -> Result<(), MsgHandleErrInternal> {
-		let mut channel_lock = self.channel_state.lock().unwrap();
-		let channel_state = &mut *channel_lock;
-		match channel_state.by_id.entry(msg.channel_id) {
-			hash_map::Entry::Occupied(mut chan) => {
+		let chan_slot = self.get_channel_slot(&msg.channel_id);
+		let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+		match chan_opt {
+			Some(mut chan) => {
 				if chan.get().get_their_node_id() != *their_node_id {
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
-				try_chan_entry!(self, chan.get_mut().update_fee(&self.fee_estimator, &msg), channel_state, chan);
+				try_chan_entry!(self, chan.get_mut().update_fee(&self.fee_estimator, &msg), chan);
 			},
-			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		}
 		Ok(())
 	}
 
 	fn internal_announcement_signatures(&self, their_node_id: &PublicKey, msg: &msgs::AnnouncementSignatures) -> Result<(), MsgHandleErrInternal> {
-		let mut channel_state_lock = self.channel_state.lock().unwrap();
-		let channel_state = &mut *channel_state_lock;
-
-		match channel_state.by_id.entry(msg.channel_id) {
-			hash_map::Entry::Occupied(mut chan) => {
+		let chan_slot = self.get_channel_slot(&msg.channel_id);
+		let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+		match chan_opt {
+			Some(mut chan) => {
 				if chan.get().get_their_node_id() != *their_node_id {
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
@@ -2738,19 +2900,19 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 				let our_node_id = self.get_our_node_id();
 				let (announcement, our_bitcoin_sig) =
-					try_chan_entry!(self, chan.get_mut().get_channel_announcement(our_node_id.clone(), self.genesis_hash.clone()), channel_state, chan);
+					try_chan_entry!(self, chan.get_mut().get_channel_announcement(our_node_id.clone(), self.genesis_hash.clone()), chan);
 
 				let were_node_one = announcement.node_id_1 == our_node_id;
 				let msghash = hash_to_message!(&Sha256dHash::hash(&announcement.encode()[..])[..]);
 				if self.secp_ctx.verify(&msghash, &msg.node_signature, if were_node_one { &announcement.node_id_2 } else { &announcement.node_id_1 }).is_err() ||
 						self.secp_ctx.verify(&msghash, &msg.bitcoin_signature, if were_node_one { &announcement.bitcoin_key_2 } else { &announcement.bitcoin_key_1 }).is_err() {
 					let chan_err: ChannelError = ChannelError::Close("Bad announcement_signatures node_signature");
-					try_chan_entry!(self, Err(chan_err), channel_state, chan);
+					try_chan_entry!(self, Err(chan_err), chan);
 				}
 
 				let our_node_sig = self.secp_ctx.sign(&msghash, &self.our_network_key);
 
-				channel_state.pending_msg_events.push(events::MessageSendEvent::BroadcastChannelAnnouncement {
+				self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::BroadcastChannelAnnouncement {
 					msg: msgs::ChannelAnnouncement {
 						node_signature_1: if were_node_one { our_node_sig } else { msg.node_signature },
 						node_signature_2: if were_node_one { msg.node_signature } else { our_node_sig },
@@ -2761,22 +2923,21 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					update_msg: self.get_channel_update(chan.get()).unwrap(), // can only fail if we're not in a ready state
 				});
 			},
-			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		}
 		Ok(())
 	}
 
 	fn internal_channel_reestablish(&self, their_node_id: &PublicKey, msg: &msgs::ChannelReestablish) -> Result<(), MsgHandleErrInternal> {
-		let mut channel_state_lock = self.channel_state.lock().unwrap();
-		let channel_state = &mut *channel_state_lock;
-
-		match channel_state.by_id.entry(msg.channel_id) {
-			hash_map::Entry::Occupied(mut chan) => {
+		let chan_slot = self.get_channel_slot(&msg.channel_id);
+		let chan_opt = self.lock_channel(&chan_slot, msg.channel_id);
+		match chan_opt {
+			Some(mut chan) => {
 				if chan.get().get_their_node_id() != *their_node_id {
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!", msg.channel_id));
 				}
 				let (funding_locked, revoke_and_ack, commitment_update, monitor_update_opt, mut order, shutdown) =
-					try_chan_entry!(self, chan.get_mut().channel_reestablish(msg, &self.logger), channel_state, chan);
+					try_chan_entry!(self, chan.get_mut().channel_reestablish(msg, &self.logger), chan);
 				if let Some(monitor_update) = monitor_update_opt {
 					if let Err(e) = self.monitor.update_monitor(chan.get().get_funding_txo().unwrap(), monitor_update) {
 						// channel_reestablish doesn't guarantee the order it returns is sensical
@@ -2788,10 +2949,11 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						if commitment_update.is_none() {
 							order = RAACommitmentOrder::RevokeAndACKFirst;
 						}
-						return_monitor_err!(self, e, channel_state, chan, order, revoke_and_ack.is_some(), commitment_update.is_some());
+						return_monitor_err!(self, e, chan, order, revoke_and_ack.is_some(), commitment_update.is_some());
 						//TODO: Resend the funding_locked if needed once we get the monitor running again
 					}
 				}
+				let mut channel_state = self.channel_state.lock().unwrap();
 				if let Some(msg) = funding_locked {
 					channel_state.pending_msg_events.push(events::MessageSendEvent::SendFundingLocked {
 						node_id: their_node_id.clone(),
@@ -2832,7 +2994,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				}
 				Ok(())
 			},
-			hash_map::Entry::Vacant(_) => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
+			None => return Err(MsgHandleErrInternal::send_err_msg_no_close("Failed to find corresponding channel", msg.channel_id))
 		}
 	}
 
@@ -2842,15 +3004,14 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	/// Note: This API is likely to change!
 	#[doc(hidden)]
 	pub fn update_fee(&self, channel_id: [u8;32], feerate_per_kw: u64) -> Result<(), APIError> {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let their_node_id;
 		let err: Result<(), _> = loop {
-			let mut channel_state_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_state_lock;
-
-			match channel_state.by_id.entry(channel_id) {
-				hash_map::Entry::Vacant(_) => return Err(APIError::APIMisuseError{err: "Failed to find corresponding channel"}),
-				hash_map::Entry::Occupied(mut chan) => {
+			let chan_slot = self.get_channel_slot(&channel_id);
+			let chan_opt = self.lock_channel(&chan_slot, channel_id);
+			match chan_opt {
+				None => return Err(APIError::APIMisuseError{err: "Failed to find corresponding channel"}),
+				Some(mut chan) => {
 					if !chan.get().is_outbound() {
 						return Err(APIError::APIMisuseError{err: "update_fee cannot be sent for an inbound channel"});
 					}
@@ -2862,12 +3023,12 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					}
 					their_node_id = chan.get().get_their_node_id();
 					if let Some((update_fee, commitment_signed, monitor_update)) =
-							break_chan_entry!(self, chan.get_mut().send_update_fee_and_commit(feerate_per_kw, &self.logger), channel_state, chan)
+							break_chan_entry!(self, chan.get_mut().send_update_fee_and_commit(feerate_per_kw, &self.logger), chan)
 					{
 						if let Err(_e) = self.monitor.update_monitor(chan.get().get_funding_txo().unwrap(), monitor_update) {
 							unimplemented!();
 						}
-						channel_state.pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
+						self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::UpdateHTLCs {
 							node_id: chan.get().get_their_node_id(),
 							updates: msgs::CommitmentUpdate {
 								update_add_htlcs: Vec::new(),
@@ -2907,7 +3068,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			for htlc_update in self.monitor.get_and_clear_pending_htlcs_updated() {
 				if let Some(preimage) = htlc_update.payment_preimage {
 					log_trace!(self.logger, "Claiming HTLC with preimage {} from our monitor", log_bytes!(preimage.0));
-					self.claim_funds_internal(self.channel_state.lock().unwrap(), htlc_update.source, preimage);
+					self.claim_funds_internal(htlc_update.source, preimage);
 				} else {
 					log_trace!(self.logger, "Failing HTLC with hash {} from our monitor", log_bytes!(htlc_update.payment_hash.0));
 					self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_update.source, &htlc_update.payment_hash, HTLCFailReason::Reason { failure_code: 0x4000 | 8, data: Vec::new() });
@@ -2938,7 +3099,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			for htlc_update in self.monitor.get_and_clear_pending_htlcs_updated() {
 				if let Some(preimage) = htlc_update.payment_preimage {
 					log_trace!(self.logger, "Claiming HTLC with preimage {} from our monitor", log_bytes!(preimage.0));
-					self.claim_funds_internal(self.channel_state.lock().unwrap(), htlc_update.source, preimage);
+					self.claim_funds_internal(htlc_update.source, preimage);
 				} else {
 					log_trace!(self.logger, "Failing HTLC with hash {} from our monitor", log_bytes!(htlc_update.payment_hash.0));
 					self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_update.source, &htlc_update.payment_hash, HTLCFailReason::Reason { failure_code: 0x4000 | 8, data: Vec::new() });
@@ -2964,87 +3125,88 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 	fn block_connected(&self, header: &BlockHeader, height: u32, txn_matched: &[&Transaction], indexes_of_txn_matched: &[u32]) {
 		let header_hash = header.bitcoin_hash();
 		log_trace!(self.logger, "Block {} at height {} connected with {} txn matched", header_hash, height, txn_matched.len());
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let mut failed_channels = Vec::new();
 		let mut timed_out_htlcs = Vec::new();
 		{
-			let mut channel_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_lock;
-			let short_to_id = &mut channel_state.short_to_id;
-			let pending_msg_events = &mut channel_state.pending_msg_events;
-			channel_state.by_id.retain(|_, channel| {
-				let res = channel.block_connected(header, height, txn_matched, indexes_of_txn_matched);
-				if let Ok((chan_res, mut timed_out_pending_htlcs)) = res {
-					for (source, payment_hash) in timed_out_pending_htlcs.drain(..) {
-						let chan_update = self.get_channel_update(&channel).map(|u| u.encode_with_len()).unwrap(); // Cannot add/recv HTLCs before we have a short_id so unwrap is safe
-						timed_out_htlcs.push((source, payment_hash,  HTLCFailReason::Reason {
-							failure_code: 0x1000 | 14, // expiry_too_soon, or at least it is now
-							data: chan_update,
-						}));
-					}
-					if let Some(funding_locked) = chan_res {
-						pending_msg_events.push(events::MessageSendEvent::SendFundingLocked {
-							node_id: channel.get_their_node_id(),
-							msg: funding_locked,
-						});
-						if let Some(announcement_sigs) = self.get_announcement_sigs(channel) {
-							log_trace!(self.logger, "Sending funding_locked and announcement_signatures for {}", log_bytes!(channel.channel_id()));
-							pending_msg_events.push(events::MessageSendEvent::SendAnnouncementSignatures {
+			for (channel_id, slot) in self.get_channel_slots() {
+				let mut chan = match ChannelEntry::lock(&slot, channel_id, &self.channel_state) {
+					Some(chan) => chan,
+					None => continue,
+				};
+				let retain = 'chan_loop: loop {
+					let channel = chan.get_mut();
+					let res = channel.block_connected(header, height, txn_matched, indexes_of_txn_matched);
+					if let Ok((chan_res, mut timed_out_pending_htlcs)) = res {
+						for (source, payment_hash) in timed_out_pending_htlcs.drain(..) {
+							let chan_update = self.get_channel_update(&channel).map(|u| u.encode_with_len()).unwrap(); // Cannot add/recv HTLCs before we have a short_id so unwrap is safe
+							timed_out_htlcs.push((source, payment_hash,  HTLCFailReason::Reason {
+								failure_code: 0x1000 | 14, // expiry_too_soon, or at least it is now
+								data: chan_update,
+							}));
+						}
+						if let Some(funding_locked) = chan_res {
+							self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::SendFundingLocked {
 								node_id: channel.get_their_node_id(),
-								msg: announcement_sigs,
+								msg: funding_locked,
 							});
-						} else {
-							log_trace!(self.logger, "Sending funding_locked WITHOUT announcement_signatures for {}", log_bytes!(channel.channel_id()));
+							if let Some(announcement_sigs) = self.get_announcement_sigs(channel) {
+								log_trace!(self.logger, "Sending funding_locked and announcement_signatures for {}", log_bytes!(channel.channel_id()));
+								self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::SendAnnouncementSignatures {
+									node_id: channel.get_their_node_id(),
+									msg: announcement_sigs,
+								});
+							} else {
+								log_trace!(self.logger, "Sending funding_locked WITHOUT announcement_signatures for {}", log_bytes!(channel.channel_id()));
+							}
+							self.channel_state.lock().unwrap().short_to_id.insert(channel.get_short_channel_id().unwrap(), channel.channel_id());
 						}
-						short_to_id.insert(channel.get_short_channel_id().unwrap(), channel.channel_id());
+					} else if let Err(e) = res {
+						self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::HandleError {
+							node_id: channel.get_their_node_id(),
+							action: msgs::ErrorAction::SendErrorMessage { msg: e },
+						});
+						break 'chan_loop false;
 					}
-				} else if let Err(e) = res {
-					pending_msg_events.push(events::MessageSendEvent::HandleError {
-						node_id: channel.get_their_node_id(),
-						action: msgs::ErrorAction::SendErrorMessage { msg: e },
-					});
-					return false;
-				}
-				if let Some(funding_txo) = channel.get_funding_txo() {
-					for tx in txn_matched {
-						for inp in tx.input.iter() {
-							if inp.previous_output == funding_txo.into_bitcoin_outpoint() {
-								log_trace!(self.logger, "Detected channel-closing tx {} spending {}:{}, closing channel {}", tx.txid(), inp.previous_output.txid, inp.previous_output.vout, log_bytes!(channel.channel_id()));
-								if let Some(short_id) = channel.get_short_channel_id() {
-									short_to_id.remove(&short_id);
-								}
-								// It looks like our counterparty went on-chain. We go ahead and
-								// broadcast our latest local state as well here, just in case its
-								// some kind of SPV attack, though we expect these to be dropped.
-								failed_channels.push(channel.force_shutdown(true));
-								if let Ok(update) = self.get_channel_update(&channel) {
-									pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
-										msg: update
-									});
+					if let Some(funding_txo) = channel.get_funding_txo() {
+						for tx in txn_matched {
+							for inp in tx.input.iter() {
+								if inp.previous_output == funding_txo.into_bitcoin_outpoint() {
+									log_trace!(self.logger, "Detected channel-closing tx {} spending {}:{}, closing channel {}", tx.txid(), inp.previous_output.txid, inp.previous_output.vout, log_bytes!(channel.channel_id()));
+									// It looks like our counterparty went on-chain. We go ahead and
+									// broadcast our latest local state as well here, just in case its
+									// some kind of SPV attack, though we expect these to be dropped.
+									failed_channels.push(channel.force_shutdown(true));
+									if let Ok(update) = self.get_channel_update(&channel) {
+										self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+											msg: update
+										});
+									}
+									break 'chan_loop false;
 								}
-								return false;
 							}
 						}
 					}
-				}
-				if channel.is_funding_initiated() && channel.channel_monitor().would_broadcast_at_height(height, &self.logger) {
-					if let Some(short_id) = channel.get_short_channel_id() {
-						short_to_id.remove(&short_id);
-					}
-					// If would_broadcast_at_height() is true, the channel_monitor will broadcast
-					// the latest local tx for us, so we should skip that here (it doesn't really
-					// hurt anything, but does make tests a bit simpler).
-					failed_channels.push(channel.force_shutdown(false));
-					if let Ok(update) = self.get_channel_update(&channel) {
-						pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
-							msg: update
-						});
+					if channel.is_funding_initiated() && channel.channel_monitor().would_broadcast_at_height(height, &self.logger) {
+						// If would_broadcast_at_height() is true, the channel_monitor will broadcast
+						// the latest local tx for us, so we should skip that here (it doesn't really
+						// hurt anything, but does make tests a bit simpler).
+						failed_channels.push(channel.force_shutdown(false));
+						if let Ok(update) = self.get_channel_update(&channel) {
+							self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+								msg: update
+							});
+						}
+						break 'chan_loop false;
 					}
-					return false;
+					break true;
+				};
+				if !retain {
+					chan.remove();
 				}
-				true
-			});
+			}
 
+			let mut channel_state = self.channel_state.lock().unwrap();
 			channel_state.claimable_htlcs.retain(|&(ref payment_hash, _), htlcs| {
 				htlcs.retain(|htlc| {
 					// If height is approaching the number of blocks we think it takes us to get
@@ -3089,29 +3251,24 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 
 	/// We force-close the channel without letting our counterparty participate in the shutdown
 	fn block_disconnected(&self, header: &BlockHeader, _: u32) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let mut failed_channels = Vec::new();
 		{
-			let mut channel_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_lock;
-			let short_to_id = &mut channel_state.short_to_id;
-			let pending_msg_events = &mut channel_state.pending_msg_events;
-			channel_state.by_id.retain(|_,  v| {
-				if v.block_disconnected(header) {
-					if let Some(short_id) = v.get_short_channel_id() {
-						short_to_id.remove(&short_id);
-					}
+			for (channel_id, slot) in self.get_channel_slots() {
+				let mut chan = match ChannelEntry::lock(&slot, channel_id, &self.channel_state) {
+					Some(chan) => chan,
+					None => continue,
+				};
+				if chan.get_mut().block_disconnected(header) {
+					let mut v = chan.remove();
 					failed_channels.push(v.force_shutdown(true));
 					if let Ok(update) = self.get_channel_update(&v) {
-						pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+						self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
 							msg: update
 						});
 					}
-					false
-				} else {
-					true
 				}
-			});
+			}
 		}
 		for failure in failed_channels.drain(..) {
 			self.finish_force_close_channel(failure);
@@ -3130,136 +3287,132 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
         L::Target: Logger,
 {
 	fn handle_open_channel(&self, their_node_id: &PublicKey, their_features: InitFeatures, msg: &msgs::OpenChannel) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_open_channel(their_node_id, their_features, msg), *their_node_id);
 	}
 
 	fn handle_accept_channel(&self, their_node_id: &PublicKey, their_features: InitFeatures, msg: &msgs::AcceptChannel) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_accept_channel(their_node_id, their_features, msg), *their_node_id);
 	}
 
 	fn handle_funding_created(&self, their_node_id: &PublicKey, msg: &msgs::FundingCreated) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_funding_created(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_funding_signed(&self, their_node_id: &PublicKey, msg: &msgs::FundingSigned) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_funding_signed(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_funding_locked(&self, their_node_id: &PublicKey, msg: &msgs::FundingLocked) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_funding_locked(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_shutdown(&self, their_node_id: &PublicKey, msg: &msgs::Shutdown) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_shutdown(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_closing_signed(&self, their_node_id: &PublicKey, msg: &msgs::ClosingSigned) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_closing_signed(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_update_add_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateAddHTLC) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_update_add_htlc(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_update_fulfill_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFulfillHTLC) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_update_fulfill_htlc(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_update_fail_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFailHTLC) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_update_fail_htlc(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_update_fail_malformed_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFailMalformedHTLC) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_update_fail_malformed_htlc(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_commitment_signed(&self, their_node_id: &PublicKey, msg: &msgs::CommitmentSigned) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_commitment_signed(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_revoke_and_ack(&self, their_node_id: &PublicKey, msg: &msgs::RevokeAndACK) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_revoke_and_ack(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_update_fee(&self, their_node_id: &PublicKey, msg: &msgs::UpdateFee) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_update_fee(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_announcement_signatures(&self, their_node_id: &PublicKey, msg: &msgs::AnnouncementSignatures) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_announcement_signatures(their_node_id, msg), *their_node_id);
 	}
 
 	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &msgs::ChannelReestablish) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let _ = handle_error!(self, self.internal_channel_reestablish(their_node_id, msg), *their_node_id);
 	}
 
 	fn peer_disconnected(&self, their_node_id: &PublicKey, no_connection_possible: bool) {
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let mut failed_channels = Vec::new();
 		let mut failed_payments = Vec::new();
 		let mut no_channels_remain = true;
 		{
-			let mut channel_state_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_state_lock;
-			let short_to_id = &mut channel_state.short_to_id;
-			let pending_msg_events = &mut channel_state.pending_msg_events;
 			if no_connection_possible {
 				log_debug!(self.logger, "Failing all channels with {} due to no_connection_possible", log_pubkey!(their_node_id));
-				channel_state.by_id.retain(|_, chan| {
-					if chan.get_their_node_id() == *their_node_id {
-						if let Some(short_id) = chan.get_short_channel_id() {
-							short_to_id.remove(&short_id);
-						}
-						failed_channels.push(chan.force_shutdown(true));
-						if let Ok(update) = self.get_channel_update(&chan) {
-							pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
-								msg: update
-							});
-						}
-						false
-					} else {
-						true
-					}
-				});
 			} else {
 				log_debug!(self.logger, "Marking channels with {} disconnected and generating channel_updates", log_pubkey!(their_node_id));
-				channel_state.by_id.retain(|_, chan| {
-					if chan.get_their_node_id() == *their_node_id {
+			}
+			for (channel_id, slot) in self.get_channel_slots() {
+				let mut chan_entry = match ChannelEntry::lock(&slot, channel_id, &self.channel_state) {
+					Some(chan_entry) => chan_entry,
+					None => continue,
+				};
+				if chan_entry.get().get_their_node_id() != *their_node_id { continue; }
+				if no_connection_possible {
+					let mut chan = chan_entry.remove();
+					failed_channels.push(chan.force_shutdown(true));
+					if let Ok(update) = self.get_channel_update(&chan) {
+						self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+							msg: update
+						});
+					}
+				} else {
+					let is_shutdown = {
+						let chan = chan_entry.get_mut();
 						let failed_adds = chan.remove_uncommitted_htlcs_and_mark_paused(&self.logger);
 						chan.to_disabled_marked();
 						if !failed_adds.is_empty() {
 							let chan_update = self.get_channel_update(&chan).map(|u| u.encode_with_len()).unwrap(); // Cannot add/recv HTLCs before we have a short_id so unwrap is safe
 							failed_payments.push((chan_update, failed_adds));
 						}
-						if chan.is_shutdown() {
-							if let Some(short_id) = chan.get_short_channel_id() {
-								short_to_id.remove(&short_id);
-							}
-							return false;
-						} else {
-							no_channels_remain = false;
-						}
+						chan.is_shutdown()
+					};
+					if is_shutdown {
+						chan_entry.remove();
+					} else {
+						no_channels_remain = false;
 					}
-					true
-				})
+				}
 			}
-			pending_msg_events.retain(|msg| {
+			// Now that every channel with the peer is paused, drop any messages we'd generated for
+			// them. Any messages generated by concurrent operations on these channels were pushed
+			// while holding the channel's lock, and thus before we paused it above.
+			self.channel_state.lock().unwrap().pending_msg_events.retain(|msg| {
 				match msg {
 					&events::MessageSendEvent::SendAcceptChannel { ref node_id, .. } => node_id != their_node_id,
 					&events::MessageSendEvent::SendOpenChannel { ref node_id, .. } => node_id != their_node_id,
@@ -3297,7 +3450,7 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 	fn peer_connected(&self, their_node_id: &PublicKey, init_msg: &msgs::Init) {
 		log_debug!(self.logger, "Generating channel_reestablish events for {}", log_pubkey!(their_node_id));
 
-		let _ = self.total_consistency_lock.read().unwrap();
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
 		{
 			let mut peer_state_lock = self.per_peer_state.write().unwrap();
@@ -3313,32 +3466,33 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 			}
 		}
 
-		let mut channel_state_lock = self.channel_state.lock().unwrap();
-		let channel_state = &mut *channel_state_lock;
-		let pending_msg_events = &mut channel_state.pending_msg_events;
-		channel_state.by_id.retain(|_, chan| {
-			if chan.get_their_node_id() == *their_node_id {
-				if !chan.have_received_message() {
+		for (channel_id, slot) in self.get_channel_slots() {
+			let mut chan = match ChannelEntry::lock(&slot, channel_id, &self.channel_state) {
+				Some(chan) => chan,
+				None => continue,
+			};
+			if chan.get().get_their_node_id() == *their_node_id {
+				if !chan.get().have_received_message() {
 					// If we created this (outbound) channel while we were disconnected from the
 					// peer we probably failed to send the open_channel message, which is now
 					// lost. We can't have had anything pending related to this channel, so we just
 					// drop it.
-					false
+					chan.remove();
 				} else {
-					pending_msg_events.push(events::MessageSendEvent::SendChannelReestablish {
-						node_id: chan.get_their_node_id(),
-						msg: chan.get_channel_reestablish(&self.logger),
+					let msg = chan.get().get_channel_reestablish(&self.logger);
+					self.channel_state.lock().unwrap().pending_msg_events.push(events::MessageSendEvent::SendChannelReestablish {
+						node_id: *their_node_id,
+						msg,
 					});
-					true
 				}
-			} else { true }
-		});
+			}
+		}
 		//TODO: Also re-broadcast announcement_signatures
 	}
 
 	fn handle_error(&self, their_node_id: &PublicKey, msg: &msgs::ErrorMessage) {
-		let _ = self.total_consistency_lock.read().unwrap();
-
+		// force_close_channel takes the total_consistency_lock itself, and we mustn't take it twice
+		// as a waiting writer may keep a second reader from ever acquiring it.
 		if msg.channel_id == [0; 32] {
 			for chan in self.list_channels() {
 				if chan.remote_network_id == *their_node_id {
@@ -3569,7 +3723,7 @@ impl<ChanSigner: ChannelKeys + Writeable, M: Deref, T: Deref, K: Deref, F: Deref
         L::Target: Logger,
 {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
-		let _ = self.total_consistency_lock.write().unwrap();
+		let _consistency_lock = self.total_consistency_lock.write().unwrap();
 
 		writer.write_all(&[SERIALIZATION_VERSION; 1])?;
 		writer.write_all(&[MIN_SERIALIZATION_VERSION; 1])?;
@@ -3578,55 +3732,72 @@ impl<ChanSigner: ChannelKeys + Writeable, M: Deref, T: Deref, K: Deref, F: Deref
 		(self.latest_block_height.load(Ordering::Acquire) as u32).write(writer)?;
 		self.last_block_hash.lock().unwrap().write(writer)?;
 
-		let channel_state = self.channel_state.lock().unwrap();
-		let mut unfunded_channels = 0;
-		for (_, channel) in channel_state.by_id.iter() {
-			if !channel.is_funding_initiated() {
-				unfunded_channels += 1;
+		// In order to write out a consistent snapshot, we lock every channel (in channel_id order)
+		// and then the channel_state, retrying if a channel was added or removed before we got the
+		// latter.
+		loop {
+			let mut channel_slots = self.get_channel_slots();
+			channel_slots.sort_by(|a, b| a.0.cmp(&b.0));
+			let mut channels = Vec::with_capacity(channel_slots.len());
+			for &(_, ref slot) in channel_slots.iter() {
+				channels.push(slot.lock().unwrap());
 			}
-		}
-		((channel_state.by_id.len() - unfunded_channels) as u64).write(writer)?;
-		for (_, channel) in channel_state.by_id.iter() {
-			if channel.is_funding_initiated() {
-				channel.write(writer)?;
+			let channel_state = self.channel_state.lock().unwrap();
+			if channel_state.by_id.len() != channel_slots.len() ||
+					channel_slots.iter().any(|&(ref id, ref slot)| channel_state.by_id.get(id).map(|s| !Arc::ptr_eq(s, slot)).unwrap_or(true)) {
+				continue;
+			}
+
+			let mut unfunded_channels = 0;
+			for channel in channels.iter() {
+				if !channel.as_ref().unwrap().is_funding_initiated() {
+					unfunded_channels += 1;
+				}
+			}
+			((channels.len() - unfunded_channels) as u64).write(writer)?;
+			for channel in channels.iter() {
+				let channel = channel.as_ref().unwrap();
+				if channel.is_funding_initiated() {
+					channel.write(writer)?;
+				}
 			}
-		}
 
-		(channel_state.forward_htlcs.len() as u64).write(writer)?;
-		for (short_channel_id, pending_forwards) in channel_state.forward_htlcs.iter() {
-			short_channel_id.write(writer)?;
-			(pending_forwards.len() as u64).write(writer)?;
-			for forward in pending_forwards {
-				forward.write(writer)?;
+			(channel_state.forward_htlcs.len() as u64).write(writer)?;
+			for (short_channel_id, pending_forwards) in channel_state.forward_htlcs.iter() {
+				short_channel_id.write(writer)?;
+				(pending_forwards.len() as u64).write(writer)?;
+				for forward in pending_forwards {
+					forward.write(writer)?;
+				}
 			}
-		}
 
-		(channel_state.claimable_htlcs.len() as u64).write(writer)?;
-		for (payment_hash, previous_hops) in channel_state.claimable_htlcs.iter() {
-			payment_hash.write(writer)?;
-			(previous_hops.len() as u64).write(writer)?;
-			for htlc in previous_hops.iter() {
-				htlc.write(writer)?;
+			(channel_state.claimable_htlcs.len() as u64).write(writer)?;
+			for (payment_hash, previous_hops) in channel_state.claimable_htlcs.iter() {
+				payment_hash.write(writer)?;
+				(previous_hops.len() as u64).write(writer)?;
+				for htlc in previous_hops.iter() {
+					htlc.write(writer)?;
+				}
 			}
-		}
 
-		let per_peer_state = self.per_peer_state.write().unwrap();
-		(per_peer_state.len() as u64).write(writer)?;
-		for (peer_pubkey, peer_state_mutex) in per_peer_state.iter() {
-			peer_pubkey.write(writer)?;
-			let peer_state = peer_state_mutex.lock().unwrap();
-			peer_state.latest_features.write(writer)?;
-		}
+			let per_peer_state = self.per_peer_state.write().unwrap();
+			(per_peer_state.len() as u64).write(writer)?;
+			for (peer_pubkey, peer_state_mutex) in per_peer_state.iter() {
+				peer_pubkey.write(writer)?;
+				let peer_state = peer_state_mutex.lock().unwrap();
+				peer_state.latest_features.write(writer)?;
+			}
 
-		let events = self.pending_events.lock().unwrap();
-		(events.len() as u64).write(writer)?;
-		for event in events.iter() {
-			event.write(writer)?;
-		}
+			let events = self.pending_events.lock().unwrap();
+			(events.len() as u64).write(writer)?;
+			for event in events.iter() {
+				event.write(writer)?;
+			}
 
-		(self.last_node_announcement_serial.load(Ordering::Acquire) as u32).write(writer)?;
+			(self.last_node_announcement_serial.load(Ordering::Acquire) as u32).write(writer)?;
 
-		Ok(())
+			return Ok(());
+		}
 	}
 }
 
@@ -3760,7 +3931,7 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
 					if let Some(short_channel_id) = channel.get_short_channel_id() {
 						short_to_id.insert(short_channel_id, channel.channel_id());
 					}
-					by_id.insert(channel.channel_id(), channel);
+					by_id.insert(channel.channel_id(), Arc::new(Mutex::new(Some(channel))));
 				}
 			} else {
 				return Err(DecodeError::InvalidValue);
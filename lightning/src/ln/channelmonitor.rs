@@ -340,6 +340,16 @@ impl<Key : Send + cmp::Eq + hash::Hash + 'static, ChanSigner: ChannelKeys, T: De
 		}
 	}
 
+	/// Passes an unconfirmed transaction to each of our ChannelMonitors, allowing any payment
+	/// preimages it reveals to be passed back immediately. See
+	/// ChannelMonitor::transaction_seen_in_mempool for more.
+	pub fn transaction_seen_in_mempool(&self, tx: &Transaction) {
+		let mut monitors = self.monitors.lock().unwrap();
+		for monitor in monitors.values_mut() {
+			monitor.transaction_seen_in_mempool(tx, &*self.logger);
+		}
+	}
+
 	/// Gets a reference to the latest copy of a given ChannelMonitor given a &Key, if any has been
 	/// registered.
 	///
@@ -820,6 +830,15 @@ pub struct ChannelMonitor<ChanSigner: ChannelKeys> {
 	pending_htlcs_updated: Vec<HTLCUpdate>,
 	pending_events: Vec<events::Event>,
 
+	// HTLCs (and their CLTV expiry) for which we've already passed a preimage back via
+	// pending_htlcs_updated after seeing it in an unconfirmed transaction, so that we don't do so
+	// again when the transaction confirms. Entries are removed once any transaction resolving the
+	// HTLC confirms, or ANTI_REORG_DELAY blocks after the HTLC expires in case the claim never
+	// confirms at all.
+	// This is ephemeral state and deliberately not serialized - after a restart we may simply
+	// pass the preimage back a second time, which ChannelManager treats as a duplicate claim.
+	htlcs_claimed_in_mempool: Vec<(HTLCSource, u32)>,
+
 	// Used to track onchain events, i.e transactions parts of channels confirmed on chain, on which
 	// we have to take actions once they reach enough confs. Key is a block height timer, i.e we enforce
 	// actions when we receive a block with given height. Actions depend on OnchainEvent type.
@@ -1162,6 +1181,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 			payment_preimages: HashMap::new(),
 			pending_htlcs_updated: Vec::new(),
 			pending_events: Vec::new(),
+			htlcs_claimed_in_mempool: Vec::new(),
 
 			onchain_events_waiting_threshold_conf: HashMap::new(),
 			outputs_to_watch: HashMap::new(),
@@ -1911,7 +1931,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 			// While all commitment/HTLC-Success/HTLC-Timeout transactions have one input, HTLCs
 			// can also be resolved in a few other ways which can have more than one output. Thus,
 			// we call is_resolving_htlc_output here outside of the tx.input.len() == 1 check.
-			self.is_resolving_htlc_output(&tx, height, &logger);
+			self.is_resolving_htlc_output(&tx, Some(height), &logger);
 
 			self.is_paying_spendable_output(&tx, height, &logger);
 		}
@@ -1949,6 +1969,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 			}
 		}
 		self.onchain_tx_handler.block_connected(txn_matched, claimable_outpoints, height, &*broadcaster, &*fee_estimator, &*logger);
+		self.htlcs_claimed_in_mempool.retain(|claimed| claimed.1 + ANTI_REORG_DELAY > height);
 
 		self.last_block_hash = block_hash.clone();
 		for &(ref txid, ref output_scripts) in watch_outputs.iter() {
@@ -1958,6 +1979,21 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		watch_outputs
 	}
 
+	/// Called when an unconfirmed transaction is seen in the mempool, eg via
+	/// SimpleManyChannelMonitor::transaction_seen_in_mempool.
+	///
+	/// If the transaction claims one of our offered HTLCs with its payment preimage, we pass the
+	/// preimage back via get_and_clear_pending_htlcs_updated immediately, rather than waiting for
+	/// the transaction to confirm, allowing the corresponding inbound HTLC to be claimed sooner.
+	/// Nothing else about the transaction is acted on until it appears in block_connected, so
+	/// transactions need not come from a trusted source.
+	///
+	/// Which HTLCs were claimed this way is not persisted, so if we are reloaded before the
+	/// transaction confirms the preimage will be passed back a second time once it does.
+	pub fn transaction_seen_in_mempool<L: Deref>(&mut self, tx: &Transaction, logger: L) where L::Target: Logger {
+		self.is_resolving_htlc_output(tx, None, &logger);
+	}
+
 	/// Called when a block has been disconnected from the best chain by <SimpleManyChannelMonitor
 	/// as ChainListener>::block_disconnected, and should thus generally not be called during
 	/// normal operation. It is exposed both for users who wish to use ChannelMonitors directly and
@@ -2047,13 +2083,18 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 
 	/// Check if any transaction broadcasted is resolving HTLC output by a success or timeout on a local
 	/// or remote commitment tx, if so send back the source, preimage if found and payment_hash of resolved HTLC
-	fn is_resolving_htlc_output<L: Deref>(&mut self, tx: &Transaction, height: u32, logger: &L) where L::Target: Logger {
+	///
+	/// height is None if the transaction is unconfirmed, in which case we only look for preimages.
+	fn is_resolving_htlc_output<L: Deref>(&mut self, tx: &Transaction, height: Option<u32>, logger: &L) where L::Target: Logger {
 		'outer_loop: for input in &tx.input {
 			let mut payment_data = None;
 			let revocation_sig_claim = (input.witness.len() == 3 && HTLCType::scriptlen_to_htlctype(input.witness[2].len()) == Some(HTLCType::OfferedHTLC) && input.witness[1].len() == 33)
 				|| (input.witness.len() == 3 && HTLCType::scriptlen_to_htlctype(input.witness[2].len()) == Some(HTLCType::AcceptedHTLC) && input.witness[1].len() == 33);
 			let accepted_preimage_claim = input.witness.len() == 5 && HTLCType::scriptlen_to_htlctype(input.witness[4].len()) == Some(HTLCType::AcceptedHTLC);
 			let offered_preimage_claim = input.witness.len() == 3 && HTLCType::scriptlen_to_htlctype(input.witness[2].len()) == Some(HTLCType::OfferedHTLC);
+			if height.is_none() && !accepted_preimage_claim && !offered_preimage_claim {
+				continue;
+			}
 
 			macro_rules! log_claim {
 				($tx_info: expr, $local_tx: expr, $htlc: expr, $source_avail: expr) => {
@@ -2084,7 +2125,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 							if pending_htlc.payment_hash == $htlc_output.payment_hash && pending_htlc.amount_msat == $htlc_output.amount_msat {
 								if let &Some(ref source) = pending_source {
 									log_claim!("revoked remote commitment tx", false, pending_htlc, true);
-									payment_data = Some(((**source).clone(), $htlc_output.payment_hash));
+									payment_data = Some(((**source).clone(), $htlc_output.payment_hash, $htlc_output.cltv_expiry));
 									break;
 								}
 							}
@@ -2104,7 +2145,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 								// transaction. This implies we either learned a preimage, the HTLC
 								// has timed out, or we screwed up. In any case, we should now
 								// resolve the source HTLC with the original sender.
-								payment_data = Some(((*source).clone(), htlc_output.payment_hash));
+								payment_data = Some(((*source).clone(), htlc_output.payment_hash, htlc_output.cltv_expiry));
 							} else if !$local_tx {
 									check_htlc_valid_remote!(self.current_remote_commitment_txid, htlc_output);
 								if payment_data.is_none() {
@@ -2137,27 +2178,41 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 
 			// Check that scan_commitment, above, decided there is some source worth relaying an
 			// HTLC resolution backwards to and figure out whether we learned a preimage from it.
-			if let Some((source, payment_hash)) = payment_data {
+			if let Some((source, payment_hash, cltv_expiry)) = payment_data {
 				let mut payment_preimage = PaymentPreimage([0; 32]);
-				if accepted_preimage_claim {
-					if !self.pending_htlcs_updated.iter().any(|update| update.source == source) {
-						payment_preimage.0.copy_from_slice(&input.witness[3]);
-						self.pending_htlcs_updated.push(HTLCUpdate {
-							source,
-							payment_preimage: Some(payment_preimage),
-							payment_hash
-						});
+				if accepted_preimage_claim || offered_preimage_claim {
+					let preimage_bytes = if accepted_preimage_claim { &input.witness[3] } else { &input.witness[1] };
+					if preimage_bytes.len() != 32 { continue; }
+					payment_preimage.0.copy_from_slice(preimage_bytes);
+					if height.is_none() && PaymentHash(Sha256::hash(&payment_preimage.0[..]).into_inner()) != payment_hash {
+						// Unconfirmed transactions may come from an untrusted source, so only
+						// believe preimages which actually match.
+						continue;
 					}
-				} else if offered_preimage_claim {
-					if !self.pending_htlcs_updated.iter().any(|update| update.source == source) {
-						payment_preimage.0.copy_from_slice(&input.witness[1]);
+					let claimed_in_mempool = match self.htlcs_claimed_in_mempool.iter().position(|claimed| claimed.0 == source) {
+						Some(idx) => {
+							if height.is_some() {
+								self.htlcs_claimed_in_mempool.swap_remove(idx);
+							}
+							true
+						},
+						None => false,
+					};
+					if !claimed_in_mempool && !self.pending_htlcs_updated.iter().any(|update| update.source == source) {
+						if height.is_none() {
+							log_info!(logger, "Learned preimage for HTLC with payment hash {} from unconfirmed transaction {}", log_bytes!(payment_hash.0), tx.txid());
+							self.htlcs_claimed_in_mempool.push((source.clone(), cltv_expiry));
+						}
 						self.pending_htlcs_updated.push(HTLCUpdate {
 							source,
 							payment_preimage: Some(payment_preimage),
 							payment_hash
 						});
 					}
-				} else {
+				} else if let Some(height) = height {
+					// If the preimage claim we saw in the mempool was replaced by a timeout claim
+					// we no longer expect it to confirm.
+					self.htlcs_claimed_in_mempool.retain(|claimed| claimed.0 != source);
 					log_info!(logger, "Failing HTLC with payment_hash {} timeout by a spend tx, waiting for confirmation (at height{})", log_bytes!(payment_hash.0), height + ANTI_REORG_DELAY - 1);
 					match self.onchain_events_waiting_threshold_conf.entry(height + ANTI_REORG_DELAY - 1) {
 						hash_map::Entry::Occupied(mut entry) => {
@@ -2510,6 +2565,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 			payment_preimages,
 			pending_htlcs_updated,
 			pending_events,
+			htlcs_claimed_in_mempool: Vec::new(),
 
 			onchain_events_waiting_threshold_conf,
 			outputs_to_watch,
@@ -335,6 +335,41 @@ impl InitFeatures {
 	pub(crate) fn to_context<C: sealed::Context>(&self) -> Features<C> {
 		self.to_context_internal()
 	}
+
+	/// Returns whether the given bit is one of the (even or odd) bits of a known feature.
+	fn is_known_bit(bit: usize) -> bool {
+		let known_mask = <sealed::InitContext as sealed::Context>::KNOWN_FEATURE_MASK;
+		bit / 8 < known_mask.len() && (known_mask[bit / 8] & (1 << (bit % 8))) != 0
+	}
+
+	/// Sets the given application-defined feature bit. Bits which belong to features known to the
+	/// implementation are left untouched, in which case false is returned.
+	pub(crate) fn set_custom_bit(&mut self, bit: usize) -> bool {
+		if Self::is_known_bit(bit) {
+			return false;
+		}
+		if self.flags.len() <= bit / 8 {
+			self.flags.resize(bit / 8 + 1, 0u8);
+		}
+		self.flags[bit / 8] |= 1 << (bit % 8);
+		true
+	}
+
+	/// Clears both the required and optional bits of the application-defined feature which
+	/// includes the given bit. Bits which belong to features known to the implementation are left
+	/// untouched.
+	pub(crate) fn clear_custom_feature(&mut self, bit: usize) {
+		if Self::is_known_bit(bit) {
+			return;
+		}
+		if self.flags.len() > bit / 8 {
+			self.flags[bit / 8] &= !(0b11 << ((bit % 8) & !1));
+		}
+
+		let last_non_zero_byte = self.flags.iter().rposition(|&byte| byte != 0);
+		let size = if let Some(offset) = last_non_zero_byte { offset + 1 } else { 0 };
+		self.flags.resize(size, 0u8);
+	}
 }
 
 impl<T: sealed::Context> Features<T> {
@@ -607,6 +642,21 @@ mod tests {
 		assert!(features.supports_unknown_bits());
 	}
 
+	#[test]
+	fn sanity_test_custom_bits() {
+		let mut features = InitFeatures::known();
+		// Bits of known features can't be set or cleared as custom bits
+		assert!(!features.set_custom_bit(0));
+		features.clear_custom_feature(13);
+		assert!(features.requires_static_remote_key());
+
+		assert!(features.set_custom_bit(100));
+		assert!(features.requires_unknown_bits());
+		features.clear_custom_feature(101);
+		assert!(!features.supports_unknown_bits());
+		assert_eq!(features, InitFeatures::known());
+	}
+
 	#[test]
 	fn convert_to_context_with_relevant_flags() {
 		let init_features = InitFeatures::known().clear_upfront_shutdown_script();
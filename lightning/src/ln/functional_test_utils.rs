@@ -249,11 +249,23 @@ macro_rules! get_htlc_update_msgs {
 	}
 }
 
+/// Gets the given channel's slot from a node's ChannelManager, releasing the channel_state lock
+/// before returning so that the channel itself can be locked.
+macro_rules! get_channel_slot {
+	($node: expr, $channel_id: expr) => {
+		{
+			let chan_slot = $node.node.channel_state.lock().unwrap().by_id.get(&$channel_id).unwrap().clone();
+			chan_slot
+		}
+	}
+}
+
 macro_rules! get_feerate {
 	($node: expr, $channel_id: expr) => {
 		{
-			let chan_lock = $node.node.channel_state.lock().unwrap();
-			let chan = chan_lock.by_id.get(&$channel_id).unwrap();
+			let chan_slot = get_channel_slot!($node, $channel_id);
+			let chan_lock = chan_slot.lock().unwrap();
+			let chan = chan_lock.as_ref().unwrap();
 			chan.get_feerate()
 		}
 	}
@@ -1249,8 +1261,9 @@ pub fn get_announce_close_broadcast_events<'a, 'b, 'c>(nodes: &Vec<Node<'a, 'b,
 
 macro_rules! get_channel_value_stat {
 	($node: expr, $channel_id: expr) => {{
-		let chan_lock = $node.node.channel_state.lock().unwrap();
-		let chan = chan_lock.by_id.get(&$channel_id).unwrap();
+		let chan_slot = get_channel_slot!($node, $channel_id);
+		let chan_lock = chan_slot.lock().unwrap();
+		let chan = chan_lock.as_ref().unwrap();
 		chan.get_value_stat()
 	}}
 }
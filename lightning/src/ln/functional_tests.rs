@@ -2563,6 +2563,74 @@ fn test_htlc_on_chain_success() {
 	check_tx_local_broadcast!(nodes[0], true, commitment_tx[0], chan_1.3);
 }
 
+#[test]
+fn test_htlc_preimage_from_mempool() {
+	// Test that B learns the preimage from C's HTLC-Success transaction as soon as it is seen in
+	// the mempool, claiming backwards towards A without waiting for it to confirm, and that it
+	// doesn't claim a second time once the transaction does confirm.
+	// A --------------------> B ----------------------> C (preimage)
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let (our_payment_preimage, _payment_hash) = route_payment(&nodes[0], &vec!(&nodes[1], &nodes[2]), 3000000);
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42};
+
+	// C claims the HTLC but goes on-chain instead of passing the preimage to B off-chain.
+	let commitment_tx = get_local_commitment_txn!(nodes[2], chan_2.2);
+	assert_eq!(commitment_tx.len(), 1);
+	nodes[2].node.claim_funds(our_payment_preimage, &None, 3_000_000);
+	check_added_monitors!(nodes[2], 1);
+	get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+	nodes[2].block_notifier.block_connected(&Block { header, txdata: vec![commitment_tx[0].clone()]}, 1);
+	check_closed_broadcast!(nodes[2], false);
+	check_added_monitors!(nodes[2], 1);
+	let htlc_success_tx = nodes[2].tx_broadcaster.txn_broadcasted.lock().unwrap()[0].clone();
+	check_spends!(htlc_success_tx, commitment_tx[0]);
+	assert_eq!(htlc_success_tx.input[0].witness.last().unwrap().len(), ACCEPTED_HTLC_SCRIPT_WEIGHT);
+
+	// A transaction spending the same output with a bogus preimage is ignored.
+	let mut bogus_tx = htlc_success_tx.clone();
+	bogus_tx.input[0].witness[3] = vec![42; 32];
+	nodes[1].chan_monitor.simple_monitor.transaction_seen_in_mempool(&bogus_tx);
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	check_added_monitors!(nodes[1], 0);
+
+	// B sees the HTLC-Success transaction in its mempool and immediately claims from A.
+	nodes[1].chan_monitor.simple_monitor.transaction_seen_in_mempool(&htlc_success_tx);
+	let events = nodes[1].node.get_and_clear_pending_msg_events();
+	{
+		let mut added_monitors = nodes[1].chan_monitor.added_monitors.lock().unwrap();
+		assert_eq!(added_monitors.len(), 1);
+		assert_eq!(added_monitors[0].0.txid, chan_1.3.txid());
+		added_monitors.clear();
+	}
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		MessageSendEvent::UpdateHTLCs { ref node_id, updates: msgs::CommitmentUpdate { ref update_add_htlcs, ref update_fail_htlcs, ref update_fulfill_htlcs, ref update_fail_malformed_htlcs, .. } } => {
+			assert!(update_add_htlcs.is_empty());
+			assert!(update_fail_htlcs.is_empty());
+			assert_eq!(update_fulfill_htlcs.len(), 1);
+			assert_eq!(update_fulfill_htlcs[0].payment_preimage, our_payment_preimage);
+			assert!(update_fail_malformed_htlcs.is_empty());
+			assert_eq!(nodes[0].node.get_our_node_id(), *node_id);
+		},
+		_ => panic!("Unexpected event"),
+	};
+
+	// Seeing it again, or seeing it confirm, doesn't result in a second claim.
+	nodes[1].chan_monitor.simple_monitor.transaction_seen_in_mempool(&htlc_success_tx);
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	check_added_monitors!(nodes[1], 0);
+	nodes[1].block_notifier.block_connected(&Block { header, txdata: vec![commitment_tx[0].clone(), htlc_success_tx]}, 1);
+	check_closed_broadcast!(nodes[1], false);
+	check_added_monitors!(nodes[1], 1);
+}
+
 #[test]
 fn test_htlc_on_chain_timeout() {
 	// Test that in case of a unilateral close onchain, we detect the state of output thanks to
@@ -2768,7 +2836,7 @@ fn do_test_commitment_revoked_fail_backward_exhaustive(deliver_bs_raa: bool, use
 	let value = if use_dust {
 		// The dust limit applied to HTLC outputs considers the fee of the HTLC transaction as
 		// well, so HTLCs at exactly the dust limit will not be included in commitment txn.
-		nodes[2].node.channel_state.lock().unwrap().by_id.get(&chan_2.2).unwrap().our_dust_limit_satoshis * 1000
+		get_channel_slot!(nodes[2], chan_2.2).lock().unwrap().as_ref().unwrap().our_dust_limit_satoshis * 1000
 	} else { 3000000 };
 
 	let (_, first_payment_hash) = route_payment(&nodes[0], &[&nodes[1], &nodes[2]], value);
@@ -3834,10 +3902,12 @@ fn test_invalid_channel_announcement() {
 
 	let chan_announcement = create_chan_between_nodes(&nodes[0], &nodes[1], InitFeatures::known(), InitFeatures::known());
 
-	let a_channel_lock = nodes[0].node.channel_state.lock().unwrap();
-	let b_channel_lock = nodes[1].node.channel_state.lock().unwrap();
-	let as_chan = a_channel_lock.by_id.get(&chan_announcement.3).unwrap();
-	let bs_chan = b_channel_lock.by_id.get(&chan_announcement.3).unwrap();
+	let as_chan_slot = get_channel_slot!(nodes[0], chan_announcement.3);
+	let bs_chan_slot = get_channel_slot!(nodes[1], chan_announcement.3);
+	let as_chan_lock = as_chan_slot.lock().unwrap();
+	let bs_chan_lock = bs_chan_slot.lock().unwrap();
+	let as_chan = as_chan_lock.as_ref().unwrap();
+	let bs_chan = bs_chan_lock.as_ref().unwrap();
 
 	nodes[0].net_graph_msg_handler.handle_htlc_fail_channel_update(&msgs::HTLCFailChannelUpdate::ChannelClosed { short_channel_id : as_chan.get_short_channel_id().unwrap(), is_permanent: false } );
 
@@ -5024,7 +5094,7 @@ fn do_test_fail_backwards_unrevoked_remote_announce(deliver_last_raa: bool, anno
 	send_payment(&nodes[1], &[&nodes[2], &nodes[3], &nodes[5]], 500000, 500_000);
 	assert_eq!(get_local_commitment_txn!(nodes[3], chan.2)[0].output.len(), 3); // to_local, to_remote, and anchor output
 
-	let ds_dust_limit = nodes[3].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().our_dust_limit_satoshis;
+	let ds_dust_limit = get_channel_slot!(nodes[3], chan.2).lock().unwrap().as_ref().unwrap().our_dust_limit_satoshis;
 	// 0th HTLC:
 	let (_, payment_hash_1) = route_payment(&nodes[0], &[&nodes[2], &nodes[3], &nodes[4]], ds_dust_limit*1000); // not added < dust limit + HTLC tx fee
 	// 1st HTLC:
@@ -5834,7 +5904,7 @@ fn test_onion_failure() {
 	run_onion_failure_test("unknown_next_peer", 0, &nodes, &bogus_route, &payment_hash, |_| {}, ||{}, true, Some(PERM|10),
 	  Some(msgs::HTLCFailChannelUpdate::ChannelClosed{short_channel_id: bogus_route.paths[0][1].short_channel_id, is_permanent:true}));
 
-	let amt_to_forward = nodes[1].node.channel_state.lock().unwrap().by_id.get(&channels[1].2).unwrap().get_their_htlc_minimum_msat() - 1;
+	let amt_to_forward = get_channel_slot!(nodes[1], channels[1].2).lock().unwrap().as_ref().unwrap().get_their_htlc_minimum_msat() - 1;
 	let mut bogus_route = route.clone();
 	let route_len = bogus_route.paths[0].len();
 	bogus_route.paths[0][route_len-1].fee_msat = amt_to_forward;
@@ -6081,7 +6151,7 @@ fn test_update_add_htlc_bolt2_sender_exceed_max_htlc_num_and_htlc_id_increment()
 	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
 	let mut nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1000000, 0, InitFeatures::known(), InitFeatures::known());
-	let max_accepted_htlcs = nodes[1].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().their_max_accepted_htlcs as u64;
+	let max_accepted_htlcs = get_channel_slot!(nodes[1], chan.2).lock().unwrap().as_ref().unwrap().their_max_accepted_htlcs as u64;
 
 	let logger = test_utils::TestLogger::new();
 	for i in 0..max_accepted_htlcs {
@@ -6155,8 +6225,9 @@ fn test_update_add_htlc_bolt2_receiver_check_amount_received_more_than_min() {
 	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 95000000, InitFeatures::known(), InitFeatures::known());
 	let htlc_minimum_msat: u64;
 	{
-		let chan_lock = nodes[0].node.channel_state.lock().unwrap();
-		let channel = chan_lock.by_id.get(&chan.2).unwrap();
+		let chan_slot = get_channel_slot!(nodes[0], chan.2);
+		let chan_lock = chan_slot.lock().unwrap();
+		let channel = chan_lock.as_ref().unwrap();
 		htlc_minimum_msat = channel.get_our_htlc_minimum_msat();
 	}
 
@@ -6673,7 +6744,7 @@ fn do_test_failure_delay_dust_htlc_local_commitment(announce_latest: bool) {
 	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 	let chan =create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
 
-	let bs_dust_limit = nodes[1].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().our_dust_limit_satoshis;
+	let bs_dust_limit = get_channel_slot!(nodes[1], chan.2).lock().unwrap().as_ref().unwrap().our_dust_limit_satoshis;
 
 	// We route 2 dust-HTLCs between A and B
 	let (_, payment_hash_1) = route_payment(&nodes[0], &[&nodes[1]], bs_dust_limit*1000);
@@ -6765,8 +6836,8 @@ fn test_no_failure_dust_htlc_local_commitment() {
 	// Rebalance a bit
 	send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000, 8_000_000);
 
-	let as_dust_limit = nodes[0].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().our_dust_limit_satoshis;
-	let bs_dust_limit = nodes[1].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().our_dust_limit_satoshis;
+	let as_dust_limit = get_channel_slot!(nodes[0], chan.2).lock().unwrap().as_ref().unwrap().our_dust_limit_satoshis;
+	let bs_dust_limit = get_channel_slot!(nodes[1], chan.2).lock().unwrap().as_ref().unwrap().our_dust_limit_satoshis;
 
 	// We route 2 dust-HTLCs between A and B
 	let (preimage_1, _) = route_payment(&nodes[0], &[&nodes[1]], bs_dust_limit*1000);
@@ -6819,7 +6890,7 @@ fn do_test_sweep_outbound_htlc_failure_update(revoked: bool, local: bool) {
 	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
 	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
 
-	let bs_dust_limit = nodes[1].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().our_dust_limit_satoshis;
+	let bs_dust_limit = get_channel_slot!(nodes[1], chan.2).lock().unwrap().as_ref().unwrap().our_dust_limit_satoshis;
 
 	let (_payment_preimage_1, dust_hash) = route_payment(&nodes[0], &[&nodes[1]], bs_dust_limit*1000);
 	let (_payment_preimage_2, non_dust_hash) = route_payment(&nodes[0], &[&nodes[1]], 1000000);
@@ -7721,7 +7792,7 @@ fn test_counterparty_raa_skip_no_crash() {
 	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 	let channel_id = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known()).2;
 
-	let commitment_seed = nodes[0].node.channel_state.lock().unwrap().by_id.get_mut(&channel_id).unwrap().local_keys.commitment_seed().clone();
+	let commitment_seed = get_channel_slot!(nodes[0], channel_id).lock().unwrap().as_ref().unwrap().local_keys.commitment_seed().clone();
 	const INITIAL_COMMITMENT_NUMBER: u64 = (1 << 48) - 1;
 	let next_per_commitment_point = PublicKey::from_secret_key(&Secp256k1::new(),
 		&SecretKey::from_slice(&chan_utils::build_commitment_secret(&commitment_seed, INITIAL_COMMITMENT_NUMBER - 2)).unwrap());
@@ -7930,7 +8001,8 @@ fn test_update_err_monitor_lockdown() {
 	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
 	assert_eq!(updates.update_fulfill_htlcs.len(), 1);
 	nodes[0].node.handle_update_fulfill_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fulfill_htlcs[0]);
-	if let Some(ref mut channel) = nodes[0].node.channel_state.lock().unwrap().by_id.get_mut(&chan_1.2) {
+	let chan_slot = get_channel_slot!(nodes[0], chan_1.2);
+	if let Some(ref mut channel) = *chan_slot.lock().unwrap() {
 		if let Ok((_, _, _, update)) = channel.commitment_signed(&updates.commitment_signed, &node_cfgs[0].fee_estimator, &node_cfgs[0].logger) {
 			if let Err(_) =  watchtower.simple_monitor.update_monitor(outpoint, update.clone()) {} else { assert!(false); }
 			if let Ok(_) = nodes[0].chan_monitor.update_monitor(outpoint, update) {} else { assert!(false); }
@@ -7941,3 +8013,148 @@ fn test_update_err_monitor_lockdown() {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 }
+
+/// Pulls all pending message events out of `provider`, stashing them in `stash` keyed by the peer
+/// they are destined for, and returns those for `node_id`. This lets several threads, each
+/// driving a different channel, share a single ChannelManager's message queue.
+fn take_msg_events_for<P: MessageSendEventsProvider>(provider: &P, stash: &Mutex<HashMap<PublicKey, Vec<MessageSendEvent>>>, node_id: &PublicKey) -> Vec<MessageSendEvent> {
+	let mut stash = stash.lock().unwrap();
+	for event in provider.get_and_clear_pending_msg_events() {
+		let dest = match event {
+			MessageSendEvent::UpdateHTLCs { ref node_id, .. } => *node_id,
+			MessageSendEvent::SendRevokeAndACK { ref node_id, .. } => *node_id,
+			_ => panic!("Unexpected event"),
+		};
+		stash.entry(dest).or_insert(Vec::new()).push(event);
+	}
+	stash.remove(node_id).unwrap_or(Vec::new())
+}
+
+fn expect_revoke_and_ack(events: Vec<MessageSendEvent>) -> msgs::RevokeAndACK {
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		MessageSendEvent::SendRevokeAndACK { ref msg, .. } => msg.clone(),
+		_ => panic!("Unexpected event"),
+	}
+}
+
+fn expect_revoke_and_commit(events: Vec<MessageSendEvent>) -> (msgs::RevokeAndACK, msgs::CommitmentSigned) {
+	assert_eq!(events.len(), 2);
+	let raa = match events[0] {
+		MessageSendEvent::SendRevokeAndACK { ref msg, .. } => msg.clone(),
+		_ => panic!("Unexpected event"),
+	};
+	let commitment_signed = match events[1] {
+		MessageSendEvent::UpdateHTLCs { ref updates, .. } => {
+			assert!(updates.update_add_htlcs.is_empty());
+			assert!(updates.update_fulfill_htlcs.is_empty());
+			updates.commitment_signed.clone()
+		},
+		_ => panic!("Unexpected event"),
+	};
+	(raa, commitment_signed)
+}
+
+#[test]
+fn test_concurrent_htlcs_on_independent_channels() {
+	// ChannelManager locks each channel individually, so HTLCs on different channels may be
+	// handled from different threads at the same time. Here node 0 has a channel with each of
+	// several peers and every peer pays node 0 from its own thread, with all the threads sharing
+	// node 0's ChannelManager.
+	const PEER_COUNT: usize = 4;
+	const PAYMENTS_PER_PEER: usize = 10;
+	const PAYMENT_MSAT: u64 = 100_000;
+
+	let chanmon_cfgs = create_chanmon_cfgs(PEER_COUNT + 1);
+	let node_cfgs = create_node_cfgs(PEER_COUNT + 1, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(PEER_COUNT + 1, &node_cfgs, &vec![None; PEER_COUNT + 1]);
+	let nodes = create_network(PEER_COUNT + 1, &node_cfgs, &node_chanmgrs);
+
+	let logger = test_utils::TestLogger::new();
+	let mut chan_ids = Vec::new();
+	let mut routes = Vec::new();
+	for i in 1..PEER_COUNT + 1 {
+		chan_ids.push(create_announced_chan_between_nodes_with_value(&nodes, i, 0, 1_000_000, 0, InitFeatures::known(), InitFeatures::known()).2);
+		let net_graph_msg_handler = &nodes[i].net_graph_msg_handler;
+		routes.push(get_route(&nodes[i].node.get_our_node_id(), net_graph_msg_handler, &nodes[0].node.get_our_node_id(), None, &Vec::new(), PAYMENT_MSAT, TEST_FINAL_CLTV, &logger).unwrap());
+	}
+
+	// The threads borrow the nodes, so are scoped to make sure they're all joined while the nodes
+	// are still around.
+	let hub = nodes[0].node;
+	let hub_msgs = Mutex::new(HashMap::new());
+	crossbeam::scope(|scope| {
+		for (idx, route) in routes.drain(..).enumerate() {
+			let peer = nodes[idx + 1].node;
+			let hub_msgs = &hub_msgs;
+			scope.spawn(move || {
+				let hub_id = hub.get_our_node_id();
+				let peer_id = peer.get_our_node_id();
+				for n in 0..PAYMENTS_PER_PEER {
+					let payment_preimage = PaymentPreimage([(100 + idx * PAYMENTS_PER_PEER + n) as u8; 32]);
+					let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0[..]).into_inner());
+
+					peer.send_payment(&route, payment_hash, &None).unwrap();
+					let mut events = peer.get_and_clear_pending_msg_events();
+					assert_eq!(events.len(), 1);
+					let payment_event = SendEvent::from_event(events.remove(0));
+					hub.handle_update_add_htlc(&peer_id, &payment_event.msgs[0]);
+					hub.handle_commitment_signed(&peer_id, &payment_event.commitment_msg);
+					let (hub_raa, hub_cs) = expect_revoke_and_commit(take_msg_events_for(hub, hub_msgs, &peer_id));
+					peer.handle_revoke_and_ack(&hub_id, &hub_raa);
+					peer.handle_commitment_signed(&hub_id, &hub_cs);
+					hub.handle_revoke_and_ack(&peer_id, &expect_revoke_and_ack(peer.get_and_clear_pending_msg_events()));
+
+					// Another thread may have picked our HTLC up in its own call to
+					// process_pending_htlc_forwards, in which case it only becomes claimable once that
+					// call completes.
+					loop {
+						hub.process_pending_htlc_forwards();
+						if hub.claim_funds(payment_preimage, &None, PAYMENT_MSAT) { break; }
+						::std::thread::yield_now();
+					}
+					let mut events = take_msg_events_for(hub, hub_msgs, &peer_id);
+					assert_eq!(events.len(), 1);
+					let (update_fulfill, hub_cs) = match events.remove(0) {
+						MessageSendEvent::UpdateHTLCs { updates, .. } => {
+							assert_eq!(updates.update_fulfill_htlcs.len(), 1);
+							(updates.update_fulfill_htlcs[0].clone(), updates.commitment_signed)
+						},
+						_ => panic!("Unexpected event"),
+					};
+					peer.handle_update_fulfill_htlc(&hub_id, &update_fulfill);
+					peer.handle_commitment_signed(&hub_id, &hub_cs);
+					let (peer_raa, peer_cs) = expect_revoke_and_commit(peer.get_and_clear_pending_msg_events());
+					hub.handle_revoke_and_ack(&peer_id, &peer_raa);
+					hub.handle_commitment_signed(&peer_id, &peer_cs);
+					peer.handle_revoke_and_ack(&hub_id, &expect_revoke_and_ack(take_msg_events_for(hub, hub_msgs, &peer_id)));
+					assert!(peer.get_and_clear_pending_msg_events().is_empty());
+
+					let events = peer.get_and_clear_pending_events();
+					assert_eq!(events.len(), 1);
+					match events[0] {
+						Event::PaymentSent { payment_preimage: ref sent_preimage } => assert_eq!(*sent_preimage, payment_preimage),
+						_ => panic!("Unexpected event"),
+					}
+				}
+			});
+		}
+	});
+
+	assert!(hub_msgs.lock().unwrap().values().all(|msgs| msgs.is_empty()));
+	let received = nodes[0].node.get_and_clear_pending_events().iter().filter(|event| match event {
+		&&Event::PaymentReceived { amt, .. } => { assert_eq!(amt, PAYMENT_MSAT); true },
+		_ => false,
+	}).count();
+	assert_eq!(received, PEER_COUNT * PAYMENTS_PER_PEER);
+	for node in nodes.iter() {
+		node.chan_monitor.added_monitors.lock().unwrap().clear();
+	}
+
+	// Every channel should have ended up in the same state as if its payments had been made in
+	// sequence, and should still be usable.
+	for (i, chan_id) in chan_ids.iter().enumerate() {
+		assert_eq!(get_channel_value_stat!(nodes[0], *chan_id).value_to_self_msat, PAYMENT_MSAT * PAYMENTS_PER_PEER as u64);
+		send_payment(&nodes[i + 1], &[&nodes[0]], 1_000_000, 1_000_000);
+	}
+}
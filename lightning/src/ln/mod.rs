@@ -15,6 +15,7 @@ pub mod msgs;
 pub mod peer_handler;
 pub mod chan_utils;
 pub mod features;
+pub mod wire_recorder;
 pub(crate) mod onchaintx;
 
 #[cfg(feature = "fuzztarget")]
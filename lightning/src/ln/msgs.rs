@@ -25,6 +25,7 @@ use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
 
 use std::{cmp, fmt};
 use std::io::Read;
+use std::net::SocketAddr;
 use std::result::Result;
 
 use util::events;
@@ -309,6 +310,15 @@ impl NetAddress {
 	pub(crate) const MAX_LEN: u16 = 37;
 }
 
+impl From<SocketAddr> for NetAddress {
+	fn from(addr: SocketAddr) -> Self {
+		match addr {
+			SocketAddr::V4(addr) => NetAddress::IPv4 { addr: addr.ip().octets(), port: addr.port() },
+			SocketAddr::V6(addr) => NetAddress::IPv6 { addr: addr.ip().octets(), port: addr.port() },
+		}
+	}
+}
+
 impl Writeable for NetAddress {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
 		match self {
@@ -613,6 +623,31 @@ pub trait RoutingMessageHandler : Send + Sync {
 	fn should_request_full_sync(&self, node_id: &PublicKey) -> bool;
 }
 
+/// A trait to describe an object which can send and receive application-defined ("custom")
+/// messages, ie messages of a type which is not defined by the Lightning specification.
+///
+/// Custom message types should generally be picked from the experimental range (32768-65535).
+/// Message types which rust-lightning itself understands are never passed to (or sent on behalf
+/// of) a CustomMessageHandler.
+pub trait CustomMessageHandler : Send + Sync {
+	/// Returns whether messages of the given type should be passed to handle_custom_message.
+	/// Messages of an unhandled type are treated as unknown messages, ie the peer is disconnected
+	/// if the type is even and the message is ignored if the type is odd, as per BOLT 1.
+	fn handles_message_type(&self, message_type: u16) -> bool;
+	/// Handle an incoming custom message from the given peer. data contains the message payload,
+	/// excluding the 2-byte message type.
+	fn handle_custom_message(&self, their_node_id: &PublicKey, message_type: u16, data: &[u8]) -> Result<(), LightningError>;
+	/// Gets the list of pending custom messages to send as (their_node_id, message_type, payload)
+	/// tuples. Messages for peers which are not currently connected are dropped.
+	fn get_and_clear_pending_custom_messages(&self) -> Vec<(PublicKey, u16, Vec<u8>)>;
+	/// Gets the list of feature bits which should be set in the Init messages we send. Bits which
+	/// belong to features known to rust-lightning are ignored.
+	///
+	/// Peers which require (ie set the even bit of) a feature included here are not disconnected
+	/// for requiring an unknown feature.
+	fn custom_init_feature_bits(&self) -> Vec<usize>;
+}
+
 mod fuzzy_internal_msgs {
 	use ln::channelmanager::PaymentSecret;
 
@@ -430,6 +430,8 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 					&InputMaterial::Revoked { .. } => 1, // XXX: Depends on spec discussion
 					&InputMaterial::RemoteHTLC { .. } => 1,
 					&InputMaterial::LocalHTLC { .. } => 1,
+					// The local commitment transaction is broadcast as signed, not from this template.
+					&InputMaterial::Funding { .. } => 0xffffffff,
 				},
 				witness: Vec::new(),
 			});
@@ -16,15 +16,17 @@ use util::ser::{VecWriter, Writeable};
 use ln::peer_channel_encryptor::{PeerChannelEncryptor,NextNoiseStep};
 use ln::wire;
 use ln::wire::Encode;
+use ln::wire_recorder::{MessageDirection, WireRecorder};
 use util::byte_utils;
 use util::events::{MessageSendEvent, MessageSendEventsProvider};
 use util::logger::Logger;
 
 use std::collections::{HashMap,hash_map,HashSet,LinkedList};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{cmp,error,hash,fmt};
+use std::{cmp,error,hash,fmt,mem};
 use std::ops::Deref;
+use std::time::{Duration, Instant};
 
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::sha256::HashEngine as Sha256Engine;
@@ -38,6 +40,21 @@ pub struct MessageHandler<CM: Deref> where CM::Target: msgs::ChannelMessageHandl
 	/// A message handler which handles messages updating our knowledge of the network channel
 	/// graph. Usually this is just a NetGraphMsgHandlerMonitor object.
 	pub route_handler: Arc<msgs::RoutingMessageHandler>,
+	/// A message handler which handles application-defined messages which are not part of the
+	/// Lightning specification. If you don't use any, this can be an IgnoringCustomMessageHandler.
+	pub custom_message_handler: Arc<msgs::CustomMessageHandler>,
+}
+
+/// A dummy CustomMessageHandler which handles no custom messages, never sends any and advertises
+/// no custom features.
+pub struct IgnoringCustomMessageHandler {}
+impl msgs::CustomMessageHandler for IgnoringCustomMessageHandler {
+	fn handles_message_type(&self, _message_type: u16) -> bool { false }
+	fn handle_custom_message(&self, _their_node_id: &PublicKey, _message_type: u16, _data: &[u8]) -> Result<(), msgs::LightningError> {
+		unreachable!();
+	}
+	fn get_and_clear_pending_custom_messages(&self) -> Vec<(PublicKey, u16, Vec<u8>)> { Vec::new() }
+	fn custom_init_feature_bits(&self) -> Vec<usize> { Vec::new() }
 }
 
 /// Provides an object which can be used to send data to and which uniquely identifies a connection
@@ -73,6 +90,104 @@ pub trait SocketDescriptor : cmp::Eq + hash::Hash + Clone {
 	/// though races may occur whereby disconnect_socket is called after a call to
 	/// socket_disconnected but prior to socket_disconnected returning.
 	fn disconnect_socket(&mut self);
+	/// Returns the address of the remote end of the connection, if known. This is used to limit
+	/// the number of inbound connections from a single IP (see
+	/// PeerManagerLimits::max_connections_per_ip).
+	///
+	/// Connections for which None is returned are not subject to the per-IP limit. Connections
+	/// from loopback or unspecified addresses (eg inbound connections forwarded by a local Tor
+	/// daemon, which would all appear to come from the same address) are exempt as well.
+	fn remote_addr(&self) -> Option<msgs::NetAddress> { None }
+}
+
+/// Limits which a PeerManager enforces to bound the resources a single peer (or a set of peers
+/// from the same IP) can consume. Peers which exceed a limit are disconnected or throttled, as
+/// described for each field.
+///
+/// Default::default() provides reasonable values for a routing node.
+#[derive(Clone, Debug)]
+pub struct PeerManagerLimits {
+	/// The maximum number of connections. Inbound connections beyond this limit are refused.
+	/// Outbound connections, which we initiate ourselves, are never refused but do count towards
+	/// the limit.
+	///
+	/// Default value: 500
+	pub max_peers: usize,
+	/// The maximum number of connections which have yet to complete the noise handshake and send
+	/// us an Init message. Inbound connections beyond this limit are refused.
+	///
+	/// Note that connections which fail to complete the handshake before the second
+	/// timer_tick_occured call after they were created are disconnected.
+	///
+	/// Default value: 64
+	pub max_pending_handshakes: usize,
+	/// The maximum number of connections from the same remote IP, as reported by
+	/// SocketDescriptor::remote_addr. IPv6 addresses are grouped by their /64 prefix. Inbound
+	/// connections beyond this limit are refused.
+	///
+	/// Default value: 8
+	pub max_connections_per_ip: usize,
+	/// The maximum number of bytes we buffer for sending to a peer. Peers which don't read the data
+	/// we send them fast enough to stay below this limit are disconnected.
+	///
+	/// Default value: 1 MiB
+	pub max_outbound_buffer_bytes: usize,
+	/// Once the number of bytes we buffer for sending to a peer reaches this limit, gossip
+	/// messages are no longer forwarded to the peer (and any initial routing table sync is
+	/// paused) until it has read more of the buffered data.
+	///
+	/// Default value: 64 KiB
+	pub gossip_outbound_buffer_bytes: usize,
+	/// The number of gossip messages (channel_announcement, channel_update and
+	/// node_announcement) we process from a peer for each timer_tick_occured call. Gossip in
+	/// excess of the rate limit is ignored.
+	///
+	/// Default value: 5000
+	pub inbound_gossip_per_tick: u32,
+	/// The number of gossip messages a peer may send in a burst, ie the size of the token bucket
+	/// which is refilled with inbound_gossip_per_tick tokens on each timer_tick_occured call. Peers
+	/// start with a full bucket, so this should be large enough to cover any initial routing table
+	/// sync we request.
+	///
+	/// Default value: 100000
+	pub inbound_gossip_burst: u32,
+}
+
+impl Default for PeerManagerLimits {
+	fn default() -> Self {
+		PeerManagerLimits {
+			max_peers: 500,
+			max_pending_handshakes: 64,
+			max_connections_per_ip: 8,
+			max_outbound_buffer_bytes: 1024 * 1024,
+			gossip_outbound_buffer_bytes: 64 * 1024,
+			inbound_gossip_per_tick: 5000,
+			inbound_gossip_burst: 100_000,
+		}
+	}
+}
+
+/// Gets the key by which connections from the same remote IP are grouped, if any. Loopback and
+/// unspecified addresses get no key, as they are likely a local proxy forwarding many peers.
+fn remote_ip_key(addr: &msgs::NetAddress) -> Option<Vec<u8>> {
+	fn ipv4_key(addr: &[u8]) -> Option<Vec<u8>> {
+		if addr[0] == 127 || addr == &[0; 4][..] { None } else { Some(addr.to_vec()) }
+	}
+	match addr {
+		&msgs::NetAddress::IPv4 { ref addr, .. } => ipv4_key(&addr[..]),
+		&msgs::NetAddress::IPv6 { ref addr, .. } => {
+			if addr[..15] == [0; 15][..] && addr[15] <= 1 {
+				// :: or ::1
+				None
+			} else if addr[..10] == [0; 10][..] && addr[10..12] == [0xff; 2][..] {
+				// IPv4-mapped addresses, as reported for IPv4 peers by dual-stack sockets
+				ipv4_key(&addr[12..])
+			} else {
+				Some(addr[..8].to_vec())
+			}
+		},
+		_ => None,
+	}
 }
 
 /// Error for PeerManager errors. If you get one of these, you must disconnect the socket and
@@ -101,6 +216,63 @@ impl error::Error for PeerHandleError {
 	}
 }
 
+/// Details of a connected peer, as returned by PeerManager::list_peers.
+#[derive(Clone)]
+pub struct PeerDetails {
+	/// The peer's node_id.
+	pub node_id: PublicKey,
+	/// Whether we opened the connection to the peer (as opposed to the peer connecting to us).
+	pub outbound: bool,
+	/// The features the peer sent us in its Init message, or None if we haven't received it yet.
+	pub their_features: Option<InitFeatures>,
+	/// How long we have been connected to the peer (including the noise handshake).
+	pub connected_for: Duration,
+	/// The round-trip time of the last ping we sent the peer which it replied to, if any.
+	///
+	/// Note that this includes any time the ping spent in our outbound buffer, so a large value
+	/// may also indicate that the peer is slow to read the data we send it.
+	pub last_ping_rtt: Option<Duration>,
+	/// The number of bytes written to the peer's socket.
+	pub bytes_sent: u64,
+	/// The number of bytes read from the peer's socket.
+	pub bytes_received: u64,
+	/// The number of messages queued for sending to the peer, by message type.
+	pub messages_sent: HashMap<u16, u64>,
+	/// The number of messages received from the peer, by message type.
+	pub messages_received: HashMap<u16, u64>,
+	/// The number of messages in our outbound buffer for the peer.
+	pub outbound_buffer_messages: usize,
+	/// The number of (encrypted) bytes in our outbound buffer for the peer.
+	pub outbound_buffer_bytes: usize,
+	/// The size of the buffer we're currently reading the peer's next message into.
+	pub read_buffer_bytes: usize,
+}
+
+/// Statistics about a connection, exposed via PeerDetails.
+struct PeerStats {
+	connected_at: Instant,
+	ping_sent_at: Option<Instant>,
+	last_ping_rtt: Option<Duration>,
+	bytes_sent: u64,
+	bytes_received: u64,
+	messages_sent: HashMap<u16, u64>,
+	messages_received: HashMap<u16, u64>,
+}
+
+impl PeerStats {
+	fn new() -> Self {
+		PeerStats {
+			connected_at: Instant::now(),
+			ping_sent_at: None,
+			last_ping_rtt: None,
+			bytes_sent: 0,
+			bytes_received: 0,
+			messages_sent: HashMap::new(),
+			messages_received: HashMap::new(),
+		}
+	}
+}
+
 enum InitSyncTracker{
 	NoSyncRequested,
 	ChannelsSyncing(u64),
@@ -115,6 +287,8 @@ struct Peer {
 
 	pending_outbound_buffer: LinkedList<Vec<u8>>,
 	pending_outbound_buffer_first_msg_offset: usize,
+	/// The total length of the (encrypted) messages in pending_outbound_buffer.
+	pending_outbound_buffer_bytes: usize,
 	awaiting_write_event: bool,
 
 	pending_read_buffer: Vec<u8>,
@@ -124,9 +298,34 @@ struct Peer {
 	sync_status: InitSyncTracker,
 
 	awaiting_pong: bool,
+	/// The number of timer ticks which have passed without the peer completing the handshake
+	/// (including the Init message exchange).
+	handshake_timer_ticks: u8,
+	/// The number of gossip messages we'll still process from this peer, see
+	/// PeerManagerLimits::inbound_gossip_burst.
+	inbound_gossip_tokens: u32,
+
+	stats: PeerStats,
+	recorder: Option<Arc<WireRecorder>>,
 }
 
 impl Peer {
+	/// Encrypts the given message and appends it to the outbound buffer.
+	fn buffer_message(&mut self, message: &[u8]) {
+		*self.stats.messages_sent.entry(byte_utils::slice_to_be16(&message[0..2])).or_insert(0) += 1;
+		if let (&Some(ref recorder), &Some(ref their_node_id)) = (&self.recorder, &self.their_node_id) {
+			recorder.record_message(their_node_id, MessageDirection::Outbound, message);
+		}
+		let encrypted_message = self.channel_encryptor.encrypt_message(message);
+		self.pending_outbound_buffer_bytes += encrypted_message.len();
+		self.pending_outbound_buffer.push_back(encrypted_message);
+	}
+
+	/// Returns true if the peer failed to complete the handshake in a timely manner.
+	fn handshake_timed_out(&self) -> bool {
+		self.their_features.is_none() && self.handshake_timer_ticks >= 1
+	}
+
 	/// Returns true if the channel announcements/updates for the given channel should be
 	/// forwarded to this peer.
 	/// If we are sending our routing table to this peer and we have not yet sent channel
@@ -157,14 +356,23 @@ enum AnnouncementMsg {
 	NodeAnnounce(msgs::NodeAnnouncement),
 }
 
+/// The set of peers we're connected to, held in PeerManager::peers.
+///
+/// Each Peer sits behind its own Mutex so that calls for different peers can be processed in
+/// parallel while only holding the PeerHolder read lock. The write lock is only taken to add or
+/// remove peers. The remaining fields are shared by all peers and thus behind their own Mutexes
+/// which must only be held briefly.
+///
+/// Lock order: the PeerHolder lock must be taken before any Peer lock, which must in turn be taken
+/// before any of the shared Mutexes below. Only one Peer lock may be held at a time.
 struct PeerHolder<Descriptor: SocketDescriptor> {
-	peers: HashMap<Descriptor, Peer>,
+	peers: HashMap<Descriptor, Mutex<Peer>>,
 	/// Added to by do_read_event for cases where we pushed a message onto the send buffer but
 	/// didn't call do_attempt_write_data to avoid reentrancy. Cleared in process_events()
-	peers_needing_send: HashSet<Descriptor>,
+	peers_needing_send: Mutex<HashSet<Descriptor>>,
 	/// Only add to this set when noise completes:
-	node_id_to_descriptor: HashMap<PublicKey, Descriptor>,
-	pending_broadcasts: Vec<(PublicKey, AnnouncementMsg)>,
+	node_id_to_descriptor: Mutex<HashMap<PublicKey, Descriptor>>,
+	pending_broadcasts: Mutex<Vec<(PublicKey, AnnouncementMsg)>>,
 }
 
 #[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
@@ -196,9 +404,12 @@ pub type SimpleRefPeerManager<'a, 'b, 'c, 'd, 'e, SD, M, T, F, L> = PeerManager<
 /// essentially you should default to using a SimpleRefPeerManager, and use a
 /// SimpleArcPeerManager when you require a PeerManager with a static lifetime, such as when
 /// you're using lightning-net-tokio.
+///
+/// A PeerManager may be driven from multiple threads at once. Each peer is locked separately, so
+/// read_event and write_buffer_space_avail calls for different peers do not block each other.
 pub struct PeerManager<Descriptor: SocketDescriptor, CM: Deref, L: Deref> where CM::Target: msgs::ChannelMessageHandler, L::Target: Logger {
 	message_handler: MessageHandler<CM>,
-	peers: Mutex<PeerHolder<Descriptor>>,
+	peers: RwLock<PeerHolder<Descriptor>>,
 	our_node_secret: SecretKey,
 	ephemeral_key_midstate: Sha256Engine,
 
@@ -207,6 +418,8 @@ pub struct PeerManager<Descriptor: SocketDescriptor, CM: Deref, L: Deref> where
 	peer_counter_low: AtomicUsize,
 	peer_counter_high: AtomicUsize,
 
+	limits: PeerManagerLimits,
+	message_recorder: Option<Arc<WireRecorder>>,
 	logger: L,
 }
 
@@ -224,34 +437,52 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 	/// Constructs a new PeerManager with the given message handlers and node_id secret key
 	/// ephemeral_random_data is used to derive per-connection ephemeral keys and must be
 	/// cryptographically secure random bytes.
+	///
+	/// The default PeerManagerLimits are used, see new_with_limits to configure them.
 	pub fn new(message_handler: MessageHandler<CM>, our_node_secret: SecretKey, ephemeral_random_data: &[u8; 32], logger: L) -> PeerManager<Descriptor, CM, L> {
+		Self::new_with_limits(message_handler, our_node_secret, ephemeral_random_data, logger, PeerManagerLimits::default())
+	}
+
+	/// Constructs a new PeerManager as new() does, but enforcing the given limits on peers.
+	pub fn new_with_limits(message_handler: MessageHandler<CM>, our_node_secret: SecretKey, ephemeral_random_data: &[u8; 32], logger: L, limits: PeerManagerLimits) -> PeerManager<Descriptor, CM, L> {
 		let mut ephemeral_key_midstate = Sha256::engine();
 		ephemeral_key_midstate.input(ephemeral_random_data);
 
 		PeerManager {
 			message_handler,
-			peers: Mutex::new(PeerHolder {
+			peers: RwLock::new(PeerHolder {
 				peers: HashMap::new(),
-				peers_needing_send: HashSet::new(),
-				node_id_to_descriptor: HashMap::new(),
-				pending_broadcasts: Vec::new(),
+				peers_needing_send: Mutex::new(HashSet::new()),
+				node_id_to_descriptor: Mutex::new(HashMap::new()),
+				pending_broadcasts: Mutex::new(Vec::new()),
 			}),
 			our_node_secret,
 			ephemeral_key_midstate,
 			peer_counter_low: AtomicUsize::new(0),
 			peer_counter_high: AtomicUsize::new(0),
+			limits,
+			message_recorder: None,
 			logger,
 		}
 	}
 
+	/// Sets a WireRecorder which will be handed every message sent to or received from peers
+	/// (after decryption), eg to debug interoperability issues. See the wire_recorder module.
+	///
+	/// Only connections created after this call are recorded.
+	pub fn set_message_recorder(&mut self, recorder: Arc<WireRecorder>) {
+		self.message_recorder = Some(recorder);
+	}
+
 	/// Get the list of node ids for peers which have completed the initial handshake.
 	///
 	/// For outbound connections, this will be the same as the their_node_id parameter passed in to
 	/// new_outbound_connection, however entries will only appear once the initial handshake has
 	/// completed and we are sure the remote peer has the private key for the given node_id.
 	pub fn get_peer_node_ids(&self) -> Vec<PublicKey> {
-		let peers = self.peers.lock().unwrap();
-		peers.peers.values().filter_map(|p| {
+		let peers = self.peers.read().unwrap();
+		peers.peers.values().filter_map(|peer_mutex| {
+			let p = peer_mutex.lock().unwrap();
 			if !p.channel_encryptor.is_ready_for_encryption() || p.their_features.is_none() {
 				return None;
 			}
@@ -259,6 +490,57 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 		}).collect()
 	}
 
+	/// Gets details and statistics for each peer which has completed the noise handshake, ie whose
+	/// node_id we know. This includes peers which have yet to send us their Init message.
+	pub fn list_peers(&self) -> Vec<PeerDetails> {
+		let peers = self.peers.read().unwrap();
+		peers.peers.values().filter_map(|peer_mutex| {
+			let p = peer_mutex.lock().unwrap();
+			let node_id = match p.their_node_id {
+				Some(node_id) => node_id,
+				None => return None,
+			};
+			Some(PeerDetails {
+				node_id,
+				outbound: p.outbound,
+				their_features: p.their_features.clone(),
+				connected_for: p.stats.connected_at.elapsed(),
+				last_ping_rtt: p.stats.last_ping_rtt,
+				bytes_sent: p.stats.bytes_sent,
+				bytes_received: p.stats.bytes_received,
+				messages_sent: p.stats.messages_sent.clone(),
+				messages_received: p.stats.messages_received.clone(),
+				outbound_buffer_messages: p.pending_outbound_buffer.len(),
+				outbound_buffer_bytes: p.pending_outbound_buffer_bytes,
+				read_buffer_bytes: p.pending_read_buffer.len(),
+			})
+		}).collect()
+	}
+
+	/// Builds the features we advertise in the Init message we send to the given peer.
+	fn our_init_features(&self, their_node_id: &PublicKey) -> InitFeatures {
+		let mut features = InitFeatures::known();
+		if !self.message_handler.route_handler.should_request_full_sync(their_node_id) {
+			features.clear_initial_routing_sync();
+		}
+		for bit in self.message_handler.custom_message_handler.custom_init_feature_bits() {
+			if !features.set_custom_bit(bit) {
+				log_debug!(self.logger, "Not setting custom feature bit {} as it belongs to a known feature", bit);
+			}
+		}
+		features
+	}
+
+	/// Checks whether the given peer features require any feature which neither we nor our
+	/// CustomMessageHandler understand.
+	fn requires_unknown_bits(&self, their_features: &InitFeatures) -> bool {
+		let mut features = their_features.clone();
+		for bit in self.message_handler.custom_message_handler.custom_init_feature_bits() {
+			features.clear_custom_feature(bit);
+		}
+		features.requires_unknown_bits()
+	}
+
 	fn get_ephemeral_key(&self) -> SecretKey {
 		let mut ephemeral_hash = self.ephemeral_key_midstate.clone();
 		let low = self.peer_counter_low.fetch_add(1, Ordering::AcqRel);
@@ -285,8 +567,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 		let res = peer_encryptor.get_act_one().to_vec();
 		let pending_read_buffer = [0; 50].to_vec(); // Noise act two is 50 bytes
 
-		let mut peers = self.peers.lock().unwrap();
-		if peers.peers.insert(descriptor, Peer {
+		let mut peers = self.peers.write().unwrap();
+		if peers.peers.insert(descriptor, Mutex::new(Peer {
 			channel_encryptor: peer_encryptor,
 			outbound: true,
 			their_node_id: None,
@@ -294,6 +576,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 
 			pending_outbound_buffer: LinkedList::new(),
 			pending_outbound_buffer_first_msg_offset: 0,
+			pending_outbound_buffer_bytes: 0,
 			awaiting_write_event: false,
 
 			pending_read_buffer: pending_read_buffer,
@@ -303,7 +586,13 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 			sync_status: InitSyncTracker::NoSyncRequested,
 
 			awaiting_pong: false,
-		}).is_some() {
+			handshake_timer_ticks: 0,
+			inbound_gossip_tokens: self.limits.inbound_gossip_burst,
+
+			// The caller must write the act one we return to the socket, so count it as sent.
+			stats: PeerStats { bytes_sent: res.len() as u64, ..PeerStats::new() },
+			recorder: self.message_recorder.clone(),
+		})).is_some() {
 			panic!("PeerManager driver duplicated descriptors!");
 		};
 		Ok(res)
@@ -322,8 +611,26 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 		let peer_encryptor = PeerChannelEncryptor::new_inbound(&self.our_node_secret);
 		let pending_read_buffer = [0; 50].to_vec(); // Noise act one is 50 bytes
 
-		let mut peers = self.peers.lock().unwrap();
-		if peers.peers.insert(descriptor, Peer {
+		let mut peers = self.peers.write().unwrap();
+		if peers.peers.len() >= self.limits.max_peers {
+			log_debug!(self.logger, "Refusing inbound connection as we already have {} peers", peers.peers.len());
+			return Err(PeerHandleError{ no_connection_possible: false });
+		}
+		let pending_handshakes = peers.peers.values().filter(|peer| peer.lock().unwrap().their_features.is_none()).count();
+		if pending_handshakes >= self.limits.max_pending_handshakes {
+			log_debug!(self.logger, "Refusing inbound connection as {} connections have yet to complete the handshake", pending_handshakes);
+			return Err(PeerHandleError{ no_connection_possible: false });
+		}
+		if let Some(ip_key) = descriptor.remote_addr().as_ref().and_then(remote_ip_key) {
+			let ip_connections = peers.peers.keys().filter(|other_descriptor| {
+				other_descriptor.remote_addr().as_ref().and_then(remote_ip_key).as_ref() == Some(&ip_key)
+			}).count();
+			if ip_connections >= self.limits.max_connections_per_ip {
+				log_debug!(self.logger, "Refusing inbound connection as we already have {} connections from the same IP", ip_connections);
+				return Err(PeerHandleError{ no_connection_possible: false });
+			}
+		}
+		if peers.peers.insert(descriptor, Mutex::new(Peer {
 			channel_encryptor: peer_encryptor,
 			outbound: false,
 			their_node_id: None,
@@ -331,6 +638,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 
 			pending_outbound_buffer: LinkedList::new(),
 			pending_outbound_buffer_first_msg_offset: 0,
+			pending_outbound_buffer_bytes: 0,
 			awaiting_write_event: false,
 
 			pending_read_buffer: pending_read_buffer,
@@ -340,7 +648,12 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 			sync_status: InitSyncTracker::NoSyncRequested,
 
 			awaiting_pong: false,
-		}).is_some() {
+			handshake_timer_ticks: 0,
+			inbound_gossip_tokens: self.limits.inbound_gossip_burst,
+
+			stats: PeerStats::new(),
+			recorder: self.message_recorder.clone(),
+		})).is_some() {
 			panic!("PeerManager driver duplicated descriptors!");
 		};
 		Ok(())
@@ -351,13 +664,13 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 			($msg: expr) => {
 				{
 					log_trace!(self.logger, "Encoding and sending sync update message of type {} to {}", $msg.type_id(), log_pubkey!(peer.their_node_id.unwrap()));
-					peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!($msg)[..]));
+					peer.buffer_message(&encode_msg!($msg)[..]);
 				}
 			}
 		}
 		const MSG_BUFF_SIZE: usize = 10;
 		while !peer.awaiting_write_event {
-			if peer.pending_outbound_buffer.len() < MSG_BUFF_SIZE {
+			if peer.pending_outbound_buffer.len() < MSG_BUFF_SIZE && peer.pending_outbound_buffer_bytes < self.limits.gossip_outbound_buffer_bytes {
 				match peer.sync_status {
 					InitSyncTracker::NoSyncRequested => {},
 					InitSyncTracker::ChannelsSyncing(c) if c < 0xffff_ffff_ffff_ffff => {
@@ -412,11 +725,14 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 				let should_be_reading = peer.pending_outbound_buffer.len() < MSG_BUFF_SIZE;
 				let pending = &next_buff[peer.pending_outbound_buffer_first_msg_offset..];
 				let data_sent = descriptor.send_data(pending, should_be_reading);
+				peer.stats.bytes_sent += data_sent as u64;
 				peer.pending_outbound_buffer_first_msg_offset += data_sent;
 				if peer.pending_outbound_buffer_first_msg_offset == next_buff.len() { true } else { false }
 			} {
 				peer.pending_outbound_buffer_first_msg_offset = 0;
-				peer.pending_outbound_buffer.pop_front();
+				if let Some(buff) = peer.pending_outbound_buffer.pop_front() {
+					peer.pending_outbound_buffer_bytes -= buff.len();
+				}
 			} else {
 				peer.awaiting_write_event = true;
 			}
@@ -431,15 +747,21 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 	/// new_*\_connection) before returning. Thus, be very careful with reentrancy issues! The
 	/// invariants around calling write_buffer_space_avail in case a write did not fully complete
 	/// must still hold - be ready to call write_buffer_space_avail again if a write call generated
-	/// here isn't sufficient! Panics if the descriptor was not previously registered in a
-	/// new_\*_connection event.
+	/// here isn't sufficient!
+	///
+	/// May be called for different descriptors in parallel.
 	pub fn write_buffer_space_avail(&self, descriptor: &mut Descriptor) -> Result<(), PeerHandleError> {
-		let mut peers = self.peers.lock().unwrap();
-		match peers.peers.get_mut(descriptor) {
-			None => panic!("Descriptor for write_event is not already known to PeerManager"),
-			Some(peer) => {
+		let peers = self.peers.read().unwrap();
+		match peers.peers.get(descriptor) {
+			None => {
+				// This is most likely a race with us disconnecting the peer, see
+				// disconnect_socket_internal, so just tell the caller to close the connection.
+				return Err(PeerHandleError{ no_connection_possible: false });
+			},
+			Some(peer_mutex) => {
+				let mut peer = peer_mutex.lock().unwrap();
 				peer.awaiting_write_event = false;
-				self.do_attempt_write_data(descriptor, peer);
+				self.do_attempt_write_data(descriptor, &mut peer);
 			}
 		};
 		Ok(())
@@ -456,7 +778,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 	/// If Ok(true) is returned, further read_events should not be triggered until a send_data call
 	/// on this file descriptor has resume_read set (preventing DoS issues in the send buffer).
 	///
-	/// Panics if the descriptor was not previously registered in a new_*_connection event.
+	/// May be called for different descriptors in parallel, in which case the messages are
+	/// decrypted and handed to the message handlers in parallel as well.
 	pub fn read_event(&self, peer_descriptor: &mut Descriptor, data: &[u8]) -> Result<bool, PeerHandleError> {
 		match self.do_read_event(peer_descriptor, data) {
 			Ok(res) => Ok(res),
@@ -468,25 +791,31 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 	}
 
 	/// Append a message to a peer's pending outbound/write buffer, and update the map of peers needing sends accordingly.
-	fn enqueue_message<M: Encode + Writeable>(&self, peers_needing_send: &mut HashSet<Descriptor>, peer: &mut Peer, descriptor: Descriptor, message: &M) {
+	fn enqueue_message<M: Encode + Writeable>(&self, peers_needing_send: &Mutex<HashSet<Descriptor>>, peer: &mut Peer, descriptor: Descriptor, message: &M) {
 		let mut buffer = VecWriter(Vec::new());
 		wire::write(message, &mut buffer).unwrap(); // crash if the write failed
 		let encoded_message = buffer.0;
 
 		log_trace!(self.logger, "Enqueueing message of type {} to {}", message.type_id(), log_pubkey!(peer.their_node_id.unwrap()));
-		peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_message[..]));
-		peers_needing_send.insert(descriptor);
+		peer.buffer_message(&encoded_message[..]);
+		peers_needing_send.lock().unwrap().insert(descriptor);
 	}
 
 	fn do_read_event(&self, peer_descriptor: &mut Descriptor, data: &[u8]) -> Result<bool, PeerHandleError> {
 		let pause_read = {
-			let mut peers_lock = self.peers.lock().unwrap();
-			let peers = &mut *peers_lock;
-			let pause_read = match peers.peers.get_mut(peer_descriptor) {
-				None => panic!("Descriptor for read_event is not already known to PeerManager"),
-				Some(peer) => {
+			let peers = self.peers.read().unwrap();
+			let pause_read = match peers.peers.get(peer_descriptor) {
+				None => {
+					// This is most likely a race with us disconnecting the peer, see
+					// disconnect_socket_internal, so just tell the caller to close the connection.
+					return Err(PeerHandleError{ no_connection_possible: false });
+				},
+				Some(peer_mutex) => {
+					let mut peer_lock = peer_mutex.lock().unwrap();
+					let peer = &mut *peer_lock;
 					assert!(peer.pending_read_buffer.len() > 0);
 					assert!(peer.pending_read_buffer.len() > peer.pending_read_buffer_pos);
+					peer.stats.bytes_received += data.len() as u64;
 
 					let mut read_pos = 0;
 					while read_pos < data.len() {
@@ -517,7 +846,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 												},
 												msgs::ErrorAction::SendErrorMessage { msg } => {
 													log_trace!(self.logger, "Got Err handling message, sending Error message because {}", e.err);
-													self.enqueue_message(&mut peers.peers_needing_send, peer, peer_descriptor.clone(), &msg);
+													self.enqueue_message(&peers.peers_needing_send, peer, peer_descriptor.clone(), &msg);
 													continue;
 												},
 											}
@@ -526,9 +855,21 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 								}
 							}
 
+							macro_rules! check_gossip_rate_limit {
+								() => {
+									if peer.inbound_gossip_tokens == 0 {
+										continue;
+									}
+									peer.inbound_gossip_tokens -= 1;
+									if peer.inbound_gossip_tokens == 0 {
+										log_debug!(self.logger, "Peer {} exceeded its gossip rate limit, ignoring its gossip until the next timer tick", log_pubkey!(peer.their_node_id.unwrap()));
+									}
+								}
+							}
+
 							macro_rules! insert_node_id {
 								() => {
-									match peers.node_id_to_descriptor.entry(peer.their_node_id.unwrap()) {
+									match peers.node_id_to_descriptor.lock().unwrap().entry(peer.their_node_id.unwrap()) {
 										hash_map::Entry::Occupied(_) => {
 											log_trace!(self.logger, "Got second connection with {}, closing", log_pubkey!(peer.their_node_id.unwrap()));
 											peer.their_node_id = None; // Unset so that we don't generate a peer_disconnected event
@@ -546,24 +887,21 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 							match next_step {
 								NextNoiseStep::ActOne => {
 									let act_two = try_potential_handleerror!(peer.channel_encryptor.process_act_one_with_keys(&peer.pending_read_buffer[..], &self.our_node_secret, self.get_ephemeral_key())).to_vec();
+									peer.pending_outbound_buffer_bytes += act_two.len();
 									peer.pending_outbound_buffer.push_back(act_two);
 									peer.pending_read_buffer = [0; 66].to_vec(); // act three is 66 bytes long
 								},
 								NextNoiseStep::ActTwo => {
 									let (act_three, their_node_id) = try_potential_handleerror!(peer.channel_encryptor.process_act_two(&peer.pending_read_buffer[..], &self.our_node_secret));
+									peer.pending_outbound_buffer_bytes += act_three.len();
 									peer.pending_outbound_buffer.push_back(act_three.to_vec());
 									peer.pending_read_buffer = [0; 18].to_vec(); // Message length header is 18 bytes
 									peer.pending_read_is_header = true;
 
 									peer.their_node_id = Some(their_node_id);
 									insert_node_id!();
-									let mut features = InitFeatures::known();
-									if !self.message_handler.route_handler.should_request_full_sync(&peer.their_node_id.unwrap()) {
-										features.clear_initial_routing_sync();
-									}
-
-									let resp = msgs::Init { features };
-									self.enqueue_message(&mut peers.peers_needing_send, peer, peer_descriptor.clone(), &resp);
+									let resp = msgs::Init { features: self.our_init_features(&their_node_id) };
+									self.enqueue_message(&peers.peers_needing_send, peer, peer_descriptor.clone(), &resp);
 								},
 								NextNoiseStep::ActThree => {
 									let their_node_id = try_potential_handleerror!(peer.channel_encryptor.process_act_three(&peer.pending_read_buffer[..]));
@@ -589,6 +927,11 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 										peer.pending_read_buffer = [0; 18].to_vec();
 										peer.pending_read_is_header = true;
 
+										*peer.stats.messages_received.entry(byte_utils::slice_to_be16(&msg_data[0..2])).or_insert(0) += 1;
+										if let Some(ref recorder) = peer.recorder {
+											recorder.record_message(&peer.their_node_id.unwrap(), MessageDirection::Inbound, &msg_data);
+										}
+
 										let mut reader = ::std::io::Cursor::new(&msg_data[..]);
 										let message_result = wire::read(&mut reader);
 										let message = match message_result {
@@ -626,11 +969,11 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 										match message {
 											// Setup and Control messages:
 											wire::Message::Init(msg) => {
-												if msg.features.requires_unknown_bits() {
+												if self.requires_unknown_bits(&msg.features) {
 													log_info!(self.logger, "Peer global features required unknown version bits");
 													return Err(PeerHandleError{ no_connection_possible: true });
 												}
-												if msg.features.requires_unknown_bits() {
+												if self.requires_unknown_bits(&msg.features) {
 													log_info!(self.logger, "Peer local features required unknown version bits");
 													return Err(PeerHandleError{ no_connection_possible: true });
 												}
@@ -648,7 +991,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 
 												if msg.features.initial_routing_sync() {
 													peer.sync_status = InitSyncTracker::ChannelsSyncing(0);
-													peers.peers_needing_send.insert(peer_descriptor.clone());
+													peers.peers_needing_send.lock().unwrap().insert(peer_descriptor.clone());
 												}
 												if !msg.features.supports_static_remote_key() {
 													log_debug!(self.logger, "Peer {} does not support static remote key, disconnecting with no_connection_possible", log_pubkey!(peer.their_node_id.unwrap()));
@@ -656,13 +999,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 												}
 
 												if !peer.outbound {
-													let mut features = InitFeatures::known();
-													if !self.message_handler.route_handler.should_request_full_sync(&peer.their_node_id.unwrap()) {
-														features.clear_initial_routing_sync();
-													}
-
-													let resp = msgs::Init { features };
-													self.enqueue_message(&mut peers.peers_needing_send, peer, peer_descriptor.clone(), &resp);
+													let resp = msgs::Init { features: self.our_init_features(&peer.their_node_id.unwrap()) };
+													self.enqueue_message(&peers.peers_needing_send, peer, peer_descriptor.clone(), &resp);
 												}
 
 												self.message_handler.chan_handler.peer_connected(&peer.their_node_id.unwrap(), &msg);
@@ -691,11 +1029,14 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 											wire::Message::Ping(msg) => {
 												if msg.ponglen < 65532 {
 													let resp = msgs::Pong { byteslen: msg.ponglen };
-													self.enqueue_message(&mut peers.peers_needing_send, peer, peer_descriptor.clone(), &resp);
+													self.enqueue_message(&peers.peers_needing_send, peer, peer_descriptor.clone(), &resp);
 												}
 											},
 											wire::Message::Pong(_msg) => {
 												peer.awaiting_pong = false;
+												if let Some(ping_sent_at) = peer.stats.ping_sent_at.take() {
+													peer.stats.last_ping_rtt = Some(ping_sent_at.elapsed());
+												}
 											},
 
 											// Channel messages:
@@ -755,27 +1096,35 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 												self.message_handler.chan_handler.handle_announcement_signatures(&peer.their_node_id.unwrap(), &msg);
 											},
 											wire::Message::ChannelAnnouncement(msg) => {
+												check_gossip_rate_limit!();
 												let should_forward = try_potential_handleerror!(self.message_handler.route_handler.handle_channel_announcement(&msg));
 
 												if should_forward {
-													peers.pending_broadcasts.push((peer.their_node_id.unwrap().clone(), AnnouncementMsg::ChanAnnounce(msg)));
+													peers.pending_broadcasts.lock().unwrap().push((peer.their_node_id.unwrap().clone(), AnnouncementMsg::ChanAnnounce(msg)));
 												}
 											},
 											wire::Message::NodeAnnouncement(msg) => {
+												check_gossip_rate_limit!();
 												let should_forward = try_potential_handleerror!(self.message_handler.route_handler.handle_node_announcement(&msg));
 
 												if should_forward {
-													peers.pending_broadcasts.push((peer.their_node_id.unwrap().clone(), AnnouncementMsg::NodeAnnounce(msg)));
+													peers.pending_broadcasts.lock().unwrap().push((peer.their_node_id.unwrap().clone(), AnnouncementMsg::NodeAnnounce(msg)));
 												}
 											},
 											wire::Message::ChannelUpdate(msg) => {
+												check_gossip_rate_limit!();
 												let should_forward = try_potential_handleerror!(self.message_handler.route_handler.handle_channel_update(&msg));
 
 												if should_forward {
-													peers.pending_broadcasts.push((peer.their_node_id.unwrap().clone(), AnnouncementMsg::ChanUpdate(msg)));
+													peers.pending_broadcasts.lock().unwrap().push((peer.their_node_id.unwrap().clone(), AnnouncementMsg::ChanUpdate(msg)));
 												}
 											},
 
+											// Custom messages:
+											wire::Message::Unknown(_) if self.message_handler.custom_message_handler.handles_message_type(byte_utils::slice_to_be16(&msg_data[0..2])) => {
+												try_potential_handleerror!(self.message_handler.custom_message_handler.handle_custom_message(&peer.their_node_id.unwrap(), byte_utils::slice_to_be16(&msg_data[0..2]), &msg_data[2..]));
+											},
+
 											// Unknown messages:
 											wire::Message::Unknown(msg_type) if msg_type.is_even() => {
 												log_debug!(self.logger, "Received unknown even message of type {}, disconnecting peer!", msg_type);
@@ -794,6 +1143,11 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 
 					self.do_attempt_write_data(peer_descriptor, peer);
 
+					if peer.pending_outbound_buffer_bytes > self.limits.max_outbound_buffer_bytes {
+						log_debug!(self.logger, "Disconnecting peer as it isn't reading the {} bytes we have buffered for it", peer.pending_outbound_buffer_bytes);
+						return Err(PeerHandleError{ no_connection_possible: false });
+					}
+
 					peer.pending_outbound_buffer.len() > 10 // pause_read
 				}
 			};
@@ -808,18 +1162,23 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 	/// response messages as well as messages generated by calls to handler functions directly (eg
 	/// functions like ChannelManager::process_pending_htlc_forward or send_payment).
 	pub fn process_events(&self) {
+		// Peers which we need to disconnect once we've released the peers lock.
+		let mut descriptors_needing_disconnect = HashSet::new();
 		{
 			// TODO: There are some DoS attacks here where you can flood someone's outbound send
 			// buffer by doing things like announcing channels on another node. We should be willing to
 			// drop optional-ish messages when send buffers get full!
 
 			let mut events_generated = self.message_handler.chan_handler.get_and_clear_pending_msg_events();
-			let mut peers_lock = self.peers.lock().unwrap();
-			let peers = &mut *peers_lock;
+			let mut custom_msgs_generated = self.message_handler.custom_message_handler.get_and_clear_pending_custom_messages();
+			let peers = self.peers.read().unwrap();
+			// Peers for which we've been asked to disconnect and thus shouldn't send anything else to.
+			let mut node_ids_being_disconnected = HashSet::new();
 
 			macro_rules! broadcast_msgs {
 				({ $($except_check: stmt), * }, { $($encoded_msg: expr), * }) => { {
-					for (ref descriptor, ref mut peer) in peers.peers.iter_mut() {
+					for (ref descriptor, ref peer_mutex) in peers.peers.iter() {
+						let mut peer = peer_mutex.lock().unwrap();
 						if !peer.channel_encryptor.is_ready_for_encryption() || peer.their_features.is_none() {
 							continue
 						}
@@ -827,27 +1186,32 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 							None => continue,
 							Some(their_node_id) => {
 								$(
-									if { $except_check }(&peer, their_node_id) { continue }
+									if { $except_check }(&*peer, their_node_id) { continue }
 								)*
 							}
 						}
-						$(peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&$encoded_msg));)*
-						self.do_attempt_write_data(&mut (*descriptor).clone(), peer);
+						if peer.pending_outbound_buffer_bytes >= self.limits.gossip_outbound_buffer_bytes {
+							log_trace!(self.logger, "Not forwarding gossip to {} as its outbound buffer is full", log_pubkey!(peer.their_node_id.unwrap()));
+							continue;
+						}
+						$(peer.buffer_message(&$encoded_msg);)*
+						self.do_attempt_write_data(&mut (*descriptor).clone(), &mut peer);
 					}
 				} }
 			}
 
-			for (from_node_id, broadcast) in peers.pending_broadcasts.drain(..) {
+			let pending_broadcasts = mem::replace(&mut *peers.pending_broadcasts.lock().unwrap(), Vec::new());
+			for (from_node_id, broadcast) in pending_broadcasts {
 				match broadcast {
 					AnnouncementMsg::ChanUpdate(msg) => {
 						let encoded_msg = encode_msg!(&msg);
-						broadcast_msgs!({ |peer: & &mut Peer, _| !peer.should_forward_channel_announcement(msg.contents.short_channel_id),
+						broadcast_msgs!({ |peer: &Peer, _| !peer.should_forward_channel_announcement(msg.contents.short_channel_id),
 										  |_, their_node_id| their_node_id == from_node_id },
 										{ encoded_msg });
 					},
 					AnnouncementMsg::ChanAnnounce(msg) => {
 						let encoded_msg = encode_msg!(&msg);
-						broadcast_msgs!({ |peer: & &mut Peer, _| !peer.should_forward_channel_announcement(msg.contents.short_channel_id),
+						broadcast_msgs!({ |peer: &Peer, _| !peer.should_forward_channel_announcement(msg.contents.short_channel_id),
 										  |_, their_node_id| their_node_id == msg.contents.node_id_1,
 										  |_, their_node_id| their_node_id == msg.contents.node_id_2,
 										  |_, their_node_id| their_node_id == from_node_id },
@@ -856,7 +1220,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 					AnnouncementMsg::NodeAnnounce(msg) => {
 						let encoded_msg = encode_msg!(&msg);
 
-						broadcast_msgs!({ |peer: & &mut Peer, _| !peer.should_forward_node_announcement(msg.contents.node_id),
+						broadcast_msgs!({ |peer: &Peer, _| !peer.should_forward_node_announcement(msg.contents.node_id),
 										  |_, their_node_id| their_node_id == msg.contents.node_id,
 										  |_, their_node_id| their_node_id == from_node_id },
 										{ encoded_msg });
@@ -868,15 +1232,21 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 				macro_rules! get_peer_for_forwarding {
 					($node_id: expr, $handle_no_such_peer: block) => {
 						{
-							let descriptor = match peers.node_id_to_descriptor.get($node_id) {
-								Some(descriptor) => descriptor.clone(),
+							if node_ids_being_disconnected.contains($node_id) {
+								$handle_no_such_peer;
+								continue;
+							}
+							let descriptor_opt = peers.node_id_to_descriptor.lock().unwrap().get($node_id).cloned();
+							let descriptor = match descriptor_opt {
+								Some(descriptor) => descriptor,
 								None => {
 									$handle_no_such_peer;
 									continue;
 								},
 							};
-							match peers.peers.get_mut(&descriptor) {
-								Some(peer) => {
+							match peers.peers.get(&descriptor) {
+								Some(peer_mutex) => {
+									let peer = peer_mutex.lock().unwrap();
 									if peer.their_features.is_none() {
 										$handle_no_such_peer;
 										continue;
@@ -893,65 +1263,65 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 						log_trace!(self.logger, "Handling SendAcceptChannel event in peer_handler for node {} for channel {}",
 								log_pubkey!(node_id),
 								log_bytes!(msg.temporary_channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Drop the pending channel? (or just let it timeout, but that sucks)
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::SendOpenChannel { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling SendOpenChannel event in peer_handler for node {} for channel {}",
 								log_pubkey!(node_id),
 								log_bytes!(msg.temporary_channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Drop the pending channel? (or just let it timeout, but that sucks)
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::SendFundingCreated { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling SendFundingCreated event in peer_handler for node {} for channel {} (which becomes {})",
 								log_pubkey!(node_id),
 								log_bytes!(msg.temporary_channel_id),
 								log_funding_channel_id!(msg.funding_txid, msg.funding_output_index));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: generate a DiscardFunding event indicating to the wallet that
 								//they should just throw away this funding transaction
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::SendFundingSigned { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling SendFundingSigned event in peer_handler for node {} for channel {}",
 								log_pubkey!(node_id),
 								log_bytes!(msg.channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: generate a DiscardFunding event indicating to the wallet that
 								//they should just throw away this funding transaction
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::SendFundingLocked { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling SendFundingLocked event in peer_handler for node {} for channel {}",
 								log_pubkey!(node_id),
 								log_bytes!(msg.channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::SendAnnouncementSignatures { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling SendAnnouncementSignatures event in peer_handler for node {} for channel {})",
 								log_pubkey!(node_id),
 								log_bytes!(msg.channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: generate a DiscardFunding event indicating to the wallet that
 								//they should just throw away this funding transaction
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::UpdateHTLCs { ref node_id, updates: msgs::CommitmentUpdate { ref update_add_htlcs, ref update_fulfill_htlcs, ref update_fail_htlcs, ref update_fail_malformed_htlcs, ref update_fee, ref commitment_signed } } => {
 						log_trace!(self.logger, "Handling UpdateHTLCs event in peer_handler for node {} with {} adds, {} fulfills, {} fails for channel {}",
@@ -960,73 +1330,73 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 								update_fulfill_htlcs.len(),
 								update_fail_htlcs.len(),
 								log_bytes!(commitment_signed.channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
 						for msg in update_add_htlcs {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							peer.buffer_message(&encode_msg!(msg));
 						}
 						for msg in update_fulfill_htlcs {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							peer.buffer_message(&encode_msg!(msg));
 						}
 						for msg in update_fail_htlcs {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							peer.buffer_message(&encode_msg!(msg));
 						}
 						for msg in update_fail_malformed_htlcs {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							peer.buffer_message(&encode_msg!(msg));
 						}
 						if let &Some(ref msg) = update_fee {
-							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+							peer.buffer_message(&encode_msg!(msg));
 						}
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(commitment_signed)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(commitment_signed));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::SendRevokeAndACK { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling SendRevokeAndACK event in peer_handler for node {} for channel {}",
 								log_pubkey!(node_id),
 								log_bytes!(msg.channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::SendClosingSigned { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling SendClosingSigned event in peer_handler for node {} for channel {}",
 								log_pubkey!(node_id),
 								log_bytes!(msg.channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::SendShutdown { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling Shutdown event in peer_handler for node {} for channel {}",
 								log_pubkey!(node_id),
 								log_bytes!(msg.channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::SendChannelReestablish { ref node_id, ref msg } => {
 						log_trace!(self.logger, "Handling SendChannelReestablish event in peer_handler for node {} for channel {}",
 								log_pubkey!(node_id),
 								log_bytes!(msg.channel_id));
-						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+						let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 								//TODO: Do whatever we're gonna do for handling dropped messages
 							});
-						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-						self.do_attempt_write_data(&mut descriptor, peer);
+						peer.buffer_message(&encode_msg!(msg));
+						self.do_attempt_write_data(&mut descriptor, &mut peer);
 					},
 					MessageSendEvent::BroadcastChannelAnnouncement { ref msg, ref update_msg } => {
 						log_trace!(self.logger, "Handling BroadcastChannelAnnouncement event in peer_handler for short channel id {}", msg.contents.short_channel_id);
 						if self.message_handler.route_handler.handle_channel_announcement(msg).is_ok() && self.message_handler.route_handler.handle_channel_update(update_msg).is_ok() {
 							let encoded_msg = encode_msg!(msg);
 							let encoded_update_msg = encode_msg!(update_msg);
-							broadcast_msgs!({ |peer: & &mut Peer, _| !peer.should_forward_channel_announcement(msg.contents.short_channel_id),
+							broadcast_msgs!({ |peer: &Peer, _| !peer.should_forward_channel_announcement(msg.contents.short_channel_id),
 											  |_, their_node_id| their_node_id == msg.contents.node_id_1,
 											  |_, their_node_id| their_node_id == msg.contents.node_id_2 },
 											{ encoded_msg, encoded_update_msg });
@@ -1037,7 +1407,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 						if self.message_handler.route_handler.handle_node_announcement(msg).is_ok() {
 							let encoded_msg = encode_msg!(msg);
 
-							broadcast_msgs!({ |peer: & &mut Peer, _| !peer.should_forward_node_announcement(msg.contents.node_id),
+							broadcast_msgs!({ |peer: &Peer, _| !peer.should_forward_node_announcement(msg.contents.node_id),
 											  |_, their_node_id| their_node_id == msg.contents.node_id },
 											{ encoded_msg });
 						}
@@ -1047,7 +1417,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 						if self.message_handler.route_handler.handle_channel_update(msg).is_ok() {
 							let encoded_msg = encode_msg!(msg);
 
-							broadcast_msgs!({ |peer: & &mut Peer, _| !peer.should_forward_channel_announcement(msg.contents.short_channel_id) },
+							broadcast_msgs!({ |peer: &Peer, _| !peer.should_forward_channel_announcement(msg.contents.short_channel_id) },
 											{ encoded_msg });
 						}
 					},
@@ -1057,14 +1427,15 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 					MessageSendEvent::HandleError { ref node_id, ref action } => {
 						match *action {
 							msgs::ErrorAction::DisconnectPeer { ref msg } => {
-								if let Some(mut descriptor) = peers.node_id_to_descriptor.remove(node_id) {
-									peers.peers_needing_send.remove(&descriptor);
-									if let Some(mut peer) = peers.peers.remove(&descriptor) {
+								let descriptor_opt = peers.node_id_to_descriptor.lock().unwrap().get(node_id).cloned();
+								if let Some(mut descriptor) = descriptor_opt {
+									if let Some(peer_mutex) = peers.peers.get(&descriptor) {
+										let mut peer = peer_mutex.lock().unwrap();
 										if let Some(ref msg) = *msg {
 											log_trace!(self.logger, "Handling DisconnectPeer HandleError event in peer_handler for node {} with message {}",
 													log_pubkey!(node_id),
 													msg.data);
-											peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
+											peer.buffer_message(&encode_msg!(msg));
 											// This isn't guaranteed to work, but if there is enough free
 											// room in the send buffer, put the error message there...
 											self.do_attempt_write_data(&mut descriptor, &mut peer);
@@ -1072,8 +1443,10 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 											log_trace!(self.logger, "Handling DisconnectPeer HandleError event in peer_handler for node {} with no message", log_pubkey!(node_id));
 										}
 									}
-									descriptor.disconnect_socket();
-									self.message_handler.chan_handler.peer_disconnected(&node_id, false);
+									// We can't remove the peer while only holding the read lock, so
+									// disconnect it (and inform the chan_handler) once we're done.
+									node_ids_being_disconnected.insert(node_id.clone());
+									descriptors_needing_disconnect.insert(descriptor);
 								}
 							},
 							msgs::ErrorAction::IgnoreError => {},
@@ -1081,23 +1454,78 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 								log_trace!(self.logger, "Handling SendErrorMessage HandleError event in peer_handler for node {} with message {}",
 										log_pubkey!(node_id),
 										msg.data);
-								let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+								let (mut descriptor, mut peer) = get_peer_for_forwarding!(node_id, {
 									//TODO: Do whatever we're gonna do for handling dropped messages
 								});
-								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg)));
-								self.do_attempt_write_data(&mut descriptor, peer);
+								peer.buffer_message(&encode_msg!(msg));
+								self.do_attempt_write_data(&mut descriptor, &mut peer);
 							},
 						}
 					}
 				}
 			}
 
-			for mut descriptor in peers.peers_needing_send.drain() {
-				match peers.peers.get_mut(&descriptor) {
-					Some(peer) => self.do_attempt_write_data(&mut descriptor, peer),
+			for (their_node_id, message_type, payload) in custom_msgs_generated.drain(..) {
+				let mut encoded_msg = Vec::with_capacity(payload.len() + 2);
+				encoded_msg.extend_from_slice(&byte_utils::be16_to_array(message_type));
+				encoded_msg.extend_from_slice(&payload[..]);
+				if encoded_msg.len() > 65535 {
+					log_debug!(self.logger, "Dropping custom message of type {} to {} as it is too long", message_type, log_pubkey!(their_node_id));
+					continue;
+				}
+				// wire::read only returns Message::Unknown if the type is unknown, irrespective of the
+				// payload, so use it to make sure we never send a message we'd interpret ourselves.
+				match wire::read(&mut ::std::io::Cursor::new(&encoded_msg[..])) {
+					Ok(wire::Message::Unknown(_)) => {},
+					_ => {
+						log_debug!(self.logger, "Dropping custom message of type {} to {} as the type is not a custom one", message_type, log_pubkey!(their_node_id));
+						continue;
+					},
+				}
+				let descriptor_opt = if node_ids_being_disconnected.contains(&their_node_id) { None } else {
+					peers.node_id_to_descriptor.lock().unwrap().get(&their_node_id).cloned()
+				};
+				let descriptor = match descriptor_opt {
+					Some(descriptor) => descriptor,
+					None => {
+						log_trace!(self.logger, "Dropping custom message of type {} to disconnected peer {}", message_type, log_pubkey!(their_node_id));
+						continue;
+					},
+				};
+				match peers.peers.get(&descriptor) {
+					Some(peer_mutex) => {
+						let mut peer = peer_mutex.lock().unwrap();
+						if peer.their_features.is_none() {
+							log_trace!(self.logger, "Dropping custom message of type {} to {} as we have not yet received their Init", message_type, log_pubkey!(their_node_id));
+							continue;
+						}
+						log_trace!(self.logger, "Enqueueing custom message of type {} to {}", message_type, log_pubkey!(their_node_id));
+						peer.buffer_message(&encoded_msg[..]);
+						peers.peers_needing_send.lock().unwrap().insert(descriptor);
+					},
 					None => panic!("Inconsistent peers set state!"),
 				}
 			}
+
+			let peers_needing_send = mem::replace(&mut *peers.peers_needing_send.lock().unwrap(), HashSet::new());
+			for mut descriptor in peers_needing_send {
+				match peers.peers.get(&descriptor) {
+					Some(peer_mutex) => self.do_attempt_write_data(&mut descriptor, &mut peer_mutex.lock().unwrap()),
+					None => panic!("Inconsistent peers set state!"),
+				}
+			}
+
+			for (descriptor, peer_mutex) in peers.peers.iter() {
+				let peer = peer_mutex.lock().unwrap();
+				if peer.pending_outbound_buffer_bytes > self.limits.max_outbound_buffer_bytes {
+					log_debug!(self.logger, "Disconnecting peer as it isn't reading the {} bytes we have buffered for it", peer.pending_outbound_buffer_bytes);
+					descriptors_needing_disconnect.insert(descriptor.clone());
+				}
+			}
+		}
+
+		for descriptor in descriptors_needing_disconnect.drain() {
+			self.disconnect_socket_internal(descriptor);
 		}
 	}
 
@@ -1108,21 +1536,36 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 	/// library (eg PeerHandleError, explicit disconnect_socket calls) instruct you to disconnect
 	/// the peer.
 	///
-	/// Panics if the descriptor was not previously registered in a successful new_*_connection event.
+	/// Descriptors which are not (or no longer) known to the PeerManager are ignored.
 	pub fn socket_disconnected(&self, descriptor: &Descriptor) {
 		self.disconnect_event_internal(descriptor, false);
 	}
 
+	/// Removes the given peer and calls disconnect_socket on its descriptor. Used when we decide to
+	/// disconnect a peer outside of read_event, and thus must not be called with the peers lock or
+	/// any Peer lock held.
+	fn disconnect_socket_internal(&self, mut descriptor: Descriptor) {
+		self.disconnect_event_internal(&descriptor, false);
+		descriptor.disconnect_socket();
+	}
+
 	fn disconnect_event_internal(&self, descriptor: &Descriptor, no_connection_possible: bool) {
-		let mut peers = self.peers.lock().unwrap();
-		peers.peers_needing_send.remove(descriptor);
+		let mut peers_lock = self.peers.write().unwrap();
+		let peers = &mut *peers_lock;
+		peers.peers_needing_send.get_mut().unwrap().remove(descriptor);
 		let peer_option = peers.peers.remove(descriptor);
 		match peer_option {
-			None => panic!("Descriptor for disconnect_event is not already known to PeerManager"),
-			Some(peer) => {
+			// We may have already removed the peer ourselves in disconnect_socket_internal, racing
+			// with the user noticing the disconnection, in which case there's nothing left to do.
+			None => {},
+			Some(peer_mutex) => {
+				let peer = peer_mutex.into_inner().unwrap();
 				match peer.their_node_id {
 					Some(node_id) => {
-						peers.node_id_to_descriptor.remove(&node_id);
+						peers.node_id_to_descriptor.get_mut().unwrap().remove(&node_id);
+						if let Some(ref recorder) = peer.recorder {
+							recorder.record_message(&node_id, MessageDirection::Disconnected, &[no_connection_possible as u8]);
+						}
 						self.message_handler.chan_handler.peer_disconnected(&node_id, no_connection_possible);
 					},
 					None => {}
@@ -1138,60 +1581,55 @@ impl<Descriptor: SocketDescriptor, CM: Deref, L: Deref> PeerManager<Descriptor,
 	pub fn timer_tick_occured(&self) {
 		let mut descriptors_needing_disconnect = Vec::new();
 		{
-			let peers_lock = self.peers.lock().unwrap();
-			for (descriptor, peer) in peers_lock.peers.iter() {
-				if peer.awaiting_pong {
-					descriptors_needing_disconnect.push(descriptor.clone());
-				}
-			}
-		}
-
-		for mut descriptor in descriptors_needing_disconnect.drain(..) {
-			descriptor.disconnect_socket();
-		}
-
-		{
-			let mut peers_lock = self.peers.lock().unwrap();
-			let peers = &mut *peers_lock;
-			let peers_needing_send = &mut peers.peers_needing_send;
-			let node_id_to_descriptor = &mut peers.node_id_to_descriptor;
-			let peers = &mut peers.peers;
-
-			peers.retain(|descriptor, peer| {
-				if peer.awaiting_pong {
-					peers_needing_send.remove(descriptor);
+			let peers = self.peers.read().unwrap();
+			for (descriptor, peer_mutex) in peers.peers.iter() {
+				let mut peer_lock = peer_mutex.lock().unwrap();
+				let peer = &mut *peer_lock;
+				if peer.awaiting_pong || peer.handshake_timed_out() {
 					match peer.their_node_id {
 						Some(node_id) => {
-							log_trace!(self.logger, "Disconnecting peer with id {} due to ping timeout", node_id);
-							node_id_to_descriptor.remove(&node_id);
-							self.message_handler.chan_handler.peer_disconnected(&node_id, false);
+							if peer.awaiting_pong {
+								log_trace!(self.logger, "Disconnecting peer with id {} due to ping timeout", node_id);
+							} else {
+								log_trace!(self.logger, "Disconnecting peer with id {} as it failed to send us an Init message in time", node_id);
+							}
 						}
 						None => {
-							// This can't actually happen as we should have hit
-							// is_ready_for_encryption() previously on this same peer.
-							unreachable!();
+							// We can only get here for peers which haven't completed the noise
+							// handshake as we've sent a ping to everyone else.
+							log_trace!(self.logger, "Disconnecting peer as it failed to complete the noise handshake in time");
 						},
 					}
-					return false;
+					descriptors_needing_disconnect.push(descriptor.clone());
+					continue;
 				}
 
+				if peer.their_features.is_none() {
+					peer.handshake_timer_ticks += 1;
+				}
+				peer.inbound_gossip_tokens = cmp::min(self.limits.inbound_gossip_burst, peer.inbound_gossip_tokens.saturating_add(self.limits.inbound_gossip_per_tick));
+
 				if !peer.channel_encryptor.is_ready_for_encryption() {
 					// The peer needs to complete its handshake before we can exchange messages
-					return true;
+					continue;
 				}
 
 				let ping = msgs::Ping {
 					ponglen: 0,
 					byteslen: 0,
 				};
-				peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(&ping)));
+				peer.buffer_message(&encode_msg!(&ping));
+				peer.stats.ping_sent_at = Some(Instant::now());
 
 				let mut descriptor_clone = descriptor.clone();
 				self.do_attempt_write_data(&mut descriptor_clone, peer);
 
 				peer.awaiting_pong = true;
-				true
-			});
+			}
+		}
+
+		for descriptor in descriptors_needing_disconnect.drain(..) {
+			self.disconnect_socket_internal(descriptor);
 		}
 	}
 }
@@ -1202,7 +1640,8 @@ mod tests {
 	use bitcoin::BitcoinHash;
 	use bitcoin::network::constants::Network;
 	use bitcoin::blockdata::constants::genesis_block;
-	use ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor};
+	use ln::peer_handler::{PeerManager, PeerManagerLimits, MessageHandler, SocketDescriptor, IgnoringCustomMessageHandler, remote_ip_key};
+	use ln::wire_recorder::{MessageDirection, WireRecordWriter, read_recording};
 	use ln::msgs;
 	use ln::features::ChannelFeatures;
 	use util::events;
@@ -1222,6 +1661,7 @@ mod tests {
 	struct FileDescriptor {
 		fd: u16,
 		outbound_data: Arc<Mutex<Vec<u8>>>,
+		remote_addr: Option<msgs::NetAddress>,
 	}
 	impl PartialEq for FileDescriptor {
 		fn eq(&self, other: &Self) -> bool {
@@ -1242,10 +1682,35 @@ mod tests {
 		}
 
 		fn disconnect_socket(&mut self) {}
+
+		fn remote_addr(&self) -> Option<msgs::NetAddress> {
+			self.remote_addr.clone()
+		}
+	}
+
+	struct TestCustomMessageHandler {
+		received_msgs: Mutex<Vec<(PublicKey, u16, Vec<u8>)>>,
+		pending_msgs: Mutex<Vec<(PublicKey, u16, Vec<u8>)>>,
+	}
+	impl msgs::CustomMessageHandler for TestCustomMessageHandler {
+		fn handles_message_type(&self, message_type: u16) -> bool {
+			message_type == 32768
+		}
+		fn handle_custom_message(&self, their_node_id: &PublicKey, message_type: u16, data: &[u8]) -> Result<(), msgs::LightningError> {
+			self.received_msgs.lock().unwrap().push((their_node_id.clone(), message_type, data.to_vec()));
+			Ok(())
+		}
+		fn get_and_clear_pending_custom_messages(&self) -> Vec<(PublicKey, u16, Vec<u8>)> {
+			self.pending_msgs.lock().unwrap().split_off(0)
+		}
+		fn custom_init_feature_bits(&self) -> Vec<usize> {
+			vec![100]
+		}
 	}
 
 	struct PeerManagerCfg {
 		chan_handler: test_utils::TestChannelMessageHandler,
+		custom_handler: Arc<TestCustomMessageHandler>,
 		logger: test_utils::TestLogger,
 	}
 
@@ -1253,10 +1718,12 @@ mod tests {
 		let mut cfgs = Vec::new();
 		for _ in 0..peer_count {
 			let chan_handler = test_utils::TestChannelMessageHandler::new();
+			let custom_handler = Arc::new(TestCustomMessageHandler { received_msgs: Mutex::new(Vec::new()), pending_msgs: Mutex::new(Vec::new()) });
 			let logger = test_utils::TestLogger::new();
 			cfgs.push(
 				PeerManagerCfg{
 					chan_handler,
+					custom_handler,
 					logger,
 				}
 			);
@@ -1280,7 +1747,7 @@ mod tests {
 				rng.fill_bytes(&mut key_slice);
 				SecretKey::from_slice(&key_slice).unwrap()
 			};
-			let msg_handler = MessageHandler { chan_handler: &cfgs[i].chan_handler, route_handler: router };
+			let msg_handler = MessageHandler { chan_handler: &cfgs[i].chan_handler, route_handler: router, custom_message_handler: cfgs[i].custom_handler.clone() };
 			let peer = PeerManager::new(msg_handler, node_id, &ephemeral_bytes, &cfgs[i].logger);
 			peers.push(peer);
 		}
@@ -1291,8 +1758,8 @@ mod tests {
 	fn establish_connection<'a>(peer_a: &PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestLogger>, peer_b: &PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestLogger>) -> (FileDescriptor, FileDescriptor) {
 		let secp_ctx = Secp256k1::new();
 		let a_id = PublicKey::from_secret_key(&secp_ctx, &peer_a.our_node_secret);
-		let mut fd_a = FileDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())) };
-		let mut fd_b = FileDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let mut fd_a = FileDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())), remote_addr: None };
+		let mut fd_b = FileDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())), remote_addr: None };
 		let initial_data = peer_b.new_outbound_connection(a_id, fd_b.clone()).unwrap();
 		peer_a.new_inbound_connection(fd_a.clone()).unwrap();
 		assert_eq!(peer_a.read_event(&mut fd_a, &initial_data).unwrap(), false);
@@ -1316,7 +1783,7 @@ mod tests {
 		let chan_handler = test_utils::TestChannelMessageHandler::new();
 		let mut peers = create_network(2, &cfgs, None);
 		establish_connection(&peers[0], &peers[1]);
-		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 1);
+		assert_eq!(peers[0].peers.read().unwrap().peers.len(), 1);
 
 		let secp_ctx = Secp256k1::new();
 		let their_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
@@ -1329,7 +1796,37 @@ mod tests {
 		peers[0].message_handler.chan_handler = &chan_handler;
 
 		peers[0].process_events();
-		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 0);
+		assert_eq!(peers[0].peers.read().unwrap().peers.len(), 0);
+	}
+
+	#[test]
+	fn test_custom_messages() {
+		// Connect two peers which both advertise (and require) a custom feature bit and check that
+		// custom messages are delivered, but only for custom message types.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs, None);
+		let (mut fd_a, mut fd_b) = establish_connection_and_read_events(&peers[0], &peers[1]);
+
+		{
+			let peer_b_lock = peers[1].peers.read().unwrap();
+			let their_features = peer_b_lock.peers.values().next().unwrap().lock().unwrap().their_features.clone().unwrap();
+			assert_eq!(their_features.le_flags()[12], 1 << 4);
+		}
+
+		let secp_ctx = Secp256k1::new();
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret);
+		let b_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		cfgs[1].custom_handler.pending_msgs.lock().unwrap().push((a_id, 32768, vec![42; 3]));
+		// Init is a known type and must never be sent by a CustomMessageHandler
+		cfgs[1].custom_handler.pending_msgs.lock().unwrap().push((a_id, 16, vec![0; 4]));
+		peers[1].process_events();
+		assert_eq!(peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap(), false);
+		assert_eq!(*cfgs[0].custom_handler.received_msgs.lock().unwrap(), vec![(b_id, 32768, vec![42; 3])]);
+
+		cfgs[0].custom_handler.pending_msgs.lock().unwrap().push((b_id, 32768, Vec::new()));
+		peers[0].process_events();
+		assert_eq!(peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap(), false);
+		assert_eq!(*cfgs[1].custom_handler.received_msgs.lock().unwrap(), vec![(a_id, 32768, Vec::new())]);
 	}
 
 	#[test]
@@ -1338,15 +1835,224 @@ mod tests {
 		let cfgs = create_peermgr_cfgs(2);
 		let peers = create_network(2, &cfgs, None);
 		establish_connection(&peers[0], &peers[1]);
-		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 1);
+		assert_eq!(peers[0].peers.read().unwrap().peers.len(), 1);
 
 		// peers[0] awaiting_pong is set to true, but the Peer is still connected
 		peers[0].timer_tick_occured();
-		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 1);
+		assert_eq!(peers[0].peers.read().unwrap().peers.len(), 1);
 
 		// Since timer_tick_occured() is called again when awaiting_pong is true, all Peers are disconnected
 		peers[0].timer_tick_occured();
-		assert_eq!(peers[0].peers.lock().unwrap().peers.len(), 0);
+		assert_eq!(peers[0].peers.read().unwrap().peers.len(), 0);
+	}
+
+	#[test]
+	fn test_list_peers() {
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs, None);
+		let (mut fd_a, mut fd_b) = establish_connection_and_read_events(&peers[0], &peers[1]);
+
+		let secp_ctx = Secp256k1::new();
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &peers[0].our_node_secret);
+		let b_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		let a_details = peers[0].list_peers();
+		let b_details = peers[1].list_peers();
+		assert_eq!(a_details.len(), 1);
+		assert_eq!(b_details.len(), 1);
+		assert_eq!(a_details[0].node_id, b_id);
+		assert_eq!(b_details[0].node_id, a_id);
+		assert!(!a_details[0].outbound);
+		assert!(b_details[0].outbound);
+		assert!(a_details[0].their_features.is_some());
+		assert!(a_details[0].last_ping_rtt.is_none());
+		// Both sides have exchanged exactly one Init message and read everything the other sent.
+		assert_eq!(a_details[0].messages_sent.get(&16), Some(&1));
+		assert_eq!(a_details[0].messages_received.get(&16), Some(&1));
+		assert_eq!(a_details[0].bytes_sent, b_details[0].bytes_received);
+		assert_eq!(a_details[0].bytes_received, b_details[0].bytes_sent);
+		assert_eq!(a_details[0].outbound_buffer_messages, 0);
+		assert_eq!(a_details[0].outbound_buffer_bytes, 0);
+
+		// Once peers[1] replies to peers[0]'s ping, peers[0] knows the round-trip time.
+		peers[0].timer_tick_occured();
+		assert_eq!(peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap(), false);
+		assert_eq!(peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap(), false);
+		let a_details = peers[0].list_peers();
+		assert!(a_details[0].last_ping_rtt.is_some());
+		assert_eq!(a_details[0].messages_sent.get(&18), Some(&1));
+		assert_eq!(a_details[0].messages_received.get(&19), Some(&1));
+		let b_details = peers[1].list_peers();
+		assert_eq!(b_details[0].messages_received.get(&18), Some(&1));
+		assert_eq!(b_details[0].messages_sent.get(&19), Some(&1));
+		assert_eq!(a_details[0].bytes_sent, b_details[0].bytes_received);
+		assert_eq!(a_details[0].bytes_received, b_details[0].bytes_sent);
+	}
+
+	#[test]
+	fn test_message_recorder() {
+		let cfgs = create_peermgr_cfgs(2);
+		let mut peers = create_network(2, &cfgs, None);
+		let recorder = Arc::new(WireRecordWriter::new(Vec::new()));
+		peers[0].set_message_recorder(recorder.clone());
+		let (mut fd_a, mut fd_b) = establish_connection_and_read_events(&peers[0], &peers[1]);
+		peers[1].timer_tick_occured();
+		assert_eq!(peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap(), false);
+		assert_eq!(peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap(), false);
+		peers[0].socket_disconnected(&fd_a);
+		let secp_ctx = Secp256k1::new();
+		let b_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		drop(peers);
+
+		// peers[0] received an Init and then a Ping, sent an Init and then a Pong, and then
+		// disconnected.
+		let mut recording = read_recording(&Arc::try_unwrap(recorder).ok().unwrap().into_inner()).unwrap();
+		assert!(recording.iter().all(|record| record.node_id == b_id));
+		let disconnect = recording.pop().unwrap();
+		assert_eq!((disconnect.direction, disconnect.message), (MessageDirection::Disconnected, vec![0]));
+		let summary: Vec<_> = recording.iter().map(|record| (record.direction, record.message[1])).collect();
+		assert_eq!(summary, vec![(MessageDirection::Inbound, 16), (MessageDirection::Outbound, 16), (MessageDirection::Inbound, 18), (MessageDirection::Outbound, 19)]);
+	}
+
+	#[test]
+	fn test_remote_ip_key() {
+		let ipv6 = |addr: [u8; 16]| remote_ip_key(&msgs::NetAddress::IPv6 { addr, port: 9735 });
+		assert_eq!(remote_ip_key(&msgs::NetAddress::IPv4 { addr: [1, 2, 3, 4], port: 9735 }), Some(vec![1, 2, 3, 4]));
+		assert_eq!(remote_ip_key(&msgs::NetAddress::IPv4 { addr: [127, 0, 0, 2], port: 9735 }), None);
+		assert_eq!(remote_ip_key(&msgs::NetAddress::IPv4 { addr: [0; 4], port: 9735 }), None);
+		assert_eq!(ipv6([0x20, 1, 0xd, 0xb8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1]), Some(vec![0x20, 1, 0xd, 0xb8, 0, 0, 0, 1]));
+		assert_eq!(ipv6([0; 16]), None);
+		assert_eq!(ipv6([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]), None);
+		assert_eq!(ipv6([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 2, 3, 4]), Some(vec![1, 2, 3, 4]));
+		assert_eq!(ipv6([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 127, 0, 0, 1]), None);
+	}
+
+	#[test]
+	fn test_inbound_connection_limits() {
+		let cfgs = create_peermgr_cfgs(1);
+		let mut peers = create_network(1, &cfgs, None);
+		peers[0].limits = PeerManagerLimits { max_peers: 3, max_pending_handshakes: 2, max_connections_per_ip: 1, ..Default::default() };
+		let new_fd = |fd: u16, ip: Option<[u8; 4]>| FileDescriptor {
+			fd,
+			outbound_data: Arc::new(Mutex::new(Vec::new())),
+			remote_addr: ip.map(|addr| msgs::NetAddress::IPv4 { addr, port: 9735 }),
+		};
+
+		// Only one connection per IP is allowed, but connections with no known IP are exempt
+		assert!(peers[0].new_inbound_connection(new_fd(1, Some([1, 2, 3, 4]))).is_ok());
+		assert!(peers[0].new_inbound_connection(new_fd(2, Some([1, 2, 3, 4]))).is_err());
+		assert!(peers[0].new_inbound_connection(new_fd(3, None)).is_ok());
+
+		// ...and only two connections may be completing the handshake at once.
+		assert!(peers[0].new_inbound_connection(new_fd(4, Some([1, 2, 3, 5]))).is_err());
+
+		// Loopback connections (eg from a local Tor daemon) are exempt from the per-IP limit
+		peers[0].limits.max_peers = 4;
+		peers[0].limits.max_pending_handshakes = 4;
+		assert!(peers[0].new_inbound_connection(new_fd(4, Some([127, 0, 0, 1]))).is_ok());
+		assert!(peers[0].new_inbound_connection(new_fd(5, Some([127, 0, 0, 1]))).is_ok());
+		peers[0].socket_disconnected(&new_fd(4, None));
+		peers[0].socket_disconnected(&new_fd(5, None));
+		peers[0].limits.max_peers = 3;
+		peers[0].limits.max_pending_handshakes = 2;
+
+		// Connections which don't complete the handshake in time are disconnected
+		peers[0].timer_tick_occured();
+		assert_eq!(peers[0].peers.read().unwrap().peers.len(), 2);
+		peers[0].timer_tick_occured();
+		assert_eq!(peers[0].peers.read().unwrap().peers.len(), 0);
+
+		peers[0].limits.max_pending_handshakes = 10;
+		for fd in 0..3 {
+			assert!(peers[0].new_inbound_connection(new_fd(fd, None)).is_ok());
+		}
+		assert!(peers[0].new_inbound_connection(new_fd(3, None)).is_err());
+	}
+
+	#[test]
+	fn test_inbound_gossip_rate_limit() {
+		// Connect two peers which sync each other 150 gossip messages, with peer 0 only willing to
+		// process 20 of them.
+		let cfgs = create_peermgr_cfgs(2);
+		let mut routing_handlers: Vec<Arc<msgs::RoutingMessageHandler>> = Vec::new();
+		let mut routing_handlers_concrete: Vec<Arc<TestRoutingMessageHandler>> = Vec::new();
+		for _ in 0..2 {
+			let routing_handler = Arc::new(TestRoutingMessageHandler::new());
+			routing_handlers.push(routing_handler.clone());
+			routing_handlers_concrete.push(routing_handler.clone());
+		}
+		let mut peers = create_network(2, &cfgs, Some(&routing_handlers));
+		peers[0].limits.inbound_gossip_burst = 20;
+		peers[0].limits.inbound_gossip_per_tick = 10;
+
+		let (mut fd_a, mut fd_b) = establish_connection(&peers[0], &peers[1]);
+		peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap();
+
+		let gossip_recvd = |handler: &TestRoutingMessageHandler| {
+			handler.chan_upds_recvd.load(Ordering::Acquire) + handler.chan_anns_recvd.load(Ordering::Acquire)
+		};
+		assert_eq!(gossip_recvd(&*routing_handlers_concrete[0]), 20);
+		assert_eq!(gossip_recvd(&*routing_handlers_concrete[1]), 150);
+
+		// The token bucket is refilled with 10 tokens on each timer tick, but never beyond the burst
+		for _ in 0..3 {
+			peers[0].timer_tick_occured();
+			peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap();
+			peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		}
+		assert_eq!(peers[0].peers.read().unwrap().peers.values().next().unwrap().lock().unwrap().inbound_gossip_tokens, 20);
+	}
+
+	#[test]
+	fn test_concurrent_read_events() {
+		// Connect a number of peers to a single hub PeerManager from separate threads and exchange
+		// pings, with read_event calls for different peers hitting the hub in parallel.
+		let hub_secret = SecretKey::from_slice(&[42; 32]).unwrap();
+		let hub_id = PublicKey::from_secret_key(&Secp256k1::new(), &hub_secret);
+		let hub_handler = MessageHandler {
+			chan_handler: Arc::new(test_utils::TestChannelMessageHandler::new()),
+			route_handler: Arc::new(test_utils::TestRoutingMessageHandler::new()),
+			custom_message_handler: Arc::new(IgnoringCustomMessageHandler {}),
+		};
+		let hub: Arc<PeerManager<FileDescriptor, Arc<test_utils::TestChannelMessageHandler>, Arc<test_utils::TestLogger>>> =
+			Arc::new(PeerManager::new(hub_handler, hub_secret, &[0; 32], Arc::new(test_utils::TestLogger::new())));
+
+		let mut threads = Vec::new();
+		for i in 1..9 {
+			let hub = hub.clone();
+			threads.push(std::thread::spawn(move || {
+				let msg_handler = MessageHandler {
+					chan_handler: Arc::new(test_utils::TestChannelMessageHandler::new()),
+					route_handler: Arc::new(test_utils::TestRoutingMessageHandler::new()),
+					custom_message_handler: Arc::new(IgnoringCustomMessageHandler {}),
+				};
+				let peer: PeerManager<FileDescriptor, Arc<test_utils::TestChannelMessageHandler>, Arc<test_utils::TestLogger>> =
+					PeerManager::new(msg_handler, SecretKey::from_slice(&[i as u8; 32]).unwrap(), &[i as u8; 32], Arc::new(test_utils::TestLogger::new()));
+
+				let mut fd_hub = FileDescriptor { fd: i, outbound_data: Arc::new(Mutex::new(Vec::new())), remote_addr: None };
+				let mut fd_peer = FileDescriptor { fd: i, outbound_data: Arc::new(Mutex::new(Vec::new())), remote_addr: None };
+				let initial_data = peer.new_outbound_connection(hub_id, fd_peer.clone()).unwrap();
+				hub.new_inbound_connection(fd_hub.clone()).unwrap();
+				assert_eq!(hub.read_event(&mut fd_hub, &initial_data).unwrap(), false);
+				for _ in 0..3 {
+					peer.read_event(&mut fd_peer, &fd_hub.outbound_data.lock().unwrap().split_off(0)).unwrap();
+					hub.read_event(&mut fd_hub, &fd_peer.outbound_data.lock().unwrap().split_off(0)).unwrap();
+				}
+				assert_eq!(peer.get_peer_node_ids(), vec![hub_id]);
+
+				for _ in 0..100 {
+					// Each tick sends a ping and disconnects the hub if it didn't pong the last one.
+					peer.timer_tick_occured();
+					hub.read_event(&mut fd_hub, &fd_peer.outbound_data.lock().unwrap().split_off(0)).unwrap();
+					peer.read_event(&mut fd_peer, &fd_hub.outbound_data.lock().unwrap().split_off(0)).unwrap();
+				}
+				assert_eq!(peer.get_peer_node_ids(), vec![hub_id]);
+			}));
+		}
+		for thread in threads.drain(..) {
+			thread.join().unwrap();
+		}
+		assert_eq!(hub.get_peer_node_ids().len(), 8);
 	}
 
 	pub struct TestRoutingMessageHandler {
@@ -1495,11 +2201,11 @@ mod tests {
 			let peers = create_network(2, &cfgs, Some(&routing_handlers));
 			let (fd_0_to_1, fd_1_to_0) = establish_connection_and_read_events(&peers[0], &peers[1]);
 
-			let peer_0 = peers[0].peers.lock().unwrap();
-			let peer_1 = peers[1].peers.lock().unwrap();
+			let peer_0 = peers[0].peers.read().unwrap();
+			let peer_1 = peers[1].peers.read().unwrap();
 
-			let peer_0_features = peer_1.peers.get(&fd_1_to_0).unwrap().their_features.as_ref();
-			let peer_1_features = peer_0.peers.get(&fd_0_to_1).unwrap().their_features.as_ref();
+			let peer_0_features = peer_1.peers.get(&fd_1_to_0).unwrap().lock().unwrap().their_features.clone();
+			let peer_1_features = peer_0.peers.get(&fd_0_to_1).unwrap().lock().unwrap().their_features.clone();
 
 			assert!(peer_0_features.unwrap().initial_routing_sync());
 			assert!(!peer_1_features.unwrap().initial_routing_sync());
@@ -1515,11 +2221,11 @@ mod tests {
 			let peers = create_network(2, &cfgs, Some(&routing_handlers));
 			let (fd_0_to_1, fd_1_to_0) = establish_connection_and_read_events(&peers[0], &peers[1]);
 
-			let peer_0 = peers[0].peers.lock().unwrap();
-			let peer_1 = peers[1].peers.lock().unwrap();
+			let peer_0 = peers[0].peers.read().unwrap();
+			let peer_1 = peers[1].peers.read().unwrap();
 
-			let peer_0_features = peer_1.peers.get(&fd_1_to_0).unwrap().their_features.as_ref();
-			let peer_1_features = peer_0.peers.get(&fd_0_to_1).unwrap().their_features.as_ref();
+			let peer_0_features = peer_1.peers.get(&fd_1_to_0).unwrap().lock().unwrap().their_features.clone();
+			let peer_1_features = peer_0.peers.get(&fd_0_to_1).unwrap().lock().unwrap().their_features.clone();
 
 			assert!(!peer_0_features.unwrap().initial_routing_sync());
 			assert!(peer_1_features.unwrap().initial_routing_sync());
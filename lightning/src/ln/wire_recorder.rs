@@ -0,0 +1,382 @@
+//! Recording and replay of the Lightning messages exchanged with peers.
+//!
+//! A WireRecorder can be handed to PeerManager::set_message_recorder to capture every message we
+//! send to or receive from our peers after decryption, as well as each disconnection from a peer
+//! we had exchanged Init messages with. WireRecordWriter is a WireRecorder which
+//! timestamps each message and appends it to any std::io::Write in a stable binary format, which
+//! can later be read back with read_recording.
+//!
+//! Each record is serialized as:
+//!  * the time the message was recorded, as milliseconds since the UNIX epoch (8 bytes),
+//!  * the direction of the message, 0 for messages received, 1 for messages sent and 2 for
+//!    disconnections (1 byte),
+//!  * the peer's node_id (33 bytes),
+//!  * the length of the message (2 bytes),
+//!  * the message itself, including its type, exactly as defined in BOLT 1. For disconnections
+//!    this is instead a single byte, 1 if no connection to the peer was deemed possible (see
+//!    ChannelMessageHandler::peer_disconnected) and 0 otherwise.
+//!
+//! All integers are big-endian. Records are simply concatenated.
+//!
+//! replay_recording feeds the messages we received back into a ChannelMessageHandler and
+//! RoutingMessageHandler (eg a ChannelManager and NetGraphMsgHandler deserialized from a snapshot
+//! taken before the recording started), allowing a problem seen in production to be reproduced
+//! in a test.
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use ln::features::InitFeatures;
+use ln::msgs;
+use ln::msgs::{ChannelMessageHandler, RoutingMessageHandler};
+use ln::msgs::DecodeError;
+use ln::wire;
+use util::logger::Logger;
+use util::ser::{Readable, Writeable, Writer};
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Whether a recorded message was sent or received by us.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageDirection {
+	/// The message was received from the peer.
+	Inbound,
+	/// The message was sent (or rather, queued for sending) to the peer.
+	Outbound,
+	/// Not a message, but a record that we disconnected from the peer. The message is a single
+	/// byte, 1 if no connection to the peer was deemed possible and 0 otherwise.
+	Disconnected,
+}
+
+/// A single message exchanged with a peer, as read back by read_recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedMessage {
+	/// The time at which the message was recorded, as a duration since the UNIX epoch. Only
+	/// millisecond precision is retained when serialized.
+	pub timestamp: Duration,
+	/// Whether we sent or received the message.
+	pub direction: MessageDirection,
+	/// The node_id of the peer the message was exchanged with.
+	pub node_id: PublicKey,
+	/// The decrypted message, including its two-byte type.
+	pub message: Vec<u8>,
+}
+
+impl Writeable for RecordedMessage {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		let timestamp_millis = self.timestamp.as_secs() * 1000 + (self.timestamp.subsec_nanos() / 1_000_000) as u64;
+		timestamp_millis.write(writer)?;
+		match self.direction {
+			MessageDirection::Inbound => 0u8.write(writer)?,
+			MessageDirection::Outbound => 1u8.write(writer)?,
+			MessageDirection::Disconnected => 2u8.write(writer)?,
+		}
+		self.node_id.write(writer)?;
+		self.message.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for RecordedMessage {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let timestamp_millis: u64 = Readable::read(reader)?;
+		let direction = match <u8 as Readable>::read(reader)? {
+			0 => MessageDirection::Inbound,
+			1 => MessageDirection::Outbound,
+			2 => MessageDirection::Disconnected,
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		let node_id = Readable::read(reader)?;
+		let message: Vec<u8> = Readable::read(reader)?;
+		let valid = match direction {
+			MessageDirection::Disconnected => message.len() == 1 && message[0] <= 1,
+			_ => message.len() >= 2,
+		};
+		if !valid {
+			return Err(DecodeError::InvalidValue);
+		}
+		Ok(RecordedMessage {
+			timestamp: Duration::new(timestamp_millis / 1000, ((timestamp_millis % 1000) * 1_000_000) as u32),
+			direction,
+			node_id,
+			message,
+		})
+	}
+}
+
+/// A hook which is handed every message exchanged with our peers by the PeerManager.
+pub trait WireRecorder : Send + Sync {
+	/// Records a message sent to or received from the peer with the given node_id. message is the
+	/// decrypted message, including its two-byte type (or, for MessageDirection::Disconnected, the
+	/// single byte described there).
+	///
+	/// This is called while the PeerManager is processing the peer, so should return quickly.
+	fn record_message(&self, their_node_id: &PublicKey, direction: MessageDirection, message: &[u8]);
+}
+
+/// A WireRecorder which timestamps each message and writes it out in the format described in the
+/// module documentation.
+///
+/// Recording is best-effort: if writing to the underlying writer fails, the message is dropped.
+pub struct WireRecordWriter<W: Write + Send> {
+	writer: Mutex<W>,
+}
+
+impl<W: Write + Send> WireRecordWriter<W> {
+	/// Creates a new WireRecordWriter which appends records to the given writer.
+	pub fn new(writer: W) -> Self {
+		WireRecordWriter { writer: Mutex::new(writer) }
+	}
+
+	/// Consumes the WireRecordWriter, returning the underlying writer.
+	pub fn into_inner(self) -> W {
+		self.writer.into_inner().unwrap()
+	}
+}
+
+impl<W: Write + Send> WireRecorder for WireRecordWriter<W> {
+	fn record_message(&self, their_node_id: &PublicKey, direction: MessageDirection, message: &[u8]) {
+		let record = RecordedMessage {
+			timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)),
+			direction,
+			node_id: their_node_id.clone(),
+			message: message.to_vec(),
+		};
+		// Serialize the record first so that it is handed to the writer in a single write_all.
+		let encoded = record.encode();
+		let _ = self.writer.lock().unwrap().write_all(&encoded);
+	}
+}
+
+/// Reads back a complete recording, as written by WireRecordWriter.
+pub fn read_recording(data: &[u8]) -> Result<Vec<RecordedMessage>, DecodeError> {
+	let mut reader = Cursor::new(data);
+	let mut messages = Vec::new();
+	while (reader.position() as usize) < data.len() {
+		messages.push(Readable::read(&mut reader)?);
+	}
+	Ok(messages)
+}
+
+/// Feeds the messages we received in the given recording into the given handlers, in order, as
+/// the PeerManager would have.
+///
+/// Messages we sent are skipped, as they were generated by our handlers and will be generated
+/// again as events by the handlers passed in here (and may thus be compared against the
+/// recording). As with the PeerManager, messages from a peer are ignored until we've seen its
+/// Init message, at which point peer_connected is called, and disconnections result in a call to
+/// peer_disconnected. A further Init message from a peer we never saw disconnect (eg in a
+/// recording from before disconnections were recorded) is treated as a reconnection, calling
+/// peer_disconnected first.
+///
+/// Messages which fail to decode or which the handlers reject are logged and otherwise skipped.
+pub fn replay_recording<CM: Deref, RM: Deref, L: Deref>(recording: &[RecordedMessage], chan_handler: CM, route_handler: RM, logger: L)
+		where CM::Target: msgs::ChannelMessageHandler,
+		      RM::Target: msgs::RoutingMessageHandler,
+		      L::Target: Logger {
+	let mut peer_features: HashMap<PublicKey, InitFeatures> = HashMap::new();
+
+	for record in recording.iter() {
+		let node_id = &record.node_id;
+		match record.direction {
+			MessageDirection::Inbound => {},
+			MessageDirection::Outbound => continue,
+			MessageDirection::Disconnected => {
+				if peer_features.remove(node_id).is_some() {
+					chan_handler.peer_disconnected(node_id, record.message[0] == 1);
+				}
+				continue;
+			},
+		}
+		let message = match wire::read(&mut Cursor::new(&record.message[..])) {
+			Ok(message) => message,
+			Err(e) => {
+				log_debug!(logger, "Skipping recorded message from {} which failed to decode: {:?}", log_pubkey!(record.node_id), e);
+				continue;
+			},
+		};
+
+		if let wire::Message::Init(ref msg) = message {
+			if peer_features.contains_key(node_id) {
+				chan_handler.peer_disconnected(node_id, false);
+			}
+			chan_handler.peer_connected(node_id, msg);
+			peer_features.insert(node_id.clone(), msg.features.clone());
+			continue;
+		}
+		let their_features = match peer_features.get(node_id) {
+			Some(features) => features.clone(),
+			None => {
+				log_debug!(logger, "Skipping recorded message of type {} from {} as it was received before its Init", message.type_id(), log_pubkey!(record.node_id));
+				continue;
+			},
+		};
+
+		macro_rules! log_routing_result {
+			($res: expr) => {
+				if let Err(e) = $res {
+					log_debug!(logger, "Recorded gossip message from {} was rejected: {}", log_pubkey!(record.node_id), e.err);
+				}
+			}
+		}
+
+		match message {
+			wire::Message::Init(_) => unreachable!(),
+			wire::Message::Error(msg) => chan_handler.handle_error(node_id, &msg),
+			wire::Message::Ping(_) | wire::Message::Pong(_) => {},
+
+			wire::Message::OpenChannel(msg) => chan_handler.handle_open_channel(node_id, their_features, &msg),
+			wire::Message::AcceptChannel(msg) => chan_handler.handle_accept_channel(node_id, their_features, &msg),
+			wire::Message::FundingCreated(msg) => chan_handler.handle_funding_created(node_id, &msg),
+			wire::Message::FundingSigned(msg) => chan_handler.handle_funding_signed(node_id, &msg),
+			wire::Message::FundingLocked(msg) => chan_handler.handle_funding_locked(node_id, &msg),
+			wire::Message::Shutdown(msg) => chan_handler.handle_shutdown(node_id, &msg),
+			wire::Message::ClosingSigned(msg) => chan_handler.handle_closing_signed(node_id, &msg),
+
+			wire::Message::UpdateAddHTLC(msg) => chan_handler.handle_update_add_htlc(node_id, &msg),
+			wire::Message::UpdateFulfillHTLC(msg) => chan_handler.handle_update_fulfill_htlc(node_id, &msg),
+			wire::Message::UpdateFailHTLC(msg) => chan_handler.handle_update_fail_htlc(node_id, &msg),
+			wire::Message::UpdateFailMalformedHTLC(msg) => chan_handler.handle_update_fail_malformed_htlc(node_id, &msg),
+			wire::Message::CommitmentSigned(msg) => chan_handler.handle_commitment_signed(node_id, &msg),
+			wire::Message::RevokeAndACK(msg) => chan_handler.handle_revoke_and_ack(node_id, &msg),
+			wire::Message::UpdateFee(msg) => chan_handler.handle_update_fee(node_id, &msg),
+			wire::Message::ChannelReestablish(msg) => chan_handler.handle_channel_reestablish(node_id, &msg),
+
+			wire::Message::AnnouncementSignatures(msg) => chan_handler.handle_announcement_signatures(node_id, &msg),
+			wire::Message::ChannelAnnouncement(msg) => log_routing_result!(route_handler.handle_channel_announcement(&msg)),
+			wire::Message::NodeAnnouncement(msg) => log_routing_result!(route_handler.handle_node_announcement(&msg)),
+			wire::Message::ChannelUpdate(msg) => log_routing_result!(route_handler.handle_channel_update(&msg)),
+
+			wire::Message::Unknown(msg_type) => {
+				log_trace!(logger, "Skipping recorded message of unknown type {} from {}", msg_type, log_pubkey!(record.node_id));
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::hashes::Hash;
+	use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin::secp256k1::Secp256k1;
+	use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+
+	use ln::features::InitFeatures;
+	use ln::msgs;
+	use ln::wire_recorder::{MessageDirection, RecordedMessage, WireRecorder, WireRecordWriter, read_recording, replay_recording};
+	use util::ser::Writeable;
+	use util::test_utils;
+
+	use std::sync::Mutex;
+	use std::time::Duration;
+
+	struct CountingRoutingMessageHandler {
+		channel_updates: Mutex<usize>,
+	}
+	impl msgs::RoutingMessageHandler for CountingRoutingMessageHandler {
+		fn handle_node_announcement(&self, _msg: &msgs::NodeAnnouncement) -> Result<bool, msgs::LightningError> { Ok(false) }
+		fn handle_channel_announcement(&self, _msg: &msgs::ChannelAnnouncement) -> Result<bool, msgs::LightningError> { Ok(false) }
+		fn handle_channel_update(&self, _msg: &msgs::ChannelUpdate) -> Result<bool, msgs::LightningError> {
+			*self.channel_updates.lock().unwrap() += 1;
+			Ok(false)
+		}
+		fn handle_htlc_fail_channel_update(&self, _update: &msgs::HTLCFailChannelUpdate) {}
+		fn get_next_channel_announcements(&self, _starting_point: u64, _batch_amount: u8) -> Vec<(msgs::ChannelAnnouncement, Option<msgs::ChannelUpdate>, Option<msgs::ChannelUpdate>)> { Vec::new() }
+		fn get_next_node_announcements(&self, _starting_point: Option<&PublicKey>, _batch_amount: u8) -> Vec<msgs::NodeAnnouncement> { Vec::new() }
+		fn should_request_full_sync(&self, _node_id: &PublicKey) -> bool { false }
+	}
+
+	fn encode_wire_msg<M: Writeable>(msg_type: u16, msg: &M) -> Vec<u8> {
+		let mut res = Vec::new();
+		res.extend_from_slice(&[(msg_type >> 8) as u8, msg_type as u8]);
+		res.extend_from_slice(&msg.encode());
+		res
+	}
+
+	#[test]
+	fn test_record_serialization() {
+		let secp_ctx = Secp256k1::new();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+		let init = encode_wire_msg(16, &msgs::Init { features: InitFeatures::known() });
+
+		let recorder = WireRecordWriter::new(Vec::new());
+		recorder.record_message(&node_id, MessageDirection::Inbound, &init);
+		recorder.record_message(&node_id, MessageDirection::Outbound, &init);
+		let recording = read_recording(&recorder.into_inner()).unwrap();
+		assert_eq!(recording.len(), 2);
+		assert_eq!(recording[0].direction, MessageDirection::Inbound);
+		assert_eq!(recording[1].direction, MessageDirection::Outbound);
+		assert_eq!(recording[0].node_id, node_id);
+		assert_eq!(recording[0].message, init);
+		assert!(recording[0].timestamp <= recording[1].timestamp);
+
+		// The format is stable, so check a record byte-for-byte.
+		let record = RecordedMessage { timestamp: Duration::new(0, 0x0102 * 1_000_000), direction: MessageDirection::Outbound, node_id, message: vec![0, 18, 0, 0, 0, 0] };
+		let encoded = record.encode();
+		assert_eq!(&encoded[0..9], &[0, 0, 0, 0, 0, 0, 1, 2, 1]);
+		assert_eq!(&encoded[9..42], &node_id.serialize()[..]);
+		assert_eq!(&encoded[42..], &[0, 6, 0, 18, 0, 0, 0, 0]);
+		assert_eq!(read_recording(&encoded).unwrap(), vec![record]);
+
+		// Truncated recordings are rejected.
+		assert!(read_recording(&encoded[..encoded.len() - 1]).is_err());
+
+		// As are disconnection records with anything but a single 0 or 1 byte.
+		let disconnect = RecordedMessage { timestamp: Duration::from_secs(0), direction: MessageDirection::Disconnected, node_id, message: vec![1] };
+		let mut encoded = disconnect.encode();
+		assert_eq!(encoded[8], 2);
+		assert_eq!(read_recording(&encoded).unwrap(), vec![disconnect]);
+		let len = encoded.len();
+		encoded[len - 1] = 2;
+		assert!(read_recording(&encoded).is_err());
+	}
+
+	#[test]
+	fn test_replay_recording() {
+		let secp_ctx = Secp256k1::new();
+		let node_key = SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &node_key);
+		let unsigned = msgs::UnsignedChannelUpdate {
+			chain_hash: Default::default(),
+			short_channel_id: 42,
+			timestamp: 1,
+			flags: 0,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000,
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 1,
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned.encode()[..])[..]);
+		let update = encode_wire_msg(258, &msgs::ChannelUpdate { signature: secp_ctx.sign(&msghash, &node_key), contents: unsigned });
+		let init = encode_wire_msg(16, &msgs::Init { features: InitFeatures::known() });
+
+		let record = |direction, message: &Vec<u8>| RecordedMessage { timestamp: Duration::from_secs(0), direction, node_id, message: message.clone() };
+		let recording = vec![
+			// Ignored as it was received before the Init
+			record(MessageDirection::Inbound, &update),
+			record(MessageDirection::Outbound, &init),
+			record(MessageDirection::Inbound, &init),
+			record(MessageDirection::Inbound, &update),
+			// Ignored as we sent it
+			record(MessageDirection::Outbound, &update),
+			// Unknown odd message type
+			record(MessageDirection::Inbound, &vec![0x80, 0x01]),
+			record(MessageDirection::Inbound, &update),
+			// Ignored as it was received after the peer disconnected but before it sent a new Init
+			record(MessageDirection::Disconnected, &vec![0]),
+			record(MessageDirection::Inbound, &update),
+			record(MessageDirection::Inbound, &init),
+			record(MessageDirection::Inbound, &update),
+		];
+
+		let chan_handler = test_utils::TestChannelMessageHandler::new();
+		let route_handler = CountingRoutingMessageHandler { channel_updates: Mutex::new(0) };
+		let logger = test_utils::TestLogger::new();
+		replay_recording(&recording, &chan_handler, &route_handler, &logger);
+		assert_eq!(*route_handler.channel_updates.lock().unwrap(), 3);
+	}
+}
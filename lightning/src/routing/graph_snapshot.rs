@@ -0,0 +1,521 @@
+//! A compact snapshot format for the network graph, allowing clients (eg on mobile) to bootstrap
+//! their graph from a server they trust rather than syncing all of gossip from their peers.
+//!
+//! Snapshots omit all signatures and the original announcement and update messages, list each
+//! node id once in a table which channels refer to by index, and encode channels in order of
+//! short_channel_id as the difference from the previous one. A snapshot may also contain only
+//! the changes since a given time, allowing clients to cheaply keep up to date.
+//!
+//! Generate snapshots from a NetworkGraph by writing a GraphSnapshot, and apply them to a live
+//! graph with import_snapshot.
+//!
+//! The format, in which all integers are big-endian and "bigsize" is the BOLT 1 variable-length
+//! integer, is:
+//!  * the four bytes "LNGS" followed by a version byte, currently 1,
+//!  * the 32-byte genesis block hash of the chain the graph is for,
+//!  * a u32 `since` time, which is 0 for a full snapshot, and a u32 time the snapshot was taken
+//!    at, which can be passed as `since` to generate the next incremental snapshot,
+//!  * a bigsize count of nodes, then for each node its 33-byte node id, a flags byte whose lowest
+//!    bit indicates that the node's node_announcement is included, and if so the announcement's
+//!    u32 timestamp and its features (as a u16 length followed by the feature bytes),
+//!  * a bigsize count of channels, then for each channel the bigsize difference between its
+//!    short_channel_id and the previous channel's (or 0), a flags byte, and:
+//!    * if bit 0 of the flags is set (the channel was announced after `since`), the bigsize
+//!      indices in the node table of the channel's two nodes, and its features,
+//!    * if bit 1 and/or bit 2 are set, the latest channel_update from node one and/or node two:
+//!      its u32 timestamp, a byte which is 1 if the channel direction is enabled and 0 otherwise,
+//!      the u16 cltv_expiry_delta, the bigsize htlc_minimum_msat, and the u32 base and
+//!      proportional fees.
+//!
+//! Nodes are only included in an incremental snapshot if their node_announcement changed or they
+//! are referred to by a newly-announced channel. Note that nodes' aliases and addresses are not
+//! included at all, and that incremental snapshots don't list channels which were removed;
+//! clients should instead prune stale channels (see NetGraphMsgHandler::timer_tick_occured).
+
+use bitcoin::secp256k1::key::PublicKey;
+use bitcoin::hash_types::BlockHash;
+
+use ln::features::{ChannelFeatures, NodeFeatures};
+use ln::msgs::DecodeError;
+use routing::network_graph::{DirectionalChannelInfo, NetworkGraph, NodeId, RoutingFees};
+use util::ser::{BigSize, Readable, Writeable, Writer};
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::sync::RwLock;
+
+const SNAPSHOT_PREFIX: [u8; 4] = [b'L', b'N', b'G', b'S'];
+const SNAPSHOT_VERSION: u8 = 1;
+
+const NODE_FLAG_ANNOUNCEMENT: u8 = 1 << 0;
+const CHANNEL_FLAG_ANNOUNCEMENT: u8 = 1 << 0;
+const CHANNEL_FLAG_ONE_TO_TWO: u8 = 1 << 1;
+const CHANNEL_FLAG_TWO_TO_ONE: u8 = 1 << 2;
+
+/// The number of channels (or nodes) import_snapshot applies each time it takes the network
+/// graph's write lock, so that route calculations aren't held up for long.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Bounds the space we preallocate for a snapshot's entries before we've read them.
+const MAX_PREALLOCATED_ENTRIES: u64 = 64 * 1024;
+
+/// A snapshot of a NetworkGraph which can be written out in the compact format described in the
+/// module documentation.
+pub struct GraphSnapshot<'a> {
+	/// The graph to take the snapshot of
+	pub network_graph: &'a NetworkGraph,
+	/// The genesis block hash of the chain the graph is for
+	pub chain_hash: BlockHash,
+	/// Only channels and updates with a timestamp at or after this (in seconds since the UNIX
+	/// epoch) are included, unless it is 0, in which case the whole graph is included.
+	pub since: u32,
+	/// The time at which the snapshot was taken, which clients may pass back to us as `since` to
+	/// fetch the following changes.
+	pub snapshot_time: u32,
+}
+
+fn write_directional_info<W: Writer>(info: &DirectionalChannelInfo, writer: &mut W) -> Result<(), ::std::io::Error> {
+	info.last_update.write(writer)?;
+	(if info.enabled { 1u8 } else { 0u8 }).write(writer)?;
+	info.cltv_expiry_delta.write(writer)?;
+	BigSize(info.htlc_minimum_msat).write(writer)?;
+	info.fees.base_msat.write(writer)?;
+	info.fees.proportional_millionths.write(writer)
+}
+
+fn read_directional_info<R: ::std::io::Read>(reader: &mut R) -> Result<DirectionalChannelInfo, DecodeError> {
+	let last_update = Readable::read(reader)?;
+	let enabled = match <u8 as Readable>::read(reader)? {
+		0 => false,
+		1 => true,
+		_ => return Err(DecodeError::InvalidValue),
+	};
+	let cltv_expiry_delta = Readable::read(reader)?;
+	let htlc_minimum_msat = <BigSize as Readable>::read(reader)?.0;
+	Ok(DirectionalChannelInfo {
+		last_update,
+		enabled,
+		cltv_expiry_delta,
+		htlc_minimum_msat,
+		fees: RoutingFees {
+			base_msat: Readable::read(reader)?,
+			proportional_millionths: Readable::read(reader)?,
+		},
+		last_update_message: None,
+	})
+}
+
+impl<'a> Writeable for GraphSnapshot<'a> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		let is_full = self.since == 0;
+		let since = self.since as u64;
+		let is_new_update = |info: &Option<DirectionalChannelInfo>| {
+			match info {
+				&Some(ref info) => is_full || info.last_update >= self.since,
+				&None => false,
+			}
+		};
+
+		// First work out which nodes we need, so that channels can refer to them by index.
+		let mut node_indices = BTreeMap::new();
+		for chan in self.network_graph.get_channels().values() {
+			if is_full || chan.announcement_received_time >= since {
+				node_indices.insert(*self.network_graph.get_node_id(chan.node_one), 0);
+				node_indices.insert(*self.network_graph.get_node_id(chan.node_two), 0);
+			}
+		}
+		for (node_id, node) in self.network_graph.get_nodes().iter() {
+			if let Some(ref info) = node.announcement_info {
+				if is_full || info.last_update >= self.since {
+					node_indices.insert(*node_id, 0);
+				}
+			}
+		}
+		for (index, (_, node_index)) in node_indices.iter_mut().enumerate() {
+			*node_index = index as u64;
+		}
+
+		SNAPSHOT_PREFIX.write(writer)?;
+		SNAPSHOT_VERSION.write(writer)?;
+		self.chain_hash.write(writer)?;
+		self.since.write(writer)?;
+		self.snapshot_time.write(writer)?;
+
+		BigSize(node_indices.len() as u64).write(writer)?;
+		for node_id in node_indices.keys() {
+			node_id.write(writer)?;
+			let announcement_info = self.network_graph.get_nodes().get(node_id).and_then(|node| node.announcement_info.as_ref());
+			match announcement_info {
+				Some(info) if is_full || info.last_update >= self.since => {
+					NODE_FLAG_ANNOUNCEMENT.write(writer)?;
+					info.last_update.write(writer)?;
+					info.features.write(writer)?;
+				},
+				_ => 0u8.write(writer)?,
+			}
+		}
+
+		let mut channel_count = 0;
+		for chan in self.network_graph.get_channels().values() {
+			if is_full || chan.announcement_received_time >= since || is_new_update(&chan.one_to_two) || is_new_update(&chan.two_to_one) {
+				channel_count += 1;
+			}
+		}
+		BigSize(channel_count).write(writer)?;
+		let mut previous_short_channel_id = 0;
+		for (short_channel_id, chan) in self.network_graph.get_channels().iter() {
+			let mut flags = 0;
+			if is_full || chan.announcement_received_time >= since { flags |= CHANNEL_FLAG_ANNOUNCEMENT; }
+			if is_new_update(&chan.one_to_two) { flags |= CHANNEL_FLAG_ONE_TO_TWO; }
+			if is_new_update(&chan.two_to_one) { flags |= CHANNEL_FLAG_TWO_TO_ONE; }
+			if flags == 0 { continue; }
+
+			BigSize(*short_channel_id - previous_short_channel_id).write(writer)?;
+			previous_short_channel_id = *short_channel_id;
+			flags.write(writer)?;
+			if flags & CHANNEL_FLAG_ANNOUNCEMENT != 0 {
+				BigSize(node_indices[self.network_graph.get_node_id(chan.node_one)]).write(writer)?;
+				BigSize(node_indices[self.network_graph.get_node_id(chan.node_two)]).write(writer)?;
+				chan.features.write(writer)?;
+			}
+			if let Some(ref info) = chan.one_to_two {
+				if flags & CHANNEL_FLAG_ONE_TO_TWO != 0 { write_directional_info(info, writer)?; }
+			}
+			if let Some(ref info) = chan.two_to_one {
+				if flags & CHANNEL_FLAG_TWO_TO_ONE != 0 { write_directional_info(info, writer)?; }
+			}
+		}
+		Ok(())
+	}
+}
+
+struct SnapshotNode {
+	node_id: NodeId,
+	announcement: Option<(u32, NodeFeatures)>,
+}
+
+struct SnapshotChannel {
+	short_channel_id: u64,
+	announcement: Option<(usize, usize, ChannelFeatures)>,
+	one_to_two: Option<DirectionalChannelInfo>,
+	two_to_one: Option<DirectionalChannelInfo>,
+}
+
+struct ParsedSnapshot {
+	snapshot_time: u32,
+	nodes: Vec<SnapshotNode>,
+	channels: Vec<SnapshotChannel>,
+}
+
+fn parse_snapshot(chain_hash: &BlockHash, snapshot: &[u8]) -> Result<ParsedSnapshot, DecodeError> {
+	let mut reader = Cursor::new(snapshot);
+	let prefix: [u8; 4] = Readable::read(&mut reader)?;
+	if prefix != SNAPSHOT_PREFIX {
+		return Err(DecodeError::InvalidValue);
+	}
+	let version: u8 = Readable::read(&mut reader)?;
+	if version != SNAPSHOT_VERSION {
+		return Err(DecodeError::UnknownVersion);
+	}
+	let snapshot_chain_hash: BlockHash = Readable::read(&mut reader)?;
+	if snapshot_chain_hash != *chain_hash {
+		return Err(DecodeError::InvalidValue);
+	}
+	let _since: u32 = Readable::read(&mut reader)?;
+	let snapshot_time: u32 = Readable::read(&mut reader)?;
+
+	let node_count = <BigSize as Readable>::read(&mut reader)?.0;
+	let mut nodes = Vec::with_capacity(cmp::min(node_count, MAX_PREALLOCATED_ENTRIES) as usize);
+	for _ in 0..node_count {
+		let node_id: PublicKey = Readable::read(&mut reader)?;
+		let node_id = NodeId::from_pubkey(&node_id);
+		let flags: u8 = Readable::read(&mut reader)?;
+		let announcement = if flags & NODE_FLAG_ANNOUNCEMENT != 0 {
+			Some((Readable::read(&mut reader)?, Readable::read(&mut reader)?))
+		} else { None };
+		nodes.push(SnapshotNode { node_id, announcement });
+	}
+
+	let channel_count = <BigSize as Readable>::read(&mut reader)?.0;
+	let mut channels = Vec::with_capacity(cmp::min(channel_count, MAX_PREALLOCATED_ENTRIES) as usize);
+	let mut short_channel_id: u64 = 0;
+	for i in 0..channel_count {
+		let delta = <BigSize as Readable>::read(&mut reader)?.0;
+		if i != 0 && delta == 0 {
+			return Err(DecodeError::InvalidValue);
+		}
+		short_channel_id = match short_channel_id.checked_add(delta) {
+			Some(short_channel_id) => short_channel_id,
+			None => return Err(DecodeError::InvalidValue),
+		};
+		let flags: u8 = Readable::read(&mut reader)?;
+		let announcement = if flags & CHANNEL_FLAG_ANNOUNCEMENT != 0 {
+			let node_one = <BigSize as Readable>::read(&mut reader)?.0;
+			let node_two = <BigSize as Readable>::read(&mut reader)?.0;
+			if node_one >= nodes.len() as u64 || node_two >= nodes.len() as u64 || node_one == node_two {
+				return Err(DecodeError::InvalidValue);
+			}
+			Some((node_one as usize, node_two as usize, Readable::read(&mut reader)?))
+		} else { None };
+		let one_to_two = if flags & CHANNEL_FLAG_ONE_TO_TWO != 0 { Some(read_directional_info(&mut reader)?) } else { None };
+		let two_to_one = if flags & CHANNEL_FLAG_TWO_TO_ONE != 0 { Some(read_directional_info(&mut reader)?) } else { None };
+		channels.push(SnapshotChannel { short_channel_id, announcement, one_to_two, two_to_one });
+	}
+
+	if reader.position() != snapshot.len() as u64 {
+		return Err(DecodeError::InvalidValue);
+	}
+	Ok(ParsedSnapshot { snapshot_time, nodes, channels })
+}
+
+/// Applies a snapshot (full or incremental) to the given network graph, usually a
+/// NetGraphMsgHandler's network_graph.
+///
+/// The snapshot is fully parsed before the graph is touched, so a malformed snapshot is rejected
+/// without changing anything. It is then applied in batches, releasing the graph's write lock
+/// between each, so that routes can still be calculated while a large snapshot is imported.
+///
+/// Channels in the snapshot which we already know of are kept, with only newer channel_updates
+/// from the snapshot applied, so importing a snapshot never rolls back information we received
+/// over gossip. Channels are considered announced at the time the snapshot was taken for the
+/// purpose of pruning stale channels.
+///
+/// Returns the time the snapshot was taken at, to be passed as `since` when fetching the next
+/// incremental snapshot, or DecodeError::InvalidValue if the snapshot is for a different chain.
+pub fn import_snapshot(network_graph: &RwLock<NetworkGraph>, chain_hash: &BlockHash, snapshot: &[u8]) -> Result<u32, DecodeError> {
+	let ParsedSnapshot { snapshot_time, nodes, channels } = parse_snapshot(chain_hash, snapshot)?;
+
+	let mut channels = channels.into_iter().peekable();
+	while channels.peek().is_some() {
+		let mut network_graph = network_graph.write().unwrap();
+		for chan in channels.by_ref().take(IMPORT_BATCH_SIZE) {
+			if let Some((node_one, node_two, features)) = chan.announcement {
+				network_graph.add_channel_from_snapshot(chan.short_channel_id, features,
+					nodes[node_one].node_id, nodes[node_two].node_id, snapshot_time as u64);
+			}
+			if let Some(info) = chan.one_to_two {
+				network_graph.update_channel_from_snapshot(chan.short_channel_id, false, info);
+			}
+			if let Some(info) = chan.two_to_one {
+				network_graph.update_channel_from_snapshot(chan.short_channel_id, true, info);
+			}
+		}
+	}
+
+	let mut nodes = nodes.into_iter().peekable();
+	while nodes.peek().is_some() {
+		let mut network_graph = network_graph.write().unwrap();
+		for node in nodes.by_ref().take(IMPORT_BATCH_SIZE) {
+			if let Some((last_update, features)) = node.announcement {
+				network_graph.update_node_from_snapshot(&node.node_id, last_update, features);
+			}
+		}
+	}
+
+	Ok(snapshot_time)
+}
+
+#[cfg(test)]
+mod tests {
+	use chain::chaininterface;
+	use ln::features::{ChannelFeatures, NodeFeatures};
+	use ln::msgs::{ChannelAnnouncement, ChannelUpdate, DecodeError, NodeAnnouncement, RoutingMessageHandler,
+		UnsignedChannelAnnouncement, UnsignedChannelUpdate, UnsignedNodeAnnouncement};
+	use routing::graph_snapshot::{GraphSnapshot, import_snapshot};
+	use routing::network_graph::{NetGraphMsgHandler, NetworkGraph, NodeId};
+	use util::ser::Writeable;
+	use util::test_utils;
+
+	use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin::hashes::Hash;
+	use bitcoin::network::constants::Network;
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::util::hash::BitcoinHash;
+
+	use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+	use bitcoin::secp256k1::{All, Secp256k1};
+
+	use std::sync::Arc;
+	use std::time::{SystemTime, UNIX_EPOCH};
+
+	type TestNetGraphMsgHandler = NetGraphMsgHandler<Arc<chaininterface::ChainWatchInterfaceUtil>, Arc<test_utils::TestLogger>>;
+
+	fn create_net_graph_msg_handler() -> TestNetGraphMsgHandler {
+		let logger = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet));
+		NetGraphMsgHandler::new(chain_monitor, logger)
+	}
+
+	fn node_key(node: u8) -> SecretKey {
+		SecretKey::from_slice(&[42 - node; 32]).unwrap()
+	}
+
+	fn announce_channel(secp_ctx: &Secp256k1<All>, handler: &TestNetGraphMsgHandler, short_channel_id: u64, node_1: u8, node_2: u8) {
+		let btckey_1 = SecretKey::from_slice(&[10 + node_1; 32]).unwrap();
+		let btckey_2 = SecretKey::from_slice(&[10 + node_2; 32]).unwrap();
+		let unsigned_announcement = UnsignedChannelAnnouncement {
+			features: ChannelFeatures::known(),
+			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+			short_channel_id,
+			node_id_1: PublicKey::from_secret_key(secp_ctx, &node_key(node_1)),
+			node_id_2: PublicKey::from_secret_key(secp_ctx, &node_key(node_2)),
+			bitcoin_key_1: PublicKey::from_secret_key(secp_ctx, &btckey_1),
+			bitcoin_key_2: PublicKey::from_secret_key(secp_ctx, &btckey_2),
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+		handler.handle_channel_announcement(&ChannelAnnouncement {
+			node_signature_1: secp_ctx.sign(&msghash, &node_key(node_1)),
+			node_signature_2: secp_ctx.sign(&msghash, &node_key(node_2)),
+			bitcoin_signature_1: secp_ctx.sign(&msghash, &btckey_1),
+			bitcoin_signature_2: secp_ctx.sign(&msghash, &btckey_2),
+			contents: unsigned_announcement,
+		}).unwrap();
+	}
+
+	fn update_channel(secp_ctx: &Secp256k1<All>, handler: &TestNetGraphMsgHandler, short_channel_id: u64, flags: u16, signer: u8, timestamp: u32, fee_base_msat: u32) {
+		let unsigned_channel_update = UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+			short_channel_id,
+			timestamp,
+			flags,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000,
+			fee_base_msat,
+			fee_proportional_millionths: 20,
+			excess_data: Vec::new()
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_channel_update.encode()[..])[..]);
+		handler.handle_channel_update(&ChannelUpdate {
+			signature: secp_ctx.sign(&msghash, &node_key(signer)),
+			contents: unsigned_channel_update
+		}).unwrap();
+	}
+
+	fn announce_node(secp_ctx: &Secp256k1<All>, handler: &TestNetGraphMsgHandler, node: u8, timestamp: u32) {
+		let unsigned_announcement = UnsignedNodeAnnouncement {
+			features: NodeFeatures::known(),
+			timestamp,
+			node_id: PublicKey::from_secret_key(secp_ctx, &node_key(node)),
+			rgb: [0; 3],
+			alias: [node; 32],
+			addresses: Vec::new(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+		handler.handle_node_announcement(&NodeAnnouncement {
+			signature: secp_ctx.sign(&msghash, &node_key(node)),
+			contents: unsigned_announcement
+		}).unwrap();
+	}
+
+	/// Checks that the routing-relevant parts of two graphs match.
+	fn assert_graphs_match(a: &NetworkGraph, b: &NetworkGraph) {
+		assert_eq!(a.get_channels().len(), b.get_channels().len());
+		for ((a_id, a_chan), (b_id, b_chan)) in a.get_channels().iter().zip(b.get_channels().iter()) {
+			assert_eq!(a_id, b_id);
+			assert_eq!(a_chan.features, b_chan.features);
+			assert_eq!((a.get_node_id(a_chan.node_one), a.get_node_id(a_chan.node_two)), (b.get_node_id(b_chan.node_one), b.get_node_id(b_chan.node_two)));
+			for &(a_info, b_info) in [(&a_chan.one_to_two, &b_chan.one_to_two), (&a_chan.two_to_one, &b_chan.two_to_one)].iter() {
+				assert_eq!(a_info.is_some(), b_info.is_some());
+				if let (&Some(ref a_info), &Some(ref b_info)) = (a_info, b_info) {
+					assert_eq!(a_info.last_update, b_info.last_update);
+					assert_eq!(a_info.enabled, b_info.enabled);
+					assert_eq!(a_info.cltv_expiry_delta, b_info.cltv_expiry_delta);
+					assert_eq!(a_info.htlc_minimum_msat, b_info.htlc_minimum_msat);
+					assert_eq!(a_info.fees, b_info.fees);
+				}
+			}
+		}
+		assert_eq!(a.get_nodes().len(), b.get_nodes().len());
+		for ((a_id, a_node), (b_id, b_node)) in a.get_nodes().iter().zip(b.get_nodes().iter()) {
+			assert_eq!(a_id, b_id);
+			assert_eq!(a_node.channels, b_node.channels);
+			assert_eq!(a_node.lowest_inbound_channel_fees, b_node.lowest_inbound_channel_fees);
+			assert_eq!(a_node.announcement_info.as_ref().map(|info| (info.last_update, info.features.clone())),
+				b_node.announcement_info.as_ref().map(|info| (info.last_update, info.features.clone())));
+		}
+	}
+
+	#[test]
+	fn full_and_incremental_snapshots() {
+		let secp_ctx = Secp256k1::new();
+		let chain_hash = genesis_block(Network::Testnet).header.bitcoin_hash();
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+
+		let source = create_net_graph_msg_handler();
+		announce_channel(&secp_ctx, &source, 1 << 40, 0, 1);
+		announce_channel(&secp_ctx, &source, (1 << 40) | (5 << 16), 1, 2);
+		announce_channel(&secp_ctx, &source, 2 << 40, 0, 2);
+		update_channel(&secp_ctx, &source, 1 << 40, 0, 0, now - 100, 1000);
+		update_channel(&secp_ctx, &source, 1 << 40, 1, 1, now - 100, 2000);
+		update_channel(&secp_ctx, &source, 2 << 40, 1 | 2, 2, now - 100, 3000);
+		announce_node(&secp_ctx, &source, 0, now - 100);
+
+		// Channels announced over gossip are timestamped with when we received them, ie now, so
+		// bootstrap the graph we serve snapshots from with a snapshot taken earlier instead.
+		let server = create_net_graph_msg_handler();
+		let bootstrap_snapshot = GraphSnapshot {
+			network_graph: &source.network_graph.read().unwrap(),
+			chain_hash,
+			since: 0,
+			snapshot_time: now - 100,
+		}.encode();
+		import_snapshot(&server.network_graph, &chain_hash, &bootstrap_snapshot).unwrap();
+		assert_graphs_match(&source.network_graph.read().unwrap(), &server.network_graph.read().unwrap());
+
+		let full_snapshot = GraphSnapshot {
+			network_graph: &server.network_graph.read().unwrap(),
+			chain_hash,
+			since: 0,
+			snapshot_time: now - 50,
+		}.encode();
+		// The snapshot is far smaller than the graph with all its messages.
+		assert!(full_snapshot.len() * 2 < source.network_graph.read().unwrap().encode().len());
+
+		let client = create_net_graph_msg_handler();
+		assert_eq!(import_snapshot(&client.network_graph, &chain_hash, &full_snapshot).unwrap(), now - 50);
+		assert_graphs_match(&server.network_graph.read().unwrap(), &client.network_graph.read().unwrap());
+		// Importing the same snapshot again changes nothing.
+		import_snapshot(&client.network_graph, &chain_hash, &full_snapshot).unwrap();
+		assert_graphs_match(&server.network_graph.read().unwrap(), &client.network_graph.read().unwrap());
+
+		// Make some changes, which should be all that is included in an incremental snapshot.
+		update_channel(&secp_ctx, &server, 1 << 40, 0, 0, now, 500);
+		announce_channel(&secp_ctx, &server, 3 << 40, 2, 3);
+		announce_node(&secp_ctx, &server, 0, now);
+		let incremental_snapshot = GraphSnapshot {
+			network_graph: &server.network_graph.read().unwrap(),
+			chain_hash,
+			since: now - 50,
+			snapshot_time: now,
+		}.encode();
+		assert!(incremental_snapshot.len() < full_snapshot.len());
+
+		// Meanwhile the client hears of an announcement from node 0 newer than the one in the full
+		// snapshot, which the incremental snapshot's then replaces entirely.
+		announce_node(&secp_ctx, &client, 0, now - 75);
+		let node_0 = NodeId::from_pubkey(&PublicKey::from_secret_key(&secp_ctx, &node_key(0)));
+		assert!(client.network_graph.read().unwrap().get_nodes().get(&node_0).unwrap().announcement_info.as_ref().unwrap().announcement_message.is_some());
+
+		import_snapshot(&client.network_graph, &chain_hash, &incremental_snapshot).unwrap();
+		assert_graphs_match(&server.network_graph.read().unwrap(), &client.network_graph.read().unwrap());
+		let node_1 = NodeId::from_pubkey(&PublicKey::from_secret_key(&secp_ctx, &node_key(1)));
+		assert_eq!(client.network_graph.read().unwrap().get_nodes().get(&node_1).unwrap().lowest_inbound_channel_fees.unwrap().base_msat, 500);
+		assert!(client.network_graph.read().unwrap().get_nodes().get(&node_0).unwrap().announcement_info.as_ref().unwrap().announcement_message.is_none());
+
+		// Snapshots for other chains, or which are truncated, are rejected without changes.
+		let fresh_client = create_net_graph_msg_handler();
+		match import_snapshot(&fresh_client.network_graph, &genesis_block(Network::Bitcoin).header.bitcoin_hash(), &full_snapshot) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!(),
+		}
+		match import_snapshot(&fresh_client.network_graph, &chain_hash, &full_snapshot[..full_snapshot.len() - 1]) {
+			Err(DecodeError::ShortRead) => {},
+			_ => panic!(),
+		}
+		assert!(fresh_client.network_graph.read().unwrap().get_channels().is_empty());
+	}
+}
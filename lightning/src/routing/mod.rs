@@ -2,3 +2,4 @@
 
 pub mod router;
 pub mod network_graph;
+pub mod graph_snapshot;
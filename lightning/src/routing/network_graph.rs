@@ -1,4 +1,14 @@
 //! The top-level network map tracking logic lives here.
+//!
+//! Nodes are identified in the graph by NodeId, the compressed serialization of their public key,
+//! rather than by PublicKey: NetworkGraph::get_nodes is keyed by NodeId. Use NodeId::from_pubkey
+//! and NodeId::as_pubkey to convert between the two. The gossip messages the graph keeps copies
+//! of (the announcement_message and last_update_message fields) are boxed, and are None if the
+//! graph doesn't retain announcements.
+//!
+//! Each node's id is stored once, in the graph's node table, and ChannelInfo's node_one and
+//! node_two are NodeIdxs indexing into it rather than NodeIds. Use NetworkGraph::get_node_id to
+//! look them up.
 
 use bitcoin::secp256k1::key::PublicKey;
 use bitcoin::secp256k1::Secp256k1;
@@ -6,10 +16,13 @@ use bitcoin::secp256k1;
 
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::hashes::Hash;
-use bitcoin::blockdata::script::Builder;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
 use bitcoin::blockdata::opcodes;
 
-use chain::chaininterface::{ChainError, ChainWatchInterface};
+use chain::chaininterface::{ChainError, ChainListener, ChainWatchInterface, UtxoLookup, UtxoLookupResult};
+use chain::transaction::OutPoint;
 use ln::features::{ChannelFeatures, NodeFeatures};
 use ln::msgs::{DecodeError,ErrorAction,LightningError,RoutingMessageHandler,NetAddress};
 use ln::msgs;
@@ -17,12 +30,182 @@ use util::ser::{Writeable, Readable, Writer};
 use util::logger::Logger;
 
 use std::cmp;
-use std::sync::RwLock;
+use std::hash;
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::collections::btree_map::Entry as BtreeEntry;
 use std;
 use std::ops::Deref;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The maximum age of the latest channel_update in each direction of a channel before the channel
+/// is considered stale and pruned from the network graph. This is the two weeks given in BOLT 7,
+/// within which each node is expected to refresh its channels with a keepalive update.
+pub const STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS: u64 = 60 * 60 * 24 * 14;
+
+/// A node's id in the network graph, the 33-byte compressed serialization of its public key.
+///
+/// The network graph stores node ids in this form rather than as PublicKeys, which take nearly
+/// twice the memory and require an expensive parse for every key we learn about. The public key
+/// is only parsed when it is actually needed, eg when building a route through the node.
+#[derive(Copy)]
+pub struct NodeId([u8; 33]);
+
+impl NodeId {
+	/// Creates a NodeId from a node's public key.
+	pub fn from_pubkey(pubkey: &PublicKey) -> Self {
+		NodeId(pubkey.serialize())
+	}
+
+	/// Gets the compressed serialization of the node's public key.
+	pub fn as_slice(&self) -> &[u8] {
+		&self.0[..]
+	}
+
+	/// Parses the node's public key, failing if the id is not a valid compressed public key.
+	pub fn as_pubkey(&self) -> Result<PublicKey, secp256k1::Error> {
+		PublicKey::from_slice(&self.0[..])
+	}
+}
+
+// [u8; 33] only gets these traits derived in newer versions of Rust.
+impl Clone for NodeId {
+	fn clone(&self) -> Self { *self }
+}
+
+impl PartialEq for NodeId {
+	fn eq(&self, other: &NodeId) -> bool { self.0[..] == other.0[..] }
+}
+impl Eq for NodeId {}
+
+impl PartialOrd for NodeId {
+	fn partial_cmp(&self, other: &NodeId) -> Option<cmp::Ordering> { Some(self.cmp(other)) }
+}
+impl Ord for NodeId {
+	fn cmp(&self, other: &NodeId) -> cmp::Ordering { self.0[..].cmp(&other.0[..]) }
+}
+
+impl hash::Hash for NodeId {
+	fn hash<H: hash::Hasher>(&self, hasher: &mut H) { self.0[..].hash(hasher) }
+}
+
+impl std::fmt::Debug for NodeId {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+		write!(f, "NodeId({})", log_bytes!(self.0[..]))
+	}
+}
+
+impl Writeable for NodeId {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.0.write(writer)
+	}
+}
+
+impl Readable for NodeId {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<NodeId, DecodeError> {
+		Ok(NodeId(Readable::read(reader)?))
+	}
+}
+
+/// A node's index into the node table of the NetworkGraph it is in, see NetworkGraph::get_node_id.
+///
+/// The indices of nodes which are removed from the graph are reused for nodes added later, so a
+/// NodeIdx should only be looked up in the graph it came from, while the channel it came from is
+/// still in the graph.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeIdx(u32);
+
+/// The table of node ids which ChannelInfo::node_one and node_two index into, holding each node's
+/// id once rather than once per channel. The slots of removed nodes are reused.
+struct NodeTable {
+	node_ids: Vec<NodeId>,
+	free_idxs: Vec<NodeIdx>,
+}
+
+impl NodeTable {
+	fn new() -> Self {
+		NodeTable { node_ids: Vec::new(), free_idxs: Vec::new() }
+	}
+
+	fn get(&self, idx: NodeIdx) -> &NodeId {
+		&self.node_ids[idx.0 as usize]
+	}
+
+	fn insert(&mut self, node_id: NodeId) -> NodeIdx {
+		match self.free_idxs.pop() {
+			Some(idx) => {
+				self.node_ids[idx.0 as usize] = node_id;
+				idx
+			},
+			None => {
+				self.node_ids.push(node_id);
+				NodeIdx(self.node_ids.len() as u32 - 1)
+			},
+		}
+	}
+
+	fn remove(&mut self, idx: NodeIdx) {
+		self.free_idxs.push(idx);
+	}
+}
+
+/// The channels and nodes removed from a NetworkGraph by a single pruning pass, see
+/// NetworkGraph::remove_stale_channels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrunedGraphEntries {
+	/// The short channel ids of the channels which were removed as stale
+	pub channels: Vec<u64>,
+	/// The ids of the nodes which were removed as they no longer had any channels
+	pub nodes: Vec<NodeId>,
+}
+
+fn time_now_unix() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The maximum number of channel announcements we hold while their funding outputs are looked up
+/// by a UtxoLookup which returned UtxoLookupResult::Async. Further announcements are rejected
+/// until some complete, bounding the memory a peer can make us use with bogus announcements.
+const MAX_PENDING_UTXO_LOOKUPS: usize = 1000;
+
+/// How long we wait for a UtxoLookup to complete a lookup before we drop the announcement.
+const PENDING_UTXO_LOOKUP_TIMEOUT_SECS: u64 = 60 * 60;
+
+struct PendingChannelAnnouncement {
+	msg: msgs::ChannelAnnouncement,
+	received_time: u64,
+}
+
+/// Checks the funding output script found for an announced channel, returning whether it was
+/// checked at all (ie the chain source supports UTXO lookups).
+fn check_funding_script(msg: &msgs::ChannelAnnouncement, script_pubkey: Result<Script, ChainError>) -> Result<bool, LightningError> {
+	match script_pubkey {
+		Ok(script_pubkey) => {
+			let expected_script = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+			                                    .push_slice(&msg.contents.bitcoin_key_1.serialize())
+			                                    .push_slice(&msg.contents.bitcoin_key_2.serialize())
+			                                    .push_opcode(opcodes::all::OP_PUSHNUM_2)
+			                                    .push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh();
+			if script_pubkey != expected_script {
+				return Err(LightningError{err: "Channel announcement keys didn't match on-chain script", action: ErrorAction::IgnoreError});
+			}
+			//TODO: Check if value is worth storing, use it to inform routing, and compare it
+			//to the new HTLC max field in channel_update
+			Ok(true)
+		},
+		Err(ChainError::NotSupported) => {
+			// Tentatively accept, potentially exposing us to DoS attacks
+			Ok(false)
+		},
+		Err(ChainError::NotWatched) => {
+			Err(LightningError{err: "Channel announced on an unknown chain", action: ErrorAction::IgnoreError})
+		},
+		Err(ChainError::UnknownTx) => {
+			Err(LightningError{err: "Channel announced without corresponding UTXO entry", action: ErrorAction::IgnoreError})
+		},
+	}
+}
 
 /// Receives and validates network updates from peers,
 /// stores authentic and relevant data as a network graph.
@@ -35,6 +218,19 @@ pub struct NetGraphMsgHandler<C: Deref, L: Deref> where C::Target: ChainWatchInt
 	pub network_graph: RwLock<NetworkGraph>,
 	chain_monitor: C,
 	full_syncs_requested: AtomicUsize,
+	/// channel_updates with a timestamp below this are rejected, as the channel direction they
+	/// refer to would be pruned as stale anyway. Set by the last call to remove_stale_channels,
+	/// though is_stale_update also applies a cutoff based on the current time.
+	stale_update_cutoff: AtomicUsize,
+	utxo_lookup: Option<Arc<UtxoLookup>>,
+	/// Channel announcements waiting on utxo_lookup, by short_channel_id.
+	pending_utxo_lookups: Mutex<HashMap<u64, PendingChannelAnnouncement>>,
+	/// The funding outpoints of channels whose announcements were checked by utxo_lookup, which
+	/// are watched so that the channels can be removed once they're spent.
+	funding_outpoints: Mutex<HashMap<OutPoint, u64>>,
+	/// Known channels funded in a block which has since been disconnected, whose announcements
+	/// are looked up again (and the channel replaced if the lookup succeeds) rather than ignored.
+	channels_to_revalidate: Mutex<HashSet<u64>>,
 	logger: L,
 }
 
@@ -50,8 +246,15 @@ impl<C: Deref, L: Deref> NetGraphMsgHandler<C, L> where C::Target: ChainWatchInt
 			network_graph: RwLock::new(NetworkGraph {
 				channels: BTreeMap::new(),
 				nodes: BTreeMap::new(),
+				node_table: NodeTable::new(),
+				retain_announcements: true,
 			}),
 			full_syncs_requested: AtomicUsize::new(0),
+			stale_update_cutoff: AtomicUsize::new(0),
+			utxo_lookup: None,
+			pending_utxo_lookups: Mutex::new(HashMap::new()),
+			funding_outpoints: Mutex::new(HashMap::new()),
+			channels_to_revalidate: Mutex::new(HashSet::new()),
 			chain_monitor,
 			logger,
 		}
@@ -64,10 +267,125 @@ impl<C: Deref, L: Deref> NetGraphMsgHandler<C, L> where C::Target: ChainWatchInt
 			secp_ctx: Secp256k1::verification_only(),
 			network_graph,
 			full_syncs_requested: AtomicUsize::new(0),
+			stale_update_cutoff: AtomicUsize::new(0),
+			utxo_lookup: None,
+			pending_utxo_lookups: Mutex::new(HashMap::new()),
+			funding_outpoints: Mutex::new(HashMap::new()),
+			channels_to_revalidate: Mutex::new(HashSet::new()),
 			chain_monitor,
 			logger,
 		}
 	}
+
+	/// Sets a UtxoLookup which is used to check the funding output of announced channels instead
+	/// of the chain monitor's get_chain_utxo.
+	///
+	/// Announcements whose lookup completes asynchronously are held (up to a limit, beyond which
+	/// further announcements are rejected) until utxo_lookup_completed is called, or for an hour
+	/// after which timer_tick_occured drops them. Such announcements are not relayed to our
+	/// peers, though they will be served to peers which request a sync.
+	///
+	/// The funding outpoints of channels checked this way are registered with the chain monitor,
+	/// and the channels are removed once they are spent, as long as we are notified of new blocks
+	/// as a ChainListener. These outpoints are not persisted with the NetworkGraph, so after a
+	/// restart the channels checked before it are no longer removed when spent, and are instead
+	/// left to be pruned once they stop receiving channel_updates.
+	///
+	/// Announcements for channels we already know are ignored, unless the block the channel was
+	/// funded in has been disconnected, in which case the funding output is looked up again and
+	/// the channel replaced if it's still found.
+	pub fn set_utxo_lookup(&mut self, utxo_lookup: Arc<UtxoLookup>) {
+		self.utxo_lookup = Some(utxo_lookup);
+	}
+
+	/// Provides the result of a lookup for which our UtxoLookup returned
+	/// UtxoLookupResult::Async, adding the announced channel to the network graph if its funding
+	/// output matches.
+	///
+	/// Returns Err if the announcement was rejected or we had given up on the lookup, and
+	/// otherwise whether the announcement would have been relayed had it been checked immediately.
+	pub fn utxo_lookup_completed(&self, short_channel_id: u64, result: Result<(OutPoint, TxOut), ChainError>) -> Result<bool, LightningError> {
+		let pending = match self.pending_utxo_lookups.lock().unwrap().remove(&short_channel_id) {
+			Some(pending) => pending,
+			None => return Err(LightningError{err: "No channel announcement pending UTXO lookup", action: ErrorAction::IgnoreError}),
+		};
+		self.add_channel_from_utxo_lookup(&pending.msg, pending.received_time, result)
+	}
+
+	/// Adds an announced channel whose signatures have already been checked given the result of
+	/// looking up its funding output with our UtxoLookup, watching the output if it was found.
+	fn add_channel_from_utxo_lookup(&self, msg: &msgs::ChannelAnnouncement, received_time: u64, result: Result<(OutPoint, TxOut), ChainError>) -> Result<bool, LightningError> {
+		let funding_output = match result {
+			Ok((outpoint, txout)) => {
+				check_funding_script(msg, Ok(txout.script_pubkey.clone()))?;
+				Some((outpoint, txout.script_pubkey))
+			},
+			Err(e) => {
+				check_funding_script(msg, Err(e))?;
+				None
+			},
+		};
+		let should_relay = self.network_graph.write().unwrap().update_channel_from_announcement(msg, funding_output.is_some(), received_time, None)?;
+		self.channels_to_revalidate.lock().unwrap().remove(&msg.contents.short_channel_id);
+		if let Some((outpoint, script_pubkey)) = funding_output {
+			self.chain_monitor.install_watch_outpoint((outpoint.txid, outpoint.index as u32), &script_pubkey);
+			let mut funding_outpoints = self.funding_outpoints.lock().unwrap();
+			// If we're replacing a channel after a reorg its funding output may have changed.
+			funding_outpoints.retain(|_, short_channel_id| *short_channel_id != msg.contents.short_channel_id);
+			funding_outpoints.insert(outpoint, msg.contents.short_channel_id);
+		}
+		log_trace!(self.logger, "Added channel_announcement for {} after UTXO lookup", msg.contents.short_channel_id);
+		Ok(should_relay)
+	}
+
+	/// Removes channels which haven't been refreshed by a channel_update in each direction within
+	/// STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS of the given time (in seconds since the UNIX epoch), as
+	/// well as any nodes left without channels. From then on channel_updates older than that limit
+	/// are rejected on receipt.
+	///
+	/// Returns the removed channels and nodes.
+	pub fn remove_stale_channels(&self, current_time_unix: u64) -> PrunedGraphEntries {
+		let min_time_unix = current_time_unix.saturating_sub(STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS);
+		self.stale_update_cutoff.store(cmp::min(min_time_unix, u32::max_value() as u64) as usize, Ordering::Release);
+		let pruned = self.network_graph.write().unwrap().remove_stale_channels(current_time_unix);
+		{
+			let mut funding_outpoints = self.funding_outpoints.lock().unwrap();
+			let network_graph = self.network_graph.read().unwrap();
+			funding_outpoints.retain(|_, short_channel_id| network_graph.get_channels().contains_key(short_channel_id));
+			self.channels_to_revalidate.lock().unwrap().retain(|short_channel_id| network_graph.get_channels().contains_key(short_channel_id));
+		}
+		self.pending_utxo_lookups.lock().unwrap().retain(|short_channel_id, pending| {
+			if pending.received_time + PENDING_UTXO_LOOKUP_TIMEOUT_SECS < current_time_unix {
+				log_debug!(self.logger, "Dropping channel_announcement for {} as its UTXO lookup didn't complete in time", short_channel_id);
+				false
+			} else { true }
+		});
+		for short_channel_id in pruned.channels.iter() {
+			log_trace!(self.logger, "Pruned stale channel {} from the network graph", short_channel_id);
+		}
+		for node_id in pruned.nodes.iter() {
+			log_trace!(self.logger, "Pruned node {} from the network graph as it no longer has channels", log_bytes!(node_id.as_slice()));
+		}
+		log_debug!(self.logger, "Pruned {} stale channels and {} nodes from the network graph", pruned.channels.len(), pruned.nodes.len());
+		pruned
+	}
+
+	/// Prunes stale channels and nodes from the network graph using the current system time.
+	/// See remove_stale_channels for details.
+	///
+	/// Should be called on startup and then roughly once an hour; pruning happens at a granularity
+	/// of weeks, so the timing doesn't have to be precise.
+	pub fn timer_tick_occured(&self) -> PrunedGraphEntries {
+		self.remove_stale_channels(time_now_unix())
+	}
+
+	fn is_stale_update(&self, msg: &msgs::ChannelUpdate) -> bool {
+		// Check against the current time too, so that stale updates aren't accepted (only to be
+		// pruned later) before remove_stale_channels is first called.
+		let now_cutoff = time_now_unix().saturating_sub(STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS);
+		(msg.contents.timestamp as u64) < now_cutoff ||
+			(msg.contents.timestamp as usize) < self.stale_update_cutoff.load(Ordering::Acquire)
+	}
 }
 
 
@@ -90,32 +408,41 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 			return Err(LightningError{err: "Channel announcement node had a channel with itself", action: ErrorAction::IgnoreError});
 		}
 
-		let checked_utxo = match self.chain_monitor.get_chain_utxo(msg.contents.chain_hash, msg.contents.short_channel_id) {
-			Ok((script_pubkey, _value)) => {
-				let expected_script = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
-				                                    .push_slice(&msg.contents.bitcoin_key_1.serialize())
-				                                    .push_slice(&msg.contents.bitcoin_key_2.serialize())
-				                                    .push_opcode(opcodes::all::OP_PUSHNUM_2)
-				                                    .push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh();
-				if script_pubkey != expected_script {
-					return Err(LightningError{err: "Channel announcement keys didn't match on-chain script", action: ErrorAction::IgnoreError});
+		if let Some(ref utxo_lookup) = self.utxo_lookup {
+			// Check the signatures first so that bogus announcements can't make us look anything
+			// up or take up one of our pending lookup slots.
+			NetworkGraph::verify_channel_announcement(msg, &self.secp_ctx)?;
+			if self.network_graph.read().unwrap().channels.contains_key(&msg.contents.short_channel_id) &&
+					!self.channels_to_revalidate.lock().unwrap().contains(&msg.contents.short_channel_id) {
+				return Err(LightningError{err: "Already have knowledge of channel", action: ErrorAction::IgnoreError});
+			}
+			let received_time = time_now_unix();
+			{
+				let mut pending_utxo_lookups = self.pending_utxo_lookups.lock().unwrap();
+				if pending_utxo_lookups.contains_key(&msg.contents.short_channel_id) {
+					return Err(LightningError{err: "Channel announcement is already pending UTXO lookup", action: ErrorAction::IgnoreError});
 				}
-				//TODO: Check if value is worth storing, use it to inform routing, and compare it
-				//to the new HTLC max field in channel_update
-				true
-			},
-			Err(ChainError::NotSupported) => {
-				// Tentatively accept, potentially exposing us to DoS attacks
-				false
-			},
-			Err(ChainError::NotWatched) => {
-				return Err(LightningError{err: "Channel announced on an unknown chain", action: ErrorAction::IgnoreError});
-			},
-			Err(ChainError::UnknownTx) => {
-				return Err(LightningError{err: "Channel announced without corresponding UTXO entry", action: ErrorAction::IgnoreError});
-			},
-		};
-		let result = self.network_graph.write().unwrap().update_channel_from_announcement(msg, checked_utxo, Some(&self.secp_ctx));
+				if pending_utxo_lookups.len() >= MAX_PENDING_UTXO_LOOKUPS {
+					return Err(LightningError{err: "Too many channel announcements pending UTXO lookup", action: ErrorAction::IgnoreError});
+				}
+				// Insert the announcement before starting the lookup in case it completes (on
+				// another thread) before get_utxo returns.
+				pending_utxo_lookups.insert(msg.contents.short_channel_id, PendingChannelAnnouncement { msg: msg.clone(), received_time });
+			}
+			return match utxo_lookup.get_utxo(&msg.contents.chain_hash, msg.contents.short_channel_id) {
+				UtxoLookupResult::Sync(result) => {
+					self.pending_utxo_lookups.lock().unwrap().remove(&msg.contents.short_channel_id);
+					self.add_channel_from_utxo_lookup(msg, received_time, result)
+				},
+				UtxoLookupResult::Async => {
+					log_trace!(self.logger, "Waiting on UTXO lookup for channel_announcement for {}", msg.contents.short_channel_id);
+					Ok(false)
+				},
+			};
+		}
+
+		let checked_utxo = check_funding_script(msg, self.chain_monitor.get_chain_utxo(msg.contents.chain_hash, msg.contents.short_channel_id).map(|(script_pubkey, _value)| script_pubkey))?;
+		let result = self.network_graph.write().unwrap().update_channel_from_announcement(msg, checked_utxo, time_now_unix(), Some(&self.secp_ctx));
 		log_trace!(self.logger, "Added channel_announcement for {}{}", msg.contents.short_channel_id, if !msg.contents.excess_data.is_empty() { " with excess uninterpreted data!" } else { "" });
 		result
 	}
@@ -123,7 +450,9 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 	fn handle_htlc_fail_channel_update(&self, update: &msgs::HTLCFailChannelUpdate) {
 		match update {
 			&msgs::HTLCFailChannelUpdate::ChannelUpdateMessage { ref msg } => {
-				let _ = self.network_graph.write().unwrap().update_channel(msg, Some(&self.secp_ctx));
+				if !self.is_stale_update(msg) {
+					let _ = self.network_graph.write().unwrap().update_channel(msg, Some(&self.secp_ctx));
+				}
 			},
 			&msgs::HTLCFailChannelUpdate::ChannelClosed { ref short_channel_id, ref is_permanent } => {
 				self.network_graph.write().unwrap().close_channel_from_update(short_channel_id, &is_permanent);
@@ -135,6 +464,9 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 	}
 
 	fn handle_channel_update(&self, msg: &msgs::ChannelUpdate) -> Result<bool, LightningError> {
+		if self.is_stale_update(msg) {
+			return Err(LightningError{err: "channel_update is older than two weeks", action: ErrorAction::IgnoreError});
+		}
 		self.network_graph.write().unwrap().update_channel(msg, Some(&self.secp_ctx))
 	}
 
@@ -144,17 +476,16 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 		let mut iter = network_graph.get_channels().range(starting_point..);
 		while result.len() < batch_amount as usize {
 			if let Some((_, ref chan)) = iter.next() {
-				if chan.announcement_message.is_some() {
-					let chan_announcement = chan.announcement_message.clone().unwrap();
+				if let Some(ref chan_announcement) = chan.announcement_message {
 					let mut one_to_two_announcement: Option<msgs::ChannelUpdate> = None;
 					let mut two_to_one_announcement: Option<msgs::ChannelUpdate> = None;
 					if let Some(one_to_two) = chan.one_to_two.as_ref() {
-						one_to_two_announcement = one_to_two.last_update_message.clone();
+						one_to_two_announcement = one_to_two.last_update_message.as_ref().map(|msg| (**msg).clone());
 					}
 					if let Some(two_to_one) = chan.two_to_one.as_ref() {
-						two_to_one_announcement = two_to_one.last_update_message.clone();
+						two_to_one_announcement = two_to_one.last_update_message.as_ref().map(|msg| (**msg).clone());
 					}
-					result.push((chan_announcement, one_to_two_announcement, two_to_one_announcement));
+					result.push(((**chan_announcement).clone(), one_to_two_announcement, two_to_one_announcement));
 				} else {
 					// TODO: We may end up sending un-announced channel_updates if we are sending
 					// initial sync data while receiving announce/updates for this channel.
@@ -170,7 +501,7 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 		let network_graph = self.network_graph.read().unwrap();
 		let mut result = Vec::with_capacity(batch_amount as usize);
 		let mut iter = if let Some(pubkey) = starting_point {
-				let mut iter = network_graph.get_nodes().range((*pubkey)..);
+				let mut iter = network_graph.get_nodes().range(NodeId::from_pubkey(pubkey)..);
 				iter.next();
 				iter
 			} else {
@@ -179,8 +510,8 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 		while result.len() < batch_amount as usize {
 			if let Some((_, ref node)) = iter.next() {
 				if let Some(node_info) = node.announcement_info.as_ref() {
-					if node_info.announcement_message.is_some() {
-						result.push(node_info.announcement_message.clone().unwrap());
+					if let Some(ref msg) = node_info.announcement_message {
+						result.push((**msg).clone());
 					}
 				}
 			} else {
@@ -202,6 +533,36 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 	}
 }
 
+impl<C: Deref + Sync + Send, L: Deref + Sync + Send> ChainListener for NetGraphMsgHandler<C, L> where C::Target: ChainWatchInterface, L::Target: Logger {
+	fn block_connected(&self, _header: &BlockHeader, _height: u32, txn_matched: &[&Transaction], _indexes_of_txn_matched: &[u32]) {
+		let mut funding_outpoints = self.funding_outpoints.lock().unwrap();
+		if funding_outpoints.is_empty() { return; }
+		for tx in txn_matched {
+			for input in tx.input.iter() {
+				if input.previous_output.vout > u16::max_value() as u32 { continue; }
+				let outpoint = OutPoint::new(input.previous_output.txid, input.previous_output.vout as u16);
+				if let Some(short_channel_id) = funding_outpoints.remove(&outpoint) {
+					log_debug!(self.logger, "Removing channel {} from the network graph as its funding output was spent", short_channel_id);
+					self.network_graph.write().unwrap().close_channel_from_update(&short_channel_id, &true);
+				}
+			}
+		}
+	}
+
+	fn block_disconnected(&self, _header: &BlockHeader, disconnected_height: u32) {
+		// Channels funded in the disconnected block may have been re-confirmed elsewhere (or not at
+		// all), so let their next announcement be checked by utxo_lookup again. Channels removed
+		// when their funding output was spent will be re-added if they're announced again (we
+		// don't track which were removed).
+		if self.utxo_lookup.is_none() { return; }
+		let network_graph = self.network_graph.read().unwrap();
+		let mut channels_to_revalidate = self.channels_to_revalidate.lock().unwrap();
+		for (short_channel_id, _) in network_graph.channels.range((disconnected_height as u64) << 40..) {
+			channels_to_revalidate.insert(*short_channel_id);
+		}
+	}
+}
+
 #[derive(PartialEq, Debug)]
 /// Details about one direction of a channel. Received
 /// within a channel update.
@@ -220,8 +581,9 @@ pub struct DirectionalChannelInfo {
 	/// Most recent update for the channel received from the network
 	/// Mostly redundant with the data we store in fields explicitly.
 	/// Everything else is useful only for sending out for initial routing sync.
-	/// Not stored if contains excess data to prevent DoS.
-	pub last_update_message: Option<msgs::ChannelUpdate>,
+	/// Not stored if contains excess data to prevent DoS, or if the graph doesn't retain
+	/// announcements (see NetworkGraph::set_retain_announcements).
+	pub last_update_message: Option<Box<msgs::ChannelUpdate>>,
 }
 
 impl std::fmt::Display for DirectionalChannelInfo {
@@ -243,41 +605,41 @@ impl_writeable!(DirectionalChannelInfo, 0, {
 #[derive(PartialEq)]
 /// Details about a channel (both directions).
 /// Received within a channel announcement.
+///
+/// The channel's nodes are indices into the node table of the graph the channel is in, so two
+/// ChannelInfos from different graphs may not be compared.
 pub struct ChannelInfo {
 	/// Protocol features of a channel communicated during its announcement
 	pub features: ChannelFeatures,
-	/// Source node of the first direction of a channel
-	pub node_one: PublicKey,
+	/// Source node of the first direction of a channel, see NetworkGraph::get_node_id
+	pub node_one: NodeIdx,
 	/// Details about the first direction of a channel
 	pub one_to_two: Option<DirectionalChannelInfo>,
-	/// Source node of the second direction of a channel
-	pub node_two: PublicKey,
+	/// Source node of the second direction of a channel, see NetworkGraph::get_node_id
+	pub node_two: NodeIdx,
 	/// Details about the second direction of a channel
 	pub two_to_one: Option<DirectionalChannelInfo>,
 	/// An initial announcement of the channel
 	/// Mostly redundant with the data we store in fields explicitly.
 	/// Everything else is useful only for sending out for initial routing sync.
-	/// Not stored if contains excess data to prevent DoS.
-	pub announcement_message: Option<msgs::ChannelAnnouncement>,
+	/// Not stored if contains excess data to prevent DoS, or if the graph doesn't retain
+	/// announcements (see NetworkGraph::set_retain_announcements).
+	pub announcement_message: Option<Box<msgs::ChannelAnnouncement>>,
+	/// When we received the channel announcement, in seconds since the UNIX epoch.
+	/// Used to give channels time to receive their first channel_updates before they are
+	/// considered stale. Channels read from a graph serialized before this was tracked are given
+	/// the time they were read.
+	pub announcement_received_time: u64,
 }
 
 impl std::fmt::Display for ChannelInfo {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-		write!(f, "features: {}, node_one: {}, one_to_two: {:?}, node_two: {}, two_to_one: {:?}",
-		   log_bytes!(self.features.encode()), log_pubkey!(self.node_one), self.one_to_two, log_pubkey!(self.node_two), self.two_to_one)?;
+		write!(f, "features: {}, node_one: {:?}, one_to_two: {:?}, node_two: {:?}, two_to_one: {:?}, announcement_received_time: {}",
+		   log_bytes!(self.features.encode()), self.node_one, self.one_to_two, self.node_two, self.two_to_one, self.announcement_received_time)?;
 		Ok(())
 	}
 }
 
-impl_writeable!(ChannelInfo, 0, {
-	features,
-	node_one,
-	one_to_two,
-	node_two,
-	two_to_one,
-	announcement_message
-});
-
 
 /// Fees for routing via a given channel or a node
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
@@ -327,8 +689,9 @@ pub struct NodeAnnouncementInfo {
 	/// An initial announcement of the node
 	/// Mostly redundant with the data we store in fields explicitly.
 	/// Everything else is useful only for sending out for initial routing sync.
-	/// Not stored if contains excess data to prevent DoS.
-	pub announcement_message: Option<msgs::NodeAnnouncement>
+	/// Not stored if contains excess data to prevent DoS, or if the graph doesn't retain
+	/// announcements (see NetworkGraph::set_retain_announcements).
+	pub announcement_message: Option<Box<msgs::NodeAnnouncement>>
 }
 
 impl Writeable for NodeAnnouncementInfo {
@@ -374,9 +737,10 @@ impl Readable for NodeAnnouncementInfo {
 	}
 }
 
-#[derive(PartialEq)]
 /// Details about a node in the network, known from the network announcement.
 pub struct NodeInfo {
+	/// This node's index in the graph's node table, by which its channels refer to it.
+	pub(crate) idx: NodeIdx,
 	/// All valid channels a node has announced
 	pub channels: Vec<u64>,
 	/// Lowest fees enabling routing via any of the enabled, known channels to a node.
@@ -389,6 +753,14 @@ pub struct NodeInfo {
 	pub announcement_info: Option<NodeAnnouncementInfo>
 }
 
+// idx depends on the order nodes were added to the graph in, so isn't compared.
+impl PartialEq for NodeInfo {
+	fn eq(&self, other: &NodeInfo) -> bool {
+		self.channels == other.channels && self.lowest_inbound_channel_fees == other.lowest_inbound_channel_fees &&
+			self.announcement_info == other.announcement_info
+	}
+}
+
 impl std::fmt::Display for NodeInfo {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
 		write!(f, "lowest_inbound_channel_fees: {:?}, channels: {:?}, announcement_info: {:?}",
@@ -411,8 +783,8 @@ impl Writeable for NodeInfo {
 
 const MAX_ALLOC_SIZE: u64 = 64*1024;
 
-impl Readable for NodeInfo {
-	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<NodeInfo, DecodeError> {
+impl NodeInfo {
+	fn read<R: ::std::io::Read>(reader: &mut R, idx: NodeIdx) -> Result<NodeInfo, DecodeError> {
 		let channels_count: u64 = Readable::read(reader)?;
 		let mut channels = Vec::with_capacity(cmp::min(channels_count, MAX_ALLOC_SIZE / 8) as usize);
 		for _ in 0..channels_count {
@@ -421,6 +793,7 @@ impl Readable for NodeInfo {
 		let lowest_inbound_channel_fees = Readable::read(reader)?;
 		let announcement_info = Readable::read(reader)?;
 		Ok(NodeInfo {
+			idx,
 			channels,
 			lowest_inbound_channel_fees,
 			announcement_info,
@@ -429,10 +802,13 @@ impl Readable for NodeInfo {
 }
 
 /// Represents the network as nodes and channels between them
-#[derive(PartialEq)]
 pub struct NetworkGraph {
 	channels: BTreeMap<u64, ChannelInfo>,
-	nodes: BTreeMap<PublicKey, NodeInfo>,
+	nodes: BTreeMap<NodeId, NodeInfo>,
+	node_table: NodeTable,
+	/// Whether we keep copies of the announcements and updates we receive to serve them to our
+	/// peers. Not serialized, it has to be set again after the graph is read.
+	retain_announcements: bool,
 }
 
 impl Writeable for NetworkGraph {
@@ -440,13 +816,24 @@ impl Writeable for NetworkGraph {
 		(self.channels.len() as u64).write(writer)?;
 		for (ref chan_id, ref chan_info) in self.channels.iter() {
 			(*chan_id).write(writer)?;
-			chan_info.write(writer)?;
+			chan_info.features.write(writer)?;
+			self.node_table.get(chan_info.node_one).write(writer)?;
+			chan_info.one_to_two.write(writer)?;
+			self.node_table.get(chan_info.node_two).write(writer)?;
+			chan_info.two_to_one.write(writer)?;
+			chan_info.announcement_message.write(writer)?;
 		}
 		(self.nodes.len() as u64).write(writer)?;
 		for (ref node_id, ref node_info) in self.nodes.iter() {
 			node_id.write(writer)?;
 			node_info.write(writer)?;
 		}
+		// Trailing fields, which graphs written by older versions lack (in which case we use
+		// defaults)
+		(self.channels.len() as u64).write(writer)?;
+		for chan_info in self.channels.values() {
+			chan_info.announcement_received_time.write(writer)?;
+		}
 		Ok(())
 	}
 }
@@ -454,26 +841,76 @@ impl Writeable for NetworkGraph {
 impl Readable for NetworkGraph {
 	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<NetworkGraph, DecodeError> {
 		let channels_count: u64 = Readable::read(reader)?;
+		// Channels are written before nodes, so hold on to their node ids until we've read the
+		// nodes and know their indices.
+		let mut channel_node_ids = Vec::with_capacity(cmp::min(channels_count, MAX_ALLOC_SIZE / 74) as usize);
 		let mut channels = BTreeMap::new();
 		for _ in 0..channels_count {
 			let chan_id: u64 = Readable::read(reader)?;
-			let chan_info = Readable::read(reader)?;
-			channels.insert(chan_id, chan_info);
+			let features = Readable::read(reader)?;
+			let node_one: NodeId = Readable::read(reader)?;
+			let one_to_two = Readable::read(reader)?;
+			let node_two: NodeId = Readable::read(reader)?;
+			channel_node_ids.push((chan_id, node_one, node_two));
+			channels.insert(chan_id, ChannelInfo {
+				features,
+				node_one: NodeIdx(0),
+				one_to_two,
+				node_two: NodeIdx(0),
+				two_to_one: Readable::read(reader)?,
+				announcement_message: Readable::read(reader)?,
+				announcement_received_time: time_now_unix(),
+			});
 		}
 		let nodes_count: u64 = Readable::read(reader)?;
 		let mut nodes = BTreeMap::new();
+		let mut node_table = NodeTable::new();
 		for _ in 0..nodes_count {
-			let node_id = Readable::read(reader)?;
-			let node_info = Readable::read(reader)?;
+			let node_id: NodeId = Readable::read(reader)?;
+			if nodes.contains_key(&node_id) { return Err(DecodeError::InvalidValue); }
+			let node_info = NodeInfo::read(reader, node_table.insert(node_id))?;
 			nodes.insert(node_id, node_info);
 		}
+		for &(ref chan_id, ref node_one, ref node_two) in channel_node_ids.iter() {
+			let chan_info = channels.get_mut(chan_id).unwrap();
+			chan_info.node_one = nodes.get(node_one).ok_or(DecodeError::InvalidValue)?.idx;
+			chan_info.node_two = nodes.get(node_two).ok_or(DecodeError::InvalidValue)?.idx;
+		}
+		match <u64 as Readable>::read(reader) {
+			Ok(received_times_count) => {
+				if received_times_count != channels_count { return Err(DecodeError::InvalidValue); }
+				for chan_info in channels.values_mut() {
+					chan_info.announcement_received_time = Readable::read(reader)?;
+				}
+			},
+			// Written before announcement_received_time was tracked, leave the default
+			Err(DecodeError::ShortRead) => {},
+			Err(e) => return Err(e),
+		}
 		Ok(NetworkGraph {
 			channels,
 			nodes,
+			node_table,
+			retain_announcements: true,
 		})
 	}
 }
 
+// Node indices depend on the order nodes were added to the graph in, so compare channels by the
+// ids of their nodes instead.
+impl PartialEq for NetworkGraph {
+	fn eq(&self, other: &NetworkGraph) -> bool {
+		self.nodes == other.nodes && self.retain_announcements == other.retain_announcements &&
+			self.channels.len() == other.channels.len() &&
+			self.channels.iter().zip(other.channels.iter()).all(|((a_id, a), (b_id, b))| {
+				a_id == b_id && a.features == b.features &&
+					self.get_node_id(a.node_one) == other.get_node_id(b.node_one) && a.one_to_two == b.one_to_two &&
+					self.get_node_id(a.node_two) == other.get_node_id(b.node_two) && a.two_to_one == b.two_to_one &&
+					a.announcement_message == b.announcement_message && a.announcement_received_time == b.announcement_received_time
+			})
+	}
+}
+
 impl std::fmt::Display for NetworkGraph {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
 		write!(f, "Network map\n[Channels]\n")?;
@@ -482,7 +919,7 @@ impl std::fmt::Display for NetworkGraph {
 		}
 		write!(f, "[Nodes]\n")?;
 		for (key, val) in self.nodes.iter() {
-			write!(f, " {}: {}\n", log_pubkey!(key), val)?;
+			write!(f, " {}: {}\n", log_bytes!(key.as_slice()), val)?;
 		}
 		Ok(())
 	}
@@ -491,14 +928,37 @@ impl std::fmt::Display for NetworkGraph {
 impl NetworkGraph {
 	/// Returns all known valid channels' short ids along with announced channel info.
 	pub fn get_channels<'a>(&'a self) -> &'a BTreeMap<u64, ChannelInfo> { &self.channels }
-	/// Returns all known nodes' public keys along with announced node info.
-	pub fn get_nodes<'a>(&'a self) -> &'a BTreeMap<PublicKey, NodeInfo> { &self.nodes }
+	/// Returns all known nodes' ids along with announced node info.
+	pub fn get_nodes<'a>(&'a self) -> &'a BTreeMap<NodeId, NodeInfo> { &self.nodes }
+	/// Gets the id of one of a ChannelInfo's nodes. Panics if the ChannelInfo isn't from this
+	/// graph.
+	pub fn get_node_id<'a>(&'a self, node_idx: NodeIdx) -> &'a NodeId { self.node_table.get(node_idx) }
+
+	/// Sets whether we keep copies of the channel_announcements, channel_updates and
+	/// node_announcements we receive (true by default). Without them we can't serve the graph to
+	/// peers doing an initial routing sync, but they make up the bulk of the graph's memory use,
+	/// so clients which only use the graph to find routes should turn this off.
+	///
+	/// Turning it off drops any copies we already have.
+	pub fn set_retain_announcements(&mut self, retain_announcements: bool) {
+		self.retain_announcements = retain_announcements;
+		if !retain_announcements {
+			for chan in self.channels.values_mut() {
+				chan.announcement_message = None;
+				if let Some(ref mut one_to_two) = chan.one_to_two { one_to_two.last_update_message = None; }
+				if let Some(ref mut two_to_one) = chan.two_to_one { two_to_one.last_update_message = None; }
+			}
+			for node in self.nodes.values_mut() {
+				if let Some(ref mut node_info) = node.announcement_info { node_info.announcement_message = None; }
+			}
+		}
+	}
 
 	/// Get network addresses by node id.
 	/// Returns None if the requested node is completely unknown,
 	/// or if node announcement for the node was never received.
 	pub fn get_addresses<'a>(&'a self, pubkey: &PublicKey) -> Option<&'a Vec<NetAddress>> {
-		if let Some(node) = self.nodes.get(pubkey) {
+		if let Some(node) = self.nodes.get(&NodeId::from_pubkey(pubkey)) {
 			if let Some(node_info) = node.announcement_info.as_ref() {
 				return Some(&node_info.addresses)
 			}
@@ -514,7 +974,7 @@ impl NetworkGraph {
 			secp_verify_sig!(sig_verifier, &msg_hash, &msg.signature, &msg.contents.node_id);
 		}
 
-		match self.nodes.get_mut(&msg.contents.node_id) {
+		match self.nodes.get_mut(&NodeId::from_pubkey(&msg.contents.node_id)) {
 			None => Err(LightningError{err: "No existing channels for node_announcement", action: ErrorAction::IgnoreError}),
 			Some(node) => {
 				if let Some(node_info) = node.announcement_info.as_ref() {
@@ -530,7 +990,7 @@ impl NetworkGraph {
 					rgb: msg.contents.rgb,
 					alias: msg.contents.alias,
 					addresses: msg.contents.addresses.clone(),
-					announcement_message: if should_relay { Some(msg.clone()) } else { None },
+					announcement_message: if should_relay && self.retain_announcements { Some(Box::new(msg.clone())) } else { None },
 				});
 
 				Ok(should_relay)
@@ -544,28 +1004,16 @@ impl NetworkGraph {
 	/// which is probably result of a reorg. In that case, we update channel info only if the
 	/// utxo was checked, otherwise stick to the existing update, to prevent DoS risks.
 	/// Announcement signatures are checked here only if Secp256k1 object is provided.
-	fn update_channel_from_announcement(&mut self, msg: &msgs::ChannelAnnouncement, checked_utxo: bool, secp_ctx: Option<&Secp256k1<secp256k1::VerifyOnly>>) -> Result<bool, LightningError> {
+	fn update_channel_from_announcement(&mut self, msg: &msgs::ChannelAnnouncement, checked_utxo: bool, received_time_unix: u64, secp_ctx: Option<&Secp256k1<secp256k1::VerifyOnly>>) -> Result<bool, LightningError> {
 		if let Some(sig_verifier) = secp_ctx {
-			let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
-			secp_verify_sig!(sig_verifier, &msg_hash, &msg.node_signature_1, &msg.contents.node_id_1);
-			secp_verify_sig!(sig_verifier, &msg_hash, &msg.node_signature_2, &msg.contents.node_id_2);
-			secp_verify_sig!(sig_verifier, &msg_hash, &msg.bitcoin_signature_1, &msg.contents.bitcoin_key_1);
-			secp_verify_sig!(sig_verifier, &msg_hash, &msg.bitcoin_signature_2, &msg.contents.bitcoin_key_2);
+			Self::verify_channel_announcement(msg, sig_verifier)?;
 		}
 
 		let should_relay = msg.contents.excess_data.is_empty();
+		let short_channel_id = msg.contents.short_channel_id;
 
-		let chan_info = ChannelInfo {
-				features: msg.contents.features.clone(),
-				node_one: msg.contents.node_id_1.clone(),
-				one_to_two: None,
-				node_two: msg.contents.node_id_2.clone(),
-				two_to_one: None,
-				announcement_message: if should_relay { Some(msg.clone()) } else { None },
-			};
-
-		match self.channels.entry(msg.contents.short_channel_id) {
-			BtreeEntry::Occupied(mut entry) => {
+		match self.channels.entry(short_channel_id) {
+			BtreeEntry::Occupied(entry) => {
 				//TODO: because asking the blockchain if short_channel_id is valid is only optional
 				//in the blockchain API, we need to handle it smartly here, though it's unclear
 				//exactly how...
@@ -578,40 +1026,40 @@ impl NetworkGraph {
 					// b) we don't track UTXOs of channels we know about and remove them if they
 					//    get reorg'd out.
 					// c) it's unclear how to do so without exposing ourselves to massive DoS risk.
-					Self::remove_channel_in_nodes(&mut self.nodes, &entry.get(), msg.contents.short_channel_id);
-					*entry.get_mut() = chan_info;
+					Self::remove_channel_in_nodes(&mut self.nodes, &mut self.node_table, &entry.get(), short_channel_id);
+					entry.remove_entry();
 				} else {
 					return Err(LightningError{err: "Already have knowledge of channel", action: ErrorAction::IgnoreError})
 				}
 			},
-			BtreeEntry::Vacant(entry) => {
-				entry.insert(chan_info);
-			}
+			BtreeEntry::Vacant(_) => {},
 		};
 
-		macro_rules! add_channel_to_node {
-			( $node_id: expr ) => {
-				match self.nodes.entry($node_id) {
-					BtreeEntry::Occupied(node_entry) => {
-						node_entry.into_mut().channels.push(msg.contents.short_channel_id);
-					},
-					BtreeEntry::Vacant(node_entry) => {
-						node_entry.insert(NodeInfo {
-							channels: vec!(msg.contents.short_channel_id),
-							lowest_inbound_channel_fees: None,
-							announcement_info: None,
-						});
-					}
-				}
-			};
-		}
-
-		add_channel_to_node!(msg.contents.node_id_1);
-		add_channel_to_node!(msg.contents.node_id_2);
+		let node_one = Self::add_channel_to_node(&mut self.nodes, &mut self.node_table, NodeId::from_pubkey(&msg.contents.node_id_1), short_channel_id);
+		let node_two = Self::add_channel_to_node(&mut self.nodes, &mut self.node_table, NodeId::from_pubkey(&msg.contents.node_id_2), short_channel_id);
+		self.channels.insert(short_channel_id, ChannelInfo {
+			features: msg.contents.features.clone(),
+			node_one,
+			one_to_two: None,
+			node_two,
+			two_to_one: None,
+			announcement_message: if should_relay && self.retain_announcements { Some(Box::new(msg.clone())) } else { None },
+			announcement_received_time: received_time_unix,
+		});
 
 		Ok(should_relay)
 	}
 
+	/// Checks all four signatures on a channel announcement.
+	fn verify_channel_announcement(msg: &msgs::ChannelAnnouncement, secp_ctx: &Secp256k1<secp256k1::VerifyOnly>) -> Result<(), LightningError> {
+		let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
+		secp_verify_sig!(secp_ctx, &msg_hash, &msg.node_signature_1, &msg.contents.node_id_1);
+		secp_verify_sig!(secp_ctx, &msg_hash, &msg.node_signature_2, &msg.contents.node_id_2);
+		secp_verify_sig!(secp_ctx, &msg_hash, &msg.bitcoin_signature_1, &msg.contents.bitcoin_key_1);
+		secp_verify_sig!(secp_ctx, &msg_hash, &msg.bitcoin_signature_2, &msg.contents.bitcoin_key_2);
+		Ok(())
+	}
+
 	/// Close a channel if a corresponding HTLC fail was sent.
 	/// If permanent, removes a channel from the local storage.
 	/// May cause the removal of nodes too, if this was their last channel.
@@ -619,7 +1067,7 @@ impl NetworkGraph {
 	pub fn close_channel_from_update(&mut self, short_channel_id: &u64, is_permanent: &bool) {
 		if *is_permanent {
 			if let Some(chan) = self.channels.remove(short_channel_id) {
-				Self::remove_channel_in_nodes(&mut self.nodes, &chan, *short_channel_id);
+				Self::remove_channel_in_nodes(&mut self.nodes, &mut self.node_table, &chan, *short_channel_id);
 			}
 		} else {
 			if let Some(chan) = self.channels.get_mut(&short_channel_id) {
@@ -633,6 +1081,41 @@ impl NetworkGraph {
 		}
 	}
 
+	/// Removes channels which haven't been refreshed by a channel_update in each direction within
+	/// STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS of the given time (in seconds since the UNIX epoch).
+	/// A direction we never received an update for is considered refreshed when the channel was
+	/// announced, so that new channels aren't pruned before their updates propagate.
+	/// Nodes left without channels are removed too.
+	///
+	/// Usually called through NetGraphMsgHandler::timer_tick_occured, which also starts
+	/// rejecting updates too old to be kept.
+	pub fn remove_stale_channels(&mut self, current_time_unix: u64) -> PrunedGraphEntries {
+		let min_time_unix = current_time_unix.saturating_sub(STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS);
+		let mut pruned = PrunedGraphEntries { channels: Vec::new(), nodes: Vec::new() };
+		for (short_channel_id, chan) in self.channels.iter() {
+			let is_fresh = |direction: &Option<DirectionalChannelInfo>| {
+				match direction {
+					&Some(ref info) => info.last_update as u64 >= min_time_unix,
+					&None => chan.announcement_received_time >= min_time_unix,
+				}
+			};
+			if !is_fresh(&chan.one_to_two) || !is_fresh(&chan.two_to_one) {
+				pruned.channels.push(*short_channel_id);
+			}
+		}
+		for short_channel_id in pruned.channels.iter() {
+			let chan = self.channels.remove(short_channel_id).unwrap();
+			let node_ids = [*self.node_table.get(chan.node_one), *self.node_table.get(chan.node_two)];
+			Self::remove_channel_in_nodes(&mut self.nodes, &mut self.node_table, &chan, *short_channel_id);
+			for node_id in node_ids.iter() {
+				if !self.nodes.contains_key(node_id) && !pruned.nodes.contains(node_id) {
+					pruned.nodes.push(*node_id);
+				}
+			}
+		}
+		pruned
+	}
+
 	fn fail_node(&mut self, _node_id: &PublicKey, is_permanent: &bool) {
 		if *is_permanent {
 			// TODO: Wholly remove the node
@@ -644,9 +1127,10 @@ impl NetworkGraph {
 	/// For an already known (from announcement) channel, update info about one of the directions of a channel.
 	/// Announcement signatures are checked here only if Secp256k1 object is provided.
 	fn update_channel(&mut self, msg: &msgs::ChannelUpdate, secp_ctx: Option<&Secp256k1<secp256k1::VerifyOnly>>) -> Result<bool, LightningError> {
-		let dest_node_id;
+		let dest_node_idx;
 		let chan_enabled = msg.contents.flags & (1 << 1) != (1 << 1);
 		let chan_was_enabled;
+		let retain_announcements = self.retain_announcements;
 
 		match self.channels.get_mut(&msg.contents.short_channel_id) {
 			None => return Err(LightningError{err: "Couldn't find channel for update", action: ErrorAction::IgnoreError}),
@@ -662,8 +1146,8 @@ impl NetworkGraph {
 							chan_was_enabled = false;
 						}
 
-						let last_update_message = if msg.contents.excess_data.is_empty() {
-							Some(msg.clone())
+						let last_update_message = if msg.contents.excess_data.is_empty() && retain_announcements {
+							Some(Box::new(msg.clone()))
 						} else {
 							None
 						};
@@ -685,28 +1169,40 @@ impl NetworkGraph {
 
 				let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
 				if msg.contents.flags & 1 == 1 {
-					dest_node_id = channel.node_one.clone();
+					dest_node_idx = channel.node_one;
 					if let Some(sig_verifier) = secp_ctx {
-						secp_verify_sig!(sig_verifier, &msg_hash, &msg.signature, &channel.node_two);
+						secp_verify_sig!(sig_verifier, &msg_hash, &msg.signature, &Self::parse_node_id(self.node_table.get(channel.node_two))?);
 					}
 					maybe_update_channel_info!(channel.two_to_one, channel.node_two);
 				} else {
-					dest_node_id = channel.node_two.clone();
+					dest_node_idx = channel.node_two;
 					if let Some(sig_verifier) = secp_ctx {
-						secp_verify_sig!(sig_verifier, &msg_hash, &msg.signature, &channel.node_one);
+						secp_verify_sig!(sig_verifier, &msg_hash, &msg.signature, &Self::parse_node_id(self.node_table.get(channel.node_one))?);
 					}
 					maybe_update_channel_info!(channel.one_to_two, channel.node_one);
 				}
 			}
 		}
 
+		self.update_lowest_inbound_fees(dest_node_idx, chan_enabled, chan_was_enabled, RoutingFees {
+			base_msat: msg.contents.fee_base_msat,
+			proportional_millionths: msg.contents.fee_proportional_millionths,
+		});
+
+		Ok(msg.contents.excess_data.is_empty())
+	}
+
+	/// Updates the lowest fees of the enabled channels into the given node after one of them was
+	/// updated to the given fees, or was disabled.
+	fn update_lowest_inbound_fees(&mut self, dest_node_idx: NodeIdx, chan_enabled: bool, chan_was_enabled: bool, fees: RoutingFees) {
+		let dest_node_id = *self.node_table.get(dest_node_idx);
 		if chan_enabled {
 			let node = self.nodes.get_mut(&dest_node_id).unwrap();
-			let mut base_msat = msg.contents.fee_base_msat;
-			let mut proportional_millionths = msg.contents.fee_proportional_millionths;
-			if let Some(fees) = node.lowest_inbound_channel_fees {
-				base_msat = cmp::min(base_msat, fees.base_msat);
-				proportional_millionths = cmp::min(proportional_millionths, fees.proportional_millionths);
+			let mut base_msat = fees.base_msat;
+			let mut proportional_millionths = fees.proportional_millionths;
+			if let Some(lowest_fees) = node.lowest_inbound_channel_fees {
+				base_msat = cmp::min(base_msat, lowest_fees.base_msat);
+				proportional_millionths = cmp::min(proportional_millionths, lowest_fees.proportional_millionths);
 			}
 			node.lowest_inbound_channel_fees = Some(RoutingFees {
 				base_msat,
@@ -719,7 +1215,7 @@ impl NetworkGraph {
 			for chan_id in node.channels.iter() {
 				let chan = self.channels.get(chan_id).unwrap();
 				let chan_info_opt;
-				if chan.node_one == dest_node_id {
+				if chan.node_one == dest_node_idx {
 					chan_info_opt = chan.two_to_one.as_ref();
 				} else {
 					chan_info_opt = chan.one_to_two.as_ref();
@@ -736,19 +1232,116 @@ impl NetworkGraph {
 
 			node.lowest_inbound_channel_fees = lowest_inbound_channel_fees;
 		}
+	}
 
-		Ok(msg.contents.excess_data.is_empty())
+	/// Adds a channel from a snapshot (see the graph_snapshot module), unless we already know of
+	/// it. As snapshots come from a source we trust, a known channel with different nodes is
+	/// replaced.
+	pub(crate) fn add_channel_from_snapshot(&mut self, short_channel_id: u64, features: ChannelFeatures, node_one: NodeId, node_two: NodeId, received_time_unix: u64) {
+		if let BtreeEntry::Occupied(entry) = self.channels.entry(short_channel_id) {
+			if *self.node_table.get(entry.get().node_one) == node_one && *self.node_table.get(entry.get().node_two) == node_two {
+				return;
+			}
+			Self::remove_channel_in_nodes(&mut self.nodes, &mut self.node_table, &entry.get(), short_channel_id);
+			entry.remove_entry();
+		}
+		let node_one = Self::add_channel_to_node(&mut self.nodes, &mut self.node_table, node_one, short_channel_id);
+		let node_two = Self::add_channel_to_node(&mut self.nodes, &mut self.node_table, node_two, short_channel_id);
+		self.channels.insert(short_channel_id, ChannelInfo {
+			features,
+			node_one,
+			one_to_two: None,
+			node_two,
+			two_to_one: None,
+			announcement_message: None,
+			announcement_received_time: received_time_unix,
+		});
+	}
+
+	/// Updates one direction of a known channel from a snapshot, if the snapshot's information is
+	/// newer than ours.
+	pub(crate) fn update_channel_from_snapshot(&mut self, short_channel_id: u64, is_two_to_one: bool, info: DirectionalChannelInfo) {
+		let dest_node_idx;
+		let chan_enabled = info.enabled;
+		let chan_was_enabled;
+		let fees = info.fees;
+		match self.channels.get_mut(&short_channel_id) {
+			None => return,
+			Some(channel) => {
+				let (target, dest) = if is_two_to_one {
+					(&mut channel.two_to_one, channel.node_one)
+				} else {
+					(&mut channel.one_to_two, channel.node_two)
+				};
+				if let Some(ref existing) = *target {
+					if existing.last_update >= info.last_update { return; }
+				}
+				chan_was_enabled = target.as_ref().map(|existing| existing.enabled).unwrap_or(false);
+				*target = Some(info);
+				dest_node_idx = dest;
+			}
+		}
+		self.update_lowest_inbound_fees(dest_node_idx, chan_enabled, chan_was_enabled, fees);
+	}
+
+	/// Updates a known node's features from a snapshot, if the snapshot's information is newer
+	/// than ours. As snapshots don't carry aliases, addresses or the node_announcement itself,
+	/// our announcement info is replaced entirely (with those left empty) rather than leaving
+	/// them describing an older announcement.
+	pub(crate) fn update_node_from_snapshot(&mut self, node_id: &NodeId, last_update: u32, features: NodeFeatures) {
+		if let Some(node) = self.nodes.get_mut(node_id) {
+			if let Some(ref node_info) = node.announcement_info {
+				if node_info.last_update >= last_update { return; }
+			}
+			node.announcement_info = Some(NodeAnnouncementInfo {
+				features,
+				last_update,
+				rgb: [0; 3],
+				alias: [0; 32],
+				addresses: Vec::new(),
+				announcement_message: None,
+			});
+		}
+	}
+
+	/// Parses a node id from the graph for checking a signature. We only add ids which came from a
+	/// valid public key, so this should never fail.
+	fn parse_node_id(node_id: &NodeId) -> Result<PublicKey, LightningError> {
+		node_id.as_pubkey().map_err(|_| LightningError{err: "Invalid node id in the network graph", action: ErrorAction::IgnoreError})
 	}
 
-	fn remove_channel_in_nodes(nodes: &mut BTreeMap<PublicKey, NodeInfo>, chan: &ChannelInfo, short_channel_id: u64) {
+	/// Adds a channel to the given node, adding the node to the graph if we don't know of it yet,
+	/// and returns the node's index.
+	fn add_channel_to_node(nodes: &mut BTreeMap<NodeId, NodeInfo>, node_table: &mut NodeTable, node_id: NodeId, short_channel_id: u64) -> NodeIdx {
+		match nodes.entry(node_id) {
+			BtreeEntry::Occupied(node_entry) => {
+				let node = node_entry.into_mut();
+				node.channels.push(short_channel_id);
+				node.idx
+			},
+			BtreeEntry::Vacant(node_entry) => {
+				let idx = node_table.insert(node_id);
+				node_entry.insert(NodeInfo {
+					idx,
+					channels: vec!(short_channel_id),
+					lowest_inbound_channel_fees: None,
+					announcement_info: None,
+				});
+				idx
+			}
+		}
+	}
+
+	fn remove_channel_in_nodes(nodes: &mut BTreeMap<NodeId, NodeInfo>, node_table: &mut NodeTable, chan: &ChannelInfo, short_channel_id: u64) {
 		macro_rules! remove_from_node {
-			($node_id: expr) => {
-				if let BtreeEntry::Occupied(mut entry) = nodes.entry($node_id) {
+			($node_idx: expr) => {
+				if let BtreeEntry::Occupied(mut entry) = nodes.entry(*node_table.get($node_idx)) {
 					entry.get_mut().channels.retain(|chan_id| {
 						short_channel_id != *chan_id
 					});
 					if entry.get().channels.is_empty() {
 						entry.remove_entry();
+						node_table.remove($node_idx);
 					}
 				} else {
 					panic!("Had channel that pointed to unknown node (ie inconsistent network map)!");
@@ -764,8 +1357,10 @@ impl NetworkGraph {
 #[cfg(test)]
 mod tests {
 	use chain::chaininterface;
+	use chain::chaininterface::{ChainError, ChainListener};
+	use chain::transaction::OutPoint;
 	use ln::features::{ChannelFeatures, NodeFeatures};
-	use routing::network_graph::{NetGraphMsgHandler, NetworkGraph};
+	use routing::network_graph::{NetGraphMsgHandler, NetworkGraph, NodeId, time_now_unix};
 	use ln::msgs::{RoutingMessageHandler, UnsignedNodeAnnouncement, NodeAnnouncement,
 		UnsignedChannelAnnouncement, ChannelAnnouncement, UnsignedChannelUpdate, ChannelUpdate, HTLCFailChannelUpdate};
 	use util::test_utils;
@@ -776,7 +1371,9 @@ mod tests {
 	use bitcoin::hashes::Hash;
 	use bitcoin::network::constants::Network;
 	use bitcoin::blockdata::constants::genesis_block;
-	use bitcoin::blockdata::script::Builder;
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+	use bitcoin::hash_types::Txid;
 	use bitcoin::blockdata::opcodes;
 	use bitcoin::util::hash::BitcoinHash;
 
@@ -786,6 +1383,7 @@ mod tests {
 	use bitcoin::secp256k1::{All, Secp256k1};
 
 	use std::sync::Arc;
+	use std::time::{SystemTime, UNIX_EPOCH};
 
 	fn create_net_graph_msg_handler() -> (Secp256k1<All>, NetGraphMsgHandler<Arc<chaininterface::ChainWatchInterfaceUtil>, Arc<test_utils::TestLogger>>) {
 		let secp_ctx = Secp256k1::new();
@@ -1100,6 +1698,132 @@ mod tests {
 		};
 	}
 
+	#[test]
+	fn handling_async_utxo_lookups() {
+		let secp_ctx = Secp256k1::new();
+		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
+		let chain_monitor = Arc::new(test_utils::TestChainWatcher::new());
+		let utxo_lookup = Arc::new(test_utils::TestUtxoLookup::new());
+		let mut net_graph_msg_handler = NetGraphMsgHandler::new(chain_monitor.clone(), Arc::clone(&logger));
+		net_graph_msg_handler.set_utxo_lookup(utxo_lookup.clone());
+
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_2_privkey = &SecretKey::from_slice(&[41; 32]).unwrap();
+		let node_1_btckey = &SecretKey::from_slice(&[40; 32]).unwrap();
+		let node_2_btckey = &SecretKey::from_slice(&[39; 32]).unwrap();
+		let good_script = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+		   .push_slice(&PublicKey::from_secret_key(&secp_ctx, node_1_btckey).serialize())
+		   .push_slice(&PublicKey::from_secret_key(&secp_ctx, node_2_btckey).serialize())
+		   .push_opcode(opcodes::all::OP_PUSHNUM_2)
+		   .push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh();
+
+		macro_rules! channel_announcement {
+			($short_channel_id: expr) => {
+				{
+					let unsigned_announcement = UnsignedChannelAnnouncement {
+						features: ChannelFeatures::known(),
+						chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
+						short_channel_id: $short_channel_id,
+						node_id_1: PublicKey::from_secret_key(&secp_ctx, node_1_privkey),
+						node_id_2: PublicKey::from_secret_key(&secp_ctx, node_2_privkey),
+						bitcoin_key_1: PublicKey::from_secret_key(&secp_ctx, node_1_btckey),
+						bitcoin_key_2: PublicKey::from_secret_key(&secp_ctx, node_2_btckey),
+						excess_data: Vec::new(),
+					};
+					let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+					ChannelAnnouncement {
+						node_signature_1: secp_ctx.sign(&msghash, node_1_privkey),
+						node_signature_2: secp_ctx.sign(&msghash, node_2_privkey),
+						bitcoin_signature_1: secp_ctx.sign(&msghash, node_1_btckey),
+						bitcoin_signature_2: secp_ctx.sign(&msghash, node_2_btckey),
+						contents: unsigned_announcement,
+					}
+				}
+			}
+		}
+
+		// The announcement is held until the lookup completes, and not looked up twice.
+		assert_eq!(net_graph_msg_handler.handle_channel_announcement(&channel_announcement!(1)).unwrap(), false);
+		assert!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().is_empty());
+		match net_graph_msg_handler.handle_channel_announcement(&channel_announcement!(1)) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Channel announcement is already pending UTXO lookup")
+		};
+		assert_eq!(*utxo_lookup.lookups.lock().unwrap(), vec![1]);
+
+		// Announcements with bad signatures never get looked up.
+		let mut invalid_sig_announcement = channel_announcement!(2);
+		invalid_sig_announcement.contents.excess_data.push(1);
+		match net_graph_msg_handler.handle_channel_announcement(&invalid_sig_announcement) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Invalid signature from remote node")
+		};
+		assert_eq!(*utxo_lookup.lookups.lock().unwrap(), vec![1]);
+
+		let funding_outpoint = OutPoint::new(Txid::from_slice(&[1; 32]).unwrap(), 1);
+		assert_eq!(net_graph_msg_handler.utxo_lookup_completed(1, Ok((funding_outpoint, TxOut { value: 0, script_pubkey: good_script.clone() }))).unwrap(), true);
+		assert!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().contains_key(&1));
+		assert_eq!(*chain_monitor.watched_outpoints.lock().unwrap(), vec![(funding_outpoint.txid, 1)]);
+		match net_graph_msg_handler.utxo_lookup_completed(1, Err(ChainError::UnknownTx)) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "No channel announcement pending UTXO lookup")
+		};
+
+		// Announcements for channels we already know aren't looked up again.
+		match net_graph_msg_handler.handle_channel_announcement(&channel_announcement!(1)) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Already have knowledge of channel")
+		};
+		assert_eq!(*utxo_lookup.lookups.lock().unwrap(), vec![1]);
+
+		// Unless the block it was funded in is disconnected, in which case the channel is replaced
+		// if its funding output is found again, once.
+		net_graph_msg_handler.block_disconnected(&genesis_block(Network::Testnet).header, 0);
+		*utxo_lookup.utxo_ret.lock().unwrap() = Some(Ok((funding_outpoint, TxOut { value: 0, script_pubkey: good_script.clone() })));
+		assert_eq!(net_graph_msg_handler.handle_channel_announcement(&channel_announcement!(1)).unwrap(), true);
+		assert_eq!(*utxo_lookup.lookups.lock().unwrap(), vec![1, 1]);
+		assert!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().contains_key(&1));
+		match net_graph_msg_handler.handle_channel_announcement(&channel_announcement!(1)) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Already have knowledge of channel")
+		};
+		assert_eq!(*utxo_lookup.lookups.lock().unwrap(), vec![1, 1]);
+		*utxo_lookup.utxo_ret.lock().unwrap() = None;
+
+		// A lookup which finds the wrong script is rejected once it completes.
+		assert_eq!(net_graph_msg_handler.handle_channel_announcement(&channel_announcement!(3)).unwrap(), false);
+		match net_graph_msg_handler.utxo_lookup_completed(3, Ok((OutPoint::new(Txid::from_slice(&[3; 32]).unwrap(), 0), TxOut { value: 0, script_pubkey: Script::new() }))) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Channel announcement keys didn't match on-chain script")
+		};
+		assert!(!net_graph_msg_handler.network_graph.read().unwrap().get_channels().contains_key(&3));
+
+		// As is one whose lookup completes synchronously.
+		*utxo_lookup.utxo_ret.lock().unwrap() = Some(Err(ChainError::UnknownTx));
+		match net_graph_msg_handler.handle_channel_announcement(&channel_announcement!(4)) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Channel announced without corresponding UTXO entry")
+		};
+		assert!(net_graph_msg_handler.pending_utxo_lookups.lock().unwrap().is_empty());
+
+		// Once the funding output is spent the channel (and its nodes) are removed.
+		let spending_tx = Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: funding_outpoint.into_bitcoin_outpoint(),
+				script_sig: Script::new(),
+				sequence: 0xffffffff,
+				witness: Vec::new(),
+			}],
+			output: Vec::new(),
+		};
+		net_graph_msg_handler.block_connected(&genesis_block(Network::Testnet).header, 1, &[&spending_tx], &[1]);
+		let network = net_graph_msg_handler.network_graph.read().unwrap();
+		assert!(network.get_channels().is_empty());
+		assert!(network.get_nodes().is_empty());
+	}
+
 	#[test]
 	fn handling_channel_update() {
 		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
@@ -1144,7 +1868,7 @@ mod tests {
 		let mut unsigned_channel_update = UnsignedChannelUpdate {
 			chain_hash,
 			short_channel_id,
-			timestamp: 100,
+			timestamp: time_now_unix() as u32,
 			flags: 0,
 			cltv_expiry_delta: 144,
 			htlc_minimum_msat: 1000000,
@@ -1277,7 +2001,7 @@ mod tests {
 			let unsigned_channel_update = UnsignedChannelUpdate {
 				chain_hash,
 				short_channel_id,
-				timestamp: 100,
+				timestamp: time_now_unix() as u32,
 				flags: 0,
 				cltv_expiry_delta: 144,
 				htlc_minimum_msat: 1000000,
@@ -1404,7 +2128,7 @@ mod tests {
 			let unsigned_channel_update = UnsignedChannelUpdate {
 				chain_hash,
 				short_channel_id,
-				timestamp: 101,
+				timestamp: time_now_unix() as u32,
 				flags: 0,
 				cltv_expiry_delta: 144,
 				htlc_minimum_msat: 1000000,
@@ -1440,7 +2164,7 @@ mod tests {
 			let unsigned_channel_update = UnsignedChannelUpdate {
 				chain_hash,
 				short_channel_id,
-				timestamp: 102,
+				timestamp: time_now_unix() as u32 + 1,
 				flags: 0,
 				cltv_expiry_delta: 144,
 				htlc_minimum_msat: 1000000,
@@ -1592,6 +2316,264 @@ mod tests {
 		assert_eq!(next_announcements.len(), 0);
 	}
 
+	#[test]
+	fn pruning_stale_channels() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let privkeys: Vec<SecretKey> = (1..5).map(|i| SecretKey::from_slice(&[42 - i; 32]).unwrap()).collect();
+		let node_ids: Vec<PublicKey> = privkeys.iter().map(|k| PublicKey::from_secret_key(&secp_ctx, k)).collect();
+		let graph_node_ids: Vec<NodeId> = node_ids.iter().map(|id| NodeId::from_pubkey(id)).collect();
+		let chain_hash = genesis_block(Network::Testnet).header.bitcoin_hash();
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		let five_days = 60 * 60 * 24 * 5;
+		let ten_days = 60 * 60 * 24 * 10;
+		let three_weeks = 60 * 60 * 24 * 21;
+
+		macro_rules! announce_channel {
+			($short_channel_id: expr, $node_1: expr, $node_2: expr) => {
+				let btckey_1 = SecretKey::from_slice(&[10 + $node_1 as u8; 32]).unwrap();
+				let btckey_2 = SecretKey::from_slice(&[10 + $node_2 as u8; 32]).unwrap();
+				let unsigned_announcement = UnsignedChannelAnnouncement {
+					features: ChannelFeatures::empty(),
+					chain_hash,
+					short_channel_id: $short_channel_id,
+					node_id_1: node_ids[$node_1],
+					node_id_2: node_ids[$node_2],
+					bitcoin_key_1: PublicKey::from_secret_key(&secp_ctx, &btckey_1),
+					bitcoin_key_2: PublicKey::from_secret_key(&secp_ctx, &btckey_2),
+					excess_data: Vec::new(),
+				};
+				let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+				let channel_announcement = ChannelAnnouncement {
+					node_signature_1: secp_ctx.sign(&msghash, &privkeys[$node_1]),
+					node_signature_2: secp_ctx.sign(&msghash, &privkeys[$node_2]),
+					bitcoin_signature_1: secp_ctx.sign(&msghash, &btckey_1),
+					bitcoin_signature_2: secp_ctx.sign(&msghash, &btckey_2),
+					contents: unsigned_announcement,
+				};
+				net_graph_msg_handler.handle_channel_announcement(&channel_announcement).unwrap();
+			}
+		}
+
+		macro_rules! channel_update {
+			($short_channel_id: expr, $flags: expr, $signer: expr, $timestamp: expr) => {
+				{
+					let unsigned_channel_update = UnsignedChannelUpdate {
+						chain_hash,
+						short_channel_id: $short_channel_id,
+						timestamp: $timestamp as u32,
+						flags: $flags,
+						cltv_expiry_delta: 144,
+						htlc_minimum_msat: 1000000,
+						fee_base_msat: 10000,
+						fee_proportional_millionths: 20,
+						excess_data: Vec::new()
+					};
+					let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_channel_update.encode()[..])[..]);
+					net_graph_msg_handler.handle_channel_update(&ChannelUpdate {
+						signature: secp_ctx.sign(&msghash, &privkeys[$signer]),
+						contents: unsigned_channel_update
+					})
+				}
+			}
+		}
+
+		// Channel 1 is refreshed in both directions, channel 2 was last updated ten days ago in
+		// one direction and channel 3 has just been announced and has no updates yet.
+		announce_channel!(1, 0, 1);
+		announce_channel!(2, 0, 2);
+		announce_channel!(3, 1, 3);
+		channel_update!(1, 0, 0, now).unwrap();
+		channel_update!(1, 1, 1, now).unwrap();
+		channel_update!(2, 0, 0, now - ten_days).unwrap();
+
+		// Updates which are already stale are rejected even before we've pruned anything.
+		match channel_update!(1, 0, 0, now - three_weeks) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "channel_update is older than two weeks")
+		};
+
+		// Five days on, channel 2's update is more than two weeks old.
+		let pruned = net_graph_msg_handler.remove_stale_channels(now + five_days);
+		assert_eq!(pruned.channels, vec![2]);
+		assert_eq!(pruned.nodes, vec![graph_node_ids[2]]);
+		{
+			let network = net_graph_msg_handler.network_graph.read().unwrap();
+			assert_eq!(network.get_channels().keys().cloned().collect::<Vec<_>>(), vec![1, 3]);
+			assert_eq!(network.get_nodes().len(), 3);
+			assert!(!network.get_nodes().contains_key(&graph_node_ids[2]));
+			assert_eq!(network.get_nodes().get(&graph_node_ids[0]).unwrap().channels, vec![1]);
+		}
+
+		// Updates which would be pruned right away are now rejected, fresh ones still go through.
+		announce_channel!(2, 0, 2);
+		match channel_update!(2, 0, 0, now - ten_days) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "channel_update is older than two weeks")
+		};
+		channel_update!(2, 0, 0, now).unwrap();
+
+		// Three weeks on, without any more updates, everything is stale.
+		let pruned = net_graph_msg_handler.remove_stale_channels(now + three_weeks);
+		assert_eq!(pruned.channels, vec![1, 2, 3]);
+		assert_eq!(pruned.nodes.len(), 4);
+		for node_id in graph_node_ids.iter() {
+			assert!(pruned.nodes.contains(node_id));
+		}
+		let network = net_graph_msg_handler.network_graph.read().unwrap();
+		assert!(network.get_channels().is_empty());
+		assert!(network.get_nodes().is_empty());
+	}
+
+	#[test]
+	fn reusing_removed_node_indices() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let privkeys: Vec<SecretKey> = (1..5).map(|i| SecretKey::from_slice(&[42 - i; 32]).unwrap()).collect();
+		let node_ids: Vec<PublicKey> = privkeys.iter().map(|k| PublicKey::from_secret_key(&secp_ctx, k)).collect();
+		let graph_node_ids: Vec<NodeId> = node_ids.iter().map(|id| NodeId::from_pubkey(id)).collect();
+		let chain_hash = genesis_block(Network::Testnet).header.bitcoin_hash();
+
+		macro_rules! announce_channel {
+			($short_channel_id: expr, $node_1: expr, $node_2: expr) => {
+				let btckey_1 = SecretKey::from_slice(&[10 + $node_1 as u8; 32]).unwrap();
+				let btckey_2 = SecretKey::from_slice(&[10 + $node_2 as u8; 32]).unwrap();
+				let unsigned_announcement = UnsignedChannelAnnouncement {
+					features: ChannelFeatures::empty(),
+					chain_hash,
+					short_channel_id: $short_channel_id,
+					node_id_1: node_ids[$node_1],
+					node_id_2: node_ids[$node_2],
+					bitcoin_key_1: PublicKey::from_secret_key(&secp_ctx, &btckey_1),
+					bitcoin_key_2: PublicKey::from_secret_key(&secp_ctx, &btckey_2),
+					excess_data: Vec::new(),
+				};
+				let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+				net_graph_msg_handler.handle_channel_announcement(&ChannelAnnouncement {
+					node_signature_1: secp_ctx.sign(&msghash, &privkeys[$node_1]),
+					node_signature_2: secp_ctx.sign(&msghash, &privkeys[$node_2]),
+					bitcoin_signature_1: secp_ctx.sign(&msghash, &btckey_1),
+					bitcoin_signature_2: secp_ctx.sign(&msghash, &btckey_2),
+					contents: unsigned_announcement,
+				}).unwrap();
+			}
+		}
+
+		announce_channel!(1, 0, 1);
+		announce_channel!(2, 1, 2);
+		let removed_idx = net_graph_msg_handler.network_graph.read().unwrap().get_nodes().get(&graph_node_ids[2]).unwrap().idx;
+
+		// Closing channel 2 removes node 2, whose index is then given to the next new node.
+		net_graph_msg_handler.network_graph.write().unwrap().close_channel_from_update(&2, &true);
+		announce_channel!(3, 0, 3);
+
+		let network = net_graph_msg_handler.network_graph.read().unwrap();
+		assert!(network.get_nodes().get(&graph_node_ids[2]).is_none());
+		assert_eq!(network.get_nodes().get(&graph_node_ids[3]).unwrap().idx, removed_idx);
+		let chan_1 = network.get_channels().get(&1).unwrap();
+		assert_eq!((network.get_node_id(chan_1.node_one), network.get_node_id(chan_1.node_two)), (&graph_node_ids[0], &graph_node_ids[1]));
+		let chan_3 = network.get_channels().get(&3).unwrap();
+		assert_eq!(chan_3.node_two, removed_idx);
+		assert_eq!((network.get_node_id(chan_3.node_one), network.get_node_id(chan_3.node_two)), (&graph_node_ids[0], &graph_node_ids[3]));
+
+		// The graph is written with full node ids, so it reads back the same despite the node
+		// indices being assigned in a different order.
+		let mut w = test_utils::TestVecWriter(Vec::new());
+		network.write(&mut w).unwrap();
+		assert!(<NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0)).unwrap() == *network);
+	}
+
+	#[test]
+	fn dropping_announcement_messages() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_2_privkey = &SecretKey::from_slice(&[41; 32]).unwrap();
+		let node_1_btckey = &SecretKey::from_slice(&[40; 32]).unwrap();
+		let node_2_btckey = &SecretKey::from_slice(&[39; 32]).unwrap();
+		let node_id_1 = PublicKey::from_secret_key(&secp_ctx, node_1_privkey);
+		let chain_hash = genesis_block(Network::Testnet).header.bitcoin_hash();
+
+		let unsigned_announcement = UnsignedChannelAnnouncement {
+			features: ChannelFeatures::empty(),
+			chain_hash,
+			short_channel_id: 1,
+			node_id_1,
+			node_id_2: PublicKey::from_secret_key(&secp_ctx, node_2_privkey),
+			bitcoin_key_1: PublicKey::from_secret_key(&secp_ctx, node_1_btckey),
+			bitcoin_key_2: PublicKey::from_secret_key(&secp_ctx, node_2_btckey),
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+		net_graph_msg_handler.handle_channel_announcement(&ChannelAnnouncement {
+			node_signature_1: secp_ctx.sign(&msghash, node_1_privkey),
+			node_signature_2: secp_ctx.sign(&msghash, node_2_privkey),
+			bitcoin_signature_1: secp_ctx.sign(&msghash, node_1_btckey),
+			bitcoin_signature_2: secp_ctx.sign(&msghash, node_2_btckey),
+			contents: unsigned_announcement,
+		}).unwrap();
+
+		let now = time_now_unix() as u32;
+		macro_rules! channel_update {
+			($timestamp: expr) => {
+				let unsigned_channel_update = UnsignedChannelUpdate {
+					chain_hash,
+					short_channel_id: 1,
+					timestamp: $timestamp,
+					flags: 0,
+					cltv_expiry_delta: 144,
+					htlc_minimum_msat: 1000000,
+					fee_base_msat: $timestamp,
+					fee_proportional_millionths: 20,
+					excess_data: Vec::new()
+				};
+				let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_channel_update.encode()[..])[..]);
+				net_graph_msg_handler.handle_channel_update(&ChannelUpdate {
+					signature: secp_ctx.sign(&msghash, node_1_privkey),
+					contents: unsigned_channel_update
+				}).unwrap();
+			}
+		}
+		channel_update!(now);
+
+		let unsigned_node_announcement = UnsignedNodeAnnouncement {
+			features: NodeFeatures::known(),
+			timestamp: 100,
+			node_id: node_id_1,
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: Vec::new(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_node_announcement.encode()[..])[..]);
+		net_graph_msg_handler.handle_node_announcement(&NodeAnnouncement {
+			signature: secp_ctx.sign(&msghash, node_1_privkey),
+			contents: unsigned_node_announcement
+		}).unwrap();
+
+		// By default we keep the messages around to serve them to our peers.
+		let channels_with_announcements = net_graph_msg_handler.get_next_channel_announcements(0, 10);
+		assert_eq!(channels_with_announcements.len(), 1);
+		assert!(channels_with_announcements[0].1.is_some());
+		assert_eq!(net_graph_msg_handler.get_next_node_announcements(None, 10).len(), 1);
+
+		// Once we stop retaining them, the ones we have are dropped but the graph is unchanged.
+		net_graph_msg_handler.network_graph.write().unwrap().set_retain_announcements(false);
+		assert!(net_graph_msg_handler.get_next_channel_announcements(0, 10).is_empty());
+		assert!(net_graph_msg_handler.get_next_node_announcements(None, 10).is_empty());
+
+		// New updates are still applied, just not stored.
+		channel_update!(now + 1);
+		assert!(net_graph_msg_handler.get_next_channel_announcements(0, 10).is_empty());
+		{
+			let network = net_graph_msg_handler.network_graph.read().unwrap();
+			let one_to_two = network.get_channels().get(&1).unwrap().one_to_two.as_ref().unwrap();
+			assert_eq!(one_to_two.last_update, now + 1);
+			assert!(one_to_two.last_update_message.is_none());
+			let node_id_1 = NodeId::from_pubkey(&node_id_1);
+			assert_eq!(network.get_nodes().get(&node_id_1).unwrap().announcement_info.as_ref().unwrap().last_update, 100);
+			assert_eq!(node_id_1.as_pubkey().unwrap(), PublicKey::from_secret_key(&secp_ctx, node_1_privkey));
+		}
+	}
+
 	#[test]
 	fn network_graph_serialization() {
 		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
@@ -1657,5 +2639,16 @@ mod tests {
 		assert!(!network.get_channels().is_empty());
 		network.write(&mut w).unwrap();
 		assert!(<NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0)).unwrap() == *network);
+
+		// Graphs written before announcement_received_time was tracked lack the trailing
+		// section, and get the current time instead.
+		let before_read = time_now_unix();
+		let legacy_len = w.0.len() - 8 - 8 * network.get_channels().len();
+		let legacy = <NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0[..legacy_len])).unwrap();
+		assert_eq!(legacy.get_channels().len(), network.get_channels().len());
+		assert!(legacy.get_channels().values().all(|chan| chan.announcement_received_time >= before_read));
+		assert!(legacy.get_nodes() == network.get_nodes());
+		// A truncated trailing section is still an error
+		assert!(<NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0[..w.0.len() - 1])).is_err());
 	}
 }
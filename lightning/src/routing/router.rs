@@ -9,7 +9,7 @@ use chain::chaininterface::ChainWatchInterface;
 use ln::channelmanager;
 use ln::features::{ChannelFeatures, NodeFeatures};
 use ln::msgs::{DecodeError,ErrorAction,LightningError};
-use routing::network_graph::{NetGraphMsgHandler, RoutingFees};
+use routing::network_graph::{NetGraphMsgHandler, NodeId, RoutingFees};
 use util::ser::{Writeable, Readable};
 use util::logger::Logger;
 
@@ -120,7 +120,7 @@ pub struct RouteHint {
 
 #[derive(Eq, PartialEq)]
 struct RouteGraphNode {
-	pubkey: PublicKey,
+	node_id: NodeId,
 	lowest_fee_to_peer_through_node: u64,
 	lowest_fee_to_node: u64,
 }
@@ -128,7 +128,7 @@ struct RouteGraphNode {
 impl cmp::Ord for RouteGraphNode {
 	fn cmp(&self, other: &RouteGraphNode) -> cmp::Ordering {
 		other.lowest_fee_to_peer_through_node.cmp(&self.lowest_fee_to_peer_through_node)
-			.then_with(|| other.pubkey.serialize().cmp(&self.pubkey.serialize()))
+			.then_with(|| other.node_id.cmp(&self.node_id))
 	}
 }
 
@@ -144,6 +144,17 @@ struct DummyDirectionalChannelInfo {
 	fees: RoutingFees,
 }
 
+/// A RouteHop while we're still looking for a route, referring to its node by the id we have in
+/// the network graph so that we only parse the public keys of the nodes in the final route.
+struct PathBuildingHop {
+	node_id: NodeId,
+	node_features: NodeFeatures,
+	short_channel_id: u64,
+	channel_features: ChannelFeatures,
+	fee_msat: u64,
+	cltv_expiry_delta: u32,
+}
+
 
 /// Gets a route from us (as specified in the provided NetGraphMsgHandler) to the given target node.
 ///
@@ -188,6 +199,10 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 		}
 	};
 
+	let our_node_id = NodeId::from_pubkey(our_node_id);
+	let target_node_id = NodeId::from_pubkey(target);
+	let last_hop_src_node_ids: Vec<NodeId> = last_hops.iter().map(|hop| NodeId::from_pubkey(&hop.src_node_id)).collect();
+
 	let network = net_graph_msg_handler.network_graph.read().unwrap();
 	let mut targets = BinaryHeap::new(); //TODO: Do we care about switching to eg Fibbonaci heap?
 	let mut dist = HashMap::with_capacity(network.get_nodes().len());
@@ -208,7 +223,7 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 					}]],
 				});
 			}
-			first_hop_targets.insert(chan.remote_network_id, (short_channel_id, chan.counterparty_features.clone()));
+			first_hop_targets.insert(NodeId::from_pubkey(&chan.remote_network_id), (short_channel_id, chan.counterparty_features.clone()));
 		}
 		if first_hop_targets.is_empty() {
 			return Err(LightningError{err: "Cannot route when there are no outbound routes away from us", action: ErrorAction::IgnoreError});
@@ -239,8 +254,8 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 						(u64::max_value(),
 							fee_base_msat,
 							fee_proportional_millionths,
-							RouteHop {
-								pubkey: $dest_node_id.clone(),
+							PathBuildingHop {
+								node_id: $dest_node_id.clone(),
 								node_features: NodeFeatures::empty(),
 								short_channel_id: 0,
 								channel_features: $chan_features.clone(),
@@ -248,7 +263,7 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 								cltv_expiry_delta: 0,
 						})
 					});
-					if $src_node_id != *our_node_id {
+					if $src_node_id != our_node_id {
 						// Ignore new_fee for channel-from-us as we assume all channels-from-us
 						// will have the same effective-fee
 						total_fee += new_fee;
@@ -260,15 +275,15 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 						}
 					}
 					let new_graph_node = RouteGraphNode {
-						pubkey: $src_node_id,
+						node_id: $src_node_id,
 						lowest_fee_to_peer_through_node: total_fee,
 						lowest_fee_to_node: $starting_fee_msat as u64 + new_fee,
 					};
 					if old_entry.0 > total_fee {
 						targets.push(new_graph_node);
 						old_entry.0 = total_fee;
-						old_entry.3 = RouteHop {
-							pubkey: $dest_node_id.clone(),
+						old_entry.3 = PathBuildingHop {
+							node_id: $dest_node_id.clone(),
 							node_features: NodeFeatures::empty(),
 							short_channel_id: $chan_id.clone(),
 							channel_features: $chan_features.clone(),
@@ -285,7 +300,7 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 		( $node: expr, $node_id: expr, $fee_to_target_msat: expr ) => {
 			if first_hops.is_some() {
 				if let Some(&(ref first_hop, ref features)) = first_hop_targets.get(&$node_id) {
-					add_entry!(first_hop, *our_node_id, $node_id, dummy_directional_info, features.to_context(), $fee_to_target_msat);
+					add_entry!(first_hop, our_node_id, $node_id, dummy_directional_info, features.to_context(), $fee_to_target_msat);
 				}
 			}
 
@@ -300,20 +315,20 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 				for chan_id in $node.channels.iter() {
 					let chan = network.get_channels().get(chan_id).unwrap();
 					if !chan.features.requires_unknown_bits() {
-						if chan.node_one == *$node_id {
+						if chan.node_one == $node.idx {
 							// ie $node is one, ie next hop in A* is two, via the two_to_one channel
-							if first_hops.is_none() || chan.node_two != *our_node_id {
+							if first_hops.is_none() || *network.get_node_id(chan.node_two) != our_node_id {
 								if let Some(two_to_one) = chan.two_to_one.as_ref() {
 									if two_to_one.enabled {
-										add_entry!(chan_id, chan.node_two, chan.node_one, two_to_one, chan.features, $fee_to_target_msat);
+										add_entry!(chan_id, *network.get_node_id(chan.node_two), *$node_id, two_to_one, chan.features, $fee_to_target_msat);
 									}
 								}
 							}
 						} else {
-							if first_hops.is_none() || chan.node_one != *our_node_id {
+							if first_hops.is_none() || *network.get_node_id(chan.node_one) != our_node_id {
 								if let Some(one_to_two) = chan.one_to_two.as_ref() {
 									if one_to_two.enabled {
-										add_entry!(chan_id, chan.node_one, chan.node_two, one_to_two, chan.features, $fee_to_target_msat);
+										add_entry!(chan_id, *network.get_node_id(chan.node_one), *$node_id, one_to_two, chan.features, $fee_to_target_msat);
 									}
 								}
 
@@ -325,39 +340,39 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 		};
 	}
 
-	match network.get_nodes().get(target) {
+	match network.get_nodes().get(&target_node_id) {
 		None => {},
 		Some(node) => {
-			add_entries_to_cheapest_to_target_node!(node, target, 0);
+			add_entries_to_cheapest_to_target_node!(node, &target_node_id, 0);
 		},
 	}
 
-	for hop in last_hops.iter() {
-		if first_hops.is_none() || hop.src_node_id != *our_node_id { // first_hop overrules last_hops
-			if network.get_nodes().get(&hop.src_node_id).is_some() {
+	for (hop, hop_src_node_id) in last_hops.iter().zip(last_hop_src_node_ids.iter()) {
+		if first_hops.is_none() || *hop_src_node_id != our_node_id { // first_hop overrules last_hops
+			if network.get_nodes().get(hop_src_node_id).is_some() {
 				if first_hops.is_some() {
-					if let Some(&(ref first_hop, ref features)) = first_hop_targets.get(&hop.src_node_id) {
+					if let Some(&(ref first_hop, ref features)) = first_hop_targets.get(hop_src_node_id) {
 						// Currently there are no channel-context features defined, so we are a
 						// bit lazy here. In the future, we should pull them out via our
 						// ChannelManager, but there's no reason to waste the space until we
 						// need them.
-						add_entry!(first_hop, *our_node_id , hop.src_node_id, dummy_directional_info, features.to_context(), 0);
+						add_entry!(first_hop, our_node_id, *hop_src_node_id, dummy_directional_info, features.to_context(), 0);
 					}
 				}
 				// BOLT 11 doesn't allow inclusion of features for the last hop hints, which
 				// really sucks, cause we're gonna need that eventually.
-				add_entry!(hop.short_channel_id, hop.src_node_id, target, hop, ChannelFeatures::empty(), 0);
+				add_entry!(hop.short_channel_id, *hop_src_node_id, &target_node_id, hop, ChannelFeatures::empty(), 0);
 			}
 		}
 	}
 
-	while let Some(RouteGraphNode { pubkey, lowest_fee_to_node, .. }) = targets.pop() {
-		if pubkey == *our_node_id {
+	while let Some(RouteGraphNode { node_id, lowest_fee_to_node, .. }) = targets.pop() {
+		if node_id == our_node_id {
 			let mut res = vec!(dist.remove(&our_node_id).unwrap().3);
 			loop {
-				if let Some(&(_, ref features)) = first_hop_targets.get(&res.last().unwrap().pubkey) {
+				if let Some(&(_, ref features)) = first_hop_targets.get(&res.last().unwrap().node_id) {
 					res.last_mut().unwrap().node_features = features.to_context();
-				} else if let Some(node) = network.get_nodes().get(&res.last().unwrap().pubkey) {
+				} else if let Some(node) = network.get_nodes().get(&res.last().unwrap().node_id) {
 					if let Some(node_info) = node.announcement_info.as_ref() {
 						res.last_mut().unwrap().node_features = node_info.features.clone();
 					} else {
@@ -368,13 +383,13 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 					// hop, if the last hop was provided via a BOLT 11 invoice (though we
 					// should be able to extend it further as BOLT 11 does have feature
 					// flags for the last hop node itself).
-					assert!(res.last().unwrap().pubkey == *target);
+					assert!(res.last().unwrap().node_id == target_node_id);
 				}
-				if res.last().unwrap().pubkey == *target {
+				if res.last().unwrap().node_id == target_node_id {
 					break;
 				}
 
-				let new_entry = match dist.remove(&res.last().unwrap().pubkey) {
+				let new_entry = match dist.remove(&res.last().unwrap().node_id) {
 					Some(hop) => hop.3,
 					None => return Err(LightningError{err: "Failed to find a non-fee-overflowing path to the given destination", action: ErrorAction::IgnoreError}),
 				};
@@ -384,15 +399,30 @@ pub fn get_route<C: Deref, L: Deref>(our_node_id: &PublicKey, net_graph_msg_hand
 			}
 			res.last_mut().unwrap().fee_msat = final_value_msat;
 			res.last_mut().unwrap().cltv_expiry_delta = final_cltv;
-			let route = Route { paths: vec![res] };
+			let mut path = Vec::with_capacity(res.len());
+			for hop in res.drain(..) {
+				let pubkey = match hop.node_id.as_pubkey() {
+					Ok(pubkey) => pubkey,
+					Err(_) => return Err(LightningError{err: "Found a path through a node with an invalid node id", action: ErrorAction::IgnoreError}),
+				};
+				path.push(RouteHop {
+					pubkey,
+					node_features: hop.node_features,
+					short_channel_id: hop.short_channel_id,
+					channel_features: hop.channel_features,
+					fee_msat: hop.fee_msat,
+					cltv_expiry_delta: hop.cltv_expiry_delta,
+				});
+			}
+			let route = Route { paths: vec![path] };
 			log_trace!(logger, "Got route: {}", log_route!(route));
 			return Ok(route);
 		}
 
-		match network.get_nodes().get(&pubkey) {
+		match network.get_nodes().get(&node_id) {
 			None => {},
 			Some(node) => {
-				add_entries_to_cheapest_to_target_node!(node, &pubkey, lowest_fee_to_node);
+				add_entries_to_cheapest_to_target_node!(node, &node_id, lowest_fee_to_node);
 			},
 		}
 	}
@@ -424,6 +454,7 @@ mod tests {
 	use bitcoin::secp256k1::{Secp256k1, All};
 
 	use std::sync::Arc;
+	use std::time::{SystemTime, UNIX_EPOCH};
 
 	// Using the same keys for LN and BTC ids
 	fn add_channel(net_graph_msg_handler: &NetGraphMsgHandler<Arc<chaininterface::ChainWatchInterfaceUtil>, Arc<test_utils::TestLogger>>, secp_ctx: &Secp256k1<All>, node_1_privkey: &SecretKey,
@@ -504,6 +535,8 @@ mod tests {
 		let logger = Arc::new(test_utils::TestLogger::new());
 		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet));
 		let net_graph_msg_handler = NetGraphMsgHandler::new(chain_monitor, Arc::clone(&logger));
+		// channel_updates older than two weeks are rejected as stale, so base them on the current time
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
 		// Build network from our_id to node8:
 		//
 		//        -1(1)2-  node1  -1(3)2-
@@ -601,7 +634,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node1_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 1,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 1,
 			cltv_expiry_delta: 0,
 			htlc_minimum_msat: 0,
@@ -615,7 +648,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, our_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 2,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 0,
 			cltv_expiry_delta: u16::max_value(),
 			htlc_minimum_msat: 0,
@@ -626,7 +659,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node2_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 2,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 1,
 			cltv_expiry_delta: 0,
 			htlc_minimum_msat: 0,
@@ -641,7 +674,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, our_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 12,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 0,
 			cltv_expiry_delta: u16::max_value(),
 			htlc_minimum_msat: 0,
@@ -652,7 +685,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node8_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 12,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 1,
 			cltv_expiry_delta: 0,
 			htlc_minimum_msat: 0,
@@ -668,7 +701,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node1_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 3,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 0,
 			cltv_expiry_delta: (3 << 8) | 1,
 			htlc_minimum_msat: 0,
@@ -679,7 +712,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node3_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 3,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 1,
 			cltv_expiry_delta: (3 << 8) | 2,
 			htlc_minimum_msat: 0,
@@ -693,7 +726,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node2_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 4,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 0,
 			cltv_expiry_delta: (4 << 8) | 1,
 			htlc_minimum_msat: 0,
@@ -704,7 +737,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node3_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 4,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 1,
 			cltv_expiry_delta: (4 << 8) | 2,
 			htlc_minimum_msat: 0,
@@ -717,7 +750,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node8_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 13,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 0,
 			cltv_expiry_delta: (13 << 8) | 1,
 			htlc_minimum_msat: 0,
@@ -728,7 +761,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node3_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 13,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 1,
 			cltv_expiry_delta: (13 << 8) | 2,
 			htlc_minimum_msat: 0,
@@ -742,7 +775,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node3_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 6,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 0,
 			cltv_expiry_delta: (6 << 8) | 1,
 			htlc_minimum_msat: 0,
@@ -753,7 +786,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node5_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 6,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 1,
 			cltv_expiry_delta: (6 << 8) | 2,
 			htlc_minimum_msat: 0,
@@ -766,7 +799,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node5_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 11,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 0,
 			cltv_expiry_delta: (11 << 8) | 1,
 			htlc_minimum_msat: 0,
@@ -777,7 +810,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node4_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 11,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 1,
 			cltv_expiry_delta: (11 << 8) | 2,
 			htlc_minimum_msat: 0,
@@ -793,7 +826,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node3_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 7,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 0,
 			cltv_expiry_delta: (7 << 8) | 1,
 			htlc_minimum_msat: 0,
@@ -804,7 +837,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node6_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 7,
-			timestamp: 1,
+			timestamp: now + 1,
 			flags: 1,
 			cltv_expiry_delta: (7 << 8) | 2,
 			htlc_minimum_msat: 0,
@@ -838,7 +871,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node2_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 4,
-			timestamp: 2,
+			timestamp: now + 2,
 			flags: 2, // to disable
 			cltv_expiry_delta: 0,
 			htlc_minimum_msat: 0,
@@ -849,7 +882,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, our_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 12,
-			timestamp: 2,
+			timestamp: now + 2,
 			flags: 2, // to disable
 			cltv_expiry_delta: 0,
 			htlc_minimum_msat: 0,
@@ -896,7 +929,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, node2_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 4,
-			timestamp: 3,
+			timestamp: now + 3,
 			flags: 0, // to enable
 			cltv_expiry_delta: (4 << 8) | 1,
 			htlc_minimum_msat: 0,
@@ -907,7 +940,7 @@ mod tests {
 		update_channel(&net_graph_msg_handler, &secp_ctx, our_privkey, UnsignedChannelUpdate {
 			chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash(),
 			short_channel_id: 12,
-			timestamp: 3,
+			timestamp: now + 3,
 			flags: 0, // to enable
 			cltv_expiry_delta: u16::max_value(),
 			htlc_minimum_msat: 0,
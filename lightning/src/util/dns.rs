@@ -0,0 +1,296 @@
+//! A minimal DNS wire-format codec for the networking crates which speak DNS themselves (BOLT 10
+//! seed queries in lightning-net-tokio and headers-over-DNS in lightning-block-sync), so that they
+//! all validate responses the same way.
+//!
+//! Only single-question recursive queries with an EDNS OPT record are supported. Responses are
+//! only accepted if they carry the ID we picked and echo back exactly the question we asked, which
+//! (together with unpredictable IDs from QueryIdSource) is what makes spoofing responses hard for
+//! an off-path attacker.
+
+use util::byte_utils;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// The record type for IPv4 addresses.
+pub const TYPE_A: u16 = 1;
+/// The record type for IPv6 addresses.
+pub const TYPE_AAAA: u16 = 28;
+/// The record type for service locations, as served by BOLT 10 DNS seeds.
+pub const TYPE_SRV: u16 = 33;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_AD: u16 = 0x0020;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// Picks query IDs which cannot be predicted by anyone who isn't watching our queries.
+///
+/// Each ID is a SipHash of a counter keyed by a std RandomState, whose keys are drawn from the
+/// operating system's random number generator, so this needs no extra dependencies while still
+/// being far better than a sequential or time-based ID.
+pub struct QueryIdSource {
+	keys: RandomState,
+	counter: u64,
+}
+
+impl QueryIdSource {
+	/// Creates a new QueryIdSource with fresh random keys.
+	pub fn new() -> Self {
+		QueryIdSource { keys: RandomState::new(), counter: 0 }
+	}
+
+	/// Gets the ID to use for the next query.
+	pub fn next_id(&mut self) -> u16 {
+		self.counter += 1;
+		let mut hasher = self.keys.build_hasher();
+		hasher.write_u64(self.counter);
+		hasher.finish() as u16
+	}
+}
+
+/// Builds a recursive query for the given name and record type, with an EDNS OPT record advertising
+/// the given UDP payload size. If request_dnssec is set we also ask the resolver to validate the
+/// answer (by setting the AD bit per RFC 6840 and the DO bit in the OPT record).
+///
+/// Returns None if the name is not a valid DNS name.
+pub fn build_query(id: u16, name: &str, qtype: u16, edns_payload_size: u16, request_dnssec: bool) -> Option<Vec<u8>> {
+	let mut query = Vec::with_capacity(12 + name.len() + 2 + 4 + 11);
+	query.extend_from_slice(&byte_utils::be16_to_array(id));
+	let flags = if request_dnssec { FLAG_RD | FLAG_AD } else { FLAG_RD };
+	query.extend_from_slice(&byte_utils::be16_to_array(flags));
+	// One question and one additional (OPT) record
+	query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]);
+
+	let name = if name.ends_with('.') { &name[..name.len() - 1] } else { name };
+	for label in name.split('.') {
+		if label.is_empty() || label.len() > 63 { return None; }
+		query.push(label.len() as u8);
+		query.extend_from_slice(label.as_bytes());
+	}
+	query.push(0);
+	if query.len() - 12 > 255 { return None; }
+	query.extend_from_slice(&byte_utils::be16_to_array(qtype));
+	query.extend_from_slice(&byte_utils::be16_to_array(CLASS_IN));
+
+	// EDNS OPT pseudo-record: root name, our UDP payload size as the class, and the DO flag (if
+	// any) in the TTL field.
+	query.push(0);
+	query.extend_from_slice(&byte_utils::be16_to_array(TYPE_OPT));
+	query.extend_from_slice(&byte_utils::be16_to_array(edns_payload_size));
+	query.extend_from_slice(&[0, 0, if request_dnssec { 0x80 } else { 0 }, 0]);
+	query.extend_from_slice(&[0, 0]);
+	Some(query)
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16, ()> {
+	if msg.len() < pos + 2 { return Err(()); }
+	Ok(byte_utils::slice_to_be16(&msg[pos..pos + 2]))
+}
+
+/// Reads a (possibly compressed) name starting at pos in msg, returning it (without a trailing
+/// '.') along with the position just after it.
+pub fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), ()> {
+	let mut name = String::new();
+	let mut end_pos = None;
+	// Bound the number of pointers we follow so that loops can't hang us.
+	let mut pointers_followed = 0;
+	loop {
+		let len = *msg.get(pos).ok_or(())? as usize;
+		if len & 0xc0 == 0xc0 {
+			let ptr = ((len & 0x3f) << 8) | *msg.get(pos + 1).ok_or(())? as usize;
+			if end_pos.is_none() { end_pos = Some(pos + 2); }
+			pointers_followed += 1;
+			if pointers_followed > 64 { return Err(()); }
+			pos = ptr;
+		} else if len & 0xc0 != 0 {
+			return Err(());
+		} else if len == 0 {
+			return Ok((name, end_pos.unwrap_or(pos + 1)));
+		} else {
+			let label = msg.get(pos + 1..pos + 1 + len).ok_or(())?;
+			if !name.is_empty() { name.push('.'); }
+			name += ::std::str::from_utf8(label).map_err(|_| ())?;
+			pos += 1 + len;
+		}
+	}
+}
+
+/// DNS names are compared case-insensitively (and resolvers may randomize the case of the names
+/// they query upstream), but only for ASCII letters.
+fn names_match(a: &str, b: &str) -> bool {
+	fn lower(c: u8) -> u8 { if c >= b'A' && c <= b'Z' { c + (b'a' - b'A') } else { c } }
+	a.len() == b.len() && a.bytes().zip(b.bytes()).all(|(a, b)| lower(a) == lower(b))
+}
+
+/// A response to a query built by build_query, as returned by parse_response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+	/// Whether the response was truncated, in which case the query should be retried over TCP.
+	pub truncated: bool,
+	/// Whether the resolver set the Authenticated Data bit, ie claims to have validated the answer
+	/// with DNSSEC. We do no validation of our own, so this is only as trustworthy as the resolver
+	/// and the path to it.
+	pub authenticated: bool,
+	/// The position and length of the record data of each answer of the queried type, in the
+	/// order the resolver returned them. Answers of other types (eg CNAMEs which the resolver has
+	/// already followed for us) are skipped, and owner names are not checked.
+	pub answers: Vec<(usize, usize)>,
+}
+
+/// Parses a response to the given query (as built by build_query), failing if it doesn't carry the
+/// query's ID or doesn't echo back the query's question (name, type and class).
+///
+/// A name which does not exist (NXDOMAIN) results in a response with no answers, any other
+/// error response code results in an Err.
+pub fn parse_response(query: &[u8], resp: &[u8]) -> Result<Response, ()> {
+	if query.len() < 12 || resp.len() < 12 { return Err(()); }
+	if resp[0..2] != query[0..2] { return Err(()); }
+	let flags = read_u16(resp, 2)?;
+	if flags & FLAG_QR == 0 { return Err(()); }
+	let truncated = flags & FLAG_TC != 0;
+	let authenticated = flags & FLAG_AD != 0;
+
+	// Check the question before even looking at the response code, so that an NXDOMAIN for some
+	// other name can't be passed off as an answer to ours.
+	if read_u16(resp, 4)? != 1 { return Err(()); }
+	let (query_name, query_pos) = read_name(query, 12)?;
+	let (resp_name, mut pos) = read_name(resp, 12)?;
+	if !names_match(&query_name, &resp_name) { return Err(()); }
+	let qtype = read_u16(query, query_pos)?;
+	if read_u16(resp, pos)? != qtype || read_u16(resp, pos + 2)? != read_u16(query, query_pos + 2)? {
+		return Err(());
+	}
+	pos += 4;
+
+	match flags & RCODE_MASK {
+		0 => {},
+		RCODE_NXDOMAIN => return Ok(Response { truncated, authenticated, answers: Vec::new() }),
+		_ => return Err(()),
+	}
+
+	let ancount = read_u16(resp, 6)?;
+	let mut answers = Vec::new();
+	for _ in 0..ancount {
+		pos = read_name(resp, pos)?.1;
+		let rr_type = read_u16(resp, pos)?;
+		let rr_class = read_u16(resp, pos + 2)?;
+		let rdlen = read_u16(resp, pos + 8)? as usize;
+		pos += 10;
+		if resp.len() < pos + rdlen { return Err(()); }
+		if rr_type == qtype && rr_class == CLASS_IN {
+			answers.push((pos, rdlen));
+		}
+		pos += rdlen;
+	}
+	Ok(Response { truncated, authenticated, answers })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Builds a response to the given query with the given flags, copying the question (minus the
+	/// OPT record) and appending an A answer pointing back at the question's name.
+	fn build_response(query: &[u8], flags: u16, answer: Option<[u8; 4]>) -> Vec<u8> {
+		let mut resp = query[..query.len() - 11].to_vec();
+		resp[2..4].copy_from_slice(&byte_utils::be16_to_array(flags | FLAG_QR));
+		resp[10] = 0;
+		resp[11] = 0;
+		if let Some(addr) = answer {
+			resp[7] = 1;
+			resp.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+			resp.extend_from_slice(&addr);
+		}
+		resp
+	}
+
+	#[test]
+	fn test_query_building() {
+		let query = build_query(0x1234, "0.0.bitcoinheaders.net.", TYPE_AAAA, 1232, true).unwrap();
+		assert_eq!(&query[..12], &[0x12, 0x34, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 1]);
+		assert_eq!(&query[12..40], &b"\x010\x010\x0ebitcoinheaders\x03net\x00\x00\x1c\x00\x01"[..]);
+		assert_eq!(&query[40..], &[0, 0, 41, 0x04, 0xd0, 0, 0, 0x80, 0, 0, 0]);
+
+		let query = build_query(0x1234, "seed.example.com", TYPE_SRV, 4096, false).unwrap();
+		assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+		assert_eq!(&query[query.len() - 11..], &[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+
+		assert!(build_query(0, "bad..name", TYPE_A, 4096, false).is_none());
+		let long_label: String = ::std::iter::repeat('a').take(64).collect();
+		assert!(build_query(0, &long_label, TYPE_A, 4096, false).is_none());
+	}
+
+	#[test]
+	fn test_response_parsing() {
+		let query = build_query(42, "node.Seed.example.com", TYPE_A, 4096, false).unwrap();
+		let resp = build_response(&query, FLAG_RD | FLAG_AD, Some([10, 0, 0, 1]));
+		let parsed = parse_response(&query, &resp).unwrap();
+		assert_eq!(parsed, Response { truncated: false, authenticated: true, answers: vec![(resp.len() - 4, 4)] });
+
+		// Resolvers may change the case of the question
+		let mut recased = resp.clone();
+		recased[18] = b'S';
+		recased[19] = b'E';
+		assert_eq!(parse_response(&query, &recased), Ok(parsed));
+
+		// Responses with a different ID, or to a different question, are rejected
+		let mut other_id = resp.clone();
+		other_id[1] = 43;
+		assert!(parse_response(&query, &other_id).is_err());
+		let mut other_name = resp.clone();
+		other_name[13] = b'm';
+		assert!(parse_response(&query, &other_name).is_err());
+		let aaaa_query = build_query(42, "node.Seed.example.com", TYPE_AAAA, 4096, false).unwrap();
+		assert!(parse_response(&aaaa_query, &resp).is_err());
+		let mut no_question = resp.clone();
+		no_question[5] = 0;
+		assert!(parse_response(&query, &no_question).is_err());
+		// As are truncated messages
+		assert!(parse_response(&query, &resp[..resp.len() - 1]).is_err());
+
+		let resp = build_response(&query, FLAG_RD | FLAG_TC, None);
+		assert_eq!(parse_response(&query, &resp), Ok(Response { truncated: true, authenticated: false, answers: Vec::new() }));
+
+		let resp = build_response(&query, FLAG_RD | RCODE_NXDOMAIN, None);
+		assert_eq!(parse_response(&query, &resp), Ok(Response { truncated: false, authenticated: false, answers: Vec::new() }));
+		// An NXDOMAIN for some other name is still rejected
+		let mut other_name = resp.clone();
+		other_name[13] = b'm';
+		assert!(parse_response(&query, &other_name).is_err());
+		// SERVFAIL
+		let resp = build_response(&query, FLAG_RD | 2, None);
+		assert!(parse_response(&query, &resp).is_err());
+	}
+
+	#[test]
+	fn test_read_name() {
+		let query = build_query(42, "seed.example.com", TYPE_SRV, 4096, false).unwrap();
+		let mut msg = query[..query.len() - 11].to_vec();
+		let target_pos = msg.len();
+		msg.push(4);
+		msg.extend_from_slice(b"node");
+		msg.extend_from_slice(&[0xc0, 12]);
+		assert_eq!(read_name(&msg, target_pos), Ok(("node.seed.example.com".to_owned(), msg.len())));
+
+		// A pointer loop is rejected rather than followed forever.
+		let loop_pos = msg.len() - 2;
+		msg[loop_pos + 1] = loop_pos as u8;
+		assert!(read_name(&msg, target_pos).is_err());
+	}
+
+	#[test]
+	fn test_query_ids() {
+		let mut ids = QueryIdSource::new();
+		let first: Vec<u16> = (0..16).map(|_| ids.next_id()).collect();
+		// Consecutive IDs aren't sequential (with overwhelming probability)
+		assert!(first.windows(2).any(|w| w[1] != w[0].wrapping_add(1)));
+		let mut other_ids = QueryIdSource::new();
+		let second: Vec<u16> = (0..16).map(|_| other_ids.next_id()).collect();
+		assert_ne!(first, second);
+	}
+}
@@ -721,6 +721,18 @@ impl<T: Writeable> Writeable for Mutex<T> {
 	}
 }
 
+impl<T: Readable> Readable for Box<T> {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let t: T = Readable::read(r)?;
+		Ok(Box::new(t))
+	}
+}
+impl<T: Writeable> Writeable for Box<T> {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		(**self).write(w)
+	}
+}
+
 impl<A: Readable, B: Readable> Readable for (A, B) {
 	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
 		let a: A = Readable::read(r)?;
@@ -1,5 +1,5 @@
 use chain::chaininterface;
-use chain::chaininterface::{ConfirmationTarget, ChainError, ChainWatchInterface};
+use chain::chaininterface::{ConfirmationTarget, ChainError, ChainWatchInterface, UtxoLookup, UtxoLookupResult};
 use chain::transaction::OutPoint;
 use chain::keysinterface;
 use ln::channelmonitor;
@@ -12,7 +12,7 @@ use util::events;
 use util::logger::{Logger, Level, Record};
 use util::ser::{Readable, Writer, Writeable};
 
-use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
 use bitcoin::blockdata::script::{Builder, Script};
 use bitcoin::blockdata::block::Block;
 use bitcoin::blockdata::opcodes;
@@ -287,18 +287,21 @@ impl TestKeysInterface {
 
 pub struct TestChainWatcher {
 	pub utxo_ret: Mutex<Result<(Script, u64), ChainError>>,
+	pub watched_outpoints: Mutex<Vec<(Txid, u32)>>,
 }
 
 impl TestChainWatcher {
 	pub fn new() -> Self {
 		let script = Builder::new().push_opcode(opcodes::OP_TRUE).into_script();
-		Self { utxo_ret: Mutex::new(Ok((script, u64::max_value()))) }
+		Self { utxo_ret: Mutex::new(Ok((script, u64::max_value()))), watched_outpoints: Mutex::new(Vec::new()) }
 	}
 }
 
 impl ChainWatchInterface for TestChainWatcher {
 	fn install_watch_tx(&self, _txid: &Txid, _script_pub_key: &Script) { }
-	fn install_watch_outpoint(&self, _outpoint: (Txid, u32), _out_script: &Script) { }
+	fn install_watch_outpoint(&self, outpoint: (Txid, u32), _out_script: &Script) {
+		self.watched_outpoints.lock().unwrap().push(outpoint);
+	}
 	fn watch_all_txn(&self) { }
 	fn filter_block<'a>(&self, _block: &'a Block) -> (Vec<&'a Transaction>, Vec<u32>) {
 		(Vec::new(), Vec::new())
@@ -309,3 +312,25 @@ impl ChainWatchInterface for TestChainWatcher {
 		self.utxo_ret.lock().unwrap().clone()
 	}
 }
+
+/// A UtxoLookup which returns utxo_ret, or UtxoLookupResult::Async if it is None.
+pub struct TestUtxoLookup {
+	pub utxo_ret: Mutex<Option<Result<(OutPoint, TxOut), ChainError>>>,
+	pub lookups: Mutex<Vec<u64>>,
+}
+
+impl TestUtxoLookup {
+	pub fn new() -> Self {
+		Self { utxo_ret: Mutex::new(None), lookups: Mutex::new(Vec::new()) }
+	}
+}
+
+impl UtxoLookup for TestUtxoLookup {
+	fn get_utxo(&self, _genesis_hash: &BlockHash, short_channel_id: u64) -> UtxoLookupResult {
+		self.lookups.lock().unwrap().push(short_channel_id);
+		match *self.utxo_ret.lock().unwrap() {
+			Some(ref res) => UtxoLookupResult::Sync(res.clone()),
+			None => UtxoLookupResult::Async,
+		}
+	}
+}